@@ -0,0 +1,132 @@
+//! Display of right-to-left and mixed-direction text.
+//!
+//! Terminals print characters in the order they are written, so text is
+//! reordered from logical to visual order with the Unicode bidirectional
+//! algorithm before it is drawn.
+
+use std::borrow::Cow;
+
+use unicode_bidi::{BidiInfo, Level};
+
+use crate::width::{graphemes, width};
+
+/// Returns `text` in the order it should appear on screen, left to right.
+#[must_use]
+pub fn visual(text: &str) -> Cow<'_, str> {
+    let info = BidiInfo::new(text, None);
+    if !info.has_rtl() {
+        return Cow::Borrowed(text);
+    }
+    let mut visual = String::with_capacity(text.len());
+    for para in &info.paragraphs {
+        visual.push_str(&info.reorder_line(para, para.range.clone()));
+    }
+    Cow::Owned(visual)
+}
+
+/// Returns a row of styled pieces of text, given in logical order, in the
+/// order they should appear on screen. The row reads left to right as a
+/// whole, so right-to-left runs are reversed where they stand and a piece
+/// split between runs comes back as several pieces.
+#[must_use]
+pub fn visual_row<S: Clone>(row: Vec<(S, String)>) -> Vec<(S, String)> {
+    let text: String = row.iter().map(|(_, piece)| piece.as_str()).collect();
+    let info = BidiInfo::new(&text, Some(Level::ltr()));
+    if !info.has_rtl() {
+        return row;
+    }
+    let mut starts = Vec::with_capacity(row.len());
+    let mut offset = 0;
+    for (_, piece) in &row {
+        starts.push(offset);
+        offset += piece.len();
+    }
+    let mut visual = Vec::with_capacity(row.len());
+    for para in &info.paragraphs {
+        let (levels, runs) = info.visual_runs(para, para.range.clone());
+        for run in runs {
+            let rtl = levels[run.start].is_rtl();
+            let mut parts = vec![];
+            let mut start = run.start;
+            while start < run.end {
+                let piece = starts.partition_point(|&s| s <= start) - 1;
+                let end = starts
+                    .get(piece + 1)
+                    .map_or(run.end, |&next| next.min(run.end));
+                parts.push((piece, start..end));
+                start = end;
+            }
+            if rtl {
+                parts.reverse();
+            }
+            for (piece, range) in parts {
+                let part = if rtl {
+                    let mut part: Vec<_> = graphemes(&text[range]).collect();
+                    part.reverse();
+                    part.concat()
+                } else {
+                    text[range].to_owned()
+                };
+                visual.push((row[piece].0.clone(), part));
+            }
+        }
+    }
+    visual
+}
+
+/// Returns the column, counted in terminal columns of
+/// [`visual`]`(text)`, where a character typed at byte offset `cursor`
+/// would appear.
+#[must_use]
+pub fn cursor_column(text: &str, cursor: usize) -> usize {
+    let info = BidiInfo::new(text, None);
+    if !info.has_rtl() {
+        return width(&text[..cursor]);
+    }
+    // the insertion point follows the character before it in reading
+    // order, or precedes the first one
+    let (anchor, after) = match text[..cursor].char_indices().next_back() {
+        Some((last, c)) => (last, last + c.len_utf8()),
+        None => (0, 0),
+    };
+    let mut column = 0;
+    for para in &info.paragraphs {
+        let (levels, runs) = info.visual_runs(para, para.range.clone());
+        for run in runs {
+            let rtl = levels[run.start].is_rtl();
+            if run.contains(&anchor) {
+                // in an RTL run, following is being on the left side
+                return if rtl {
+                    column + width(&text[after..run.end])
+                } else {
+                    column + width(&text[run.start..after])
+                };
+            }
+            column += width(&text[run]);
+        }
+    }
+    column
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_keep_their_styles_when_reordered() {
+        let row = vec![
+            (1, "#1 ".to_owned()),
+            (2, "אבג ".to_owned()),
+            (3, "דה".to_owned()),
+        ];
+        let visual = visual_row(row);
+        assert_eq!(
+            visual,
+            [
+                (1, "#1 ".to_owned()),
+                (3, "הד".to_owned()),
+                (2, " גבא".to_owned()),
+            ]
+        );
+    }
+}
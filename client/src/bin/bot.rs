@@ -0,0 +1,120 @@
+//! A bot that answers `!help`, `!ping` and `!uptime`, in the channel and
+//! in private messages.
+//!
+//! ```text
+//! bot <address> [name] [password]
+//! ```
+//!
+//! It shows how to talk to a server with [`client::Server`] without the
+//! terminal UI, and exits when the server disconnects it.
+
+use std::env;
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::commands::{ClientCommand, ContentType, ServerCommand};
+use common::UserId;
+
+use client::{Server, Trust};
+
+/// How long to wait between polls of the server.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const HELP: &str = "Commands: !help, !ping, !uptime";
+
+/// The answer to `message`, if it is a command.
+fn answer(message: &str, started: Instant) -> Option<String> {
+    match message.split_whitespace().next()? {
+        "!help" => Some(HELP.to_owned()),
+        "!ping" => Some("pong".to_owned()),
+        "!uptime" => {
+            let secs = started.elapsed().as_secs();
+            Some(format!(
+                "Up for {}h {}m {}s",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let Some(addr) = args.next() else {
+        eprintln!("usage: bot <address> [name] [password]");
+        exit(2);
+    };
+    let name = args.next().unwrap_or_else(|| "bot".to_owned());
+    let password = args.next();
+    let mut server = match Server::connect(&addr, &Trust::default()) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to connect to {addr}: {e}");
+            exit(1);
+        }
+    };
+    server.send(&ClientCommand::Connect {
+        name,
+        invite: None,
+        credential: None,
+        password: password.clone(),
+    });
+    server.flush();
+    let started = Instant::now();
+    let mut own_id = None::<UserId>;
+    while server.connected() {
+        while let Some(msg) = server.poll() {
+            match msg {
+                ServerCommand::Welcome { user_id, .. } => {
+                    println!("Connected as user {user_id}");
+                    own_id = Some(user_id);
+                }
+                ServerCommand::NameTaken { name, suggestions } => {
+                    let Some(next) = suggestions.into_iter().next() else {
+                        eprintln!("The name '{name}' is taken");
+                        exit(1);
+                    };
+                    println!("The name '{name}' is taken, trying '{next}'");
+                    server.send(&ClientCommand::Connect {
+                        name: next,
+                        invite: None,
+                        credential: None,
+                        password: password.clone(),
+                    });
+                }
+                ServerCommand::ConnectRejected { reason } => {
+                    eprintln!("Connection rejected: {reason}");
+                    exit(1);
+                }
+                ServerCommand::Message {
+                    user_id, message, ..
+                } if Some(user_id) != own_id => {
+                    if let Some(reply) = answer(&message, started) {
+                        server.send(&ClientCommand::Message {
+                            message: reply,
+                            content_type: ContentType::Plain,
+                            quote: None,
+                        });
+                    }
+                }
+                ServerCommand::Whisper {
+                    user_id, message, ..
+                } if Some(user_id) != own_id => {
+                    if let Some(reply) = answer(&message, started) {
+                        server.send(&ClientCommand::Whisper {
+                            target_user_id: user_id,
+                            message: reply,
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+        server.flush();
+        thread::sleep(POLL_INTERVAL);
+    }
+    println!("Disconnected");
+}
@@ -0,0 +1,155 @@
+//! Chat messages written to files, by `/save` and `--log-file`.
+//!
+//! Files ending in `.json` get one JSON object per message and line:
+//!
+//! ```text
+//! {"time":1718000000,"channel":"lobby","name":"alice","message":"hi"}
+//! ```
+//!
+//! with `channel` null for private messages. Other files get one line of
+//! text per message, like `2024-06-10 08:13:20 #lobby alice: hi`.
+
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, LineWriter, Result, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+
+/// A chat message as written to a file.
+#[derive(Debug, Clone)]
+pub struct ChatRecord {
+    /// When the message was sent, in seconds since the Unix epoch.
+    pub time: u64,
+    /// Channel the message was sent to, `None` for private messages.
+    pub channel: Option<String>,
+    /// Name of the sender.
+    pub name: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    fn of(path: &Path) -> Self {
+        if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+        {
+            Self::Json
+        } else {
+            Self::Text
+        }
+    }
+}
+
+impl ChatRecord {
+    fn write(&self, mut w: impl Write, format: Format) -> Result<()> {
+        match format {
+            Format::Text => writeln!(w, "{}", TextRecord(self)),
+            Format::Json => writeln!(w, "{}", JsonRecord(self)),
+        }
+    }
+}
+
+struct TextRecord<'a>(&'a ChatRecord);
+
+impl Display for TextRecord<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let record = self.0;
+        let time = i64::try_from(record.time)
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .map(|time| time.with_timezone(&Local));
+        if let Some(time) = time {
+            write!(f, "{} ", time.format("%Y-%m-%d %H:%M:%S"))?;
+        }
+        match &record.channel {
+            Some(channel) => write!(f, "#{channel} ")?,
+            None => f.write_str("[whisper] ")?,
+        }
+        // continuation lines are indented, so every message starts a line
+        write!(
+            f,
+            "{}: {}",
+            record.name,
+            record.message.replace('\n', "\n    ")
+        )
+    }
+}
+
+struct JsonRecord<'a>(&'a ChatRecord);
+
+impl Display for JsonRecord<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let record = self.0;
+        write!(f, "{{\"time\":{},\"channel\":", record.time)?;
+        match &record.channel {
+            Some(channel) => write_json_string(f, channel)?,
+            None => f.write_str("null")?,
+        }
+        f.write_str(",\"name\":")?;
+        write_json_string(f, &record.name)?;
+        f.write_str(",\"message\":")?;
+        write_json_string(f, &record.message)?;
+        f.write_str("}")
+    }
+}
+
+fn write_json_string(
+    f: &mut std::fmt::Formatter<'_>,
+    s: &str,
+) -> std::fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Writes `records` to a new file at `path`, replacing what was there.
+pub fn save<'a>(
+    path: &Path,
+    records: impl IntoIterator<Item = &'a ChatRecord>,
+) -> Result<()> {
+    let format = Format::of(path);
+    let mut file = BufWriter::new(File::create(path)?);
+    for record in records {
+        record.write(&mut file, format)?;
+    }
+    file.flush()
+}
+
+/// A file messages are appended to as they arrive.
+#[derive(Debug)]
+pub struct ChatLog {
+    file: LineWriter<File>,
+    format: Format,
+}
+
+impl ChatLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ),
+            format: Format::of(path),
+        })
+    }
+
+    pub fn write(&mut self, record: &ChatRecord) -> Result<()> {
+        record.write(&mut self.file, self.format)
+    }
+}
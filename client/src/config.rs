@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::warn;
+
+use common::{ChannelId, DataSize, DEFAULT_MAX_FRAME_SIZE};
+
+use crate::keymap::Keymap;
+use crate::links;
+use crate::notify::{parse_duration, NotifyMode, QuietHours, RoomNotify};
+use crate::theme::Theme;
+
+/// Client settings, read from a `key = value` file.
+///
+/// The file is looked up at `$TCPCHAT_CONFIG`, then
+/// `$XDG_CONFIG_HOME/tcpchat/client.conf`, then
+/// `$HOME/.config/tcpchat/client.conf`. A missing file gives the defaults.
+#[derive(Debug)]
+pub struct Config {
+    /// Automatically reconnect with the first suggested name when the
+    /// requested one is taken.
+    pub accept_name_suggestion: bool,
+    /// Upper bound on screen redraws per second.
+    pub max_fps: u32,
+    /// Largest frame sent to or accepted from the server, in bytes.
+    pub max_frame_size: DataSize,
+    /// Times to try getting back to the server after losing the
+    /// connection, 0 to not try.
+    pub reconnect_attempts: u32,
+    /// How long to wait for the server to answer a connect, e.g. `10s`.
+    pub connect_timeout: Duration,
+    /// Show when messages were sent, can be changed with `/timestamps`.
+    pub timestamps: bool,
+    /// Tell users when their whispers were read, and show when ours were
+    /// with a double check mark.
+    pub read_receipts: bool,
+    /// Shell command used by `/translate`, see
+    /// [`Translator`](crate::translate::Translator).
+    pub translate_command: Option<String>,
+    /// Language incoming messages are translated to.
+    pub translate_language: String,
+    /// Shell command that reads its stdin out loud, used by `/tts`.
+    pub tts_command: String,
+    /// Daily do-not-disturb range, e.g. `22:00-08:00`.
+    pub quiet_hours: Option<QuietHours>,
+    /// `default`, `colorblind` or `monochrome`; monochrome unless set if
+    /// `NO_COLOR` is.
+    pub theme: Theme,
+    /// Password or token sent when connecting, if the server asks for one.
+    pub credential: Option<String>,
+    /// PEM file with the certificates `tls://` servers are checked against,
+    /// instead of the usual web authorities.
+    pub tls_ca: Option<PathBuf>,
+    /// Fingerprints of the certificates `tls://` servers presented the
+    /// first time, by `host:port`, from `fingerprint.<host:port>` keys
+    /// that are added when connecting.
+    pub fingerprints: HashMap<String, String>,
+    /// Which messages ring the terminal bell, changed with `/notify`.
+    pub notify: NotifyMode,
+    /// Notification settings by room, from `notify.<room>` keys.
+    pub room_notify: HashMap<String, RoomNotify>,
+    /// Key bindings, from `key.<action>` keys like `key.exit = ctrl+q`.
+    pub keymap: Keymap,
+    /// File every chat message received is appended to, see
+    /// [`chat_log`](crate::chat_log); also set with `--log-file`.
+    pub log_file: Option<PathBuf>,
+    /// Where files accepted with `/accept` are saved.
+    pub download_dir: PathBuf,
+    /// Opens links, given the URL as its last argument; `xdg-open`, or
+    /// `open` on macOS, unless set.
+    pub open_command: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            accept_name_suggestion: false,
+            max_fps: 30,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            reconnect_attempts: 5,
+            connect_timeout: Duration::from_secs(10),
+            timestamps: true,
+            read_receipts: true,
+            translate_command: None,
+            translate_language: "en".to_owned(),
+            tts_command: "espeak".to_owned(),
+            quiet_hours: None,
+            theme: Theme::from_env(),
+            credential: None,
+            tls_ca: None,
+            fingerprints: HashMap::new(),
+            notify: NotifyMode::Off,
+            room_notify: HashMap::new(),
+            keymap: Keymap::default(),
+            log_file: None,
+            download_dir: PathBuf::from("."),
+            open_command: links::default_command().to_owned(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let mut config = Self::default();
+        let Some(path) = Self::path() else {
+            return Ok(config);
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(config),
+            Err(e) => return Err(e),
+        };
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                warn!(
+                    "{}:{}: expected `key = value`",
+                    path.display(),
+                    line_no + 1
+                );
+                continue;
+            };
+            if let Err(e) = config.set(key.trim(), value.trim()) {
+                warn!("{}:{}: {e}", path.display(), line_no + 1);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Applies command line arguments, which take precedence over the
+    /// file: `--log-file <path>`.
+    pub fn apply_args(
+        &mut self,
+        args: impl IntoIterator<Item = String>,
+    ) -> std::result::Result<(), String> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--log-file" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| "--log-file needs a path".to_owned())?;
+                    self.log_file = Some(path.into());
+                }
+                _ => return Err(format!("unknown argument `{arg}`")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `key = value` to the config file, replacing an earlier value
+    /// of `key` and keeping everything else as it was.
+    pub fn save_setting(key: &str, value: &str) -> Result<()> {
+        let path = Self::path().ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "no place for a config file")
+        })?;
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let setting = format!("{key} = {value}");
+        let mut replaced = false;
+        let mut lines: Vec<_> = text
+            .lines()
+            .map(|line| match line.split_once('=') {
+                Some((k, _)) if k.trim() == key && !replaced => {
+                    replaced = true;
+                    setting.as_str()
+                }
+                _ => line,
+            })
+            .collect();
+        if !replaced {
+            lines.push(&setting);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, lines.join("\n") + "\n")
+    }
+
+    fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("TCPCHAT_CONFIG") {
+            return Some(path.into());
+        }
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var_os("HOME").map(|h| PathBuf::from(h).join(".config"))
+            })?;
+        Some(config_dir.join("tcpchat").join("client.conf"))
+    }
+
+    fn set(
+        &mut self,
+        key: &str,
+        value: &str,
+    ) -> std::result::Result<(), String> {
+        match key {
+            "accept_name_suggestion" => {
+                self.accept_name_suggestion = parse_bool(value)?;
+            }
+            "max_fps" => {
+                self.max_fps =
+                    value.parse().ok().filter(|&fps| fps > 0).ok_or_else(
+                        || format!("expected a positive number, got `{value}`"),
+                    )?;
+            }
+            "max_frame_size" => {
+                self.max_frame_size = value
+                    .parse()
+                    .ok()
+                    .filter(|&size| size > u32::from(u16::MAX))
+                    .ok_or_else(|| {
+                        format!("expected a number over 65535, got `{value}`")
+                    })?;
+            }
+            "reconnect_attempts" => {
+                self.reconnect_attempts = value
+                    .parse()
+                    .map_err(|_| format!("expected a number, got `{value}`"))?;
+            }
+            "connect_timeout" => {
+                self.connect_timeout = parse_duration(value)
+                    .ok()
+                    .filter(|timeout| !timeout.is_zero())
+                    .ok_or_else(|| {
+                        format!("expected a duration like `10s`, got `{value}`")
+                    })?;
+            }
+            "translate_command" => {
+                self.translate_command = Some(value.to_owned());
+            }
+            "translate_language" => {
+                value.clone_into(&mut self.translate_language)
+            }
+            "tts_command" => value.clone_into(&mut self.tts_command),
+            "quiet_hours" => self.quiet_hours = Some(value.parse()?),
+            "timestamps" => self.timestamps = parse_bool(value)?,
+            "read_receipts" => self.read_receipts = parse_bool(value)?,
+            "theme" => self.theme = value.parse()?,
+            "credential" => self.credential = Some(value.to_owned()),
+            "tls_ca" => self.tls_ca = Some(value.into()),
+            "log_file" => self.log_file = Some(value.into()),
+            "download_dir" => self.download_dir = value.into(),
+            "open_command" => value.clone_into(&mut self.open_command),
+            "notify" => {
+                self.notify = value.parse().map_err(|()| {
+                    format!("expected `on`, `off` or `mentions`, got `{value}`")
+                })?;
+            }
+            _ => {
+                if let Some(action) = key.strip_prefix("key.") {
+                    return self.keymap.set(action, value);
+                }
+                if let Some(addr) =
+                    key.strip_prefix("fingerprint.").filter(|a| !a.is_empty())
+                {
+                    self.fingerprints.insert(addr.to_owned(), value.to_owned());
+                    return Ok(());
+                }
+                match key.strip_prefix("notify.") {
+                    Some(room) if ChannelId::valid_name(room) => {
+                        self.room_notify
+                            .insert(room.to_owned(), value.parse()?);
+                    }
+                    _ => return Err(format!("unknown setting `{key}`")),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> std::result::Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("expected a boolean, got `{value}`")),
+    }
+}
@@ -0,0 +1,298 @@
+//! End-to-end encrypted whispers, started with `/e2e`.
+//!
+//! The two clients exchange fresh X25519 public keys through the server
+//! and derive a ChaCha20-Poly1305 key for each direction from the shared
+//! secret, so the server only relays ciphertext. Both show a fingerprint
+//! of the exchanged keys, which differs if anyone swapped them on the way.
+//! Once whispers with a user were encrypted, they are not sent in plain
+//! text again, even after the keys are gone.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use log::warn;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest::{digest, SHA256};
+use ring::error::Unspecified;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+
+use common::commands::{ClientCommand, ServerCommand};
+use common::{Bytes, UserId};
+
+/// Bytes of the key hash shown as the fingerprint.
+const FINGERPRINT_LEN: usize = 10;
+
+/// The keys of an encrypted conversation with one user.
+struct Session {
+    send: LessSafeKey,
+    receive: LessSafeKey,
+    /// Counter of the next message sent.
+    sent: u64,
+    /// Lowest counter of a message not received yet, older ones are
+    /// replays.
+    received: u64,
+    fingerprint: String,
+}
+
+/// The encrypted conversations of a connection.
+pub struct Sessions {
+    rng: SystemRandom,
+    /// Key pairs sent to users who have not answered with theirs yet.
+    pending: HashMap<UserId, (EphemeralPrivateKey, Vec<u8>)>,
+    sessions: HashMap<UserId, Session>,
+    /// Names of the users keys were ever agreed with, kept across
+    /// connections.
+    encrypted: HashSet<String>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sessions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            rng: SystemRandom::new(),
+            pending: HashMap::new(),
+            sessions: HashMap::new(),
+            encrypted: HashSet::new(),
+        }
+    }
+
+    /// Starts agreeing on keys with `peer`, or on new ones if there are
+    /// some already, returning the command to send.
+    pub fn start(
+        &mut self,
+        peer: UserId,
+    ) -> Result<ClientCommand, Unspecified> {
+        let (private_key, public_key) = self.key_pair()?;
+        self.pending.insert(peer, (private_key, public_key.clone()));
+        Ok(ClientCommand::KeyExchange {
+            target_user_id: peer,
+            public_key: Bytes(public_key),
+        })
+    }
+
+    /// Whether keys were offered to `peer`, who has not answered yet.
+    #[must_use]
+    pub fn is_pending(&self, peer: UserId) -> bool {
+        self.pending.contains_key(&peer)
+    }
+
+    /// The fingerprint of the keys agreed with `peer`, if there are any.
+    #[must_use]
+    pub fn fingerprint(&self, peer: UserId) -> Option<&str> {
+        self.sessions.get(&peer).map(|s| s.fingerprint.as_str())
+    }
+
+    /// Remembers that whispers with the user called `name` are encrypted,
+    /// so they are refused rather than sent in plain text once the keys
+    /// are gone.
+    pub fn set_encrypted(&mut self, name: String) {
+        self.encrypted.insert(name);
+    }
+
+    /// Whether whispers with the user called `name` were encrypted, so
+    /// they must not be sent without keys.
+    #[must_use]
+    pub fn was_encrypted(&self, name: &str) -> bool {
+        self.encrypted.contains(name)
+    }
+
+    /// Agrees on keys from what the server sent, returning a command to
+    /// send if the other user started the exchange.
+    pub fn handle(
+        &mut self,
+        msg: &ServerCommand,
+        own_id: Option<UserId>,
+    ) -> Option<ClientCommand> {
+        match msg {
+            ServerCommand::KeyExchange {
+                user_id,
+                public_key,
+                ..
+            } if Some(*user_id) != own_id => {
+                let (reply, (private_key, own_key)) =
+                    match self.pending.remove(user_id) {
+                        Some(pair) => (None, pair),
+                        None => {
+                            let (private_key, own_key) =
+                                self.key_pair().ok()?;
+                            let reply = ClientCommand::KeyExchange {
+                                target_user_id: *user_id,
+                                public_key: Bytes(own_key.clone()),
+                            };
+                            (Some(reply), (private_key, own_key))
+                        }
+                    };
+                match Session::agree(private_key, &own_key, &public_key.0) {
+                    Ok(session) => {
+                        self.sessions.insert(*user_id, session);
+                        reply
+                    }
+                    Err(Unspecified) => {
+                        warn!("Failed to agree on keys with user {user_id}");
+                        self.sessions.remove(user_id);
+                        None
+                    }
+                }
+            }
+            // ids are reused, a new user must not get the old one's keys
+            ServerCommand::RemoveUser { user_id } => {
+                self.pending.remove(user_id);
+                self.sessions.remove(user_id);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Seals `text` for `peer`, if keys were agreed with them.
+    pub fn encrypt(
+        &mut self,
+        peer: UserId,
+        text: &str,
+    ) -> Option<ClientCommand> {
+        let session = self.sessions.get_mut(&peer)?;
+        let counter = session.sent;
+        session.sent += 1;
+        let mut data = text.as_bytes().to_vec();
+        session
+            .send
+            .seal_in_place_append_tag(nonce(counter), Aad::empty(), &mut data)
+            .ok()?;
+        Some(ClientCommand::EncryptedWhisper {
+            target_user_id: peer,
+            counter,
+            ciphertext: Bytes(data),
+        })
+    }
+
+    /// Turns an encrypted whisper into the plain one it was, both those
+    /// received and the server's copy of those sent. Anything else, and
+    /// whispers that can't be decrypted, are returned as they are.
+    pub fn decrypt(
+        &mut self,
+        msg: ServerCommand,
+        own_id: Option<UserId>,
+    ) -> ServerCommand {
+        let ServerCommand::EncryptedWhisper {
+            user_id,
+            target_user_id,
+            counter,
+            ciphertext,
+        } = &msg
+        else {
+            return msg;
+        };
+        let sent = Some(*user_id) == own_id;
+        let peer = if sent { target_user_id } else { user_id };
+        let Some(session) = self.sessions.get_mut(peer) else {
+            return msg;
+        };
+        let key = if sent {
+            &session.send
+        } else if *counter < session.received {
+            warn!("Dropping a replayed message from user {user_id}");
+            return msg;
+        } else {
+            &session.receive
+        };
+        let mut data = ciphertext.0.clone();
+        let Ok(plain) =
+            key.open_in_place(nonce(*counter), Aad::empty(), &mut data)
+        else {
+            return msg;
+        };
+        let Ok(message) = String::from_utf8(plain.to_vec()) else {
+            return msg;
+        };
+        if !sent {
+            session.received = counter + 1;
+        }
+        ServerCommand::Whisper {
+            user_id: *user_id,
+            target_user_id: *target_user_id,
+            message,
+        }
+    }
+
+    /// Forgets every key, as they are only good for one connection, but
+    /// not who they were agreed with.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.sessions.clear();
+    }
+
+    fn key_pair(&self) -> Result<(EphemeralPrivateKey, Vec<u8>), Unspecified> {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &self.rng)?;
+        let public_key = private_key.compute_public_key()?.as_ref().to_vec();
+        Ok((private_key, public_key))
+    }
+}
+
+impl Session {
+    /// Derives the keys of both directions from the shared secret. The
+    /// user with the lower public key sends with the first one.
+    fn agree(
+        private_key: EphemeralPrivateKey,
+        own_key: &[u8],
+        peer_key: &[u8],
+    ) -> Result<Self, Unspecified> {
+        let (low, high) = if own_key < peer_key {
+            (own_key, peer_key)
+        } else {
+            (peer_key, own_key)
+        };
+        let transcript = [low, high].concat();
+        let (low_to_high, high_to_low) = agreement::agree_ephemeral(
+            private_key,
+            &UnparsedPublicKey::new(&X25519, peer_key),
+            |secret| {
+                let prk = Salt::new(HKDF_SHA256, &transcript).extract(secret);
+                let key = |info: &[u8]| -> Result<LessSafeKey, Unspecified> {
+                    let info = [info];
+                    let okm = prk.expand(&info, &CHACHA20_POLY1305)?;
+                    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+                };
+                Ok::<_, Unspecified>((
+                    key(b"tcpchat e2e low to high")?,
+                    key(b"tcpchat e2e high to low")?,
+                ))
+            },
+        )??;
+        let (send, receive) = if own_key == low {
+            (low_to_high, high_to_low)
+        } else {
+            (high_to_low, low_to_high)
+        };
+        let hash = digest(&SHA256, &transcript);
+        let mut fingerprint = String::new();
+        for (i, byte) in hash.as_ref()[..FINGERPRINT_LEN].iter().enumerate() {
+            if i > 0 && i % 2 == 0 {
+                fingerprint.push(' ');
+            }
+            let _ = write!(fingerprint, "{byte:02X}");
+        }
+        Ok(Self {
+            send,
+            receive,
+            sent: 0,
+            received: 0,
+            fingerprint,
+        })
+    }
+}
+
+/// The nonce of message `counter`, unique as each key is only used for
+/// one direction of one conversation.
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
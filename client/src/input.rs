@@ -0,0 +1,295 @@
+//! Editing of the line being typed.
+
+use std::collections::HashMap;
+
+use crate::width::graphemes;
+
+/// Number of sent lines remembered.
+const HISTORY_LIMIT: usize = 500;
+
+/// What the last edit was, to merge runs of typing into one undo step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Insert,
+    Delete,
+    Other,
+}
+
+/// The typing buffer with undo and redo, and the lines sent before.
+#[derive(Debug, Default)]
+pub struct Input {
+    text: String,
+    /// Byte offset in `text` where typing goes, always between grapheme
+    /// clusters.
+    cursor: usize,
+    /// Earlier texts and their cursors.
+    undo: Vec<(String, usize)>,
+    redo: Vec<(String, usize)>,
+    last_edit: Option<Edit>,
+    /// Submitted lines, oldest first.
+    history: Vec<String>,
+    /// The history entry being shown, `None` for the line being typed.
+    browsing: Option<usize>,
+    /// The line being typed while browsing the history.
+    draft: String,
+    /// Edited copies of history entries, which stay as they were; only
+    /// kept until the next submit.
+    forks: HashMap<usize, String>,
+}
+
+impl Input {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    #[must_use]
+    pub const fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Remembers the current text before an edit, unless the edit
+    /// continues a run of the same kind.
+    fn checkpoint(&mut self, edit: Edit) {
+        if edit == Edit::Other || self.last_edit != Some(edit) {
+            self.undo.push((self.text.clone(), self.cursor));
+        }
+        self.redo.clear();
+        self.last_edit = Some(edit);
+    }
+
+    pub fn insert(&mut self, c: char) {
+        // every word is its own undo step
+        let edit = if c.is_whitespace() {
+            Edit::Other
+        } else {
+            Edit::Insert
+        };
+        self.checkpoint(edit);
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// Inserts `text` at the cursor, with its line breaks turned into
+    /// `\n`, which terminals often send as `\r`.
+    pub fn paste(&mut self, text: &str) {
+        let text = &text.replace("\r\n", "\n").replace('\r', "\n");
+        self.checkpoint(Edit::Other);
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    /// Deletes the grapheme cluster before the cursor.
+    pub fn backspace(&mut self) {
+        let start = self.previous_boundary();
+        if start == self.cursor {
+            return;
+        }
+        self.checkpoint(Edit::Delete);
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    /// Deletes the grapheme cluster after the cursor.
+    pub fn delete(&mut self) {
+        let end = self.next_boundary();
+        if end == self.cursor {
+            return;
+        }
+        self.checkpoint(Edit::Delete);
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    /// Deletes the word before the cursor, and the whitespace between
+    /// them.
+    pub fn delete_word(&mut self) {
+        let start = self.word_start();
+        if start == self.cursor {
+            return;
+        }
+        self.checkpoint(Edit::Other);
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn clear(&mut self) {
+        if self.text.is_empty() {
+            return;
+        }
+        self.checkpoint(Edit::Other);
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// Returns whether there was anything to undo.
+    pub fn undo(&mut self) -> bool {
+        let Some((text, cursor)) = self.undo.pop() else {
+            return false;
+        };
+        let text = std::mem::replace(&mut self.text, text);
+        self.redo
+            .push((text, std::mem::replace(&mut self.cursor, cursor)));
+        self.last_edit = None;
+        true
+    }
+
+    /// Returns whether there was anything to redo.
+    pub fn redo(&mut self) -> bool {
+        let Some((text, cursor)) = self.redo.pop() else {
+            return false;
+        };
+        let text = std::mem::replace(&mut self.text, text);
+        self.undo
+            .push((text, std::mem::replace(&mut self.cursor, cursor)));
+        self.last_edit = None;
+        true
+    }
+
+    /// Start of the grapheme cluster before the cursor.
+    fn previous_boundary(&self) -> usize {
+        graphemes(&self.text[..self.cursor])
+            .last()
+            .map_or(self.cursor, |g| self.cursor - g.len())
+    }
+
+    /// End of the grapheme cluster after the cursor.
+    fn next_boundary(&self) -> usize {
+        graphemes(&self.text[self.cursor..])
+            .next()
+            .map_or(self.cursor, |g| self.cursor + g.len())
+    }
+
+    /// Start of the word before the cursor, skipping whitespace first.
+    fn word_start(&self) -> usize {
+        let before = &self.text[..self.cursor];
+        let trimmed = before.trim_end().len();
+        before[..trimmed]
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + 1)
+    }
+
+    /// End of the word after the cursor, skipping whitespace first.
+    fn word_end(&self) -> usize {
+        let after = &self.text[self.cursor..];
+        let skipped = after.len() - after.trim_start().len();
+        self.cursor
+            + after[skipped..]
+                .find(char::is_whitespace)
+                .map_or(after.len(), |i| skipped + i)
+    }
+
+    /// Puts the cursor at byte offset `cursor`, returning whether it
+    /// moved.
+    fn move_to(&mut self, cursor: usize) -> bool {
+        if cursor == self.cursor {
+            return false;
+        }
+        self.cursor = cursor;
+        // typing somewhere else is a new undo step
+        self.last_edit = None;
+        true
+    }
+
+    /// Moves the cursor one grapheme cluster left, returning whether it
+    /// moved; the same goes for the other movements.
+    pub fn left(&mut self) -> bool {
+        self.move_to(self.previous_boundary())
+    }
+
+    pub fn right(&mut self) -> bool {
+        self.move_to(self.next_boundary())
+    }
+
+    /// Moves the cursor to the start of the word before it.
+    pub fn word_left(&mut self) -> bool {
+        self.move_to(self.word_start())
+    }
+
+    /// Moves the cursor to the end of the word after it.
+    pub fn word_right(&mut self) -> bool {
+        self.move_to(self.word_end())
+    }
+
+    pub fn home(&mut self) -> bool {
+        self.move_to(0)
+    }
+
+    pub fn end(&mut self) -> bool {
+        self.move_to(self.text.len())
+    }
+
+    /// Shows the history entry before the current one, returning whether
+    /// there was one.
+    pub fn older(&mut self) -> bool {
+        let current = self.browsing.unwrap_or(self.history.len());
+        if current == 0 {
+            return false;
+        }
+        self.browse(current - 1);
+        true
+    }
+
+    /// Shows the history entry after the current one, or the line being
+    /// typed after the last one, returning whether it moved.
+    pub fn newer(&mut self) -> bool {
+        let Some(current) = self.browsing else {
+            return false;
+        };
+        self.browse(current + 1);
+        true
+    }
+
+    /// Leaves the current line, keeping what was typed or changed, and
+    /// shows entry `index`, the line being typed if it's past the end.
+    fn browse(&mut self, index: usize) {
+        let text = std::mem::take(&mut self.text);
+        match self.browsing {
+            None => self.draft = text,
+            Some(i) if text == self.history[i] => {
+                self.forks.remove(&i);
+            }
+            Some(i) => {
+                self.forks.insert(i, text);
+            }
+        }
+        self.browsing = (index < self.history.len()).then_some(index);
+        self.text = match self.browsing {
+            None => std::mem::take(&mut self.draft),
+            Some(i) => self.forks.get(&i).unwrap_or(&self.history[i]).clone(),
+        };
+        self.cursor = self.text.len();
+        self.undo.clear();
+        self.redo.clear();
+        self.last_edit = None;
+    }
+
+    /// Empties the buffer for the next line, returning what was typed. The
+    /// line is added to the history, also when it was an edited entry.
+    pub fn submit(&mut self) -> String {
+        self.undo.clear();
+        self.redo.clear();
+        self.last_edit = None;
+        self.browsing = None;
+        self.draft.clear();
+        self.forks.clear();
+        self.cursor = 0;
+        let text = std::mem::take(&mut self.text);
+        if self.history.last() != Some(&text) {
+            if self.history.len() == HISTORY_LIMIT {
+                self.history.remove(0);
+            }
+            self.history.push(text.clone());
+        }
+        text
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// A key together with the modifiers held with it, written like `esc`,
+/// `ctrl+q` or `alt+pageup` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyBinding {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    const fn new(code: KeyCode) -> Self {
+        Self {
+            code,
+            modifiers: KeyModifiers::NONE,
+        }
+    }
+
+    /// Whether `event` is this key with exactly these modifiers. Shift is
+    /// ignored for characters, as it is already part of the character.
+    #[must_use]
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        let mut modifiers = event.modifiers;
+        if let KeyCode::Char(_) = event.code {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        self.code == event.code && self.modifiers == modifiers
+    }
+}
+
+impl Display for KeyBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl+"),
+            (KeyModifiers::ALT, "Alt+"),
+            (KeyModifiers::SHIFT, "Shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(name)?;
+            }
+        }
+        write!(f, "{}", self.code)
+    }
+}
+
+impl FromStr for KeyBinding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<_> = s.split('+').map(str::trim).collect();
+        // `+` itself, as in `ctrl++`
+        if parts.len() > 1 && parts[parts.len() - 1].is_empty() {
+            parts.pop();
+            let last = parts.len() - 1;
+            parts[last] = "+";
+        }
+        let (key, modifier_names) = parts
+            .split_last()
+            .ok_or_else(|| "expected a key".to_owned())?;
+        let mut modifiers = KeyModifiers::NONE;
+        for name in modifier_names {
+            modifiers |= match name.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" | "meta" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier `{name}`")),
+            };
+        }
+        let code = match key.to_lowercase().as_str() {
+            "esc" | "escape" => KeyCode::Esc,
+            "enter" | "return" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            lower => {
+                let mut chars = key.chars();
+                match (chars.next(), chars.next()) {
+                    // terminals report Ctrl and Alt letters in lowercase
+                    (Some(c), None) if !modifiers.is_empty() => {
+                        KeyCode::Char(c.to_ascii_lowercase())
+                    }
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => lower
+                        .strip_prefix('f')
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| (1..=24).contains(n))
+                        .map(KeyCode::F)
+                        .ok_or_else(|| format!("unknown key `{key}`"))?,
+                }
+            }
+        };
+        Ok(Self { code, modifiers })
+    }
+}
+
+/// The rebindable keys of the UI, set with `key.<action>` settings.
+/// Text editing keys (arrows, Backspace and the Ctrl shortcuts) are fixed,
+/// but a binding here takes precedence over them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keymap {
+    pub exit: KeyBinding,
+    pub send: KeyBinding,
+    pub scroll_up: KeyBinding,
+    pub scroll_down: KeyBinding,
+    pub scroll_top: KeyBinding,
+    pub scroll_bottom: KeyBinding,
+    /// Opens and closes the roster panel.
+    pub roster: KeyBinding,
+    /// Reveals the newest hidden spoiler.
+    pub spoiler: KeyBinding,
+    /// Shows the tab after the current one, Alt and a digit shows the
+    /// tab with that number.
+    pub next_tab: KeyBinding,
+    /// Opens the newest link shown in the browser.
+    pub open_link: KeyBinding,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            exit: KeyBinding::new(KeyCode::Esc),
+            send: KeyBinding::new(KeyCode::Enter),
+            scroll_up: KeyBinding::new(KeyCode::PageUp),
+            scroll_down: KeyBinding::new(KeyCode::PageDown),
+            scroll_top: KeyBinding {
+                code: KeyCode::Home,
+                modifiers: KeyModifiers::CONTROL,
+            },
+            scroll_bottom: KeyBinding {
+                code: KeyCode::End,
+                modifiers: KeyModifiers::CONTROL,
+            },
+            roster: KeyBinding::new(KeyCode::F(2)),
+            spoiler: KeyBinding::new(KeyCode::Tab),
+            next_tab: KeyBinding {
+                code: KeyCode::Tab,
+                modifiers: KeyModifiers::CONTROL,
+            },
+            open_link: KeyBinding {
+                code: KeyCode::Char('o'),
+                modifiers: KeyModifiers::CONTROL,
+            },
+        }
+    }
+}
+
+/// Something a key of the [`Keymap`] does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Exit,
+    Send,
+    ScrollUp,
+    ScrollDown,
+    ScrollTop,
+    ScrollBottom,
+    Roster,
+    Spoiler,
+    NextTab,
+    OpenLink,
+}
+
+impl Keymap {
+    /// The action bound to `event`, if any.
+    #[must_use]
+    pub fn action(&self, event: &KeyEvent) -> Option<Action> {
+        [
+            (self.exit, Action::Exit),
+            (self.send, Action::Send),
+            (self.scroll_up, Action::ScrollUp),
+            (self.scroll_down, Action::ScrollDown),
+            (self.scroll_top, Action::ScrollTop),
+            (self.scroll_bottom, Action::ScrollBottom),
+            (self.roster, Action::Roster),
+            (self.spoiler, Action::Spoiler),
+            (self.next_tab, Action::NextTab),
+            (self.open_link, Action::OpenLink),
+        ]
+        .into_iter()
+        .find(|(binding, _)| binding.matches(event))
+        .map(|(_, action)| action)
+    }
+
+    /// Binds the action named `action` to `binding`.
+    pub fn set(&mut self, action: &str, binding: &str) -> Result<(), String> {
+        let binding = binding.parse()?;
+        let slot = match action {
+            "exit" => &mut self.exit,
+            "send" => &mut self.send,
+            "scroll_up" => &mut self.scroll_up,
+            "scroll_down" => &mut self.scroll_down,
+            "scroll_top" => &mut self.scroll_top,
+            "scroll_bottom" => &mut self.scroll_bottom,
+            "roster" => &mut self.roster,
+            "spoiler" => &mut self.spoiler,
+            "next_tab" => &mut self.next_tab,
+            "open_link" => &mut self.open_link,
+            _ => return Err(format!("unknown key action `{action}`")),
+        };
+        *slot = binding;
+        Ok(())
+    }
+}
@@ -1,4 +1,19 @@
+pub mod bidi;
 pub mod channel_logger;
+pub mod chat_log;
+pub mod config;
+pub mod e2e;
+pub mod input;
+pub mod keymap;
+pub mod links;
+pub mod markdown;
+pub mod notify;
+pub mod reconnect;
 mod server;
+pub mod theme;
+pub mod transfers;
+pub mod translate;
 pub mod ui;
+pub mod users;
+pub mod width;
 pub use server::*;
@@ -0,0 +1,77 @@
+//! URLs in messages, shown as links and opened with `/open` or the
+//! `open_link` key.
+
+use std::ops::Range;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use log::warn;
+
+/// Schemes of the words treated as links.
+const SCHEMES: [&str; 2] = ["https://", "http://"];
+
+/// The byte ranges of the URLs in `text`. Punctuation ending a sentence
+/// is not part of a URL, nor are closing parentheses without an opening
+/// one in it.
+#[must_use]
+pub fn find(text: &str) -> Vec<Range<usize>> {
+    let mut links = vec![];
+    let mut from = 0;
+    while let Some((start, scheme)) = SCHEMES
+        .iter()
+        .filter_map(|scheme| Some((from + text[from..].find(scheme)?, scheme)))
+        .min()
+    {
+        let end = text[start..]
+            .find(|c: char| c.is_whitespace() || c == '<' || c == '>')
+            .map_or(text.len(), |len| start + len);
+        let url = trim_url(&text[start..end]);
+        if url.len() > scheme.len() {
+            links.push(start..start + url.len());
+        }
+        from = end;
+    }
+    links
+}
+
+fn trim_url(mut url: &str) -> &str {
+    loop {
+        url = url.trim_end_matches(|c| ".,;:!?'\"".contains(c));
+        match url.strip_suffix(')') {
+            Some(rest)
+                if url.matches(')').count() > url.matches('(').count() =>
+            {
+                url = rest;
+            }
+            _ => return url,
+        }
+    }
+}
+
+/// Opens `url` with `command`, e.g. `xdg-open`, which gets it as its last
+/// argument; the shell never sees the URL itself.
+pub fn open(command: &str, url: &str) {
+    let child = Command::new("sh")
+        .args(["-c", &format!("{command} \"$1\""), "sh", url])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match child {
+        // reap the process without blocking the UI
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => warn!("Failed to run `{command}`: {e}"),
+    }
+}
+
+/// The usual command opening URLs in a browser on this system.
+#[must_use]
+pub const fn default_command() -> &'static str {
+    if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    }
+}
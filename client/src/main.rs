@@ -1,77 +1,937 @@
+use std::collections::HashSet;
+use std::env;
 use std::io::Result;
-use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::exit;
 use std::time::Duration;
 
-use common::commands::ClientCommand;
-use log::{error, info};
+use chrono::Local;
 
-use client::ui::{UI, UIEvent};
+use common::commands::{ClientCommand, ContentType, ServerCommand};
+use common::ChannelId;
+use log::{error, info, warn};
+
+use client::ui::{UIEvent, UI};
+use client::users::{UserRegistry, DEPARTED_USER_GRACE};
 
 use client::channel_logger;
-use client::Server;
+use client::chat_log::{self, ChatLog, ChatRecord};
+use client::config::Config;
+use client::e2e::Sessions;
+use client::links;
+use client::notify::Notifier;
+use client::reconnect::Reconnect;
+use client::transfers::Transfers;
+use client::translate::Translator;
+use client::{Certificate, Connecting, Server, Trust};
+
+/// Number of results requested by `/search`.
+const SEARCH_LIMIT: u16 = 50;
+/// Number of older messages requested when scrolling past the top.
+const HISTORY_CHUNK: u16 = 50;
+
+/// Drops the connection to the server, keeping the messages it didn't
+/// answer in the outbox to send them again over the next one.
+fn disconnect(
+    server: &mut Option<Server>,
+    outbox: &mut Vec<ClientCommand>,
+    ui: &mut UI,
+) {
+    let Some(mut server) = server.take() else {
+        return;
+    };
+    let unacked = server.take_unacked();
+    if !unacked.is_empty() {
+        ui.requeue();
+        outbox.splice(0..0, unacked);
+    }
+}
+
+/// A connection being made, and what to send once it is up.
+struct PendingConnect {
+    connecting: Connecting,
+    hello: Vec<ClientCommand>,
+}
+
+/// Starts connecting as `user_name`, logging in first if `account` has
+/// the password of the name's account.
+fn connect(
+    server_addr: &str,
+    user_name: String,
+    account: Option<String>,
+    password: Option<String>,
+    invite: Option<String>,
+    config: &Config,
+) -> PendingConnect {
+    info!("Connecting to {server_addr}, `/cancel` to give up");
+    let mut hello = vec![];
+    if let Some(account) = account {
+        hello.push(ClientCommand::Login {
+            name: user_name.clone(),
+            password: account,
+        });
+    }
+    hello.push(ClientCommand::Connect {
+        name: user_name,
+        invite,
+        credential: config.credential.clone(),
+        password,
+    });
+    let trust = Trust {
+        ca: config.tls_ca.clone(),
+        on_first_use: true,
+        pinned: server_addr
+            .strip_prefix("tls://")
+            .and_then(|host| config.fingerprints.get(host))
+            .cloned(),
+    };
+    PendingConnect {
+        connecting: Server::connect_in_background(
+            server_addr,
+            trust,
+            config.connect_timeout,
+        ),
+        hello,
+    }
+}
 
+/// Pins the certificate of the `tls://` server `addr` to be trusted from
+/// now on, if it is not the one pinned already; the verifier only let
+/// another one through if an authority signed it.
+fn pin_certificate(config: &mut Config, addr: &str, certificate: Certificate) {
+    let Some(host) = addr.strip_prefix("tls://") else {
+        return;
+    };
+    let fingerprint = certificate.fingerprint;
+    match config.fingerprints.get(host) {
+        Some(pinned) if *pinned == fingerprint => return,
+        Some(_) => {
+            info!("{host} has a new certificate, signed by an authority");
+        }
+        None if certificate.signed => (),
+        None => warn!(
+            "{host} presented a certificate no authority signed, with the \
+             fingerprint {fingerprint}. Check with its admin that it is \
+             theirs, it is trusted from now on. `/fingerprint` shows it \
+             again."
+        ),
+    }
+    if let Err(e) =
+        Config::save_setting(&format!("fingerprint.{host}"), &fingerprint)
+    {
+        warn!("Failed to save the fingerprint: {e}");
+    }
+    config.fingerprints.insert(host.to_owned(), fingerprint);
+}
+
+/// Whether the connection to the `tls://` server `addr` was refused for
+/// presenting another certificate than the one pinned, telling so.
+fn certificate_changed(config: &Config, addr: &str, server: &Server) -> bool {
+    let Some(host) = addr.strip_prefix("tls://") else {
+        return false;
+    };
+    let (Some(pinned), Some(certificate)) =
+        (config.fingerprints.get(host), server.certificate())
+    else {
+        return false;
+    };
+    if certificate.signed || certificate.fingerprint == *pinned {
+        return false;
+    }
+    error!(
+        "THE CERTIFICATE OF {host} HAS CHANGED, someone may be listening \
+         in! It has the fingerprint {} instead of {pinned}. If its admin \
+         replaced it, remove `fingerprint.{host}` from the config file to \
+         trust the new one.",
+        certificate.fingerprint
+    );
+    true
+}
+
+/// Takes the server of a finished connect, saying hello to it.
+fn finish_connect(
+    pending: &mut Option<PendingConnect>,
+    config: &Config,
+) -> Option<Server> {
+    let result = pending.as_ref()?.connecting.poll()?;
+    let PendingConnect { hello, .. } = pending.take()?;
+    let mut server = result
+        .inspect_err(|e| error!("Failed to connect to the server: {e}"))
+        .ok()?;
+    server.set_max_frame_size(config.max_frame_size);
+    for command in &hello {
+        server.send(command);
+    }
+    Some(server)
+}
+
+/// Appends a received message to the `--log-file`, if there is one.
+fn log_message(chat_log: &mut Option<ChatLog>, record: ChatRecord) {
+    if let Some(chat_log) = chat_log {
+        if let Err(e) = chat_log.write(&record) {
+            error!("Failed to write to the log file: {e}");
+        }
+    }
+}
 
 fn main() -> Result<()> {
     let log_receiver = channel_logger::init_and_get_receiver();
-    let mut ui = UI::new()?;
+    let mut config = Config::load()?;
+    if let Err(e) = config.apply_args(env::args().skip(1)) {
+        eprintln!("{e}");
+        exit(2);
+    }
+    let mut ui = UI::new(&config)?;
+    let mut chat_log = config.log_file.as_deref().and_then(|path| {
+        ChatLog::open(path)
+            .inspect_err(|e| {
+                error!("Failed to open the log file {}: {e}", path.display());
+            })
+            .ok()
+    });
     let mut run = true;
     let mut server = None::<Server>;
+    let mut pending = None::<PendingConnect>;
+    let mut users = UserRegistry::new();
+    // kept to retry with another name if the first one was taken
+    let mut invite = None::<String>;
+    let mut password = None::<String>;
+    // password of the account logged in with, to log in again on reconnect
+    let mut account = None::<String>;
+    let translator = config
+        .translate_command
+        .clone()
+        .map(|c| Translator::new(c, config.translate_language.clone()));
+    // channels whose messages are translated, by name
+    let mut translating = HashSet::<String>::new();
+    let mut notifier = Notifier::new(&config);
+    // messages written while not connected, sent once we are
+    let mut outbox = Vec::<ClientCommand>::new();
+    let mut content_type = ContentType::Markdown;
+    // address and name of the current connection, to get back to it
+    let mut session = None::<(String, String)>;
+    let mut reconnect = None::<Reconnect>;
+    let mut transfers = Transfers::new(config.download_dir.clone());
+    let mut e2e = Sessions::new();
 
     while run {
+        if let Some(connected) = finish_connect(&mut pending, &config) {
+            server = Some(connected);
+        }
         if let Some(server) = &mut server {
             while let Some(msg) = server.poll() {
-                ui.add_message(msg);
+                let msg = e2e.decrypt(msg, users.own_id());
+                if let ServerCommand::NameTaken { suggestions, .. } = &msg {
+                    // a rename that clashed leaves the current name as is
+                    if let Some(name) = suggestions.first().filter(|_| {
+                        config.accept_name_suggestion
+                            && users.own_id().is_none()
+                    }) {
+                        info!("Connecting with suggested name '{name}'");
+                        if let Some((_, session_name)) = &mut session {
+                            session_name.clone_from(name);
+                        }
+                        server.send(&ClientCommand::Connect {
+                            name: name.clone(),
+                            invite: invite.clone(),
+                            credential: config.credential.clone(),
+                            password: password.clone(),
+                        });
+                    }
+                }
+                if let ServerCommand::Message {
+                    msg_id,
+                    user_id,
+                    message,
+                    time,
+                    ..
+                } = &msg
+                {
+                    log_message(
+                        &mut chat_log,
+                        ChatRecord {
+                            time: *time,
+                            channel: ui.channel().map(str::to_owned),
+                            name: users.display_name(*user_id),
+                            message: message.clone(),
+                        },
+                    );
+                    if notifier.notable(*user_id, message, ui.channel(), &users)
+                    {
+                        ui.mark_unread();
+                    }
+                    if notifier.bell(*user_id, message, ui.channel(), &users) {
+                        ui.bell()?;
+                    }
+                    notifier.message(*user_id, message, ui.channel(), &users);
+                    let translated =
+                        ui.channel().is_some_and(|c| translating.contains(c));
+                    if let Some(translator) =
+                        translator.as_ref().filter(|_| translated)
+                    {
+                        if !users.is_own(*user_id) {
+                            translator.request(*msg_id, message.clone());
+                        }
+                    }
+                }
+                if let ServerCommand::Whisper {
+                    user_id, message, ..
+                }
+                | ServerCommand::OfflineWhisper {
+                    user_id, message, ..
+                } = &msg
+                {
+                    log_message(
+                        &mut chat_log,
+                        ChatRecord {
+                            time: Local::now()
+                                .timestamp()
+                                .try_into()
+                                .unwrap_or(0),
+                            channel: None,
+                            name: users.display_name(*user_id),
+                            message: message.clone(),
+                        },
+                    );
+                    if notifier.bell(*user_id, message, None, &users) {
+                        ui.bell()?;
+                    }
+                    notifier.message(*user_id, message, None, &users);
+                }
+                if let ServerCommand::Kicked { user_id, .. }
+                | ServerCommand::Banned { user_id, .. } = &msg
+                {
+                    // coming right back would only undo the kick
+                    if users.is_own(*user_id) {
+                        session = None;
+                    }
+                }
+                if let ServerCommand::UserRenamed { user_id, name, .. } = &msg {
+                    if let Some((_, session_name)) =
+                        session.as_mut().filter(|_| users.is_own(*user_id))
+                    {
+                        session_name.clone_from(name);
+                    }
+                }
+                if let ServerCommand::Welcome { .. } = &msg {
+                    if let (Some((addr, _)), Some(certificate)) =
+                        (&session, server.certificate())
+                    {
+                        pin_certificate(&mut config, addr, certificate);
+                    }
+                    // queued messages were written in the channel we were in
+                    if let Some(channel) = reconnect
+                        .take()
+                        .and_then(|r| r.channel)
+                        .filter(|c| c != ChannelId::LOBBY_NAME)
+                    {
+                        server.send(&ClientCommand::Join { name: channel });
+                    }
+                    ui.set_reconnect_status(None);
+                    if !outbox.is_empty() {
+                        info!("Sending {} queued message(s)", outbox.len());
+                    }
+                    for message in outbox.drain(..) {
+                        server.send(&message);
+                    }
+                    server.flush();
+                    ui.mark_sent();
+                }
+                if let Some(reply) = transfers.handle(&msg, users.own_id()) {
+                    server.send(&reply);
+                }
+                if let Some(reply) = e2e.handle(&msg, users.own_id()) {
+                    server.send(&reply);
+                }
+                if let ServerCommand::KeyExchange { user_id, .. } = &msg {
+                    if let Some(fingerprint) = e2e.fingerprint(*user_id) {
+                        ui.show_encrypted(*user_id, fingerprint, &users);
+                        e2e.set_encrypted(users.display_name(*user_id));
+                    }
+                }
+                users.handle(&msg);
+                ui.add_message(msg, &users);
             }
         }
+        if let Some(translator) = &translator {
+            while let Some((msg_id, translation)) = translator.poll() {
+                ui.add_translation(msg_id, translation);
+            }
+        }
+        users.prune(DEPARTED_USER_GRACE);
         while let Ok(log) = log_receiver.try_recv() {
             ui.add_log(log);
         }
         while let Some(event) = ui.poll()? {
             match event {
                 UIEvent::Exit => run = false,
-                UIEvent::Message(msg) => {
+                UIEvent::Message { text, quote } => {
+                    let message = ClientCommand::Message {
+                        message: text.clone(),
+                        content_type,
+                        quote,
+                    };
+                    match &mut server {
+                        Some(server) if users.own_id().is_some() => {
+                            ui.add_outgoing(&text, true);
+                            server.send(&message);
+                            server.flush();
+                        }
+                        _ => {
+                            if server.is_none()
+                                && pending.is_none()
+                                && outbox.is_empty()
+                            {
+                                info!(
+                                    "Not connected, messages will be sent \
+                                     once you `/connect <address> \
+                                     <username>`."
+                                );
+                            }
+                            ui.add_outgoing(&text, false);
+                            outbox.push(message);
+                        }
+                    }
+                }
+                UIEvent::Connect {
+                    server_addr,
+                    user_name,
+                    password: new_password,
+                    invite: new_invite,
+                } => {
+                    users.clear();
+                    transfers.clear();
+                    e2e.clear();
+                    ui.reset_history();
+                    ui.leave_channel();
+                    ui.close_conversations();
+                    disconnect(&mut server, &mut outbox, &mut ui);
+                    reconnect = None;
+                    ui.set_reconnect_status(None);
+                    session = Some((server_addr.clone(), user_name.clone()));
+                    invite = new_invite;
+                    password = new_password;
+                    account = None;
+                    pending = Some(connect(
+                        &server_addr,
+                        user_name,
+                        None,
+                        password.clone(),
+                        invite.clone(),
+                        &config,
+                    ));
+                }
+                UIEvent::Login {
+                    server_addr,
+                    user_name,
+                    password: account_password,
+                } => {
+                    users.clear();
+                    transfers.clear();
+                    e2e.clear();
+                    ui.reset_history();
+                    ui.leave_channel();
+                    ui.close_conversations();
+                    disconnect(&mut server, &mut outbox, &mut ui);
+                    reconnect = None;
+                    ui.set_reconnect_status(None);
+                    session = Some((server_addr.clone(), user_name.clone()));
+                    invite = None;
+                    password = None;
+                    account = Some(account_password);
+                    pending = Some(connect(
+                        &server_addr,
+                        user_name,
+                        account.clone(),
+                        None,
+                        None,
+                        &config,
+                    ));
+                }
+                UIEvent::Register(account_password) => {
+                    match (&mut server, &session) {
+                        (Some(server), Some((_, name))) => {
+                            server.send(&ClientCommand::Register {
+                                name: name.clone(),
+                                password: account_password,
+                            });
+                        }
+                        _ => error!("Server not connected!"),
+                    }
+                }
+                UIEvent::Name(name) => match &mut server {
+                    // the session name follows once the server agrees
+                    Some(server) if users.own_id().is_some() => {
+                        server.send(&ClientCommand::Rename { new_name: name });
+                    }
+                    Some(server) => {
+                        if let Some((_, session_name)) = &mut session {
+                            session_name.clone_from(&name);
+                        }
+                        server.send(&ClientCommand::Connect {
+                            name,
+                            invite: invite.clone(),
+                            credential: config.credential.clone(),
+                            password: password.clone(),
+                        });
+                    }
+                    None => error!("Server not connected!"),
+                },
+                UIEvent::Search(query) => {
                     if let Some(server) = &mut server {
-                        server.send(&ClientCommand::Message { message: msg });
-                        server.flush();
+                        server.send(&ClientCommand::Search {
+                            query,
+                            before_msg_id: None,
+                            limit: SEARCH_LIMIT,
+                        });
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::SearchMore {
+                    query,
+                    before_msg_id,
+                } => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::Search {
+                            query,
+                            before_msg_id: Some(before_msg_id),
+                            limit: SEARCH_LIMIT,
+                        });
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::Close => ui.close(),
+                UIEvent::Theme(theme) => ui.set_theme(theme),
+                UIEvent::Timestamps(on) => ui.set_timestamps(on),
+                UIEvent::Plain => {
+                    let plain = ui.toggle_plain();
+                    content_type = if plain {
+                        ContentType::Plain
+                    } else {
+                        ContentType::Markdown
+                    };
+                    info!(
+                        "Message formatting turned {}",
+                        if plain { "off" } else { "on" }
+                    );
+                }
+                UIEvent::Fingerprint => {
+                    match server.as_ref().and_then(Server::certificate) {
+                        Some(Certificate {
+                            fingerprint,
+                            signed,
+                        }) => info!(
+                            "The server's certificate has the fingerprint \
+                             {fingerprint}, {}",
+                            if signed {
+                                "signed by an authority"
+                            } else {
+                                "trusted for being the one seen first"
+                            }
+                        ),
+                        None => error!("Not connected over TLS"),
+                    }
+                }
+                UIEvent::NetStats => {
+                    if let Some(server) = &mut server {
+                        info!("{}", server.sample_stats());
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::LoadHistory { before_msg_id } => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::GetHistory {
+                            before_msg_id,
+                            limit: HISTORY_CHUNK,
+                        });
+                    } else {
+                        ui.reset_history();
+                    }
+                }
+                UIEvent::SetRole { user_id, role } => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::SetRole { user_id, role });
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::Kick {
+                    target,
+                    reason,
+                    ban,
+                } => match &mut server {
+                    Some(server) => match users.find(&target) {
+                        Some(user_id) => server.send(&if ban {
+                            ClientCommand::Ban { user_id, reason }
+                        } else {
+                            ClientCommand::Kick { user_id, reason }
+                        }),
+                        None => error!("No user '{target}' is online"),
+                    },
+                    None => error!("Server not connected!"),
+                },
+                UIEvent::CreateInvite {
+                    uses,
+                    valid_minutes,
+                } => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::CreateInvite {
+                            uses,
+                            valid_minutes,
+                        });
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::RevokeInvite(token) => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::RevokeInvite { token });
                     } else {
                         error!("Server not connected!");
+                    }
+                }
+                UIEvent::Translate(on) => match ui.channel() {
+                    _ if translator.is_none() => {
+                        error!("Set `translate_command` in the config first");
+                    }
+                    Some(channel) => {
+                        if on {
+                            translating.insert(channel.to_owned());
+                        } else {
+                            translating.remove(channel);
+                        }
                         info!(
-                            "Use `/connect <address> <username>` to connect."
+                            "Translation turned {} in #{channel}",
+                            if on { "on" } else { "off" }
                         );
                     }
+                    None => error!("Join a channel first"),
+                },
+                UIEvent::Tts(mode) => {
+                    if let Err(e) = notifier.set_tts(mode) {
+                        error!("{e}");
+                    }
                 }
-                UIEvent::Connect {
-                    server_addr,
-                    user_name,
-                } => {
-                    server = TcpStream::connect(server_addr)
-                        .inspect_err(|e| {
-                            error!("Failed to connect to the server: {e}")
-                        })
-                        .ok()
-                        .and_then(|s| Server::new(s).inspect_err(|e| 
-                            error!("Failed to connect to the server: {e}")
-                        ).ok())
-                        .map(|mut s| {
-                            s.send(&ClientCommand::Connect {
-                                name: user_name,
+                UIEvent::Dnd(duration) => {
+                    notifier.set_dnd(duration);
+                    match duration {
+                        Some(d) => info!(
+                            "Notifications muted for {} minute(s)",
+                            d.as_secs().div_ceil(60)
+                        ),
+                        None => info!("Notifications unmuted"),
+                    }
+                }
+                UIEvent::Notify(mode) => {
+                    notifier.set_bell(mode);
+                    if let Err(e) =
+                        Config::save_setting("notify", &mode.to_string())
+                    {
+                        warn!("Failed to save the setting: {e}");
+                    }
+                    info!("Bell for new messages: {mode}");
+                }
+                UIEvent::RoomNotify { room, setting } => {
+                    let Some(room) =
+                        room.or_else(|| ui.channel().map(str::to_owned))
+                    else {
+                        error!("Not in a room, name one with `/notify room <setting> <room>`");
+                        continue;
+                    };
+                    if let Err(e) = Config::save_setting(
+                        &format!("notify.{room}"),
+                        &setting.to_string(),
+                    ) {
+                        warn!("Failed to save the setting: {e}");
+                    }
+                    info!("Notifications for #{room}: {setting}");
+                    notifier.set_room(room, setting);
+                }
+                UIEvent::Whisper { target, text } => match &mut server {
+                    Some(server) => match users.find(&target) {
+                        Some(target_user_id)
+                            if e2e.is_pending(target_user_id) =>
+                        {
+                            error!(
+                                "Waiting for {target} to agree on keys, the \
+                                 message was not sent"
+                            );
+                        }
+                        Some(target_user_id) => {
+                            let whisper = e2e.encrypt(target_user_id, &text);
+                            let name = users.display_name(target_user_id);
+                            let whisper = match whisper {
+                                Some(whisper) => whisper,
+                                None if e2e.was_encrypted(&name) => {
+                                    error!(
+                                        "The keys agreed with {name} are \
+                                         gone, `/e2e {name}` to agree on \
+                                         new ones, the message was not sent"
+                                    );
+                                    continue;
+                                }
+                                None => ClientCommand::Whisper {
+                                    target_user_id,
+                                    message: text,
+                                },
+                            };
+                            server.send(&whisper);
+                            server.flush();
+                        }
+                        None if e2e.was_encrypted(&target) => {
+                            error!(
+                                "Whispers with {target} are encrypted, they \
+                                 can't be kept for them while they are \
+                                 offline, the message was not sent"
+                            );
+                        }
+                        None => {
+                            server.send(&ClientCommand::OfflineWhisper {
+                                name: target.clone(),
+                                message: text,
                             });
-                            s
-                        })
+                            server.flush();
+                            info!(
+                                "{target} is not online, they will get the \
+                                 message when they connect"
+                            );
+                        }
+                    },
+                    None => error!("Server not connected!"),
+                },
+                UIEvent::Forward { msg_id, channel } => {
+                    if let Some(server) = &mut server {
+                        server
+                            .send(&ClientCommand::Forward { msg_id, channel });
+                        server.flush();
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::React { msg_id, emoji } => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::React { msg_id, emoji });
+                        server.flush();
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::Join(name) => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::Join { name });
+                        server.flush();
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::Save(path) => {
+                    let path = path.unwrap_or_else(|| {
+                        PathBuf::from(format!(
+                            "tcpchat-{}-{}.txt",
+                            ui.channel().unwrap_or("log"),
+                            Local::now().format("%Y%m%d-%H%M%S")
+                        ))
+                    });
+                    let records = ui.records();
+                    match chat_log::save(&path, &records) {
+                        Ok(()) => info!(
+                            "Saved {} message(s) to {}",
+                            records.len(),
+                            path.display()
+                        ),
+                        Err(e) => {
+                            error!("Failed to save {}: {e}", path.display());
+                        }
+                    }
+                }
+                UIEvent::Topic(topic) => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::SetTopic { topic });
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::ServerInfo => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::ServerInfo);
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::RoomStats => {
+                    if let Some(server) = &mut server {
+                        server.send(&ClientCommand::RoomStats);
+                    } else {
+                        error!("Server not connected!");
+                    }
                 }
+                UIEvent::OpenLink(msg_id) => match ui.link(msg_id) {
+                    Some(url) => links::open(&config.open_command, &url),
+                    None => error!("No link to open"),
+                },
+                UIEvent::SendFile { path, target } => match &mut server {
+                    Some(server) => {
+                        let Some(target) = target else {
+                            error!("Name who to send the file to");
+                            continue;
+                        };
+                        let Some(user_id) = users.find(&target) else {
+                            error!("No user '{target}' is online");
+                            continue;
+                        };
+                        match transfers.offer(&path, user_id) {
+                            Ok(offer) => server.send(&offer),
+                            Err(e) => {
+                                error!(
+                                    "Failed to read {}: {e}",
+                                    path.display()
+                                );
+                            }
+                        }
+                    }
+                    None => error!("Server not connected!"),
+                },
+                UIEvent::Encrypt(target) => {
+                    match &mut server {
+                        Some(server) => {
+                            let Some(target) = target else {
+                                error!("Name who to encrypt the whispers with");
+                                continue;
+                            };
+                            let Some(user_id) = users.find(&target) else {
+                                error!("No user '{target}' is online");
+                                continue;
+                            };
+                            if users.is_own(user_id) {
+                                error!("There are no keys to agree on with yourself");
+                                continue;
+                            }
+                            match e2e.start(user_id) {
+                                Ok(exchange) => {
+                                    info!("Agreeing on keys with {target}");
+                                    server.send(&exchange);
+                                }
+                                Err(_) => error!("Failed to generate a key"),
+                            }
+                        }
+                        None => error!("Server not connected!"),
+                    }
+                }
+                UIEvent::AcceptFile(transfer_id) => match &mut server {
+                    Some(server) => match transfers.accept(transfer_id) {
+                        Ok(accept) => server.send(&accept),
+                        Err(e) => error!("Failed to accept the file: {e}"),
+                    },
+                    None => error!("Server not connected!"),
+                },
+                UIEvent::RejectFile(transfer_id) => match &mut server {
+                    Some(server) => match transfers.reject(transfer_id) {
+                        Some(reject) => server.send(&reject),
+                        None => error!("There is no transfer {transfer_id}"),
+                    },
+                    None => error!("Server not connected!"),
+                },
+                UIEvent::ListUsers => {
+                    if let Some(server) = &mut server {
+                        ui.show_roster();
+                        server.send(&ClientCommand::ListUsers);
+                    } else {
+                        error!("Server not connected!");
+                    }
+                }
+                UIEvent::CancelConnect => match pending.take() {
+                    Some(cancelled) => {
+                        info!(
+                            "Stopped connecting to {}",
+                            cancelled.connecting.addr()
+                        );
+                        reconnect = None;
+                        ui.set_reconnect_status(None);
+                    }
+                    None => error!("Not connecting to any server"),
+                },
                 UIEvent::Disconnect => {
-                    server = None
+                    pending = None;
+                    disconnect(&mut server, &mut outbox, &mut ui);
+                    transfers.clear();
+                    e2e.clear();
+                    session = None;
+                    reconnect = None;
+                    ui.set_reconnect_status(None);
+                    ui.leave_channel();
                 }
             }
         }
         ui.render()?;
-        if let Some(s) = &mut server {
-            if !s.connected() {
-                server = None;
+        // sends are queued, anything not written yet goes out here
+        if let Some(server) = &mut server {
+            if let Some(up_to_msg_id) = ui.newly_read() {
+                server.send(&ClientCommand::MarkRead { up_to_msg_id });
+            }
+            if let Some(user_id) = ui.newly_read_whispers() {
+                if config.read_receipts {
+                    server.send(&ClientCommand::MarkWhispersRead { user_id });
+                }
+            }
+            server.flush();
+            transfers.send_chunks(server);
+        }
+        if server.as_ref().is_some_and(|s| !s.connected()) {
+            if let (Some((addr, _)), Some(server)) = (&session, &server) {
+                // trying again won't help
+                if certificate_changed(&config, addr, server) {
+                    session = None;
+                    reconnect = None;
+                    ui.set_reconnect_status(None);
+                }
+            }
+            let channel = ui.channel().map(str::to_owned);
+            disconnect(&mut server, &mut outbox, &mut ui);
+            ui.leave_channel();
+            // a connection that drops before the welcome is another failed
+            // attempt of the reconnect under way
+            if let Some((addr, name)) = session.clone().filter(|_| {
+                reconnect.is_none() && config.reconnect_attempts > 0
+            }) {
+                warn!("Lost the connection to the server, reconnecting");
+                let r = Reconnect::new(
+                    addr,
+                    name,
+                    channel,
+                    config.reconnect_attempts,
+                );
+                ui.set_reconnect_status(Some(r.status()));
+                reconnect = Some(r);
+            }
+        }
+        if let Some(r) = reconnect
+            .as_mut()
+            .filter(|r| server.is_none() && pending.is_none() && r.due())
+        {
+            if let Some(attempt) = r.attempt() {
+                info!("Reconnecting to {} (attempt {attempt})", r.addr);
+                users.clear();
+                transfers.clear();
+                e2e.clear();
+                pending = Some(connect(
+                    &r.addr,
+                    r.name.clone(),
+                    account.clone(),
+                    password.clone(),
+                    invite.clone(),
+                    &config,
+                ));
+                ui.set_reconnect_status(Some(r.status()));
+            } else {
+                error!("Could not get back to the server, giving up");
+                reconnect = None;
+                ui.set_reconnect_status(None);
             }
         }
         std::thread::sleep(Duration::from_millis(10));
     }
     Ok(())
 }
-
@@ -0,0 +1,108 @@
+//! The markdown subset used by [`ContentType::Markdown`] messages:
+//! `**bold**`, `*italic*` or `_italic_`, `` `inline code` `` and
+//! ```` ```code blocks``` ````, plus `||spoilers||`. Markers without a closing counterpart are
+//! kept as text.
+//!
+//! [`ContentType::Markdown`]: common::commands::ContentType::Markdown
+
+/// How a run of text is emphasized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub code: bool,
+    pub code_block: bool,
+    /// Hidden until the reader asks to see it.
+    pub spoiler: bool,
+}
+
+/// A run of text with the same style.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+/// Splits `text` into styled spans, dropping the markers.
+#[must_use]
+pub fn parse(text: &str) -> Vec<Span> {
+    let mut spans = vec![];
+    let mut current = String::new();
+    let mut style = Style::default();
+    let mut code: Option<&str> = None;
+    let mut italic: Option<&str> = None;
+    let mut prev: Option<char> = None;
+    let mut rest = text;
+    let mut flush = |current: &mut String, style: Style| {
+        if !current.is_empty() {
+            spans.push(Span {
+                text: std::mem::take(current),
+                style,
+            });
+        }
+    };
+    'outer: while let Some(c) = rest.chars().next() {
+        if let Some(delim) = code {
+            if let Some(after) = rest.strip_prefix(delim) {
+                flush(&mut current, style);
+                code = None;
+                style.code = false;
+                style.code_block = false;
+                rest = after;
+                continue;
+            }
+        } else {
+            for delim in ["```", "`"] {
+                if let Some(after) = rest.strip_prefix(delim) {
+                    if after.contains(delim) {
+                        flush(&mut current, style);
+                        code = Some(delim);
+                        style.code = delim == "`";
+                        style.code_block = delim == "```";
+                        rest = after;
+                        continue 'outer;
+                    }
+                }
+            }
+            if let Some(after) = rest.strip_prefix("||") {
+                if style.spoiler || after.contains("||") {
+                    flush(&mut current, style);
+                    style.spoiler = !style.spoiler;
+                    rest = after;
+                    continue;
+                }
+            }
+            if let Some(after) = rest.strip_prefix("**") {
+                if style.bold || after.contains("**") {
+                    flush(&mut current, style);
+                    style.bold = !style.bold;
+                    rest = after;
+                    continue;
+                }
+            }
+            for delim in ["*", "_"] {
+                let Some(after) = rest.strip_prefix(delim) else {
+                    continue;
+                };
+                let closes = italic == Some(delim);
+                // `snake_case` words are not emphasis
+                let opens = italic.is_none()
+                    && !prev.is_some_and(char::is_alphanumeric)
+                    && !after.starts_with(delim)
+                    && after.contains(delim);
+                if closes || opens {
+                    flush(&mut current, style);
+                    italic = if closes { None } else { Some(delim) };
+                    style.italic = italic.is_some();
+                    rest = after;
+                    continue 'outer;
+                }
+            }
+        }
+        current.push(c);
+        prev = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    flush(&mut current, style);
+    spans
+}
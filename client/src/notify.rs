@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveTime};
+use common::UserId;
+
+use crate::config::Config;
+use crate::users::UserRegistry;
+
+/// Which messages are read out loud, or ring the bell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyMode {
+    #[default]
+    Off,
+    Mentions,
+    All,
+}
+
+impl Display for NotifyMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Mentions => "mentions",
+            Self::All => "on",
+        })
+    }
+}
+
+impl FromStr for NotifyMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "mentions" => Ok(Self::Mentions),
+            "on" | "all" => Ok(Self::All),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which messages of a room deserve attention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoomNotify {
+    #[default]
+    All,
+    Mentions,
+    Mute,
+}
+
+impl Display for RoomNotify {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::All => "all",
+            Self::Mentions => "mentions",
+            Self::Mute => "mute",
+        })
+    }
+}
+
+impl FromStr for RoomNotify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "mentions" => Ok(Self::Mentions),
+            "mute" | "off" => Ok(Self::Mute),
+            _ => {
+                Err(format!("expected `all`, `mentions` or `mute`, got `{s}`"))
+            }
+        }
+    }
+}
+
+/// A daily time range, in local time, during which notifications are
+/// suppressed. It may wrap around midnight, e.g. `22:00-08:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    #[must_use]
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|_| format!("expected a time like `22:00`, got `{t}`"))
+        };
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected `HH:MM-HH:MM`, got `{s}`"))?;
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+/// Parses a duration like `90s`, `30m` or `2h`; a bare number is minutes.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = s
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((s, "m"), |i| s.split_at(i));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a duration like `30m`, got `{s}`"))?;
+    let secs = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        _ => return Err(format!("unknown duration unit `{unit}`")),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// Decides how the user is notified about incoming messages.
+#[derive(Debug)]
+pub struct Notifier {
+    tts_mode: NotifyMode,
+    bell_mode: NotifyMode,
+    quiet_hours: Option<QuietHours>,
+    dnd_until: Option<Instant>,
+    /// Rooms not set here notify about everything.
+    rooms: HashMap<String, RoomNotify>,
+    #[cfg_attr(not(feature = "tts"), allow(dead_code))]
+    tts_command: String,
+}
+
+impl Notifier {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        Self {
+            tts_mode: NotifyMode::Off,
+            bell_mode: config.notify,
+            quiet_hours: config.quiet_hours,
+            dnd_until: None,
+            rooms: config.room_notify.clone(),
+            tts_command: config.tts_command.clone(),
+        }
+    }
+
+    /// Changes what is read out loud, failing if the client was built
+    /// without the `tts` feature.
+    pub fn set_tts(&mut self, mode: NotifyMode) -> Result<(), &'static str> {
+        if cfg!(feature = "tts") || mode == NotifyMode::Off {
+            self.tts_mode = mode;
+            Ok(())
+        } else {
+            Err("this client was built without text-to-speech support")
+        }
+    }
+
+    /// Changes which messages ring the bell.
+    pub fn set_bell(&mut self, mode: NotifyMode) {
+        self.bell_mode = mode;
+    }
+
+    /// Suppresses notifications for `duration`, or lifts an earlier
+    /// `/dnd` when `None`. Quiet hours still apply.
+    pub fn set_dnd(&mut self, duration: Option<Duration>) {
+        self.dnd_until = duration.map(|d| Instant::now() + d);
+    }
+
+    pub fn set_room(&mut self, room: String, setting: RoomNotify) {
+        self.rooms.insert(room, setting);
+    }
+
+    /// Whether a message from another user deserves attention under the
+    /// setting of `room`. Whispers, which have no room, always do.
+    #[must_use]
+    pub fn notable(
+        &self,
+        user_id: UserId,
+        message: &str,
+        room: Option<&str>,
+        users: &UserRegistry,
+    ) -> bool {
+        if users.is_own(user_id) {
+            return false;
+        }
+        match room.and_then(|r| self.rooms.get(r)) {
+            None | Some(RoomNotify::All) => true,
+            Some(RoomNotify::Mentions) => mentions_me(message, users),
+            Some(RoomNotify::Mute) => false,
+        }
+    }
+
+    /// Whether notifications are currently suppressed.
+    #[must_use]
+    pub fn quiet(&self) -> bool {
+        self.dnd_until.is_some_and(|until| Instant::now() < until)
+            || self
+                .quiet_hours
+                .is_some_and(|q| q.contains(Local::now().time()))
+    }
+
+    /// Routes a chat message from another user in `room`, or a whisper if
+    /// `None`, to the enabled notifications.
+    pub fn message(
+        &self,
+        user_id: UserId,
+        message: &str,
+        room: Option<&str>,
+        users: &UserRegistry,
+    ) {
+        if self.alerts(self.tts_mode, user_id, message, room, users) {
+            self.speak(&format!(
+                "{} says: {message}",
+                users.display_name(user_id)
+            ));
+        }
+    }
+
+    /// Whether a chat message from another user in `room`, or a whisper if
+    /// `None`, should ring the terminal bell.
+    #[must_use]
+    pub fn bell(
+        &self,
+        user_id: UserId,
+        message: &str,
+        room: Option<&str>,
+        users: &UserRegistry,
+    ) -> bool {
+        self.alerts(self.bell_mode, user_id, message, room, users)
+    }
+
+    fn alerts(
+        &self,
+        mode: NotifyMode,
+        user_id: UserId,
+        message: &str,
+        room: Option<&str>,
+        users: &UserRegistry,
+    ) -> bool {
+        if !self.notable(user_id, message, room, users) || self.quiet() {
+            return false;
+        }
+        match mode {
+            NotifyMode::Off => false,
+            NotifyMode::Mentions => mentions_me(message, users),
+            NotifyMode::All => true,
+        }
+    }
+
+    #[cfg(feature = "tts")]
+    fn speak(&self, text: &str) {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let child = Command::new("sh")
+            .args(["-c", &self.tts_command])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                log::warn!("Failed to start the TTS command: {e}");
+                return;
+            }
+        };
+        let text = text.to_owned();
+        // reap the process without blocking the UI
+        std::thread::spawn(move || {
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            let _ = child.wait();
+        });
+    }
+
+    #[cfg(not(feature = "tts"))]
+    #[allow(clippy::unused_self)]
+    const fn speak(&self, _text: &str) {}
+}
+
+/// Whether `message` mentions the name this client connected with.
+#[must_use]
+pub fn mentions_me(message: &str, users: &UserRegistry) -> bool {
+    users
+        .own_id()
+        .and_then(|id| users.get(id))
+        .is_some_and(|me| is_mention(message, &me.name))
+}
+
+/// Whether `message` mentions `name`, either bare or as `@name`, ignoring
+/// case.
+#[must_use]
+pub fn is_mention(message: &str, name: &str) -> bool {
+    let name = name.to_lowercase();
+    message
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+        .any(|word| word == name)
+}
@@ -0,0 +1,69 @@
+//! Getting back to the server after the connection was lost.
+
+use std::time::{Duration, Instant};
+
+/// Wait before the first attempt, doubled after each one that failed.
+const FIRST_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Where to reconnect to, as whom and when.
+#[derive(Debug)]
+pub struct Reconnect {
+    pub addr: String,
+    pub name: String,
+    /// The channel to join again once connected.
+    pub channel: Option<String>,
+    /// Attempts made so far.
+    attempts: u32,
+    max_attempts: u32,
+    next_attempt: Instant,
+}
+
+impl Reconnect {
+    #[must_use]
+    pub fn new(
+        addr: String,
+        name: String,
+        channel: Option<String>,
+        max_attempts: u32,
+    ) -> Self {
+        Self {
+            addr,
+            name,
+            channel,
+            attempts: 0,
+            max_attempts,
+            next_attempt: Instant::now() + FIRST_DELAY,
+        }
+    }
+
+    /// Whether it's time for the next attempt.
+    #[must_use]
+    pub fn due(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// Counts an attempt and schedules the one after it, returning its
+    /// number, or `None` if all attempts were used up.
+    pub fn attempt(&mut self) -> Option<u32> {
+        if self.attempts >= self.max_attempts {
+            return None;
+        }
+        self.attempts += 1;
+        let delay = FIRST_DELAY
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(MAX_DELAY);
+        self.next_attempt = Instant::now() + delay;
+        Some(self.attempts)
+    }
+
+    /// Progress to show while reconnecting.
+    #[must_use]
+    pub fn status(&self) -> String {
+        format!(
+            "reconnecting {}/{}",
+            (self.attempts + 1).min(self.max_attempts),
+            self.max_attempts
+        )
+    }
+}
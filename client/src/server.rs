@@ -1,47 +1,221 @@
-use std::io::{Error, ErrorKind, Result, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::{debug, info, trace};
+use log::{debug, error, info, trace};
 
 use common::commands::{ClientCommand, ServerCommand};
-use common::Connection;
+use common::{Connection, ConnectionStats, DataSize, Transport};
 
 #[derive(Debug)]
 pub struct Server {
     addr: SocketAddr,
     connection: Connection<ClientCommand, ServerCommand>,
     connected: bool,
+    /// What the certificate verifier saw, for `tls://` servers.
+    #[cfg(feature = "tls")]
+    certificate: Option<common::tls::SeenCertificate>,
+    last_sample: (Instant, ConnectionStats),
+    /// Messages sent and not yet acknowledged, oldest first.
+    in_flight: VecDeque<ClientCommand>,
+}
+
+/// Traffic totals of a connection and the rates since the previous sample.
+#[derive(Debug)]
+pub struct NetStats {
+    pub total: ConnectionStats,
+    pub bytes_in_per_sec: f64,
+    pub bytes_out_per_sec: f64,
+    pub frames_in_per_sec: f64,
+    pub frames_out_per_sec: f64,
+}
+
+impl Display for NetStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "in: {} B / {} frames ({:.0} B/s, {:.1} frames/s), \
+             out: {} B / {} frames ({:.0} B/s, {:.1} frames/s), \
+             queued: {} B ({} stalls)",
+            self.total.bytes_received,
+            self.total.frames_received,
+            self.bytes_in_per_sec,
+            self.frames_in_per_sec,
+            self.total.bytes_sent,
+            self.total.frames_sent,
+            self.bytes_out_per_sec,
+            self.frames_out_per_sec,
+            self.total.bytes_queued,
+            self.total.write_stalls,
+        )
+    }
+}
+
+/// How the certificate of a `tls://` server is checked.
+#[derive(Debug, Clone, Default)]
+pub struct Trust {
+    /// PEM file with the certificates that may sign it, the usual web
+    /// authorities if `None`.
+    pub ca: Option<PathBuf>,
+    /// Trust a certificate none of them signed if it is the first one
+    /// seen from the server.
+    pub on_first_use: bool,
+    /// Fingerprint of the certificate seen from the server before, which
+    /// is trusted whoever signed it.
+    pub pinned: Option<String>,
+}
+
+/// The certificate a `tls://` server presented.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    /// SHA-256 of the certificate as colon separated hex bytes, like
+    /// `openssl x509 -fingerprint -sha256` shows it.
+    pub fingerprint: String,
+    /// Whether one of the [`Trust::ca`] signed it, rather than it being
+    /// trusted for its fingerprint.
+    pub signed: bool,
+}
+
+/// A connection to a server being made on a background thread, so an
+/// unreachable host doesn't hold up the UI. Dropping it cancels the
+/// connect.
+#[derive(Debug)]
+pub struct Connecting {
+    addr: String,
+    trust: Trust,
+    stream: Receiver<Result<TcpStream>>,
+}
+
+impl Connecting {
+    /// The address being connected to, as given.
+    #[must_use]
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// The server once connected, or why it could not be; `None` while
+    /// still connecting.
+    pub fn poll(&self) -> Option<Result<Server>> {
+        match self.stream.try_recv() {
+            Ok(stream) => Some(stream.and_then(|stream| {
+                Server::start(&self.addr, stream, &self.trust)
+            })),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(Error::other("the connecting thread stopped")))
+            }
+        }
+    }
 }
 
 impl Server {
-    pub fn new(stream: TcpStream) -> Result<Self> {
+    /// Connects to `addr`, over TLS if it is given as `tls://host:port`,
+    /// checking the server's certificate as `trust` says.
+    pub fn connect(addr: &str, trust: &Trust) -> Result<Self> {
+        let stream = TcpStream::connect(tcp_addr(addr))?;
+        Self::start(addr, stream, trust)
+    }
+
+    /// Connects like [`Self::connect`] on a background thread, giving up
+    /// on each address the host has after `timeout`.
+    #[must_use]
+    pub fn connect_in_background(
+        addr: &str,
+        trust: Trust,
+        timeout: Duration,
+    ) -> Connecting {
+        let (sender, stream) = channel();
+        let tcp_addr = tcp_addr(addr).to_owned();
+        thread::spawn(move || {
+            // nobody is waiting any more if the connect was cancelled
+            let _ = sender.send(connect_timeout(&tcp_addr, timeout));
+        });
+        Connecting {
+            addr: addr.to_owned(),
+            trust,
+            stream,
+        }
+    }
+
+    /// Starts talking to the server `addr` on a connected stream, see
+    /// [`Self::connect`].
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    fn start(addr: &str, stream: TcpStream, trust: &Trust) -> Result<Self> {
+        let Some(addr) = addr.strip_prefix("tls://") else {
+            return Self::new(stream);
+        };
+        #[cfg(feature = "tls")]
+        {
+            use common::tls::Unsigned;
+
+            let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let unsigned = match &trust.pinned {
+                Some(pinned) => Unsigned::Pinned(pinned.clone()),
+                None if trust.on_first_use => Unsigned::TrustFirst,
+                None => Unsigned::Refuse,
+            };
+            let (config, seen) =
+                common::tls::client_config(trust.ca.as_deref(), unsigned)?;
+            let mut this =
+                Self::new(common::tls::connect(config, host, stream)?)?;
+            this.certificate = Some(seen);
+            Ok(this)
+        }
+        #[cfg(not(feature = "tls"))]
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "the client was built without TLS support",
+        ))
+    }
+
+    pub fn new(stream: impl Transport + 'static) -> Result<Self> {
         let this = Self {
             addr: stream.peer_addr()?,
             connection: Connection::new(stream)?,
             connected: true,
+            #[cfg(feature = "tls")]
+            certificate: None,
+            last_sample: (Instant::now(), ConnectionStats::default()),
+            in_flight: VecDeque::new(),
         };
         info!("Server connected: {}", this.addr);
         Ok(this)
     }
 
     pub fn poll(&mut self) -> Option<ServerCommand> {
-        if !self.connected {
-            return None;
-        }
-        match self.connection.receive() {
-            Ok(msg) => {
-                debug!("Got message '{:?}' from {}", msg, self.addr);
-                Some(msg)
-            }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
-            Err(e) => {
-                self.disconnect(Some(e));
-                None
+        while self.connected {
+            match self.connection.receive() {
+                Ok(ServerCommand::Ping { token }) => {
+                    trace!("Answering ping {token} from {}", self.addr);
+                    self.send(&ClientCommand::Pong { token });
+                    self.flush();
+                }
+                Ok(msg) => {
+                    debug!("Got message '{:?}' from {}", msg, self.addr);
+                    if answers_message(&msg) {
+                        self.in_flight.pop_front();
+                    }
+                    return Some(msg);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) => self.disconnect(Some(e)),
             }
         }
+        None
     }
 
+    /// Sends `message`, keeping chat messages until they are answered so
+    /// they can be sent again over another connection.
     pub fn send(&mut self, message: &ClientCommand) {
+        if let ClientCommand::Message { .. } = message {
+            self.in_flight.push_back(message.clone());
+        }
         if !self.connected {
             return;
         }
@@ -49,10 +223,22 @@ impl Server {
         match self.connection.send(message) {
             Ok(()) => (),
             Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                error!("Not sending {}: {e}", message.name());
+                if let ClientCommand::Message { .. } = message {
+                    // it will never be answered
+                    self.in_flight.pop_back();
+                }
+            }
             Err(e) => self.disconnect(Some(e)),
         }
     }
 
+    /// See [`Connection::set_max_frame_size`].
+    pub fn set_max_frame_size(&mut self, size: DataSize) {
+        self.connection.set_max_frame_size(size);
+    }
+
     pub fn flush(&mut self) {
         if !self.connected {
             return;
@@ -83,8 +269,87 @@ impl Server {
         self.connected = false;
     }
 
+    /// Bytes sent and still waiting for the connection to take them.
+    #[must_use]
+    pub fn bytes_queued(&self) -> u64 {
+        self.connection.stats().bytes_queued
+    }
+
     #[must_use]
     pub const fn connected(&self) -> bool {
         self.connected
     }
+
+    /// The certificate the server presented, once the TLS handshake got
+    /// to it, also if it was refused; `None` for plain connections.
+    #[must_use]
+    pub fn certificate(&self) -> Option<Certificate> {
+        #[cfg(feature = "tls")]
+        {
+            let seen = self.certificate.as_ref()?.get()?;
+            Some(Certificate {
+                fingerprint: seen.fingerprint,
+                signed: seen.signed,
+            })
+        }
+        #[cfg(not(feature = "tls"))]
+        None
+    }
+
+    /// Takes the messages the server did not answer yet, oldest first.
+    pub fn take_unacked(&mut self) -> Vec<ClientCommand> {
+        self.in_flight.drain(..).collect()
+    }
+
+    /// Returns the traffic totals and the rates since the last call (or
+    /// since connecting).
+    #[allow(clippy::cast_precision_loss)]
+    pub fn sample_stats(&mut self) -> NetStats {
+        let now = Instant::now();
+        let total = self.connection.stats();
+        let (last_time, last) = self.last_sample;
+        self.last_sample = (now, total);
+        let secs = (now - last_time).as_secs_f64().max(f64::EPSILON);
+        let rate = |new: u64, old: u64| (new - old) as f64 / secs;
+        NetStats {
+            total,
+            bytes_in_per_sec: rate(total.bytes_received, last.bytes_received),
+            bytes_out_per_sec: rate(total.bytes_sent, last.bytes_sent),
+            frames_in_per_sec: rate(
+                total.frames_received,
+                last.frames_received,
+            ),
+            frames_out_per_sec: rate(total.frames_sent, last.frames_sent),
+        }
+    }
+}
+
+/// The `host:port` part of a server address.
+fn tcp_addr(addr: &str) -> &str {
+    addr.strip_prefix("tls://").unwrap_or(addr)
+}
+
+/// Connects to the first address of `addr` that answers within `timeout`.
+fn connect_timeout(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut error =
+        Error::new(ErrorKind::NotFound, format!("{addr} has no addresses"));
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Whether `command` answers the oldest message in flight, see
+/// [`ServerCommand::Ack`].
+fn answers_message(command: &ServerCommand) -> bool {
+    match command {
+        ServerCommand::Ack { .. } => true,
+        ServerCommand::CommandFailed { command, .. } => {
+            command == "message" || command == "quote"
+        }
+        _ => false,
+    }
 }
@@ -0,0 +1,226 @@
+use std::env;
+use std::str::FromStr;
+
+use crossterm::style::{Attribute, Attributes, Color};
+
+/// What a piece of text means, which a [`Theme`] turns into a look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tone {
+    /// Message text and anything without a special meaning.
+    Normal,
+    /// Hints and decorations, like line numbers.
+    Dim,
+    /// Secondary text, like translations and queued messages.
+    Muted,
+    /// Other users' names.
+    Name,
+    /// Our own name.
+    OwnName,
+    /// Server events, like users joining.
+    Event,
+    Info,
+    Warning,
+    Error,
+    /// Role badges.
+    Badge,
+    /// Inline code.
+    Code,
+    /// Code blocks.
+    CodeBlock,
+    /// Private messages.
+    Whisper,
+    /// URLs in messages.
+    Link,
+}
+
+/// How a [`Tone`] is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Look {
+    pub foreground: Color,
+    pub background: Option<Color>,
+    pub attributes: Attributes,
+}
+
+impl Look {
+    const fn color(foreground: Color) -> Self {
+        Self {
+            foreground,
+            background: None,
+            attributes: Attributes::none(),
+        }
+    }
+
+    const fn attributes(attributes: &[Attribute]) -> Self {
+        let mut all = Attributes::none();
+        let mut i = 0;
+        while i < attributes.len() {
+            all = all.with(attributes[i]);
+            i += 1;
+        }
+        Self {
+            foreground: Color::Reset,
+            background: None,
+            attributes: all,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    Default,
+    /// Colors that stay distinct with the common kinds of color blindness
+    /// (the Okabe-Ito palette).
+    Colorblind,
+    /// No colors, distinctions are made with bold, underline and reverse.
+    Monochrome,
+}
+
+impl Theme {
+    /// The theme to use when none is configured: monochrome if `NO_COLOR`
+    /// is set (see <https://no-color.org>), the default one otherwise.
+    #[must_use]
+    pub fn from_env() -> Self {
+        if env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+            Self::Monochrome
+        } else {
+            Self::Default
+        }
+    }
+
+    #[must_use]
+    pub const fn look(self, tone: Tone) -> Look {
+        match self {
+            Self::Default => Look::color(match tone {
+                Tone::Normal => Color::Reset,
+                Tone::Dim => Color::DarkGrey,
+                Tone::Muted => Color::Grey,
+                Tone::Name => Color::White,
+                Tone::OwnName => Color::Cyan,
+                Tone::Event => Color::Blue,
+                Tone::Info => Color::Green,
+                Tone::Warning | Tone::Badge => Color::DarkYellow,
+                Tone::Error => Color::Red,
+                Tone::Code => Color::Yellow,
+                Tone::Whisper => Color::Magenta,
+                Tone::Link => {
+                    return Look {
+                        foreground: Color::Blue,
+                        background: None,
+                        attributes: Attributes::none()
+                            .with(Attribute::Underlined),
+                    }
+                }
+                Tone::CodeBlock => {
+                    return Look {
+                        foreground: Color::Reset,
+                        background: Some(Color::DarkGrey),
+                        attributes: Attributes::none(),
+                    }
+                }
+            }),
+            Self::Colorblind => Look::color(match tone {
+                Tone::Normal => Color::Reset,
+                Tone::Dim => Color::DarkGrey,
+                Tone::Muted => Color::Grey,
+                Tone::Name => Color::White,
+                // sky blue
+                Tone::OwnName => Color::Rgb {
+                    r: 86,
+                    g: 180,
+                    b: 233,
+                },
+                // blue
+                Tone::Event => Color::Rgb {
+                    r: 0,
+                    g: 114,
+                    b: 178,
+                },
+                // bluish green
+                Tone::Info => Color::Rgb {
+                    r: 0,
+                    g: 158,
+                    b: 115,
+                },
+                // orange
+                Tone::Warning | Tone::Badge => Color::Rgb {
+                    r: 230,
+                    g: 159,
+                    b: 0,
+                },
+                // vermillion
+                Tone::Error => Color::Rgb {
+                    r: 213,
+                    g: 94,
+                    b: 0,
+                },
+                // yellow
+                Tone::Code => Color::Rgb {
+                    r: 240,
+                    g: 228,
+                    b: 66,
+                },
+                // reddish purple
+                Tone::Whisper => Color::Rgb {
+                    r: 204,
+                    g: 121,
+                    b: 167,
+                },
+                // blue
+                Tone::Link => {
+                    return Look {
+                        foreground: Color::Rgb {
+                            r: 0,
+                            g: 114,
+                            b: 178,
+                        },
+                        background: None,
+                        attributes: Attributes::none()
+                            .with(Attribute::Underlined),
+                    }
+                }
+                Tone::CodeBlock => {
+                    return Look {
+                        foreground: Color::Reset,
+                        background: Some(Color::DarkGrey),
+                        attributes: Attributes::none(),
+                    }
+                }
+            }),
+            Self::Monochrome => match tone {
+                Tone::Normal | Tone::Muted | Tone::Name | Tone::Info => {
+                    Look::attributes(&[])
+                }
+                Tone::Dim => Look::attributes(&[Attribute::Dim]),
+                Tone::OwnName | Tone::Warning | Tone::Badge => {
+                    Look::attributes(&[Attribute::Bold])
+                }
+                Tone::Event => Look::attributes(&[Attribute::Underlined]),
+                Tone::Whisper => Look::attributes(&[Attribute::Italic]),
+                Tone::Link => {
+                    Look::attributes(&[Attribute::Underlined, Attribute::Bold])
+                }
+                Tone::Error => {
+                    Look::attributes(&[Attribute::Bold, Attribute::Reverse])
+                }
+                Tone::Code | Tone::CodeBlock => {
+                    Look::attributes(&[Attribute::Reverse])
+                }
+            },
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "colorblind" => Ok(Self::Colorblind),
+            "monochrome" | "mono" => Ok(Self::Monochrome),
+            _ => Err(format!(
+                "expected `default`, `colorblind` or `monochrome`, got `{s}`"
+            )),
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Files sent to and received from other users with `/sendfile`, relayed
+//! by the server in chunks once the receiver accepts them.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+
+use common::commands::{ClientCommand, ServerCommand};
+use common::{Bytes, TransferId, UserId};
+
+use crate::Server;
+
+/// Bytes of a file sent in one chunk.
+const CHUNK_SIZE: usize = 16 << 10;
+/// Chunks sent per call to [`Transfers::send_chunks`], so a large file
+/// doesn't hold up the UI.
+const CHUNKS_PER_CALL: usize = 8;
+
+/// A file offered to another user.
+#[derive(Debug)]
+struct Outgoing {
+    /// Given by the server in its answer to the offer.
+    transfer_id: Option<TransferId>,
+    target: UserId,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    /// Opened once the offer is accepted.
+    file: Option<File>,
+    sent: u64,
+}
+
+/// A file offered by another user.
+#[derive(Debug)]
+struct Incoming {
+    name: String,
+    size: u64,
+    /// Where the file is saved, once it is accepted.
+    file: Option<(PathBuf, BufWriter<File>)>,
+    received: u64,
+}
+
+/// The file transfers of a connection.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    outgoing: Vec<Outgoing>,
+    incoming: HashMap<TransferId, Incoming>,
+    /// Accepted files are saved here.
+    download_dir: PathBuf,
+}
+
+impl Transfers {
+    #[must_use]
+    pub fn new(download_dir: PathBuf) -> Self {
+        Self {
+            download_dir,
+            ..Self::default()
+        }
+    }
+
+    /// Offers the file at `path` to `target`, returning the command to send.
+    pub fn offer(
+        &mut self,
+        path: &Path,
+        target: UserId,
+    ) -> Result<ClientCommand> {
+        let size = fs::metadata(path)?.len();
+        let name = path
+            .file_name()
+            .ok_or_else(|| std::io::Error::from(ErrorKind::InvalidInput))?
+            .to_string_lossy()
+            .into_owned();
+        self.outgoing.push(Outgoing {
+            transfer_id: None,
+            target,
+            name: name.clone(),
+            path: path.to_owned(),
+            size,
+            file: None,
+            sent: 0,
+        });
+        Ok(ClientCommand::FileOffer {
+            target_user_id: target,
+            name,
+            size,
+        })
+    }
+
+    /// Accepts an incoming file, saving it under its name in the download
+    /// directory, with a number added if the name is taken.
+    pub fn accept(&mut self, transfer_id: TransferId) -> Result<ClientCommand> {
+        let incoming = self
+            .incoming
+            .get_mut(&transfer_id)
+            .filter(|i| i.file.is_none())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("there is no offer {transfer_id}"),
+                )
+            })?;
+        let (path, file) = create_unique(&self.download_dir, &incoming.name)?;
+        info!("Saving {} to {}", incoming.name, path.display());
+        incoming.file = Some((path, BufWriter::new(file)));
+        Ok(ClientCommand::FileAccept { transfer_id })
+    }
+
+    /// Declines an incoming file or stops a transfer under way, returning
+    /// the command to send if there was one.
+    pub fn reject(&mut self, transfer_id: TransferId) -> Option<ClientCommand> {
+        let reason = if let Some(incoming) = self.incoming.remove(&transfer_id)
+        {
+            discard(incoming);
+            "Declined by the receiver"
+        } else {
+            let index = self
+                .outgoing
+                .iter()
+                .position(|o| o.transfer_id == Some(transfer_id))?;
+            self.outgoing.remove(index);
+            "Cancelled by the sender"
+        };
+        Some(ClientCommand::FileReject {
+            transfer_id,
+            reason: reason.to_owned(),
+        })
+    }
+
+    /// Keeps track of the transfers from what the server sent, returning
+    /// a command to send if a transfer has to be stopped.
+    pub fn handle(
+        &mut self,
+        msg: &ServerCommand,
+        own_id: Option<UserId>,
+    ) -> Option<ClientCommand> {
+        match msg {
+            ServerCommand::FileOffer {
+                transfer_id,
+                user_id,
+                target_user_id,
+                name,
+                size,
+            } => {
+                if Some(*user_id) == own_id {
+                    let outgoing = self.outgoing.iter_mut().find(|o| {
+                        o.transfer_id.is_none()
+                            && o.target == *target_user_id
+                            && o.name == *name
+                    })?;
+                    outgoing.transfer_id = Some(*transfer_id);
+                } else {
+                    self.incoming.insert(
+                        *transfer_id,
+                        Incoming {
+                            name: name.clone(),
+                            size: *size,
+                            file: None,
+                            received: 0,
+                        },
+                    );
+                }
+                None
+            }
+            // an offer the server refused never gets an id
+            ServerCommand::CommandFailed { command, .. }
+                if command == "file_offer" =>
+            {
+                let index = self
+                    .outgoing
+                    .iter()
+                    .position(|o| o.transfer_id.is_none())?;
+                self.outgoing.remove(index);
+                None
+            }
+            ServerCommand::FileAccept { transfer_id } => {
+                let outgoing = self
+                    .outgoing
+                    .iter_mut()
+                    .find(|o| o.transfer_id == Some(*transfer_id))?;
+                match File::open(&outgoing.path) {
+                    Ok(file) => {
+                        info!("Sending {}", outgoing.name);
+                        outgoing.file = Some(file);
+                        None
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to open {}: {e}",
+                            outgoing.path.display()
+                        );
+                        self.reject(*transfer_id)
+                    }
+                }
+            }
+            ServerCommand::FileReject { transfer_id, .. } => {
+                if let Some(incoming) = self.incoming.remove(transfer_id) {
+                    discard(incoming);
+                }
+                self.outgoing
+                    .retain(|o| o.transfer_id != Some(*transfer_id));
+                None
+            }
+            ServerCommand::FileChunk { transfer_id, data } => {
+                let incoming = self.incoming.get_mut(transfer_id)?;
+                let (path, file) = incoming.file.as_mut()?;
+                incoming.received += data.0.len() as u64;
+                let result = if incoming.received > incoming.size {
+                    Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        "more data than offered",
+                    ))
+                } else {
+                    file.write_all(&data.0)
+                };
+                if let Err(e) = result {
+                    error!("Failed to save {}: {e}", path.display());
+                    return self.reject(*transfer_id);
+                }
+                None
+            }
+            ServerCommand::FileDone { transfer_id } => {
+                let incoming = self.incoming.remove(transfer_id)?;
+                let (path, mut file) = incoming.file?;
+                match file.flush() {
+                    Ok(()) => info!(
+                        "Saved {} ({}) to {}",
+                        incoming.name,
+                        format_size(incoming.size),
+                        path.display()
+                    ),
+                    Err(e) => error!("Failed to save {}: {e}", path.display()),
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends the next chunks of the accepted files, as long as the
+    /// connection keeps up with them.
+    pub fn send_chunks(&mut self, server: &mut Server) {
+        let mut budget = CHUNKS_PER_CALL;
+        let mut finished = vec![];
+        for outgoing in &mut self.outgoing {
+            let (Some(transfer_id), Some(file)) =
+                (outgoing.transfer_id, &mut outgoing.file)
+            else {
+                continue;
+            };
+            while budget > 0 && server.bytes_queued() == 0 {
+                budget -= 1;
+                let mut data = vec![0; CHUNK_SIZE];
+                let read = match file.read(&mut data) {
+                    Ok(read) => read,
+                    Err(e) => {
+                        error!(
+                            "Failed to read {}: {e}",
+                            outgoing.path.display()
+                        );
+                        server.send(&ClientCommand::FileReject {
+                            transfer_id,
+                            reason: "The sender could not read the file"
+                                .to_owned(),
+                        });
+                        finished.push(transfer_id);
+                        break;
+                    }
+                };
+                // a file that changed size since the offer is cut to it,
+                // the server stops it if it shrank
+                let read = read.min(
+                    usize::try_from(outgoing.size - outgoing.sent)
+                        .unwrap_or(usize::MAX),
+                );
+                if read == 0 {
+                    server.send(&ClientCommand::FileDone { transfer_id });
+                    info!("Sent {}", outgoing.name);
+                    finished.push(transfer_id);
+                    break;
+                }
+                data.truncate(read);
+                outgoing.sent += read as u64;
+                server.send(&ClientCommand::FileChunk {
+                    transfer_id,
+                    data: Bytes(data),
+                });
+                server.flush();
+            }
+        }
+        self.outgoing
+            .retain(|o| o.transfer_id.is_none_or(|id| !finished.contains(&id)));
+    }
+
+    /// Forgets every transfer, as the server does when the connection is
+    /// lost, removing partly received files.
+    pub fn clear(&mut self) {
+        self.outgoing.clear();
+        for (_, incoming) in self.incoming.drain() {
+            discard(incoming);
+        }
+    }
+}
+
+/// Removes what was saved of a file that will not be complete.
+fn discard(incoming: Incoming) {
+    if let Some((path, file)) = incoming.file {
+        drop(file);
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Failed to remove {}: {e}", path.display());
+        }
+    }
+}
+
+/// Creates a new file named `name` in `dir`, or `name (1)` and so on if
+/// the name is taken. Only the last component of `name` is used, so an
+/// offer can't write outside of `dir`.
+fn create_unique(dir: &Path, name: &str) -> Result<(PathBuf, File)> {
+    let name = Path::new(name)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| !n.starts_with('.'))
+        .unwrap_or("download");
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            (stem, format!(".{extension}"))
+        }
+        _ => (name, String::new()),
+    };
+    for n in 0_u32.. {
+        let path = if n == 0 {
+            dir.join(name)
+        } else {
+            dir.join(format!("{stem} ({n}){extension}"))
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Formats a file size like `512 B`, `1.5 KB` or `20.0 MB`.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
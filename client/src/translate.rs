@@ -0,0 +1,77 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use common::MsgId;
+use log::warn;
+
+/// Translates messages with an external command on a background thread,
+/// so a slow translation never blocks the UI.
+///
+/// The command is run with `sh -c`, gets the message on stdin and must
+/// print the translation to stdout. `{lang}` in the command is replaced by
+/// the target language, which is also available as `$TCPCHAT_LANG`.
+#[derive(Debug)]
+pub struct Translator {
+    requests: Sender<(MsgId, String)>,
+    results: Receiver<(MsgId, String)>,
+}
+
+impl Translator {
+    #[must_use]
+    pub fn new(command: String, language: String) -> Self {
+        let (requests, request_receiver) = channel::<(MsgId, String)>();
+        let (result_sender, results) = channel();
+        thread::spawn(move || {
+            let command = command.replace("{lang}", &language);
+            for (msg_id, text) in request_receiver {
+                match translate(&command, &language, &text) {
+                    Ok(translation) if translation.is_empty() => (),
+                    Ok(translation) => {
+                        if result_sender.send((msg_id, translation)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("Translating message {msg_id} failed: {e}"),
+                }
+            }
+        });
+        Self { requests, results }
+    }
+
+    /// Queues a message for translation.
+    pub fn request(&self, msg_id: MsgId, text: String) {
+        // the worker only stops once we are dropped
+        let _ = self.requests.send((msg_id, text));
+    }
+
+    /// Returns a finished translation, if there is one.
+    #[must_use]
+    pub fn poll(&self) -> Option<(MsgId, String)> {
+        self.results.try_recv().ok()
+    }
+}
+
+fn translate(command: &str, language: &str, text: &str) -> Result<String> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .env("TCPCHAT_LANG", language)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "translator exited with {}",
+            output.status
+        )));
+    }
+    String::from_utf8(output.stdout)
+        .map(|s| s.trim().to_owned())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
@@ -1,172 +1,2008 @@
+use std::collections::BTreeSet;
 use std::io::{stdout, Result, StdoutLock, Write};
+use std::iter;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use common::commands::ServerCommand;
+use chrono::{DateTime, Local};
+use common::commands::{
+    ContentType, MessageRecord, Quote, Reaction, Role, ServerCommand, UserInfo,
+};
+use common::{ChannelId, MsgId, TransferId, UserId};
 use crossterm::cursor::MoveTo;
-use crossterm::event::{self, Event, KeyCode, KeyEvent};
-use crossterm::style::{Color, SetForegroundColor};
+use crossterm::event::{
+    self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode,
+    KeyEvent, KeyModifiers,
+};
+use crossterm::style::{
+    Attribute, Attributes, SetAttribute, SetAttributes, SetBackgroundColor,
+    SetForegroundColor,
+};
 use crossterm::terminal::{
     self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
 };
 use crossterm::{ExecutableCommand, QueueableCommand};
 use log::error;
 
+use crate::bidi;
 use crate::channel_logger;
+use crate::chat_log::ChatRecord;
+use crate::config::Config;
+use crate::input::Input;
+use crate::keymap::{Action, KeyBinding, Keymap};
+use crate::links;
+use crate::markdown::{self, Span};
+use crate::notify::{mentions_me, parse_duration, NotifyMode, RoomNotify};
+use crate::theme::{Theme, Tone};
+use crate::transfers::format_size;
+use crate::users::UserRegistry;
+use crate::width;
+
+/// Columns taken by the roster panel, with its border.
+/// Drawn in the input for a line break.
+const NEWLINE_SYMBOL: &str = "\u{21B5}";
+const ROSTER_WIDTH: u16 = 28;
+
+/// A piece of a [`Line`] drawn in one style.
+struct Segment {
+    tone: Tone,
+    /// Emphasis on top of what the theme gives the tone.
+    attributes: Attributes,
+    text: String,
+    /// Only shown once the line is revealed.
+    spoiler: bool,
+}
+
+impl From<(Tone, String)> for Segment {
+    fn from((tone, text): (Tone, String)) -> Self {
+        Self {
+            tone,
+            attributes: Attributes::default(),
+            text,
+            spoiler: false,
+        }
+    }
+}
+
+impl From<Span> for Segment {
+    fn from(span: Span) -> Self {
+        let mut attributes = Attributes::default();
+        if span.style.bold {
+            attributes.set(Attribute::Bold);
+        }
+        if span.style.italic {
+            attributes.set(Attribute::Italic);
+        }
+        Self {
+            tone: if span.style.code_block {
+                Tone::CodeBlock
+            } else if span.style.code {
+                Tone::Code
+            } else {
+                Tone::Normal
+            },
+            attributes,
+            text: span.text,
+            spoiler: span.style.spoiler,
+        }
+    }
+}
+
+/// How far a message written here got on its way to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    /// Waiting for a connection.
+    Queued,
+    /// Sent and not yet acknowledged.
+    Sent,
+    /// Posted by the server, waiting for its copy to take the line's place.
+    Acked,
+}
+
+/// A message written here that the server has not echoed yet.
+struct Pending {
+    text: String,
+    delivery: Delivery,
+}
+
+impl Pending {
+    fn segments(&self) -> Vec<Segment> {
+        let (tone, tag) = match self.delivery {
+            Delivery::Queued => (Tone::Muted, "(queued) "),
+            Delivery::Sent => (Tone::Muted, "(sending) "),
+            Delivery::Acked => (Tone::Normal, ""),
+        };
+        let mut segments = vec![];
+        if !tag.is_empty() {
+            segments.push(Segment::from((Tone::Dim, tag.to_owned())));
+        }
+        segments.push(Segment::from((tone, self.text.clone())));
+        segments
+    }
+}
+
+/// A row of the message pane made of styled segments.
+struct Line {
+    /// The chat message shown on this line, if any.
+    msg_id: Option<MsgId>,
+    /// The message this line is ordered by, also set on quotes and
+    /// translations. Lines of earlier connections have none.
+    sequence: Option<MsgId>,
+    /// A message written here and not yet echoed by the server. Such lines
+    /// stay below everything else, in the order they were written.
+    pending: Option<Pending>,
+    segments: Vec<Segment>,
+    /// What to show instead when formatting is turned off, for formatted
+    /// messages.
+    plain_segments: Option<Vec<Segment>>,
+    /// Whether spoilers on this line are shown.
+    revealed: bool,
+    /// When the message on this line was sent, in seconds since the Unix
+    /// epoch.
+    time: Option<u64>,
+    /// Whether the message mentions us, which highlights the line.
+    mention: bool,
+    /// The chat message shown, as `/save` writes it.
+    record: Option<ChatRecord>,
+    /// Whether this is the divider above the messages not read yet.
+    unread_divider: bool,
+    /// For whispers we sent, whether the peer read them.
+    read: Option<bool>,
+    /// The message whose reactions are counted on this line.
+    reactions_of: Option<MsgId>,
+}
+
+impl Line {
+    fn has_hidden_spoiler(&self) -> bool {
+        !self.revealed && self.segments.iter().any(|s| s.spoiler)
+    }
+
+    /// The URLs shown on this line, not counting hidden spoilers.
+    fn links(&self) -> impl Iterator<Item = &str> {
+        self.segments
+            .iter()
+            .filter(|s| s.tone == Tone::Link && (self.revealed || !s.spoiler))
+            .map(|s| s.text.as_str())
+    }
+}
+
+impl From<Vec<(Tone, String)>> for Line {
+    fn from(segments: Vec<(Tone, String)>) -> Self {
+        Self {
+            msg_id: None,
+            sequence: None,
+            pending: None,
+            segments: segments.into_iter().map(Segment::from).collect(),
+            plain_segments: None,
+            revealed: false,
+            time: None,
+            mention: false,
+            record: None,
+            unread_divider: false,
+            read: None,
+            reactions_of: None,
+        }
+    }
+}
+
+/// Private messages with one user, shown in a tab of their own.
+struct Conversation {
+    peer: UserId,
+    /// Name of the peer, as shown in the tab.
+    name: String,
+    lines: Vec<Line>,
+    /// Messages that arrived while the tab was not in view.
+    unread: usize,
+    /// Whether whispers of the peer arrived since we last told them we
+    /// read them.
+    unacknowledged: bool,
+}
+
+/// A part of the screen that can be redrawn on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// The row above the message pane showing the topic, if there is one.
+    Header,
+    /// The whole message pane.
+    Messages,
+    /// One line of the message pane, by index among the lines shown (the
+    /// messages or the search results).
+    MessageLine(usize),
+    /// The row between the message pane and the input.
+    Status,
+    /// The row being typed in.
+    Input,
+    /// The panel listing the connected users.
+    Roster,
+}
+
+/// What needs to be redrawn by the next render.
+#[derive(Debug, Default)]
+struct Dirty {
+    /// Clear the whole screen first, e.g. after a resize.
+    screen: bool,
+    header: bool,
+    messages: bool,
+    message_lines: BTreeSet<usize>,
+    status: bool,
+    input: bool,
+    roster: bool,
+}
 
-const EXIT_KEY: KeyCode = KeyCode::Esc;
+impl Dirty {
+    fn all() -> Self {
+        Self {
+            screen: true,
+            header: true,
+            messages: true,
+            message_lines: BTreeSet::new(),
+            status: true,
+            input: true,
+            roster: true,
+        }
+    }
+
+    fn any(&self) -> bool {
+        self.screen
+            || self.header
+            || self.messages
+            || !self.message_lines.is_empty()
+            || self.status
+            || self.input
+            || self.roster
+    }
+}
 
 pub struct UI {
     stdout: StdoutLock<'static>,
-    messages: Vec<Vec<(Color, String)>>,
-    typing_buffer: String,
+    /// Whether the terminal was taken over, to be given back on drop.
+    attached: bool,
+    messages: Vec<Line>,
+    search_results: Option<Vec<Line>>,
+    /// Where the next, older page of the search results shown starts.
+    search_more: Option<SearchCursor>,
+    input: Input,
     width: u16,
     height: u16,
-    dirty: bool,
+    dirty: Dirty,
+    frame_time: Duration,
+    last_render: Instant,
+    /// Number of lines scrolled up from the newest message.
+    scroll: usize,
+    oldest_msg_id: Option<MsgId>,
+    history: HistoryState,
+    /// History replies received and expected while a long replay streams
+    /// in, drawn only once it is all here.
+    replay: Option<(usize, usize)>,
+    /// Show formatted messages as their raw text.
+    plain: bool,
+    /// Show when messages were sent.
+    timestamps: bool,
+    /// Show which of our whispers were read.
+    read_receipts: bool,
+    /// How far we read the channel before joining it, as the server told.
+    read_marker: Option<MsgId>,
+    /// The newest message of the channel we told the server we read.
+    marked_read: Option<MsgId>,
+    theme: Theme,
+    keymap: Keymap,
+    /// Name of the channel the user is in, shown in the status line.
+    channel: Option<String>,
+    /// Topic of the channel, shown in the header.
+    topic: Option<String>,
+    /// Notable messages that arrived while scrolled away from them.
+    unread: usize,
+    /// Progress of getting back to the server, while at it.
+    reconnect_status: Option<String>,
+    /// Whether the roster panel is open.
+    roster_open: bool,
+    /// The connected users, as last listed by the server and updated since.
+    roster: Vec<UserInfo>,
+    /// Tabs after the channel's, in the order they were opened.
+    conversations: Vec<Conversation>,
+    /// The tab shown, 0 for the channel and then the conversations.
+    tab: usize,
 }
 
-impl UI {
-    pub fn new() -> Result<Self> {
-        let mut this = Self {
-            stdout: stdout().lock(),
-            messages: vec![vec![(
-                Color::DarkGrey,
-                format!("Press {EXIT_KEY} to exit"),
-            )]],
-            typing_buffer: String::new(),
-            width: 0,
-            height: 0,
-            dirty: true,
+/// Where the next page of `/search` results starts.
+struct SearchCursor {
+    query: String,
+    before_msg_id: MsgId,
+    loading: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum HistoryState {
+    Idle,
+    Loading,
+    Exhausted,
+}
+
+impl UI {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut this = Self::detached(config);
+        this.attached = true;
+        this.stdout.execute(EnterAlternateScreen)?;
+        this.stdout.execute(EnableBracketedPaste)?;
+        terminal::enable_raw_mode()?;
+        (this.width, this.height) = terminal::size()?;
+        Ok(this)
+    }
+
+    /// A UI that hasn't taken over the terminal yet, with no room to draw.
+    fn detached(config: &Config) -> Self {
+        Self {
+            stdout: stdout().lock(),
+            attached: false,
+            messages: vec![Line::from(vec![(
+                Tone::Dim,
+                format!("Press {} to exit", config.keymap.exit),
+            )])],
+            search_results: None,
+            search_more: None,
+            input: Input::new(),
+            width: 0,
+            height: 0,
+            dirty: Dirty::all(),
+            frame_time: Duration::from_secs(1) / config.max_fps,
+            last_render: Instant::now(),
+            scroll: 0,
+            oldest_msg_id: None,
+            history: HistoryState::Idle,
+            replay: None,
+            plain: false,
+            timestamps: config.timestamps,
+            read_receipts: config.read_receipts,
+            read_marker: None,
+            marked_read: None,
+            theme: config.theme,
+            keymap: config.keymap,
+            channel: None,
+            topic: None,
+            unread: 0,
+            reconnect_status: None,
+            roster_open: false,
+            roster: vec![],
+            conversations: vec![],
+            tab: 0,
+        }
+    }
+
+    /// Redraws the parts of the screen that changed, at most once per frame
+    /// time; changes made in between are drawn together by the next render.
+    pub fn render(&mut self) -> Result<()> {
+        if !self.dirty.any() || self.last_render.elapsed() < self.frame_time {
+            return Ok(());
+        }
+        self.last_render = Instant::now();
+        if self.replay.is_some() {
+            // the rest waits for the end of the replay, drawing every
+            // reply of it would take longer than receiving them
+            if std::mem::take(&mut self.dirty.status) {
+                self.render_status()?;
+                self.render_input()?;
+                self.stdout.flush()?;
+            }
+            return Ok(());
+        }
+        let dirty = std::mem::take(&mut self.dirty);
+        if dirty.screen {
+            self.stdout.queue(Clear(ClearType::All))?;
+        }
+        // drawing messages clears whole rows, the panel included
+        let messages_drawn =
+            dirty.screen || dirty.messages || !dirty.message_lines.is_empty();
+        if dirty.screen || dirty.messages {
+            self.render_messages(0..self.lines().len())?;
+        } else if !dirty.message_lines.is_empty() {
+            for index in dirty.message_lines {
+                self.render_messages(index..index + 1)?;
+            }
+        }
+        if self.topic.is_some() && (dirty.screen || dirty.header) {
+            self.render_header()?;
+        }
+        if self.roster_shown() && (messages_drawn || dirty.roster) {
+            self.render_roster()?;
+        }
+        if dirty.screen || dirty.status {
+            self.render_status()?;
+        }
+        // always last, so the cursor ends up in the input
+        self.render_input()?;
+        self.stdout.flush()?;
+        Ok(())
+    }
+
+    /// Redraws the rows of the message pane showing lines in `range`.
+    fn render_messages(&mut self, range: std::ops::Range<usize>) -> Result<()> {
+        let lines = match self.tab {
+            0 => self.search_results.as_ref().unwrap_or(&self.messages),
+            tab => &self.conversations[tab - 1].lines,
+        };
+        let header = self.header_rows();
+        let rows = self.height.saturating_sub(2 + header);
+        let whole_pane = range.start == 0 && range.end >= lines.len();
+        if whole_pane {
+            for row in header..header + rows {
+                self.stdout.queue(MoveTo(0, row))?;
+                self.stdout.queue(Clear(ClearType::CurrentLine))?;
+            }
+        }
+        // lines are wrapped to the width and stacked from the bottom, the
+        // top one may only partly fit
+        let mut bottom = rows as usize;
+        for (index, line) in lines.iter().enumerate().rev().skip(self.scroll) {
+            if bottom == 0 {
+                break;
+            }
+            let wrapped = wrap_line(line, index, self.layout());
+            let top = bottom.saturating_sub(wrapped.len());
+            if range.contains(&index) {
+                let hidden = wrapped.len() - (bottom - top);
+                for (row, pieces) in (top..bottom).zip(&wrapped[hidden..]) {
+                    // fits, rows are fewer than `u16::MAX`
+                    self.stdout.queue(MoveTo(0, header + row as u16))?;
+                    if !whole_pane {
+                        self.stdout.queue(Clear(ClearType::CurrentLine))?;
+                    }
+                    for (tone, attributes, text) in pieces {
+                        set_tone(
+                            &mut self.stdout,
+                            self.theme,
+                            *tone,
+                            *attributes,
+                        )?;
+                        write!(self.stdout, "{text}")?;
+                    }
+                }
+            }
+            bottom = top;
+        }
+        if whole_pane && self.history == HistoryState::Loading {
+            self.stdout.queue(MoveTo(0, header))?;
+            self.stdout.queue(Clear(ClearType::CurrentLine))?;
+            set_tone(
+                &mut self.stdout,
+                self.theme,
+                Tone::Dim,
+                Attributes::none(),
+            )?;
+            write!(self.stdout, "Loading older messages...")?;
+        }
+        set_tone(
+            &mut self.stdout,
+            self.theme,
+            Tone::Normal,
+            Attributes::none(),
+        )?;
+        Ok(())
+    }
+
+    /// Draws the roster panel at the right of the message pane.
+    fn render_roster(&mut self) -> Result<()> {
+        let left = self.width - ROSTER_WIDTH;
+        let columns = usize::from(ROSTER_WIDTH - 2);
+        let mut users = self.roster.iter();
+        let top = self.header_rows();
+        for row in top..self.height.saturating_sub(2) {
+            self.stdout.queue(MoveTo(left, row))?;
+            self.stdout.queue(Clear(ClearType::UntilNewLine))?;
+            set_tone(
+                &mut self.stdout,
+                self.theme,
+                Tone::Dim,
+                Attributes::none(),
+            )?;
+            write!(self.stdout, "\u{2502} ")?;
+            if row == top {
+                set_tone(
+                    &mut self.stdout,
+                    self.theme,
+                    Tone::Event,
+                    Attributes::none(),
+                )?;
+                write!(self.stdout, "Online ({})", self.roster.len())?;
+                continue;
+            }
+            let Some(user) = users.next() else {
+                continue;
+            };
+            let details = format!(
+                " #{}{}",
+                user.user_id,
+                local_time(user.joined, " %H:%M")
+            );
+            let (name, _) = width::head(
+                &user.name,
+                columns.saturating_sub(width::width(&details)),
+            );
+            set_tone(
+                &mut self.stdout,
+                self.theme,
+                Tone::Name,
+                Attributes::none(),
+            )?;
+            write!(self.stdout, "{}", bidi::visual(name))?;
+            set_tone(
+                &mut self.stdout,
+                self.theme,
+                Tone::Dim,
+                Attributes::none(),
+            )?;
+            write!(self.stdout, "{}", width::head(&details, columns).0)?;
+        }
+        Ok(())
+    }
+
+    /// Draws the topic of the channel across the top row.
+    fn render_header(&mut self) -> Result<()> {
+        let Some(topic) = &self.topic else {
+            return Ok(());
+        };
+        self.stdout.queue(MoveTo(0, 0))?;
+        self.stdout.queue(Clear(ClearType::CurrentLine))?;
+        let channel = self
+            .channel
+            .as_ref()
+            .map_or_else(String::new, |c| format!("#{c}: "));
+        set_tone(&mut self.stdout, self.theme, Tone::Name, Attributes::none())?;
+        write!(self.stdout, "{channel}")?;
+        set_tone(&mut self.stdout, self.theme, Tone::Info, Attributes::none())?;
+        let columns =
+            (self.width as usize).saturating_sub(width::width(&channel));
+        write!(
+            self.stdout,
+            "{}",
+            bidi::visual(width::head(topic, columns).0)
+        )?;
+        set_tone(
+            &mut self.stdout,
+            self.theme,
+            Tone::Normal,
+            Attributes::none(),
+        )?;
+        Ok(())
+    }
+
+    fn render_status(&mut self) -> Result<()> {
+        self.stdout.queue(MoveTo(0, self.height - 2))?;
+        set_tone(
+            &mut self.stdout,
+            self.theme,
+            Tone::Normal,
+            Attributes::none(),
+        )?;
+        let mut labels = vec![];
+        let channel = self
+            .channel
+            .as_ref()
+            .map_or_else(|| "server".to_owned(), |c| format!("#{c}"));
+        if self.conversations.is_empty() {
+            if self.channel.is_some() {
+                labels.push((Tone::Name, channel));
+            }
+        } else {
+            let tabs = std::iter::once((channel, self.unread)).chain(
+                self.conversations
+                    .iter()
+                    .map(|c| (format!("@{}", c.name), c.unread)),
+            );
+            for (index, (name, unread)) in tabs.enumerate() {
+                let mut label = format!("{}:{name}", index + 1);
+                let tone = if index == self.tab {
+                    Tone::Name
+                } else if unread > 0 {
+                    label.push_str(&format!(" ({unread})"));
+                    Tone::Warning
+                } else {
+                    Tone::Dim
+                };
+                labels.push((tone, label));
+            }
+        }
+        if self.scroll > 0 {
+            labels.push((Tone::Dim, format!("{} lines below", self.scroll)));
+        }
+        if self.unread > 0 && self.tab == 0 {
+            labels.push((Tone::Warning, format!("{} unread", self.unread)));
+        }
+        if let Some(status) = &self.reconnect_status {
+            labels.push((Tone::Warning, status.clone()));
+        }
+        if let Some((received, expected)) = self.replay {
+            let percent = received * 100 / expected;
+            labels.push((Tone::Dim, format!("loading history {percent}%")));
+        }
+        let mut used = 0;
+        for (tone, label) in labels {
+            write!(self.stdout, "-- ")?;
+            set_tone(&mut self.stdout, self.theme, tone, Attributes::none())?;
+            write!(self.stdout, "{label}")?;
+            set_tone(
+                &mut self.stdout,
+                self.theme,
+                Tone::Normal,
+                Attributes::none(),
+            )?;
+            write!(self.stdout, " ")?;
+            used += width::width(&label) + 4;
+        }
+        write!(
+            self.stdout,
+            "{}",
+            "-".repeat((self.width as usize).saturating_sub(used))
+        )?;
+        Ok(())
+    }
+
+    fn render_input(&mut self) -> Result<()> {
+        self.stdout.queue(MoveTo(0, self.height - 1))?;
+        self.stdout.queue(Clear(ClearType::CurrentLine))?;
+
+        // pasted line breaks are shown as a symbol, on the one row
+        let input = self.input.text();
+        let cursor = self.input.cursor()
+            + input[..self.input.cursor()].matches('\n').count()
+                * (NEWLINE_SYMBOL.len() - 1);
+        let text = &input.replace('\n', NEWLINE_SYMBOL);
+        let columns = self.width as usize;
+        // keep the cursor in view, with one column for it at the end
+        let (prefix, start) = if width::width(text) <= columns
+            || width::width(&text[..cursor]) < columns
+        {
+            ("", 0)
+        } else {
+            let before =
+                width::tail(&text[..cursor], columns.saturating_sub(4));
+            ("...", cursor - before.len())
+        };
+        let (shown, _) =
+            width::head(&text[start..], columns.saturating_sub(prefix.len()));
+        set_tone(
+            &mut self.stdout,
+            self.theme,
+            Tone::Muted,
+            Attributes::none(),
+        )?;
+        write!(self.stdout, "{prefix}")?;
+        set_tone(
+            &mut self.stdout,
+            self.theme,
+            Tone::Normal,
+            Attributes::none(),
+        )?;
+        write!(self.stdout, "{}", bidi::visual(shown))?;
+        #[allow(clippy::cast_possible_truncation)]
+        self.stdout.queue(MoveTo(
+            (prefix.len() + bidi::cursor_column(shown, cursor - start)) as u16,
+            self.height - 1,
+        ))?;
+        Ok(())
+    }
+
+    /// Switches to another theme, redrawing everything.
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+        self.dirty = Dirty::all();
+    }
+
+    /// Marks a region to be redrawn by the next render.
+    pub fn invalidate(&mut self, region: Region) {
+        match region {
+            Region::Header => self.dirty.header = true,
+            Region::Messages => self.dirty.messages = true,
+            Region::MessageLine(index) => {
+                self.dirty.message_lines.insert(index);
+            }
+            Region::Status => self.dirty.status = true,
+            Region::Input => self.dirty.input = true,
+            Region::Roster => self.dirty.roster = true,
+        }
+    }
+
+    /// The lines of the tab shown, or the search results over the
+    /// channel's.
+    fn lines(&self) -> &Vec<Line> {
+        match self.tab {
+            0 => self.search_results.as_ref().unwrap_or(&self.messages),
+            tab => &self.conversations[tab - 1].lines,
+        }
+    }
+
+    fn lines_mut(&mut self) -> &mut Vec<Line> {
+        match self.tab {
+            0 => self.search_results.as_mut().unwrap_or(&mut self.messages),
+            tab => &mut self.conversations[tab - 1].lines,
+        }
+    }
+
+    /// Reveals the spoilers of the newest message with hidden ones, not
+    /// counting the messages scrolled past.
+    fn reveal_spoiler(&mut self) {
+        let (scroll, layout) = (self.scroll, self.layout());
+        let lines = self.lines_mut();
+        let visible = lines.len().saturating_sub(scroll);
+        if let Some(index) =
+            lines[..visible].iter().rposition(Line::has_hidden_spoiler)
+        {
+            let height = wrap_line(&lines[index], index, layout).len();
+            lines[index].revealed = true;
+            // a taller line pushes the ones above it up
+            if wrap_line(&lines[index], index, layout).len() == height {
+                self.invalidate(Region::MessageLine(index));
+            } else {
+                self.invalidate(Region::Messages);
+            }
+        }
+    }
+
+    /// The first link of message `msg_id`, or the newest link shown if
+    /// `None`, not counting the messages scrolled past.
+    #[must_use]
+    pub fn link(&self, msg_id: Option<MsgId>) -> Option<String> {
+        let lines = self.lines();
+        let url = if let Some(msg_id) = msg_id {
+            lines
+                .iter()
+                .filter(|line| line.msg_id == Some(msg_id))
+                .find_map(|line| line.links().next())
+        } else {
+            let visible = lines.len().saturating_sub(self.scroll);
+            lines[..visible]
+                .iter()
+                .rev()
+                .find_map(|line| line.links().last())
+        };
+        url.map(str::to_owned)
+    }
+
+    /// Appends a line to the message pane, keeping the view in place if it
+    /// is scrolled up.
+    fn push_line(&mut self, line: impl Into<Line>) {
+        let at = self.pending_start();
+        self.insert_lines(at, vec![line.into()]);
+    }
+
+    fn insert_lines(&mut self, at: usize, lines: Vec<Line>) {
+        let count = lines.len();
+        self.messages.splice(at..at, lines);
+        if self.scroll > 0 && self.search_results.is_none() && self.tab == 0 {
+            self.scroll += count;
+            self.invalidate(Region::Status);
+        }
+    }
+
+    /// Index of the first line of a queued message.
+    fn pending_start(&self) -> usize {
+        self.messages
+            .iter()
+            .position(|line| line.pending.is_some())
+            .unwrap_or(self.messages.len())
+    }
+
+    /// Inserts the lines of message `msg_id` in server order, so that a
+    /// message arriving late (e.g. replayed history) doesn't end up below
+    /// newer ones.
+    fn insert_message(&mut self, msg_id: MsgId, mut lines: Vec<Line>) {
+        for line in &mut lines {
+            line.sequence = Some(msg_id);
+        }
+        let end = self.pending_start();
+        let at = self.messages[..end]
+            .iter()
+            .position(|line| line.sequence.is_some_and(|id| id > msg_id))
+            .unwrap_or(end);
+        self.insert_lines(at, lines);
+    }
+
+    /// Removes the pending line of message `msg_id`, now that the server
+    /// has echoed it.
+    fn confirm_pending(&mut self, msg_id: MsgId) {
+        if let Some(index) = self.messages.iter().position(|line| {
+            line.pending.is_some() && line.msg_id == Some(msg_id)
+        }) {
+            self.messages.remove(index);
+        }
+    }
+
+    /// The oldest pending line in the `delivery` state.
+    fn oldest_pending(&mut self, delivery: Delivery) -> Option<&mut Line> {
+        self.invalidate(Region::Messages);
+        self.messages.iter_mut().find(|line| {
+            line.pending
+                .as_ref()
+                .is_some_and(|p| p.delivery == delivery)
+        })
+    }
+
+    /// Moves every pending line in the `from` state to `to`.
+    fn set_delivery(&mut self, from: Delivery, to: Delivery) {
+        self.invalidate(Region::Messages);
+        for line in &mut self.messages {
+            if let Some(pending) =
+                line.pending.as_mut().filter(|p| p.delivery == from)
+            {
+                pending.delivery = to;
+                line.segments = pending.segments();
+            }
+        }
+    }
+
+    /// Shows the messages that were queued as sent, now that they are.
+    pub fn mark_sent(&mut self) {
+        self.set_delivery(Delivery::Queued, Delivery::Sent);
+    }
+
+    /// Shows the messages that were sent and not acknowledged as queued
+    /// again, e.g. after the connection was lost.
+    pub fn requeue(&mut self) {
+        self.set_delivery(Delivery::Sent, Delivery::Queued);
+    }
+
+    fn page_size(&self) -> usize {
+        self.height.saturating_sub(2 + self.header_rows()) as usize
+    }
+
+    /// Rows above the message pane, taken by the topic if there is one.
+    fn header_rows(&self) -> u16 {
+        u16::from(self.topic.is_some())
+    }
+
+    /// Whether the roster panel is open and there is room for it.
+    const fn roster_shown(&self) -> bool {
+        self.roster_open && self.width >= 2 * ROSTER_WIDTH
+    }
+
+    /// Columns of the message pane, left of the roster panel if shown.
+    const fn pane_width(&self) -> u16 {
+        if self.roster_shown() {
+            self.width - ROSTER_WIDTH
+        } else {
+            self.width
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        Layout {
+            width: self.pane_width().into(),
+            plain: self.plain,
+            timestamps: self.timestamps,
+            read_receipts: self.read_receipts,
+            spoiler_key: self.keymap.spoiler,
+        }
+    }
+
+    /// Opens the roster panel, which shows the users of the next
+    /// [`ServerCommand::Users`].
+    pub fn show_roster(&mut self) {
+        if !self.roster_open {
+            self.roster_open = true;
+            self.invalidate(Region::Messages);
+        }
+    }
+
+    /// The scroll that shows the oldest line at the top, as far as the
+    /// lines fill the pane.
+    fn max_scroll(&self) -> usize {
+        let lines = self.lines();
+        let mut height = 0;
+        for (index, line) in lines.iter().enumerate() {
+            height += wrap_line(line, index, self.layout()).len();
+            if height >= self.page_size() {
+                return lines.len() - 1 - index;
+            }
+        }
+        0
+    }
+
+    fn scroll_up(&mut self) -> Option<UIEvent> {
+        self.scroll_to((self.scroll + self.page_size()).min(self.max_scroll()));
+        self.load_older()
+    }
+
+    fn scroll_down(&mut self) {
+        self.scroll_to(self.scroll.saturating_sub(self.page_size()));
+    }
+
+    /// Scrolls to the oldest line, asking for older messages.
+    fn scroll_to_top(&mut self) -> Option<UIEvent> {
+        self.scroll_to(self.max_scroll());
+        self.load_older()
+    }
+
+    /// Scrolls so that `scroll` lines are below the view.
+    fn scroll_to(&mut self, scroll: usize) {
+        self.invalidate(Region::Messages);
+        self.invalidate(Region::Status);
+        self.scroll = scroll;
+        self.clear_unread();
+    }
+
+    /// Asks for the messages before the oldest one, or for the next page
+    /// of search results, if scrolled to the top of the scrollback.
+    fn load_older(&mut self) -> Option<UIEvent> {
+        if self.scroll < self.max_scroll() || self.tab != 0 {
+            return None;
+        }
+        if self.search_results.is_some() {
+            let cursor = self.search_more.as_mut().filter(|c| !c.loading)?;
+            cursor.loading = true;
+            return Some(UIEvent::SearchMore {
+                query: cursor.query.clone(),
+                before_msg_id: cursor.before_msg_id,
+            });
+        }
+        if self.history != HistoryState::Idle {
+            return None;
+        }
+        self.history = HistoryState::Loading;
+        Some(UIEvent::LoadHistory {
+            before_msg_id: self.oldest_msg_id.unwrap_or(MsgId::MAX),
+        })
+    }
+
+    /// Whether the newest messages of the channel are in view.
+    const fn at_bottom(&self) -> bool {
+        self.scroll == 0 && self.search_results.is_none() && self.tab == 0
+    }
+
+    /// Shows tab `tab`, 0 being the channel's and the next ones the
+    /// conversations'.
+    fn switch_tab(&mut self, tab: usize) {
+        if tab == self.tab || tab > self.conversations.len() {
+            return;
+        }
+        self.invalidate(Region::Messages);
+        self.invalidate(Region::Status);
+        self.tab = tab;
+        self.scroll = 0;
+        match tab {
+            0 => self.clear_unread(),
+            tab => self.conversations[tab - 1].unread = 0,
+        }
+    }
+
+    /// Adds a private message to the conversation with `peer`, opening a
+    /// tab for it if there is none.
+    fn push_whisper(&mut self, peer: UserId, line: Line, users: &UserRegistry) {
+        let index = self
+            .conversations
+            .iter()
+            .position(|c| c.peer == peer)
+            .unwrap_or_else(|| {
+                self.conversations.push(Conversation {
+                    peer,
+                    name: users.display_name(peer),
+                    lines: vec![],
+                    unread: 0,
+                    unacknowledged: false,
+                });
+                self.conversations.len() - 1
+            });
+        self.invalidate(Region::Status);
+        let conversation = &mut self.conversations[index];
+        // whispers of the peer are the ones without a read receipt
+        if line.record.is_some() && line.read.is_none() {
+            conversation.unacknowledged = true;
+        }
+        conversation.lines.push(line);
+        if self.tab != index + 1 {
+            conversation.unread += 1;
+        } else if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    /// Tells in the conversation with `peer` that it is encrypted, with
+    /// the fingerprint to compare with theirs.
+    pub fn show_encrypted(
+        &mut self,
+        peer: UserId,
+        fingerprint: &str,
+        users: &UserRegistry,
+    ) {
+        let line = Line::from(vec![
+            (
+                Tone::Event,
+                format!(
+                    "Messages with {} are end-to-end encrypted, check that \
+                     they see the same fingerprint: ",
+                    users.display_name(peer)
+                ),
+            ),
+            (Tone::Normal, fingerprint.to_owned()),
+        ]);
+        self.push_whisper(peer, line, users);
+    }
+
+    /// Returns the newest message of the channel if it came into view since
+    /// the last call, to tell the server it was read.
+    pub fn newly_read(&mut self) -> Option<MsgId> {
+        if !self.at_bottom() {
+            return None;
+        }
+        let newest = self.messages.iter().rev().find_map(|line| line.msg_id)?;
+        if self.marked_read.is_some_and(|read| read >= newest) {
+            return None;
+        }
+        self.marked_read = Some(newest);
+        Some(newest)
+    }
+
+    /// Returns the peer of the conversation in view if whispers of theirs
+    /// came into view since the last call.
+    pub fn newly_read_whispers(&mut self) -> Option<UserId> {
+        if self.tab == 0 || self.scroll > 0 {
+            return None;
+        }
+        let conversation = &mut self.conversations[self.tab - 1];
+        if !conversation.unacknowledged {
+            return None;
+        }
+        conversation.unacknowledged = false;
+        Some(conversation.peer)
+    }
+
+    /// Puts the unread messages divider above the first message newer than
+    /// the read marker.
+    fn place_unread_divider(&mut self) {
+        self.messages.retain(|line| !line.unread_divider);
+        let Some(marker) = self.read_marker else {
+            return;
+        };
+        let Some(index) = self
+            .messages
+            .iter()
+            .position(|line| line.msg_id.is_some_and(|id| id > marker))
+        else {
+            return;
+        };
+        let mut divider =
+            Line::from(vec![(Tone::Warning, "── unread messages ──".into())]);
+        divider.unread_divider = true;
+        // replayed messages that were read still go above it
+        divider.sequence = Some(marker);
+        self.messages.insert(index, divider);
+        self.invalidate(Region::Messages);
+    }
+
+    /// Counts a notable message if it arrives out of view.
+    pub fn mark_unread(&mut self) {
+        if !self.at_bottom() {
+            self.unread += 1;
+            self.invalidate(Region::Status);
+        }
+    }
+
+    fn clear_unread(&mut self) {
+        if self.at_bottom() && self.unread > 0 {
+            self.unread = 0;
+            self.invalidate(Region::Status);
+        }
+    }
+
+    fn handle_key(&mut self, key_event: KeyEvent) -> Option<UIEvent> {
+        if let Some(action) = self.keymap.action(&key_event) {
+            return self.handle_action(action);
+        }
+        if key_event.modifiers.contains(KeyModifiers::ALT) {
+            if let Some(digit) = match key_event.code {
+                KeyCode::Char(c) => c.to_digit(10).filter(|&d| d > 0),
+                _ => None,
+            } {
+                self.switch_tab(digit as usize - 1);
+                return None;
+            }
+        }
+        if key_event.modifiers.contains(KeyModifiers::CONTROL) {
+            if let KeyCode::Char(c) = key_event.code {
+                self.handle_control(c);
+                return None;
+            }
+        }
+        match key_event.code {
+            KeyCode::Backspace => {
+                self.invalidate(Region::Input);
+                self.input.backspace();
+                None
+            }
+            KeyCode::Delete => {
+                self.invalidate(Region::Input);
+                self.input.delete();
+                None
+            }
+            KeyCode::Char(c) => {
+                self.input.insert(c);
+                self.invalidate(Region::Input);
+                None
+            }
+            KeyCode::Left | KeyCode::Right | KeyCode::Home | KeyCode::End => {
+                let word = key_event.modifiers.contains(KeyModifiers::CONTROL);
+                let moved = match key_event.code {
+                    KeyCode::Left if word => self.input.word_left(),
+                    KeyCode::Right if word => self.input.word_right(),
+                    KeyCode::Left => self.input.left(),
+                    KeyCode::Right => self.input.right(),
+                    KeyCode::Home => self.input.home(),
+                    _ => self.input.end(),
+                };
+                if moved {
+                    self.invalidate(Region::Input);
+                }
+                None
+            }
+            KeyCode::Up => {
+                if self.input.older() {
+                    self.invalidate(Region::Input);
+                }
+                None
+            }
+            KeyCode::Down => {
+                if self.input.newer() {
+                    self.invalidate(Region::Input);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Does what a key of the [`Keymap`] is bound to.
+    fn handle_action(&mut self, action: Action) -> Option<UIEvent> {
+        match action {
+            Action::Exit => Some(UIEvent::Exit),
+            Action::Send => {
+                if self.input.is_empty() {
+                    None
+                } else {
+                    let mut event = self.input.text().parse().ok()?;
+                    self.invalidate(Region::Input);
+                    self.input.submit();
+                    // a conversation's tab talks to its peer
+                    if let Some(conversation) = self.tab.checked_sub(1) {
+                        let peer = self.conversations[conversation].peer;
+                        match &mut event {
+                            UIEvent::Message { text, .. } => {
+                                event = UIEvent::Whisper {
+                                    target: peer.to_string(),
+                                    text: text.clone(),
+                                };
+                            }
+                            UIEvent::SendFile { target, .. }
+                            | UIEvent::Encrypt(target) => {
+                                target.get_or_insert_with(|| peer.to_string());
+                            }
+                            _ => (),
+                        }
+                    }
+                    Some(event)
+                }
+            }
+            Action::Spoiler => {
+                self.reveal_spoiler();
+                None
+            }
+            Action::OpenLink => Some(UIEvent::OpenLink(None)),
+            Action::ScrollUp => self.scroll_up(),
+            Action::ScrollDown => {
+                self.scroll_down();
+                None
+            }
+            Action::Roster if self.roster_open => {
+                self.roster_open = false;
+                self.invalidate(Region::Messages);
+                None
+            }
+            Action::Roster => Some(UIEvent::ListUsers),
+            Action::NextTab => {
+                self.switch_tab(
+                    (self.tab + 1) % (self.conversations.len() + 1),
+                );
+                None
+            }
+            Action::ScrollTop => self.scroll_to_top(),
+            Action::ScrollBottom => {
+                self.scroll_to(0);
+                None
+            }
+        }
+    }
+
+    /// Handles the editing shortcuts: Ctrl+W deletes a word, Ctrl+U the
+    /// whole line, Ctrl+Z undoes and Ctrl+Y redoes.
+    fn handle_control(&mut self, c: char) {
+        match c {
+            'w' => self.input.delete_word(),
+            'u' => self.input.clear(),
+            'z' => {
+                self.input.undo();
+            }
+            'y' => {
+                self.input.redo();
+            }
+            _ => return,
+        }
+        self.invalidate(Region::Input);
+    }
+
+    /// Shows that `user_id` was kicked or banned by `by`.
+    fn push_removal(
+        &mut self,
+        user_id: UserId,
+        by: UserId,
+        what: &str,
+        reason: &str,
+        users: &UserRegistry,
+    ) {
+        let mut line = vec![
+            (Tone::Name, users.display_name(user_id)),
+            (Tone::Event, format!(" was {what} by ")),
+            (Tone::Name, users.display_name(by)),
+        ];
+        if !reason.is_empty() {
+            line.push((Tone::Event, format!(": {reason}")));
+        }
+        self.push_line(line);
+    }
+
+    pub fn add_message(
+        &mut self,
+        message: ServerCommand,
+        users: &UserRegistry,
+    ) {
+        self.invalidate(Region::Messages);
+        match message {
+            ServerCommand::Padding | ServerCommand::Ping { .. } => (),
+            ServerCommand::AddUser { user_id, name } => {
+                self.roster.push(UserInfo {
+                    user_id,
+                    name: name.clone(),
+                    role: Role::User,
+                    joined: chrono::Utc::now()
+                        .timestamp()
+                        .try_into()
+                        .unwrap_or(0),
+                });
+                self.invalidate(Region::Roster);
+                let mut line = vec![
+                    (Tone::Event, format!("User Connected {user_id} ")),
+                    (Tone::Name, name),
+                ];
+                if users.is_own(user_id) {
+                    line.push((Tone::Dim, " (you)".to_owned()));
+                }
+                self.push_line(line);
+            }
+            ServerCommand::UserRenamed {
+                user_id,
+                old_name,
+                name,
+            } => {
+                for user in
+                    self.roster.iter_mut().filter(|u| u.user_id == user_id)
+                {
+                    user.name.clone_from(&name);
+                }
+                for conversation in
+                    self.conversations.iter_mut().filter(|c| c.peer == user_id)
+                {
+                    conversation.name.clone_from(&name);
+                }
+                self.invalidate(Region::Roster);
+                self.invalidate(Region::Status);
+                let mut line = vec![
+                    (Tone::Name, old_name),
+                    (Tone::Event, " is now known as ".to_owned()),
+                    (Tone::Name, name),
+                ];
+                if users.is_own(user_id) {
+                    line.push((Tone::Dim, " (you)".to_owned()));
+                }
+                self.push_line(line);
+            }
+            ServerCommand::RemoveUser { user_id } => {
+                self.roster.retain(|u| u.user_id != user_id);
+                self.invalidate(Region::Roster);
+                self.push_line(vec![
+                    (Tone::Event, format!("User Disconnected {user_id} ")),
+                    (Tone::Name, users.display_name(user_id)),
+                ]);
+            }
+            ServerCommand::Message {
+                msg_id,
+                user_id,
+                message,
+                content_type,
+                quote,
+                time,
+                ..
+            } => {
+                self.oldest_msg_id.get_or_insert(msg_id);
+                if users.is_own(user_id) {
+                    self.confirm_pending(msg_id);
+                }
+                let lines = message_lines(
+                    msg_id,
+                    user_id,
+                    message,
+                    content_type,
+                    quote,
+                    time,
+                    users,
+                );
+                self.insert_message(msg_id, lines);
+            }
+            ServerCommand::Welcome { user_id, motd } => {
+                self.push_line(vec![(
+                    Tone::Event,
+                    format!("Joined the server as user {user_id}"),
+                )]);
+                if let Some(motd) = motd {
+                    self.push_line(vec![(Tone::Info, motd)]);
+                }
+            }
+            ServerCommand::NameTaken { name, suggestions } => {
+                let mut line = vec![(
+                    Tone::Error,
+                    format!("The name '{name}' is already taken. "),
+                )];
+                if !suggestions.is_empty() {
+                    line.push((
+                        Tone::Normal,
+                        format!(
+                            "Use `/name <name>` to pick another, e.g. {}",
+                            suggestions.join(", ")
+                        ),
+                    ));
+                }
+                self.push_line(line);
+            }
+            ServerCommand::SearchResults {
+                query,
+                messages,
+                before_msg_id,
+            } => {
+                self.show_search_results(query, messages, before_msg_id, users);
+            }
+            ServerCommand::History {
+                messages,
+                remaining,
+            } => {
+                self.replay = match (self.replay, usize::from(remaining)) {
+                    (_, 0) => None,
+                    (Some((received, expected)), _) => {
+                        Some((received + 1, expected))
+                    }
+                    (None, remaining) => Some((1, remaining + 1)),
+                };
+                self.invalidate(Region::Status);
+                if messages.is_empty() {
+                    self.history = HistoryState::Exhausted;
+                    return;
+                }
+                self.history = HistoryState::Idle;
+                // a reconnect replays what is still on screen
+                let shown: BTreeSet<MsgId> =
+                    self.messages.iter().filter_map(|l| l.sequence).collect();
+                let lines = messages
+                    .into_iter()
+                    .filter(|m| !shown.contains(&m.msg_id))
+                    .flat_map(|m| {
+                        let msg_id = m.msg_id;
+                        self.oldest_msg_id = Some(
+                            self.oldest_msg_id
+                                .map_or(msg_id, |id| id.min(msg_id)),
+                        );
+                        let mut lines = message_lines(
+                            msg_id,
+                            m.user_id,
+                            m.message,
+                            m.content_type,
+                            m.quote,
+                            m.time,
+                            users,
+                        );
+                        for line in &mut lines {
+                            line.sequence = Some(msg_id);
+                        }
+                        lines
+                    });
+                self.messages.splice(0..0, lines.collect::<Vec<_>>());
+                self.place_unread_divider();
+            }
+            ServerCommand::ReadMarker { up_to_msg_id, .. } => {
+                self.read_marker = Some(up_to_msg_id);
+                self.place_unread_divider();
+            }
+            ServerCommand::ReactionUpdate {
+                msg_id, reactions, ..
+            } => self.show_reactions(msg_id, &reactions, users),
+            ServerCommand::WhispersRead { user_id } => {
+                if let Some(conversation) =
+                    self.conversations.iter_mut().find(|c| c.peer == user_id)
+                {
+                    for line in &mut conversation.lines {
+                        if line.read == Some(false) {
+                            line.read = Some(true);
+                        }
+                    }
+                    self.invalidate(Region::Messages);
+                }
+            }
+            ServerCommand::RoleChanged { user_id, role } => {
+                if let Some(user) =
+                    self.roster.iter_mut().find(|u| u.user_id == user_id)
+                {
+                    user.role = role;
+                }
+                self.push_line(vec![
+                    (Tone::Name, users.display_name(user_id)),
+                    (Tone::Event, format!(" is now {role}")),
+                ]);
+            }
+            ServerCommand::InviteCreated { token } => {
+                self.push_line(vec![
+                    (Tone::Event, "Invite created: ".to_owned()),
+                    (Tone::Name, token),
+                ]);
+            }
+            ServerCommand::InviteRevoked { token, existed } => {
+                self.push_line(vec![(
+                    Tone::Event,
+                    if existed {
+                        format!("Invite {token} revoked")
+                    } else {
+                        format!("Invite {token} does not exist")
+                    },
+                )]);
+            }
+            ServerCommand::Users { users } => {
+                self.roster = users;
+                self.invalidate(Region::Roster);
+            }
+            ServerCommand::FileOffer {
+                transfer_id,
+                user_id,
+                target_user_id,
+                name,
+                size,
+            } => {
+                let size = format_size(size);
+                if users.is_own(user_id) {
+                    self.push_line(vec![(
+                        Tone::Event,
+                        format!(
+                            "Offered {name} ({size}) to {} as transfer \
+                             {transfer_id}, `/reject {transfer_id}` to cancel",
+                            users.display_name(target_user_id)
+                        ),
+                    )]);
+                } else {
+                    self.push_line(vec![
+                        (Tone::Name, users.display_name(user_id)),
+                        (
+                            Tone::Event,
+                            format!(
+                                " offers you {name} ({size}), \
+                                 `/accept {transfer_id}` or \
+                                 `/reject {transfer_id}`"
+                            ),
+                        ),
+                    ]);
+                }
+            }
+            ServerCommand::FileAccept { transfer_id } => {
+                self.push_line(vec![(
+                    Tone::Event,
+                    format!("Transfer {transfer_id} was accepted"),
+                )]);
+            }
+            ServerCommand::FileReject {
+                transfer_id,
+                reason,
+            } => {
+                self.push_line(vec![(
+                    Tone::Error,
+                    format!("Transfer {transfer_id} stopped: {reason}"),
+                )]);
+            }
+            // the transfers report how saving the file went
+            ServerCommand::FileChunk { .. }
+            | ServerCommand::FileDone { .. } => {}
+            ServerCommand::ServerInfo {
+                uptime,
+                user_count,
+                version,
+                motd,
+            } => {
+                self.push_line(vec![(
+                    Tone::Info,
+                    format!(
+                        "Server version {version}, up for {}, {user_count} \
+                         user{} online",
+                        format_uptime(uptime),
+                        if user_count == 1 { "" } else { "s" }
+                    ),
+                )]);
+                if let Some(motd) = motd {
+                    self.push_line(vec![(Tone::Info, motd)]);
+                }
+            }
+            ServerCommand::UserList { users } => {
+                let mut line = vec![(Tone::Event, "Online: ".to_owned())];
+                for (i, (_, name)) in users.into_iter().enumerate() {
+                    if i > 0 {
+                        line.push((Tone::Event, ", ".to_owned()));
+                    }
+                    line.push((Tone::Name, name));
+                }
+                self.push_line(line);
+            }
+            ServerCommand::Kicked {
+                user_id,
+                by,
+                reason,
+            } => self.push_removal(user_id, by, "kicked", &reason, users),
+            ServerCommand::Banned {
+                user_id,
+                by,
+                reason,
+            } => self.push_removal(user_id, by, "banned", &reason, users),
+            ServerCommand::Registered { name, .. } => {
+                self.push_line(vec![
+                    (Tone::Name, name),
+                    (
+                        Tone::Event,
+                        " is registered, use `/login` to connect \
+                         with it from now on"
+                            .to_owned(),
+                    ),
+                ]);
+            }
+            ServerCommand::LoggedIn { name, .. } => {
+                self.push_line(vec![
+                    (Tone::Event, "Logged in as ".to_owned()),
+                    (Tone::Name, name),
+                ]);
+            }
+            ServerCommand::TopicChanged { user_id, topic, .. } => {
+                if let Some(user_id) = user_id {
+                    let change = if topic.is_empty() {
+                        " cleared the topic".to_owned()
+                    } else {
+                        format!(" set the topic: {topic}")
+                    };
+                    self.push_line(vec![
+                        (Tone::Name, users.display_name(user_id)),
+                        (Tone::Event, change),
+                    ]);
+                }
+                self.set_topic(Some(topic).filter(|t| !t.is_empty()));
+            }
+            ServerCommand::ServerShutdown { reason } => {
+                self.push_line(vec![(
+                    Tone::Error,
+                    format!("The server is shutting down: {reason}"),
+                )]);
+            }
+            ServerCommand::Notice { text } => {
+                self.push_line(vec![
+                    (Tone::Dim, "-server- ".to_owned()),
+                    (Tone::Event, text),
+                ]);
+            }
+            ServerCommand::RoomArchived { name, .. } => {
+                self.push_line(vec![
+                    (Tone::Name, format!("#{name}")),
+                    (
+                        Tone::Event,
+                        " was archived, joining it brings it back".to_owned(),
+                    ),
+                ]);
+            }
+            ServerCommand::ConnectRejected { reason } => {
+                let mut line = vec![(
+                    Tone::Error,
+                    format!("Connection rejected: {reason}. "),
+                )];
+                if users.own_id().is_none() {
+                    line.push((
+                        Tone::Normal,
+                        "Use `/name <name>` to try another name".to_owned(),
+                    ));
+                }
+                self.push_line(line);
+            }
+            ServerCommand::PermissionDenied { command, required } => {
+                self.push_line(vec![(
+                    Tone::Error,
+                    format!("You need to be {required} to use {command}"),
+                )]);
+            }
+            ServerCommand::Ack { msg_id } => {
+                if let Some(line) = self.oldest_pending(Delivery::Sent) {
+                    line.msg_id = Some(msg_id);
+                    if let Some(pending) = &mut line.pending {
+                        pending.delivery = Delivery::Acked;
+                        line.segments = pending.segments();
+                    }
+                }
+            }
+            ServerCommand::CommandFailed { command, reason } => {
+                // the message stays where it was, but it's no longer on its
+                // way
+                if command == "message" || command == "quote" {
+                    if let Some(line) = self.oldest_pending(Delivery::Sent) {
+                        let text = line.pending.take().map(|p| p.text);
+                        line.segments = vec![
+                            (Tone::Error, "(not sent) ".to_owned()).into(),
+                            (Tone::Muted, text.unwrap_or_default()).into(),
+                        ];
+                    }
+                }
+                self.push_line(vec![(
+                    Tone::Error,
+                    format!("Could not {command}: {reason}"),
+                )]);
+            }
+            ServerCommand::Whisper {
+                user_id,
+                target_user_id,
+                message,
+            } => {
+                let (peer, tone) = if users.is_own(user_id) {
+                    (target_user_id, Tone::OwnName)
+                } else {
+                    (user_id, Tone::Name)
+                };
+                let record = ChatRecord {
+                    time: chrono::Utc::now()
+                        .timestamp()
+                        .try_into()
+                        .unwrap_or(0),
+                    channel: None,
+                    name: users.display_name(user_id),
+                    message: message.clone(),
+                };
+                let mut line = Line::from(vec![
+                    (tone, format!("{}: ", users.display_name(user_id))),
+                    (Tone::Whisper, message),
+                ]);
+                line.record = Some(record);
+                if tone == Tone::OwnName {
+                    line.read = Some(false);
+                }
+                self.push_whisper(peer, line, users);
+            }
+            ServerCommand::OfflineWhisper {
+                user_id,
+                name,
+                message,
+                time,
+            } => {
+                let record = ChatRecord {
+                    time,
+                    channel: None,
+                    name: name.clone(),
+                    message: message.clone(),
+                };
+                let mut line = Line::from(vec![
+                    (Tone::Name, format!("{name}: ")),
+                    (Tone::Whisper, message),
+                    (Tone::Info, " (sent while you were away)".to_owned()),
+                ]);
+                line.time = Some(time);
+                line.record = Some(record);
+                self.push_whisper(user_id, line, users);
+            }
+            // only whispers that could not be decrypted get here
+            ServerCommand::EncryptedWhisper {
+                user_id,
+                target_user_id,
+                ..
+            } => {
+                let peer = if users.is_own(user_id) {
+                    target_user_id
+                } else {
+                    user_id
+                };
+                let line = Line::from(vec![
+                    (Tone::Name, format!("{}: ", users.display_name(user_id))),
+                    (
+                        Tone::Error,
+                        "[encrypted message that could not be decrypted]"
+                            .to_owned(),
+                    ),
+                ]);
+                self.push_whisper(peer, line, users);
+            }
+            ServerCommand::KeyExchange { .. } => (),
+            ServerCommand::Joined { name, .. } => {
+                // the scrollback belongs to the channel that was left
+                if self.channel.is_some() {
+                    self.messages.retain(|line| line.pending.is_some());
+                    self.search_results = None;
+                    self.search_more = None;
+                    self.scroll = 0;
+                    self.unread = 0;
+                    self.reset_history();
+                }
+                self.read_marker = None;
+                self.marked_read = None;
+                self.invalidate(Region::Status);
+                self.push_line(vec![
+                    (Tone::Event, "Joined channel ".to_owned()),
+                    (Tone::Name, format!("#{name}")),
+                ]);
+                self.channel = Some(name);
+                // the server sends the new channel's topic next, if any
+                self.set_topic(None);
+            }
+        }
+    }
+
+    /// Shows the translation of a message below it.
+    pub fn add_translation(&mut self, msg_id: MsgId, translation: String) {
+        let Some(index) = self
+            .messages
+            .iter()
+            .rposition(|line| line.msg_id == Some(msg_id))
+        else {
+            return;
         };
-        this.stdout.execute(EnterAlternateScreen)?;
-        terminal::enable_raw_mode()?;
-        (this.width, this.height) = terminal::size()?;
-        Ok(this)
+        self.invalidate(Region::Messages);
+        let mut line = Line::from(vec![
+            (Tone::Dim, "  -> ".to_owned()),
+            (Tone::Muted, translation),
+        ]);
+        line.sequence = self.messages[index].sequence;
+        self.messages.insert(index + 1, line);
     }
 
-    pub fn render(&mut self) -> Result<()> {
-        if !self.dirty {
-            return Ok(());
-        }
-        self.stdout.queue(Clear(ClearType::All))?;
-        self.dirty = false;
-        for (offset, (index, message)) in self
+    /// Counts the reactions to a message below it, replacing the counts
+    /// shown before.
+    fn show_reactions(
+        &mut self,
+        msg_id: MsgId,
+        reactions: &[Reaction],
+        users: &UserRegistry,
+    ) {
+        if let Some(index) = self
             .messages
             .iter()
-            .enumerate()
-            .rev()
-            .enumerate()
-            .take(self.height as usize - 2)
+            .position(|line| line.reactions_of == Some(msg_id))
         {
-            self.stdout
-                .queue(MoveTo(0, self.height - 3 - (offset as u16)))?;
-            self.stdout.queue(SetForegroundColor(Color::DarkGrey))?;
-            write!(self.stdout, "{index}> ")?;
-            for (color, text) in message {
-                self.stdout.queue(SetForegroundColor(*color))?;
-                write!(self.stdout, "{text}")?;
+            self.messages.remove(index);
+            if self.scroll > 0 && self.search_results.is_none() && self.tab == 0
+            {
+                self.scroll -= 1;
             }
         }
+        self.invalidate(Region::Messages);
+        if reactions.is_empty() {
+            return;
+        }
+        let Some(index) = self
+            .messages
+            .iter()
+            .rposition(|line| line.sequence == Some(msg_id))
+        else {
+            return;
+        };
+        let mut segments = vec![(Tone::Dim, " ".to_owned())];
+        for reaction in reactions {
+            let own = reaction.user_ids.iter().any(|&id| users.is_own(id));
+            segments.push((
+                if own { Tone::OwnName } else { Tone::Dim },
+                format!(" {} {}", reaction.emoji, reaction.user_ids.len()),
+            ));
+        }
+        let mut line = Line::from(segments);
+        line.sequence = Some(msg_id);
+        line.reactions_of = Some(msg_id);
+        self.insert_lines(index + 1, vec![line]);
+    }
 
-        self.stdout.queue(MoveTo(0, self.height - 2))?;
-        self.stdout.queue(SetForegroundColor(Color::Reset))?;
-        write!(self.stdout, "{}", "-".repeat(self.width as usize))?;
-
-        self.stdout.queue(MoveTo(0, self.height - 1))?;
+    /// Shows a message written here until the server echoes it, greyed
+    /// out while it's `sent` or queued to be sent once connected.
+    pub fn add_outgoing(&mut self, message: &str, sent: bool) {
+        self.invalidate(Region::Messages);
+        let pending = Pending {
+            text: message.to_owned(),
+            delivery: if sent {
+                Delivery::Sent
+            } else {
+                Delivery::Queued
+            },
+        };
+        let mut line = Line::from(vec![]);
+        line.segments = pending.segments();
+        line.pending = Some(pending);
+        self.insert_lines(self.messages.len(), vec![line]);
+    }
 
-        // TODO: handle wide characters
-        let char_count = self.typing_buffer.chars().count();
-        if char_count > self.width as usize {
-            self.stdout.queue(SetForegroundColor(Color::Grey))?;
-            write!(self.stdout, "...")?;
-            self.stdout.queue(SetForegroundColor(Color::Reset))?;
-            for c in self
-                .typing_buffer
-                .chars()
-                .skip(char_count - self.width as usize + 3)
-            {
-                write!(self.stdout, "{c}")?;
-            }
-        } else {
-            self.stdout.queue(SetForegroundColor(Color::Reset))?;
-            for c in self.typing_buffer.chars() {
-                write!(self.stdout, "{c}")?;
-            }
+    /// Forgets what is known about the server's history, e.g. after
+    /// connecting to a different server.
+    pub fn reset_history(&mut self) {
+        self.oldest_msg_id = None;
+        self.history = HistoryState::Idle;
+        self.replay = None;
+        // ids of another connection say nothing about the order of new
+        // messages, which go below them
+        for line in &mut self.messages {
+            line.sequence = None;
         }
-        self.stdout.flush()?;
-        Ok(())
     }
 
-    fn mark_dirty(&mut self) {
-        self.dirty = true;
+    /// Closes the conversation tabs, whose peers are users of the server
+    /// connected to before.
+    pub fn close_conversations(&mut self) {
+        self.conversations.clear();
+        self.switch_tab(0);
+        self.invalidate(Region::Status);
     }
 
-    fn handle_key(&mut self, key_event: KeyEvent) -> Option<UIEvent> {
-        match key_event.code {
-            EXIT_KEY => Some(UIEvent::Exit),
-            KeyCode::Backspace => {
-                self.mark_dirty();
-                self.typing_buffer.pop();
-                None
-            }
-            KeyCode::Enter => {
-                if self.typing_buffer.is_empty() {
-                    None
-                } else {
-                    let event = self.typing_buffer.parse().ok()?;
-                    self.mark_dirty();
-                    // TODO: add to history
-                    self.typing_buffer.clear();
-                    Some(event)
-                }
-            }
-            KeyCode::Char(c) => {
-                self.typing_buffer.push(c);
-                self.mark_dirty();
-                None
-            }
-            _ => None,
+    /// Forgets the current channel and the users, e.g. after
+    /// disconnecting.
+    pub fn leave_channel(&mut self) {
+        self.invalidate(Region::Status);
+        self.invalidate(Region::Roster);
+        self.channel = None;
+        self.set_topic(None);
+        self.roster.clear();
+    }
+
+    /// Rings the terminal bell.
+    pub fn bell(&mut self) -> Result<()> {
+        write!(self.stdout, "\x07")?;
+        self.stdout.flush()
+    }
+
+    /// Shows `topic` in the header, which takes a row from the message
+    /// pane while there is one.
+    fn set_topic(&mut self, topic: Option<String>) {
+        if topic != self.topic {
+            self.invalidate(Region::Header);
+            self.invalidate(Region::Messages);
+            self.topic = topic;
         }
     }
 
-    pub fn add_message(&mut self, message: ServerCommand) {
-        self.mark_dirty();
-        match message {
-            ServerCommand::Padding => (),
-            ServerCommand::AddUser { user_id, name } => {
-                self.messages.push(vec![
-                    (Color::Blue, format!("User Connected {user_id}")),
-                    (Color::White, name),
-                ]);
+    /// Shows how reconnecting goes in the status line, or stops showing it
+    /// if `None`.
+    pub fn set_reconnect_status(&mut self, status: Option<String>) {
+        self.invalidate(Region::Status);
+        self.reconnect_status = status;
+    }
+
+    /// Switches between formatted and raw display of messages, returning
+    /// whether raw display is now on.
+    pub fn toggle_plain(&mut self) -> bool {
+        self.invalidate(Region::Messages);
+        self.plain = !self.plain;
+        self.plain
+    }
+
+    /// Shows or hides when messages were sent.
+    pub fn set_timestamps(&mut self, timestamps: bool) {
+        self.invalidate(Region::Messages);
+        self.timestamps = timestamps;
+    }
+
+    /// Shows a page of search results, above the ones shown if it is the
+    /// next page of the same search.
+    fn show_search_results(
+        &mut self,
+        query: String,
+        messages: Vec<MessageRecord>,
+        before_msg_id: Option<MsgId>,
+        users: &UserRegistry,
+    ) {
+        let lines: Vec<_> = messages
+            .into_iter()
+            .flat_map(|m| {
+                message_lines(
+                    m.msg_id,
+                    m.user_id,
+                    m.message,
+                    m.content_type,
+                    m.quote,
+                    m.time,
+                    users,
+                )
+            })
+            .collect();
+        let next_page = self
+            .search_more
+            .take()
+            .is_some_and(|c| c.loading && c.query == query);
+        let results = match &mut self.search_results {
+            Some(results) if next_page => {
+                // the view stays in place, scroll counts from the bottom
+                results.splice(1..1, lines);
+                results
             }
-            ServerCommand::RemoveUser { user_id } => {
-                self.messages.push(vec![(
-                    Color::Blue,
-                    format!("User Disconnected {user_id}"),
-                )]);
+            _ => {
+                self.scroll = 0;
+                self.search_results.insert(
+                    iter::once(Line::from(vec![])).chain(lines).collect(),
+                )
             }
-            ServerCommand::Message {
-                msg_id: _,
-                user_id: _,
-                message,
-            } => self.messages.push(vec![(Color::Reset, message)]),
+        };
+        let count = results.iter().filter(|l| l.msg_id.is_some()).count();
+        let more = if before_msg_id.is_some() {
+            ", scroll up for older ones"
+        } else {
+            ""
+        };
+        results[0] = Line::from(vec![(
+            Tone::Event,
+            format!("{count} results for '{query}'{more}, `/close` to go back"),
+        )]);
+        self.search_more = before_msg_id.map(|before_msg_id| SearchCursor {
+            query,
+            before_msg_id,
+            loading: false,
+        });
+        self.invalidate(Region::Status);
+    }
+
+    /// Closes the conversation tab shown, or the search results when the
+    /// channel's tab is.
+    pub fn close(&mut self) {
+        self.invalidate(Region::Messages);
+        self.invalidate(Region::Status);
+        if self.tab > 0 {
+            self.conversations.remove(self.tab - 1);
+            self.tab = 0;
+        } else {
+            self.search_results = None;
+            self.search_more = None;
         }
+        self.scroll = 0;
+        self.clear_unread();
+    }
+
+    /// The chat messages of the tab shown, for `/save`.
+    #[must_use]
+    pub fn records(&self) -> Vec<ChatRecord> {
+        let channel = self.channel.clone().filter(|_| self.tab == 0);
+        let lines = match self.tab {
+            0 => &self.messages,
+            tab => &self.conversations[tab - 1].lines,
+        };
+        lines
+            .iter()
+            .filter_map(|line| line.record.clone())
+            .map(|record| ChatRecord {
+                channel: channel.clone(),
+                ..record
+            })
+            .collect()
+    }
+
+    /// Name of the channel the user is in.
+    #[must_use]
+    pub fn channel(&self) -> Option<&str> {
+        self.channel.as_deref()
     }
 
     pub fn add_log(&mut self, log: channel_logger::LogEntry) {
-        self.mark_dirty();
-        self.messages.push(vec![
+        self.invalidate(Region::Messages);
+        self.push_line(vec![
             (
                 match log.level {
-                    log::Level::Error => Color::Red,
-                    log::Level::Warn => Color::DarkYellow,
-                    log::Level::Info => Color::Green,
-                    log::Level::Debug => Color::Blue,
-                    log::Level::Trace => Color::Yellow,
+                    log::Level::Error => Tone::Error,
+                    log::Level::Warn => Tone::Warning,
+                    log::Level::Info => Tone::Info,
+                    log::Level::Debug => Tone::Event,
+                    log::Level::Trace => Tone::Dim,
                 },
                 format!("{}: ", log.level),
             ),
-            (Color::Reset, log.message),
+            (Tone::Normal, log.message),
         ]);
     }
 
@@ -174,9 +2010,14 @@ impl UI {
         Ok(if event::poll(Duration::ZERO)? {
             match event::read()? {
                 Event::Key(event) => self.handle_key(event),
+                Event::Paste(text) => {
+                    self.input.paste(&text);
+                    self.invalidate(Region::Input);
+                    None
+                }
                 Event::Resize(w, h) => {
                     (self.width, self.height) = (w, h);
-                    self.mark_dirty();
+                    self.dirty = Dirty::all();
                     None
                 }
                 _ => None,
@@ -187,12 +2028,398 @@ impl UI {
     }
 }
 
+const fn role_badge(role: Role) -> Option<&'static str> {
+    match role {
+        Role::Admin => Some("[admin] "),
+        Role::Moderator => Some("[mod] "),
+        Role::Guest => Some("[guest] "),
+        Role::User => None,
+    }
+}
+
+/// Formats seconds since the Unix epoch in the local time zone.
+fn local_time(secs: u64, format: &str) -> String {
+    i64::try_from(secs)
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map_or_else(String::new, |time| {
+            time.with_timezone(&Local).format(format).to_string()
+        })
+}
+
+/// Formats a duration in seconds like `2d 3h 4m`, or `42s` below a minute.
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes) =
+        (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, _) => format!("{minutes}m"),
+        (0, _, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+/// One row of the message pane, as pieces drawn in one style each.
+type Row = Vec<(Tone, Attributes, String)>;
+
+/// How lines of the message pane are laid out.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    /// Columns of the message pane.
+    width: usize,
+    /// Show formatted messages as their raw text.
+    plain: bool,
+    /// Show when messages were sent.
+    timestamps: bool,
+    /// Mark our whispers as sent or read.
+    read_receipts: bool,
+    /// Key that reveals spoilers, named in their placeholder.
+    spoiler_key: KeyBinding,
+}
+
+/// Lays out line `index` in rows of at most `layout.width` columns,
+/// breaking between words where it can. Rows after the first are indented
+/// to line up with the text after the label.
+fn wrap_line(line: &Line, index: usize, layout: Layout) -> Vec<Row> {
+    let Layout {
+        width,
+        plain,
+        timestamps,
+        read_receipts,
+        spoiler_key,
+    } = layout;
+    let mut label = match line.msg_id {
+        Some(msg_id) => format!("#{msg_id} "),
+        None => format!("{index}> "),
+    };
+    if let Some(time) = line.time.filter(|_| timestamps) {
+        label.push_str(&local_time(time, "%H:%M:%S "));
+    }
+    let label_width = width::width(&label);
+    // mentions stand out across the whole line
+    let highlight = |attributes: Attributes| {
+        if line.mention {
+            attributes | Attribute::Reverse
+        } else {
+            attributes
+        }
+    };
+    let mut wrapper = Wrapper {
+        rows: vec![vec![]],
+        used: 0,
+        width: width.max(1),
+        // a long label on a narrow screen would leave no room for text
+        indent: if label_width * 2 <= width {
+            label_width
+        } else {
+            0
+        },
+    };
+    wrapper.add(Tone::Dim, highlight(Attributes::none()), &label);
+    let segments = line
+        .plain_segments
+        .as_ref()
+        .filter(|_| plain)
+        .unwrap_or(&line.segments);
+    for segment in segments {
+        if segment.spoiler && !line.revealed {
+            wrapper.add(
+                Tone::Dim,
+                highlight(Attributes::none()),
+                &format!("[spoiler, {spoiler_key} to show]"),
+            );
+        } else {
+            wrapper.add(
+                segment.tone,
+                highlight(segment.attributes),
+                &segment.text,
+            );
+        }
+    }
+    if let Some(read) = line.read.filter(|_| read_receipts) {
+        let mark = if read { " ✓✓" } else { " ✓" };
+        wrapper.add(Tone::Dim, highlight(Attributes::none()), mark);
+    }
+    // wrapped in reading order, so the words that come first in a
+    // right-to-left message end up on its first row
+    wrapper
+        .rows
+        .into_iter()
+        .map(|row| {
+            let row = row.into_iter().map(|(tone, attributes, text)| {
+                ((tone, attributes), text)
+            });
+            bidi::visual_row(row.collect())
+                .into_iter()
+                .map(|((tone, attributes), text)| (tone, attributes, text))
+                .collect()
+        })
+        .collect()
+}
+
+/// Fills rows with styled text word by word.
+struct Wrapper {
+    rows: Vec<Row>,
+    /// Columns taken on the last row.
+    used: usize,
+    width: usize,
+    indent: usize,
+}
+
+impl Wrapper {
+    fn add(&mut self, tone: Tone, attributes: Attributes, text: &str) {
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let end = rest
+                .find(|next: char| {
+                    next == '\n'
+                        || c == '\n'
+                        || next.is_whitespace() != c.is_whitespace()
+                })
+                .unwrap_or(rest.len())
+                .max(c.len_utf8());
+            let (token, after) = rest.split_at(end);
+            rest = after;
+            if c == '\n' {
+                self.break_row();
+            } else if c.is_whitespace() {
+                // tabs and the like would move the cursor on their own
+                let spaces = " ".repeat(token.chars().count());
+                if self.used + spaces.len() > self.width {
+                    self.break_row();
+                } else {
+                    self.push(tone, attributes, &spaces);
+                }
+            } else {
+                self.add_word(tone, attributes, token);
+            }
+        }
+    }
+
+    /// Puts a word on the next row if it doesn't fit on this one but would
+    /// on an empty one, words longer than a row are split anywhere.
+    fn add_word(&mut self, tone: Tone, attributes: Attributes, word: &str) {
+        let word_width = width::width(word);
+        if self.used + word_width > self.width
+            && self.used > self.indent
+            && word_width <= self.width - self.indent
+        {
+            self.break_row();
+        }
+        for grapheme in width::graphemes(word) {
+            if self.used + width::grapheme_width(grapheme) > self.width
+                && self.used > self.indent
+            {
+                self.break_row();
+            }
+            self.push(tone, attributes, grapheme);
+        }
+    }
+
+    fn push(&mut self, tone: Tone, attributes: Attributes, text: &str) {
+        self.used += width::width(text);
+        let row = self.rows.last_mut().expect("there is always a row");
+        match row.last_mut() {
+            Some((last_tone, last_attributes, last))
+                if *last_tone == tone && *last_attributes == attributes =>
+            {
+                last.push_str(text);
+            }
+            _ => row.push((tone, attributes, text.to_owned())),
+        }
+    }
+
+    fn break_row(&mut self) {
+        self.rows.push(vec![(
+            Tone::Normal,
+            Attributes::none(),
+            " ".repeat(self.indent),
+        )]);
+        self.used = self.indent;
+    }
+}
+
+/// Makes the following text look like `tone` in `theme`, with `attributes`
+/// added.
+fn set_tone(
+    stdout: &mut StdoutLock<'static>,
+    theme: Theme,
+    tone: Tone,
+    attributes: Attributes,
+) -> Result<()> {
+    let look = theme.look(tone);
+    stdout.queue(SetAttribute(Attribute::Reset))?;
+    stdout.queue(SetForegroundColor(look.foreground))?;
+    if let Some(background) = look.background {
+        stdout.queue(SetBackgroundColor(background))?;
+    }
+    let mut all = look.attributes;
+    all.extend(attributes);
+    if !all.is_empty() {
+        stdout.queue(SetAttributes(all))?;
+    }
+    Ok(())
+}
+
+/// The lines showing a message: the message itself and what it quotes,
+/// above a reply or below a forward.
+fn message_lines(
+    msg_id: MsgId,
+    user_id: UserId,
+    message: String,
+    content_type: ContentType,
+    quote: Option<Quote>,
+    time: u64,
+    users: &UserRegistry,
+) -> Vec<Line> {
+    let Some(quote) = quote else {
+        return vec![message_line(
+            msg_id,
+            user_id,
+            message,
+            content_type,
+            time,
+            users,
+        )];
+    };
+    let quote_line = Line::from(vec![
+        (Tone::Dim, format!("  | #{} ", quote.msg_id)),
+        (
+            Tone::Name,
+            format!("{}: ", users.display_name(quote.user_id)),
+        ),
+        (Tone::Muted, quote.text),
+    ]);
+    if message.is_empty() {
+        let mut forward = message_line(
+            msg_id,
+            user_id,
+            String::new(),
+            ContentType::Plain,
+            time,
+            users,
+        );
+        // drop the empty text and turn "name: " into "name forwarded:"
+        forward.segments.pop();
+        if let Some(name) = forward.segments.last_mut() {
+            name.text.truncate(name.text.trim_end_matches(": ").len());
+            name.text.push_str(" forwarded:");
+        }
+        vec![forward, quote_line]
+    } else {
+        vec![
+            quote_line,
+            message_line(msg_id, user_id, message, content_type, time, users),
+        ]
+    }
+}
+
+fn message_line(
+    msg_id: MsgId,
+    user_id: UserId,
+    message: String,
+    content_type: ContentType,
+    time: u64,
+    users: &UserRegistry,
+) -> Line {
+    let badge = users.get(user_id).and_then(|u| role_badge(u.role));
+    let mut line: Vec<_> = badge
+        .map(|b| (Tone::Badge, b.to_owned()))
+        .into_iter()
+        .collect();
+    line.push((
+        if users.is_own(user_id) {
+            Tone::OwnName
+        } else {
+            Tone::Name
+        },
+        format!("{}: ", users.display_name(user_id)),
+    ));
+    let mut segments: Vec<_> = line.into_iter().map(Segment::from).collect();
+    let mention = !users.is_own(user_id) && mentions_me(&message, users);
+    let plain_segments = match content_type {
+        ContentType::Plain => None,
+        ContentType::Markdown => {
+            let mut plain: Vec<_> = segments
+                .iter()
+                .map(|s| Segment::from((s.tone, s.text.clone())))
+                .collect();
+            plain.push((Tone::Normal, message.clone()).into());
+            segments.extend(
+                markdown::parse(&message).into_iter().map(Segment::from),
+            );
+            Some(plain)
+        }
+    };
+    let record = ChatRecord {
+        time,
+        channel: None,
+        name: users.display_name(user_id),
+        message: message.clone(),
+    };
+    if plain_segments.is_none() {
+        segments.push((Tone::Normal, message).into());
+    }
+    Line {
+        msg_id: Some(msg_id),
+        sequence: Some(msg_id),
+        pending: None,
+        segments: link_segments(segments),
+        plain_segments: plain_segments.map(link_segments),
+        revealed: false,
+        time: Some(time).filter(|&t| t > 0),
+        mention,
+        record: Some(record),
+        unread_divider: false,
+        read: None,
+        reactions_of: None,
+    }
+}
+
+/// Splits the URLs out of normal text into segments of their own, shown
+/// as links.
+fn link_segments(segments: Vec<Segment>) -> Vec<Segment> {
+    let mut split = vec![];
+    for segment in segments {
+        let links = links::find(&segment.text);
+        if segment.tone != Tone::Normal || links.is_empty() {
+            split.push(segment);
+            continue;
+        }
+        let piece = |tone, text: &str| Segment {
+            tone,
+            attributes: segment.attributes,
+            text: text.to_owned(),
+            spoiler: segment.spoiler,
+        };
+        let mut end = 0;
+        for link in links {
+            if link.start > end {
+                split.push(piece(Tone::Normal, &segment.text[end..link.start]));
+            }
+            split.push(piece(Tone::Link, &segment.text[link.clone()]));
+            end = link.end;
+        }
+        if end < segment.text.len() {
+            split.push(piece(Tone::Normal, &segment.text[end..]));
+        }
+    }
+    split
+}
+
 impl Drop for UI {
     fn drop(&mut self) {
+        if !self.attached {
+            return;
+        }
         match terminal::disable_raw_mode() {
             Ok(()) => (),
             Err(e) => error!("Error while disabling raw mode: {e}"),
         }
+        match stdout().execute(DisableBracketedPaste) {
+            Ok(_) => (),
+            Err(e) => error!("Error while disabling bracketed paste: {e}"),
+        }
         match stdout().execute(LeaveAlternateScreen) {
             Ok(_) => (),
             Err(e) => error!("Error while leaving alternate screen: {e}"),
@@ -202,11 +2429,121 @@ impl Drop for UI {
 
 pub enum UIEvent {
     Exit,
-    Message(String),
+    Message {
+        text: String,
+        /// Set by starting the message with `>>` and a message id.
+        quote: Option<MsgId>,
+    },
+    /// Change which messages ring the bell.
+    Notify(NotifyMode),
+    /// Change the notifications of a room, the current one if `None`.
+    RoomNotify {
+        room: Option<String>,
+        setting: RoomNotify,
+    },
+    /// Send a private message to the user with the given name or id.
+    Whisper {
+        target: String,
+        text: String,
+    },
+    /// Send a message of the current channel to another one.
+    Forward {
+        msg_id: MsgId,
+        channel: String,
+    },
+    /// React to a message of the current channel, or take the reaction
+    /// back.
+    React {
+        msg_id: MsgId,
+        emoji: String,
+    },
     Connect {
         server_addr: String,
         user_name: String,
+        /// The server's password, `-` when connecting with an invite only.
+        password: Option<String>,
+        invite: Option<String>,
+    },
+    /// Connect under a registered name.
+    Login {
+        server_addr: String,
+        user_name: String,
+        password: String,
+    },
+    /// Reserve the current name with a password.
+    Register(String),
+    Name(String),
+    Search(String),
+    /// Ask for the page of search results older than `before_msg_id`.
+    SearchMore {
+        query: String,
+        before_msg_id: MsgId,
+    },
+    /// Close the conversation tab shown, or the search results.
+    Close,
+    /// Show the fingerprint of the server's certificate.
+    Fingerprint,
+    NetStats,
+    LoadHistory {
+        before_msg_id: MsgId,
+    },
+    SetRole {
+        user_id: UserId,
+        role: Role,
+    },
+    /// Disconnect a user by name or id, banning them if `ban` is set.
+    Kick {
+        target: String,
+        reason: String,
+        ban: bool,
     },
+    CreateInvite {
+        uses: u16,
+        valid_minutes: u16,
+    },
+    RevokeInvite(String),
+    /// Translate incoming messages in the current channel, or stop.
+    Translate(bool),
+    Tts(NotifyMode),
+    /// Toggle formatting of messages, both shown and sent.
+    Plain,
+    /// Show or hide when messages were sent.
+    Timestamps(bool),
+    Theme(Theme),
+    /// Suppress notifications for a while, or stop doing so if `None`.
+    Dnd(Option<Duration>),
+    /// Move to the channel with the given name.
+    Join(String),
+    /// Open the roster panel with the connected users.
+    ListUsers,
+    /// Ask the server for its version, uptime and user count.
+    ServerInfo,
+    /// Ask the server how busy each channel is, for operators.
+    RoomStats,
+    /// Open the first link of a message in the browser, the newest link
+    /// shown if `None`.
+    OpenLink(Option<MsgId>),
+    /// Offer a file to a user by name or id, to the peer of the
+    /// conversation shown if `None`.
+    SendFile {
+        path: PathBuf,
+        target: Option<String>,
+    },
+    /// Agree on keys with a user by name or id, to encrypt the whispers
+    /// with them end to end, with the peer of the conversation shown if
+    /// `None`.
+    Encrypt(Option<String>),
+    /// Accept an offered file, saving it to the download directory.
+    AcceptFile(TransferId),
+    /// Decline an offered file, or stop a transfer under way.
+    RejectFile(TransferId),
+    /// Set the topic of the current channel, clear it if empty.
+    Topic(String),
+    /// Write the messages of the tab shown to a file, to a name made up
+    /// from the channel and the time if `None`.
+    Save(Option<PathBuf>),
+    /// Stop connecting to a server that doesn't answer.
+    CancelConnect,
     Disconnect,
 }
 
@@ -221,12 +2558,353 @@ impl FromStr for UIEvent {
                 "connect" => Ok(Self::Connect {
                     server_addr: args.next().ok_or(())?.to_owned(),
                     user_name: args.next().ok_or(())?.to_owned(),
+                    password: args
+                        .next()
+                        .filter(|&p| p != "-")
+                        .map(str::to_owned),
+                    invite: args.next().map(str::to_owned),
+                }),
+                "login" => Ok(Self::Login {
+                    server_addr: args.next().ok_or(())?.to_owned(),
+                    user_name: args.next().ok_or(())?.to_owned(),
+                    password: args.next().ok_or(())?.to_owned(),
+                }),
+                "register" => {
+                    Ok(Self::Register(args.next().ok_or(())?.to_owned()))
+                }
+                "name" => Ok(Self::Name(args.next().ok_or(())?.to_owned())),
+                "search" => {
+                    let query = args.collect::<Vec<_>>().join(" ");
+                    if query.is_empty() {
+                        Err(())
+                    } else {
+                        Ok(Self::Search(query))
+                    }
+                }
+                "close" => Ok(Self::Close),
+                "save" => Ok(Self::Save(args.next().map(PathBuf::from))),
+                "netstats" => Ok(Self::NetStats),
+                "fingerprint" => Ok(Self::Fingerprint),
+                "plain" => Ok(Self::Plain),
+                "timestamps" => match args.next().ok_or(())? {
+                    "on" => Ok(Self::Timestamps(true)),
+                    "off" => Ok(Self::Timestamps(false)),
+                    _ => Err(()),
+                },
+                "theme" => Ok(Self::Theme(
+                    args.next().ok_or(())?.parse().map_err(|_| ())?,
+                )),
+                "tts" => Ok(Self::Tts(args.next().ok_or(())?.parse()?)),
+                "dnd" => match args.next().ok_or(())? {
+                    "off" => Ok(Self::Dnd(None)),
+                    d => {
+                        Ok(Self::Dnd(Some(parse_duration(d).map_err(|_| ())?)))
+                    }
+                },
+                "translate" => match args.next().ok_or(())? {
+                    "on" => Ok(Self::Translate(true)),
+                    "off" => Ok(Self::Translate(false)),
+                    _ => Err(()),
+                },
+                "invite" => match args.next().ok_or(())? {
+                    "create" => Ok(Self::CreateInvite {
+                        uses: args
+                            .next()
+                            .map_or(Ok(1), str::parse)
+                            .map_err(|_| ())?,
+                        valid_minutes: args
+                            .next()
+                            .map_or(Ok(0), str::parse)
+                            .map_err(|_| ())?,
+                    }),
+                    "revoke" => Ok(Self::RevokeInvite(
+                        args.next().ok_or(())?.to_owned(),
+                    )),
+                    _ => Err(()),
+                },
+                "role" => Ok(Self::SetRole {
+                    user_id: args.next().ok_or(())?.parse().map_err(|_| ())?,
+                    role: args.next().ok_or(())?.parse().map_err(|_| ())?,
                 }),
+                cmd @ ("kick" | "ban") => Ok(Self::Kick {
+                    target: args.next().ok_or(())?.to_owned(),
+                    reason: args.collect::<Vec<_>>().join(" "),
+                    ban: cmd == "ban",
+                }),
+                "notify" => match args.next().ok_or(())? {
+                    "room" => Ok(Self::RoomNotify {
+                        setting: args
+                            .next()
+                            .ok_or(())?
+                            .parse()
+                            .map_err(|_| ())?,
+                        room: args.next().map(|r| {
+                            r.strip_prefix('#').unwrap_or(r).to_owned()
+                        }),
+                    }),
+                    mode => Ok(Self::Notify(mode.parse()?)),
+                },
+                "msg" => {
+                    let target = args.next().ok_or(())?.to_owned();
+                    let text = args.collect::<Vec<_>>().join(" ");
+                    if text.is_empty() {
+                        Err(())
+                    } else {
+                        Ok(Self::Whisper { target, text })
+                    }
+                }
+                "forward" => {
+                    let msg_id = args.next().ok_or(())?;
+                    let channel = args.next().ok_or(())?;
+                    Ok(Self::Forward {
+                        msg_id: msg_id
+                            .trim_start_matches('#')
+                            .parse()
+                            .map_err(|_| ())?,
+                        channel: channel
+                            .strip_prefix('#')
+                            .unwrap_or(channel)
+                            .to_owned(),
+                    })
+                }
+                "react" => {
+                    let msg_id = args.next().ok_or(())?;
+                    let emoji = args.next().ok_or(())?;
+                    Ok(Self::React {
+                        msg_id: msg_id
+                            .trim_start_matches('#')
+                            .parse()
+                            .map_err(|_| ())?,
+                        emoji: emoji.to_owned(),
+                    })
+                }
+                "join" => {
+                    let name = args.next().ok_or(())?;
+                    let name = name.strip_prefix('#').unwrap_or(name);
+                    if ChannelId::valid_name(name) {
+                        Ok(Self::Join(name.to_owned()))
+                    } else {
+                        Err(())
+                    }
+                }
                 "disconnect" => Ok(Self::Disconnect),
+                "cancel" => Ok(Self::CancelConnect),
+                "who" => Ok(Self::ListUsers),
+                "info" => Ok(Self::ServerInfo),
+                "stats" => match args.next() {
+                    Some("rooms") => Ok(Self::RoomStats),
+                    _ => Err(()),
+                },
+                "open" => Ok(Self::OpenLink(
+                    args.next()
+                        .map(|id| id.trim_start_matches('#').parse())
+                        .transpose()
+                        .map_err(|_| ())?,
+                )),
+                "sendfile" => Ok(Self::SendFile {
+                    path: args.next().ok_or(())?.into(),
+                    target: args.next().map(str::to_owned),
+                }),
+                "e2e" => Ok(Self::Encrypt(args.next().map(str::to_owned))),
+                cmd @ ("accept" | "reject") => {
+                    let transfer_id =
+                        args.next().ok_or(())?.parse().map_err(|_| ())?;
+                    Ok(if cmd == "accept" {
+                        Self::AcceptFile(transfer_id)
+                    } else {
+                        Self::RejectFile(transfer_id)
+                    })
+                }
+                "topic" => Ok(Self::Topic(args.collect::<Vec<_>>().join(" "))),
                 _ => Err(()),
             }
+        } else if let Some((msg_id, text)) = s
+            .strip_prefix(">>")
+            .and_then(|rest| rest.split_once(' '))
+            .and_then(|(id, text)| Some((id.parse().ok()?, text.trim())))
+        {
+            if text.is_empty() {
+                return Err(());
+            }
+            Ok(Self::Message {
+                text: text.to_owned(),
+                quote: Some(msg_id),
+            })
         } else {
-            Ok(Self::Message(s.to_string()))
+            Ok(Self::Message {
+                text: s.to_string(),
+                quote: None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ME: UserId = UserId(1);
+    const THEM: UserId = UserId(2);
+
+    fn ui() -> UI {
+        let mut ui = UI::detached(&Config::default());
+        ui.messages.clear();
+        ui
+    }
+
+    fn users() -> UserRegistry {
+        let mut users = UserRegistry::new();
+        users.handle(&ServerCommand::Welcome {
+            user_id: ME,
+            motd: None,
+        });
+        users
+    }
+
+    fn message(msg_id: u32, user_id: UserId, text: &str) -> ServerCommand {
+        ServerCommand::Message {
+            msg_id: MsgId(msg_id),
+            user_id,
+            channel_id: ChannelId::LOBBY,
+            message: text.to_owned(),
+            content_type: ContentType::Plain,
+            quote: None,
+            time: 0,
         }
     }
+
+    /// A message from them, as listed in a history reply.
+    fn record(msg_id: u32, text: &str) -> MessageRecord {
+        message(msg_id, THEM, text).try_into().unwrap()
+    }
+
+    /// The text of every line, top to bottom.
+    fn shown(ui: &UI) -> Vec<String> {
+        ui.messages
+            .iter()
+            .map(|line| line.segments.iter().map(|s| &*s.text).collect())
+            .collect()
+    }
+
+    /// Which of `texts` each line shows, top to bottom.
+    fn order<'a>(ui: &UI, texts: &[&'a str]) -> Vec<&'a str> {
+        shown(ui)
+            .iter()
+            .filter_map(|line| texts.iter().find(|t| line.contains(*t)))
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn message_stays_below_until_echoed_then_takes_its_place() {
+        let (mut ui, users) = (ui(), users());
+        let texts = ["first", "mine", "between", "after"];
+        ui.add_message(message(5, THEM, "first"), &users);
+        ui.add_outgoing("mine", true);
+        // the server is slow to acknowledge, others keep talking
+        ui.add_message(message(6, THEM, "between"), &users);
+        assert_eq!(order(&ui, &texts), ["first", "between", "mine"]);
+        assert!(shown(&ui)[2].starts_with("(sending) "));
+        ui.add_message(ServerCommand::Ack { msg_id: MsgId(7) }, &users);
+        ui.add_message(message(8, THEM, "after"), &users);
+        assert_eq!(order(&ui, &texts), ["first", "between", "after", "mine"]);
+        // the echo comes late, but goes where the server put it
+        ui.add_message(message(7, ME, "mine"), &users);
+        assert_eq!(order(&ui, &texts), ["first", "between", "mine", "after"]);
+        assert!(ui.messages.iter().all(|line| line.pending.is_none()));
+    }
+
+    #[test]
+    fn late_messages_go_above_newer_ones() {
+        let (mut ui, users) = (ui(), users());
+        ui.add_message(message(3, THEM, "three"), &users);
+        ui.add_message(message(1, THEM, "one"), &users);
+        ui.add_message(message(2, THEM, "two"), &users);
+        assert_eq!(
+            order(&ui, &["one", "two", "three"]),
+            ["one", "two", "three"]
+        );
+    }
+
+    #[test]
+    fn reconnecting_keeps_old_lines_above_and_queued_ones_below() {
+        let (mut ui, users) = (ui(), users());
+        let texts = ["old", "unsent", "later", "new"];
+        ui.add_message(message(9, THEM, "old"), &users);
+        ui.add_outgoing("unsent", true);
+        // the connection drops before the message is acknowledged
+        ui.requeue();
+        ui.reset_history();
+        ui.add_outgoing("later", false);
+        assert!(shown(&ui)[1].starts_with("(queued) "));
+        // the next server counts from scratch
+        ui.add_message(message(1, THEM, "new"), &users);
+        assert_eq!(order(&ui, &texts), ["old", "new", "unsent", "later"]);
+        ui.mark_sent();
+        assert!(shown(&ui)[2].starts_with("(sending) "));
+        assert!(shown(&ui)[3].starts_with("(sending) "));
+        ui.add_message(ServerCommand::Ack { msg_id: MsgId(2) }, &users);
+        ui.add_message(ServerCommand::Ack { msg_id: MsgId(3) }, &users);
+        ui.add_message(message(3, ME, "later"), &users);
+        ui.add_message(message(2, ME, "unsent"), &users);
+        assert_eq!(order(&ui, &texts), ["old", "new", "unsent", "later"]);
+        assert!(ui.messages.iter().all(|line| line.pending.is_none()));
+    }
+
+    #[test]
+    fn long_replays_are_tracked_until_their_last_reply() {
+        let (mut ui, users) = (ui(), users());
+        let history = |msg_id, remaining| ServerCommand::History {
+            messages: vec![record(msg_id, &format!("m{msg_id}"))],
+            remaining,
+        };
+        // newest first, each reply goes above what came before
+        ui.add_message(history(3, 2), &users);
+        assert_eq!(ui.replay, Some((1, 3)));
+        ui.add_message(history(2, 1), &users);
+        assert_eq!(ui.replay, Some((2, 3)));
+        ui.add_message(history(1, 0), &users);
+        assert_eq!(ui.replay, None);
+        assert_eq!(order(&ui, &["m1", "m2", "m3"]), ["m1", "m2", "m3"]);
+        // a short one never counts as a replay
+        ui.add_message(history(4, 0), &users);
+        assert_eq!(ui.replay, None);
+    }
+
+    #[test]
+    fn replays_after_a_reconnect_skip_what_is_shown() {
+        let (mut ui, users) = (ui(), users());
+        let texts = ["m1", "m2", "m3"];
+        ui.add_message(message(2, THEM, "m2"), &users);
+        ui.add_message(message(3, THEM, "m3"), &users);
+        ui.add_message(
+            ServerCommand::History {
+                messages: (1..=3)
+                    .map(|id| record(id, &format!("m{id}")))
+                    .collect(),
+                remaining: 0,
+            },
+            &users,
+        );
+        assert_eq!(order(&ui, &texts), texts);
+        assert_eq!(shown(&ui).len(), 3);
+    }
+
+    #[test]
+    fn right_to_left_messages_wrap_in_reading_order() {
+        let (mut ui, users) = (ui(), users());
+        ui.add_message(message(1, THEM, "אחת שתיים שלוש"), &users);
+        let layout = Layout {
+            width: 20,
+            ..ui.layout()
+        };
+        let rows: Vec<String> = wrap_line(&ui.messages[0], 0, layout)
+            .iter()
+            .map(|row| row.iter().map(|(_, _, text)| &**text).collect())
+            .collect();
+        // the first word read is on the first row, at its right end
+        assert!(rows.len() > 1, "{rows:?}");
+        assert!(rows[0].trim_end().ends_with("תחא"), "{rows:?}");
+        assert!(rows.last().unwrap().contains("שולש"), "{rows:?}");
+    }
 }
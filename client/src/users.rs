@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use common::commands::{Role, ServerCommand};
+use common::UserId;
+
+/// How long a disconnected user's name is kept around for resolving
+/// messages that still refer to them.
+pub const DEPARTED_USER_GRACE: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    departed: Option<Instant>,
+}
+
+impl User {
+    #[must_use]
+    pub const fn online(&self) -> bool {
+        self.departed.is_none()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct UserRegistry {
+    users: HashMap<UserId, User>,
+    own_id: Option<UserId>,
+}
+
+impl UserRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the registry from a command received from the server.
+    pub fn handle(&mut self, command: &ServerCommand) {
+        match command {
+            ServerCommand::AddUser { user_id, name } => {
+                self.users.insert(
+                    *user_id,
+                    User {
+                        name: name.clone(),
+                        role: Role::User,
+                        departed: None,
+                    },
+                );
+            }
+            ServerCommand::UserList { users } => {
+                for (user_id, name) in users {
+                    self.users.insert(
+                        *user_id,
+                        User {
+                            name: name.clone(),
+                            role: Role::User,
+                            departed: None,
+                        },
+                    );
+                }
+            }
+            ServerCommand::RemoveUser { user_id } => {
+                if let Some(user) = self.users.get_mut(user_id) {
+                    user.departed.get_or_insert_with(Instant::now);
+                }
+            }
+            ServerCommand::UserRenamed { user_id, name, .. } => {
+                if let Some(user) = self.users.get_mut(user_id) {
+                    user.name.clone_from(name);
+                }
+            }
+            ServerCommand::RoleChanged { user_id, role } => {
+                if let Some(user) = self.users.get_mut(user_id) {
+                    user.role = *role;
+                }
+            }
+            // the sender may be long gone, but the conversation needs a name
+            ServerCommand::OfflineWhisper { user_id, name, .. } => {
+                self.users.entry(*user_id).or_insert_with(|| User {
+                    name: name.clone(),
+                    role: Role::User,
+                    departed: Some(Instant::now()),
+                });
+            }
+            ServerCommand::Welcome { user_id, .. } => {
+                self.own_id = Some(*user_id)
+            }
+            _ => (),
+        }
+    }
+
+    /// The id the server assigned to this client, once welcomed.
+    #[must_use]
+    pub const fn own_id(&self) -> Option<UserId> {
+        self.own_id
+    }
+
+    #[must_use]
+    pub fn is_own(&self, user_id: UserId) -> bool {
+        self.own_id == Some(user_id)
+    }
+
+    #[must_use]
+    pub fn get(&self, user_id: UserId) -> Option<&User> {
+        self.users.get(&user_id)
+    }
+
+    /// Finds an online user by name, or by id if `name` is a number.
+    #[must_use]
+    pub fn find(&self, name: &str) -> Option<UserId> {
+        self.online()
+            .find(|(_, u)| u.name == name)
+            .map(|(id, _)| id)
+            .or_else(|| {
+                name.parse()
+                    .ok()
+                    .filter(|id| self.get(*id).is_some_and(User::online))
+            })
+    }
+
+    /// Returns the name of the user, or `user#<id>` if it is not known.
+    #[must_use]
+    pub fn display_name(&self, user_id: UserId) -> String {
+        self.get(user_id)
+            .map_or_else(|| format!("user#{user_id}"), |u| u.name.clone())
+    }
+
+    pub fn online(&self) -> impl Iterator<Item = (UserId, &User)> {
+        self.users
+            .iter()
+            .filter(|(_, u)| u.online())
+            .map(|(id, u)| (*id, u))
+    }
+
+    /// Forgets users that have been offline for longer than `grace`.
+    pub fn prune(&mut self, grace: Duration) {
+        self.users
+            .retain(|_, u| u.departed.is_none_or(|t| t.elapsed() < grace));
+    }
+
+    pub fn clear(&mut self) {
+        self.users.clear();
+        self.own_id = None;
+    }
+}
@@ -0,0 +1,267 @@
+//! Terminal columns taken by text.
+//!
+//! Characters are grouped into grapheme clusters, a base character with
+//! the combining marks, variation selectors, skin tones and zero-width
+//! joined characters that follow it, which the terminal draws as one
+//! glyph. East Asian wide characters and emoji take two columns, most
+//! others one. The rules are simplified from UAX #11 and UAX #29, and the
+//! tables cover the common scripts and emoji rather than all of Unicode.
+
+const ZWJ: char = '\u{200D}';
+const EMOJI_PRESENTATION: char = '\u{FE0F}';
+
+/// Characters drawn on top of the one before them.
+const ZERO_WIDTH: &[(u32, u32)] = &[
+    (0x0300, 0x036F),
+    (0x0483, 0x0489),
+    (0x0591, 0x05BD),
+    (0x05BF, 0x05BF),
+    (0x05C1, 0x05C2),
+    (0x05C4, 0x05C5),
+    (0x05C7, 0x05C7),
+    (0x0610, 0x061A),
+    (0x064B, 0x065F),
+    (0x0670, 0x0670),
+    (0x06D6, 0x06DC),
+    (0x06DF, 0x06E4),
+    (0x06E7, 0x06E8),
+    (0x06EA, 0x06ED),
+    (0x0900, 0x0902),
+    (0x093A, 0x093A),
+    (0x093C, 0x093C),
+    (0x0941, 0x0948),
+    (0x094D, 0x094D),
+    (0x0951, 0x0957),
+    (0x0E31, 0x0E31),
+    (0x0E34, 0x0E3A),
+    (0x0E47, 0x0E4E),
+    (0x1AB0, 0x1AFF),
+    (0x1DC0, 0x1DFF),
+    (0x200B, 0x200F),
+    (0x202A, 0x202E),
+    (0x2060, 0x2064),
+    (0x20D0, 0x20FF),
+    (0x302A, 0x302D),
+    (0x3099, 0x309A),
+    (0xFE00, 0xFE0F),
+    (0xFE20, 0xFE2F),
+    (0xFEFF, 0xFEFF),
+    (0x1F3FB, 0x1F3FF),
+    (0xE0000, 0xE0FFF),
+];
+
+/// East Asian wide and fullwidth characters, and emoji shown as pictures
+/// by default.
+const WIDE: &[(u32, u32)] = &[
+    (0x1100, 0x115F),
+    (0x231A, 0x231B),
+    (0x2329, 0x232A),
+    (0x23E9, 0x23EC),
+    (0x23F0, 0x23F0),
+    (0x23F3, 0x23F3),
+    (0x25FD, 0x25FE),
+    (0x2614, 0x2615),
+    (0x2648, 0x2653),
+    (0x267F, 0x267F),
+    (0x2693, 0x2693),
+    (0x26A1, 0x26A1),
+    (0x26AA, 0x26AB),
+    (0x26BD, 0x26BE),
+    (0x26C4, 0x26C5),
+    (0x26CE, 0x26CE),
+    (0x26D4, 0x26D4),
+    (0x26EA, 0x26EA),
+    (0x26F2, 0x26F3),
+    (0x26F5, 0x26F5),
+    (0x26FA, 0x26FA),
+    (0x26FD, 0x26FD),
+    (0x2705, 0x2705),
+    (0x270A, 0x270B),
+    (0x2728, 0x2728),
+    (0x274C, 0x274C),
+    (0x274E, 0x274E),
+    (0x2753, 0x2755),
+    (0x2757, 0x2757),
+    (0x2795, 0x2797),
+    (0x27B0, 0x27B0),
+    (0x27BF, 0x27BF),
+    (0x2B1B, 0x2B1C),
+    (0x2B50, 0x2B50),
+    (0x2B55, 0x2B55),
+    (0x2E80, 0x303E),
+    (0x3041, 0x3247),
+    (0x3250, 0x4DBF),
+    (0x4E00, 0xA4CF),
+    (0xA960, 0xA97F),
+    (0xAC00, 0xD7A3),
+    (0xF900, 0xFAFF),
+    (0xFE10, 0xFE19),
+    (0xFE30, 0xFE6F),
+    (0xFF00, 0xFF60),
+    (0xFFE0, 0xFFE6),
+    (0x16FE0, 0x16FE4),
+    (0x17000, 0x18CFF),
+    (0x1B000, 0x1B2FF),
+    (0x1F004, 0x1F004),
+    (0x1F0CF, 0x1F0CF),
+    (0x1F18E, 0x1F18E),
+    (0x1F191, 0x1F19A),
+    (0x1F200, 0x1F251),
+    (0x1F300, 0x1F320),
+    (0x1F32D, 0x1F335),
+    (0x1F337, 0x1F37C),
+    (0x1F37E, 0x1F393),
+    (0x1F3A0, 0x1F3CA),
+    (0x1F3CF, 0x1F3D3),
+    (0x1F3E0, 0x1F3F0),
+    (0x1F3F4, 0x1F3F4),
+    (0x1F3F8, 0x1F43E),
+    (0x1F440, 0x1F440),
+    (0x1F442, 0x1F4FC),
+    (0x1F4FF, 0x1F53D),
+    (0x1F54B, 0x1F54E),
+    (0x1F550, 0x1F567),
+    (0x1F57A, 0x1F57A),
+    (0x1F595, 0x1F596),
+    (0x1F5A4, 0x1F5A4),
+    (0x1F5FB, 0x1F64F),
+    (0x1F680, 0x1F6C5),
+    (0x1F6CC, 0x1F6CC),
+    (0x1F6D0, 0x1F6D2),
+    (0x1F6D5, 0x1F6D7),
+    (0x1F6DC, 0x1F6DF),
+    (0x1F6EB, 0x1F6EC),
+    (0x1F6F4, 0x1F6FC),
+    (0x1F7E0, 0x1F7EB),
+    (0x1F7F0, 0x1F7F0),
+    (0x1F90C, 0x1F93A),
+    (0x1F93C, 0x1F945),
+    (0x1F947, 0x1F9FF),
+    (0x1FA70, 0x1FAFF),
+    (0x20000, 0x2FFFD),
+    (0x30000, 0x3FFFD),
+];
+
+fn in_table(table: &[(u32, u32)], c: char) -> bool {
+    let c = u32::from(c);
+    table
+        .binary_search_by(|&(start, end)| {
+            if end < c {
+                std::cmp::Ordering::Less
+            } else if start > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+const fn is_regional_indicator(c: char) -> bool {
+    matches!(c, '\u{1F1E6}'..='\u{1F1FF}')
+}
+
+/// Columns taken by `c` on its own.
+#[must_use]
+pub fn char_width(c: char) -> usize {
+    if c.is_control() || in_table(ZERO_WIDTH, c) {
+        0
+    } else if in_table(WIDE, c) {
+        2
+    } else {
+        1
+    }
+}
+
+/// Splits text into grapheme clusters.
+#[derive(Debug, Clone)]
+pub struct Graphemes<'a> {
+    text: &'a str,
+}
+
+impl<'a> Iterator for Graphemes<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let mut chars = self.text.char_indices();
+        let (_, first) = chars.next()?;
+        let mut prev = first;
+        let mut end = self.text.len();
+        for (i, c) in chars {
+            let joined = prev == ZWJ && !c.is_control();
+            let flag = is_regional_indicator(c)
+                && is_regional_indicator(first)
+                && i == first.len_utf8();
+            if !(joined || flag || c == ZWJ || in_table(ZERO_WIDTH, c)) {
+                end = i;
+                break;
+            }
+            prev = c;
+        }
+        let (grapheme, rest) = self.text.split_at(end);
+        self.text = rest;
+        Some(grapheme)
+    }
+}
+
+#[must_use]
+pub const fn graphemes(text: &str) -> Graphemes<'_> {
+    Graphemes { text }
+}
+
+/// Columns taken by a grapheme cluster, the width of its base character
+/// unless it asks to be drawn as an emoji or is a flag.
+#[must_use]
+pub fn grapheme_width(grapheme: &str) -> usize {
+    let mut chars = grapheme.chars();
+    let Some(first) = chars.next() else {
+        return 0;
+    };
+    let second = chars.next();
+    if second.is_some_and(is_regional_indicator)
+        || grapheme.contains(EMOJI_PRESENTATION) && char_width(first) > 0
+    {
+        2
+    } else {
+        char_width(first)
+    }
+}
+
+/// Columns taken by `text`.
+#[must_use]
+pub fn width(text: &str) -> usize {
+    graphemes(text).map(grapheme_width).sum()
+}
+
+/// The start of `text` that fits in `columns`, cut between grapheme
+/// clusters, and its width.
+#[must_use]
+pub fn head(text: &str, columns: usize) -> (&str, usize) {
+    let mut used = 0;
+    let mut end = 0;
+    for grapheme in graphemes(text) {
+        let width = grapheme_width(grapheme);
+        if used + width > columns {
+            break;
+        }
+        used += width;
+        end += grapheme.len();
+    }
+    (&text[..end], used)
+}
+
+/// The end of `text` that fits in `columns`, cut between grapheme
+/// clusters.
+#[must_use]
+pub fn tail(text: &str, columns: usize) -> &str {
+    let mut used = 0;
+    let mut start = text.len();
+    for grapheme in graphemes(text).collect::<Vec<_>>().into_iter().rev() {
+        used += grapheme_width(grapheme);
+        if used > columns {
+            break;
+        }
+        start -= grapheme.len();
+    }
+    &text[start..]
+}
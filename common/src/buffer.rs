@@ -1,6 +1,6 @@
 use std::io::{Error, ErrorKind, Read, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Buffer {
     buf: Vec<u8>,
     cursor: usize,
@@ -25,10 +25,51 @@ impl Codec for str {
     }
 }
 
-#[allow(clippy::cast_possible_truncation)]
+impl Codec for String {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.as_str().code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        str::decode(r)
+    }
+
+    fn coded_size(&self) -> usize {
+        self.as_str().coded_size()
+    }
+}
+
+/// Codes the length of a string, byte slice or list, failing with
+/// [`ErrorKind::InvalidData`] if it doesn't fit in a `u16`.
+fn code_len(len: usize, w: &mut impl Write) -> Result<()> {
+    let len = u16::try_from(len).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("a length of {len} is over the limit of {}", u16::MAX),
+        )
+    })?;
+    len.code(w)
+}
+
+impl<T: Codec<Owned = T> + Clone> Codec for Vec<T> {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        code_len(self.len(), w)?;
+        self.iter().try_for_each(|item| item.code(w))
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        let len = u16::decode(r)?;
+        (0..len).map(|_| T::decode(r)).collect()
+    }
+
+    fn coded_size(&self) -> usize {
+        size_of::<u16>() + self.iter().map(Codec::coded_size).sum::<usize>()
+    }
+}
+
 impl Codec for [u8] {
     fn code(&self, w: &mut impl Write) -> Result<()> {
-        (self.len() as u16).code(w)?;
+        code_len(self.len(), w)?;
         w.write_all(self)
     }
 
@@ -40,7 +81,7 @@ impl Codec for [u8] {
     }
 
     fn coded_size(&self) -> usize {
-        (self.len() as u16).coded_size() + self.len()
+        size_of::<u16>() + self.len()
     }
 }
 
@@ -60,3 +101,235 @@ impl Codec for u16 {
         size_of::<Self>()
     }
 }
+
+impl Codec for u32 {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        let buf = self.to_be_bytes();
+        w.write_all(&buf)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        let mut buf = [0; 4];
+        r.read_exact(&mut buf)?;
+        Ok(Self::from_be_bytes(buf))
+    }
+
+    fn coded_size(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+impl Codec for u64 {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        let buf = self.to_be_bytes();
+        w.write_all(&buf)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf)?;
+        Ok(Self::from_be_bytes(buf))
+    }
+
+    fn coded_size(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+impl Codec for bool {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(&[u8::from(*self)])
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        let mut buf = [0; 1];
+        r.read_exact(&mut buf)?;
+        match buf[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::from(ErrorKind::InvalidData)),
+        }
+    }
+
+    fn coded_size(&self) -> usize {
+        1
+    }
+}
+
+impl<A, B> Codec for (A, B)
+where
+    A: Codec<Owned = A> + Clone,
+    B: Codec<Owned = B> + Clone,
+{
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.0.code(w)?;
+        self.1.code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        Ok((A::decode(r)?, B::decode(r)?))
+    }
+
+    fn coded_size(&self) -> usize {
+        self.0.coded_size() + self.1.coded_size()
+    }
+}
+
+impl<T: Codec<Owned = T> + Clone> Codec for Option<T> {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.is_some().code(w)?;
+        self.as_ref().map_or(Ok(()), |value| value.code(w))
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        if bool::decode(r)? {
+            T::decode(r).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn coded_size(&self) -> usize {
+        self.is_some().coded_size() + self.as_ref().map_or(0, Codec::coded_size)
+    }
+}
+
+/// Raw bytes, coded like a string but without the UTF-8 check, so at most
+/// `u16::MAX` of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Codec for Bytes {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.0.as_slice().code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        type Slice = [u8];
+        Slice::decode(r).map(Self)
+    }
+
+    fn coded_size(&self) -> usize {
+        self.0.as_slice().coded_size()
+    }
+}
+
+/// Defines a struct with named fields or an enum and implements [`Codec`]
+/// for it, coding the fields in the order they are declared.
+///
+/// Every enum variant is given the `u16` its value is tagged with, after
+/// its fields, e.g. `Ping { token: u16 } = 16`, so ids stay the same when
+/// variants are moved around. Variants can only have named fields or none.
+#[macro_export]
+macro_rules! codec_type {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_attr:meta])* $field_vis:vis $field:ident: $ty:ty),*
+            $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name {
+            $($(#[$field_attr])* $field_vis $field: $ty),*
+        }
+
+        impl $crate::Codec for $name {
+            fn code(&self, w: &mut impl std::io::Write) -> std::io::Result<()> {
+                $($crate::Codec::code(&self.$field, w)?;)*
+                Ok(())
+            }
+
+            fn decode(r: &mut impl std::io::Read) -> std::io::Result<Self> {
+                Ok(Self {
+                    $($field: <$ty as $crate::Codec>::decode(r)?),*
+                })
+            }
+
+            fn coded_size(&self) -> usize {
+                0 $(+ $crate::Codec::coded_size(&self.$field))*
+            }
+        }
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $(#[$variant_attr:meta])*
+                $variant:ident $({
+                    $($(#[$field_attr:meta])* $field:ident: $ty:ty),*
+                    $(,)?
+                })? = $id:literal
+            ),*
+            $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis enum $name {
+            $(
+                $(#[$variant_attr])*
+                $variant $({
+                    $($(#[$field_attr])* $field: $ty),*
+                })?
+            ),*
+        }
+
+        impl $crate::Codec for $name {
+            fn code(&self, w: &mut impl std::io::Write) -> std::io::Result<()> {
+                match self {
+                    $(Self::$variant $({ $($field),* })? => {
+                        let id: u16 = $id;
+                        $crate::Codec::code(&id, w)?;
+                        $($($crate::Codec::code($field, w)?;)*)?
+                    })*
+                }
+                Ok(())
+            }
+
+            fn decode(r: &mut impl std::io::Read) -> std::io::Result<Self> {
+                Ok(match <u16 as $crate::Codec>::decode(r)? {
+                    $($id => Self::$variant $({
+                        $($field: <$ty as $crate::Codec>::decode(r)?),*
+                    })?,)*
+                    _ => {
+                        return Err(std::io::Error::from(
+                            std::io::ErrorKind::InvalidData,
+                        ))
+                    }
+                })
+            }
+
+            fn coded_size(&self) -> usize {
+                match self {
+                    $(Self::$variant $({ $($field),* })? => {
+                        size_of::<u16>()
+                            $($(+ $crate::Codec::coded_size($field))*)?
+                    })*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_string_is_refused_not_truncated() {
+        let long = "a".repeat(usize::from(u16::MAX) + 1);
+        let mut buf = vec![];
+        let e = long.code(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn longest_string_round_trips() {
+        let long = "a".repeat(usize::from(u16::MAX));
+        let mut buf = vec![];
+        long.code(&mut buf).unwrap();
+        assert_eq!(buf.len(), long.coded_size());
+        assert_eq!(String::decode(&mut buf.as_slice()).unwrap(), long);
+    }
+}
@@ -1,136 +1,660 @@
-use std::io::{Error, ErrorKind, Result, Write};
+use std::fmt::Display;
+use std::str::FromStr;
 
-use super::Codec;
+use crate::{codec_type, Bytes, ChannelId, MsgId, TransferId, UserId};
 
-#[derive(Debug, Clone)]
-pub enum ClientCommand {
-    Padding,
-    Connect { name: String },
-    Message { message: String },
+codec_type! {
+    /// Privilege level of a user, ordered from least to most privileged.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub enum Role {
+        Guest = 0,
+        User = 1,
+        Moderator = 2,
+        Admin = 3,
+    }
 }
 
-#[derive(Debug, Clone)]
-pub enum ServerCommand {
-    Padding,
-    AddUser {
-        user_id: u16,
-        name: String,
-    },
-    RemoveUser {
-        user_id: u16,
-    },
-    Message {
-        msg_id: u16,
-        user_id: u16,
-        message: String,
-    },
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Guest => "guest",
+            Self::User => "user",
+            Self::Moderator => "moderator",
+            Self::Admin => "admin",
+        })
+    }
 }
 
-impl Codec for ClientCommand {
-    fn code(&self, w: &mut impl Write) -> Result<()> {
-        match self {
-            Self::Padding => 0u16.code(w),
-            Self::Connect { name } => {
-                1u16.code(w)?;
-                name.code(w)
-            }
-            Self::Message { message } => {
-                2u16.code(w)?;
-                message.code(w)
-            }
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "guest" => Ok(Self::Guest),
+            "user" => Ok(Self::User),
+            "moderator" | "mod" => Ok(Self::Moderator),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!("unknown role `{s}`")),
         }
     }
+}
 
-    fn decode(r: &mut impl std::io::Read) -> Result<Self::Owned> {
-        let id = u16::decode(r)?;
-        Ok(match id {
-            0 => Self::Padding,
-            1 => Self::Connect {
-                name: str::decode(r)?,
-            },
-            2 => Self::Message {
-                message: str::decode(r)?,
-            },
-            _ => return Err(Error::from(ErrorKind::InvalidData)),
-        })
+codec_type! {
+    /// How the text of a message should be interpreted.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum ContentType {
+        #[default]
+        Plain = 0,
+        /// Bold, italic, inline code and code blocks in markdown syntax.
+        Markdown = 1,
     }
+}
 
-    fn coded_size(&self) -> usize {
-        match self {
-            Self::Padding => 0u16.coded_size(),
-            Self::Connect { name } => 1u16.coded_size() + name.coded_size(),
-            Self::Message { message } => {
-                2u16.coded_size() + message.coded_size()
-            }
-        }
+codec_type! {
+    /// An earlier message shown above the one quoting or forwarding it.
+    #[derive(Debug, Clone)]
+    pub struct Quote {
+        pub msg_id: MsgId,
+        /// Author of the quoted message.
+        pub user_id: UserId,
+        /// The start of the quoted text, or all of it when forwarded.
+        pub text: String,
     }
 }
 
-impl Codec for ServerCommand {
-    fn code(&self, w: &mut impl Write) -> Result<()> {
-        match self {
-            Self::Padding => 0u16.code(w),
-            Self::AddUser { user_id, name } => {
-                1u16.code(w)?;
-                user_id.code(w)?;
-                name.code(w)
-            }
-            Self::RemoveUser { user_id } => {
-                2u16.code(w)?;
-                user_id.code(w)
-            }
-            Self::Message {
+codec_type! {
+    /// A connected user, as listed by [`ServerCommand::Users`].
+    #[derive(Debug, Clone)]
+    pub struct UserInfo {
+        pub user_id: UserId,
+        pub name: String,
+        pub role: Role,
+        /// When the user connected, in seconds since the Unix epoch.
+        pub joined: u64,
+    }
+}
+
+codec_type! {
+    /// The users who reacted to a message with the same emoji, as listed by
+    /// [`ServerCommand::ReactionUpdate`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Reaction {
+        pub emoji: String,
+        /// In the order they reacted.
+        pub user_ids: Vec<UserId>,
+    }
+}
+
+codec_type! {
+    /// A chat message listed in a reply, like a search result or the
+    /// history, with the fields of
+    /// [`ServerCommand::Message`], which can't hold other commands.
+    #[derive(Debug, Clone)]
+    pub struct MessageRecord {
+        pub msg_id: MsgId,
+        pub user_id: UserId,
+        pub channel_id: ChannelId,
+        pub message: String,
+        pub content_type: ContentType,
+        pub quote: Option<Quote>,
+        /// When the server received the message, in seconds since the Unix
+        /// epoch, 0 if it was stored without one.
+        pub time: u64,
+    }
+}
+
+codec_type! {
+    #[derive(Debug, Clone)]
+    pub enum ClientCommand {
+        Padding = 0,
+        Connect {
+            name: String,
+            invite: Option<String>,
+            /// Password or token checked by the server's authentication.
+            credential: Option<String>,
+            /// Shared password of the server, if it has one.
+            password: Option<String>,
+        } = 1,
+        Message {
+            message: String,
+            content_type: ContentType,
+            /// Message in the same channel that this one replies to.
+            quote: Option<MsgId>,
+        } = 2,
+        /// Searches all stored messages of the current channel for those
+        /// containing `query`, ignoring case. Answered with a
+        /// [`ServerCommand::SearchResults`] page of the newest matches
+        /// older than `before_msg_id`, if given.
+        Search {
+            query: String,
+            before_msg_id: Option<MsgId>,
+            limit: u16,
+        } = 3,
+        GetHistory {
+            before_msg_id: MsgId,
+            limit: u16,
+        } = 4,
+        SetRole {
+            user_id: UserId,
+            role: Role,
+        } = 5,
+        CreateInvite {
+            uses: u16,
+            valid_minutes: u16,
+        } = 6,
+        RevokeInvite {
+            token: String,
+        } = 7,
+        /// Moves the user to the channel with `name`, creating it if needed.
+        Join {
+            name: String,
+        } = 8,
+        /// Sends a message from the user's channel to another channel, with
+        /// its author shown.
+        Forward {
+            msg_id: MsgId,
+            channel: String,
+        } = 9,
+        /// A private message, only sent to the target and back to the sender.
+        Whisper {
+            target_user_id: UserId,
+            message: String,
+        } = 10,
+        /// Answers a [`ServerCommand::Ping`] with its token.
+        Pong {
+            token: u16,
+        } = 11,
+        /// Asks for the connected users, answered with a
+        /// [`ServerCommand::Users`].
+        ListUsers = 12,
+        /// Disconnects a user, they may connect again.
+        Kick {
+            user_id: UserId,
+            reason: String,
+        } = 13,
+        /// Disconnects a user and bans their name and address.
+        Ban {
+            user_id: UserId,
+            reason: String,
+        } = 14,
+        /// Reserves `name` for whoever knows `password`. The user id of the
+        /// client is kept for the account if it is connected as `name`.
+        Register {
+            name: String,
+            password: String,
+        } = 15,
+        /// Takes the user id of an account, so that its name can be used
+        /// by the next [`ClientCommand::Connect`].
+        Login {
+            name: String,
+            password: String,
+        } = 16,
+        /// Sets the topic of the user's channel, clearing it if empty.
+        SetTopic {
+            topic: String,
+        } = 17,
+        /// Asks for the status of the server, answered with a
+        /// [`ServerCommand::ServerInfo`].
+        ServerInfo = 18,
+        /// Offers the target a file of `size` bytes, the server answers
+        /// both with a [`ServerCommand::FileOffer`] giving it an id.
+        FileOffer {
+            target_user_id: UserId,
+            /// Name of the file, without its directory.
+            name: String,
+            size: u64,
+        } = 19,
+        /// The target of an offer wants the file.
+        FileAccept {
+            transfer_id: TransferId,
+        } = 20,
+        /// Declines an offer, or stops a transfer under way from either
+        /// side.
+        FileReject {
+            transfer_id: TransferId,
+            reason: String,
+        } = 21,
+        /// The next part of an accepted file, from its sender.
+        FileChunk {
+            transfer_id: TransferId,
+            data: Bytes,
+        } = 22,
+        /// The whole file was sent in chunks.
+        FileDone {
+            transfer_id: TransferId,
+        } = 23,
+        /// Sends the target an X25519 public key to agree on the keys of
+        /// an end-to-end encrypted conversation. The server only relays it.
+        KeyExchange {
+            target_user_id: UserId,
+            public_key: Bytes,
+        } = 25,
+        /// A private message only the target can read, sealed with
+        /// ChaCha20-Poly1305 under the keys agreed with a
+        /// [`ClientCommand::KeyExchange`]. `counter` is the nonce, it
+        /// increases with every message.
+        EncryptedWhisper {
+            target_user_id: UserId,
+            counter: u64,
+            ciphertext: Bytes,
+        } = 26,
+        /// Leaves a private message for the registered user `name`, who is
+        /// offline, delivered as a [`ServerCommand::OfflineWhisper`] when
+        /// they next connect.
+        OfflineWhisper {
+            name: String,
+            message: String,
+        } = 27,
+        /// The user read the messages of their channel up to
+        /// `up_to_msg_id`, told back with a [`ServerCommand::ReadMarker`]
+        /// when they join it again, also from another connection to their
+        /// account.
+        MarkRead {
+            up_to_msg_id: MsgId,
+        } = 28,
+        /// The user read the whispers `user_id` sent them so far, relayed
+        /// to `user_id` as a [`ServerCommand::WhispersRead`] if there were
+        /// any unread ones.
+        MarkWhispersRead {
+            user_id: UserId,
+        } = 29,
+        /// Reacts to a message in the user's channel with `emoji`, or takes
+        /// the reaction back if the user already reacted with it.
+        React {
+            msg_id: MsgId,
+            emoji: String,
+        } = 30,
+        /// Changes the name of a connected user, answered with a
+        /// [`ServerCommand::UserRenamed`] to everyone.
+        Rename {
+            new_name: String,
+        } = 31,
+        /// Asks how busy each channel is, answered with a
+        /// [`ServerCommand::Notice`] to operators allowed to know.
+        RoomStats = 32,
+    }
+}
+
+codec_type! {
+    #[derive(Debug, Clone)]
+    pub enum ServerCommand {
+        Padding = 0,
+        AddUser {
+            user_id: UserId,
+            name: String,
+        } = 1,
+        RemoveUser {
+            user_id: UserId,
+        } = 2,
+        Message {
+            msg_id: MsgId,
+            user_id: UserId,
+            channel_id: ChannelId,
+            message: String,
+            content_type: ContentType,
+            quote: Option<Quote>,
+            /// When the server received the message, in seconds since the Unix
+            /// epoch, 0 if it was stored without one.
+            time: u64,
+        } = 3,
+        Welcome {
+            user_id: UserId,
+            /// The server's message of the day, if it has one.
+            motd: Option<String>,
+        } = 4,
+        NameTaken {
+            name: String,
+            suggestions: Vec<String>,
+        } = 5,
+        /// Answers a [`ClientCommand::Search`], oldest match first.
+        SearchResults {
+            query: String,
+            messages: Vec<MessageRecord>,
+            /// Asks for the next, older page of matches when sent back in
+            /// the search, `None` if this page is the last. A page may
+            /// have no matches and still not be the last.
+            before_msg_id: Option<MsgId>,
+        } = 6,
+        History {
+            messages: Vec<MessageRecord>,
+            /// Number of history replies still to come after this one, for
+            /// showing how far a long replay got.
+            remaining: u16,
+        } = 7,
+        RoleChanged {
+            user_id: UserId,
+            role: Role,
+        } = 8,
+        PermissionDenied {
+            command: String,
+            required: Role,
+        } = 9,
+        InviteCreated {
+            token: String,
+        } = 10,
+        InviteRevoked {
+            token: String,
+            existed: bool,
+        } = 11,
+        ConnectRejected {
+            reason: String,
+        } = 12,
+        /// The user is now in the channel, only its messages are sent to them.
+        Joined {
+            channel_id: ChannelId,
+            name: String,
+        } = 13,
+        /// A command was understood but could not be carried out.
+        CommandFailed {
+            command: String,
+            reason: String,
+        } = 14,
+        /// A private message, sent to its target and, as a confirmation, to
+        /// its sender.
+        Whisper {
+            user_id: UserId,
+            target_user_id: UserId,
+            message: String,
+        } = 15,
+        /// Checks that the client is still there, it must answer with a
+        /// [`ClientCommand::Pong`].
+        Ping {
+            token: u16,
+        } = 16,
+        /// The server is stopping and about to close the connection.
+        ServerShutdown {
+            reason: String,
+        } = 17,
+        /// Users that were online before the client connected, sent after
+        /// [`ServerCommand::Welcome`], maybe split over several replies.
+        UserList {
+            users: Vec<(UserId, String)>,
+        } = 18,
+        /// Answers a [`ClientCommand::ListUsers`] with everyone connected, the
+        /// client included.
+        Users {
+            users: Vec<UserInfo>,
+        } = 19,
+        /// Tells the sender of a [`ClientCommand::Message`] that it was posted
+        /// as `msg_id`. Every message is answered with either this or a
+        /// [`ServerCommand::CommandFailed`] for `message` or `quote`, in the
+        /// order they were sent.
+        Ack {
+            msg_id: MsgId,
+        } = 20,
+        /// The user was kicked by `by`, sent to everyone before they are
+        /// disconnected.
+        Kicked {
+            user_id: UserId,
+            by: UserId,
+            reason: String,
+        } = 21,
+        /// Like [`ServerCommand::Kicked`], and the user can't come back.
+        Banned {
+            user_id: UserId,
+            by: UserId,
+            reason: String,
+        } = 22,
+        /// Answers a [`ClientCommand::Register`].
+        Registered {
+            name: String,
+            user_id: UserId,
+        } = 23,
+        /// Answers a [`ClientCommand::Login`].
+        LoggedIn {
+            name: String,
+            user_id: UserId,
+        } = 24,
+        /// The topic of a channel, sent when it changes with who changed it
+        /// and to users joining the channel without.
+        TopicChanged {
+            channel_id: ChannelId,
+            user_id: Option<UserId>,
+            topic: String,
+        } = 25,
+        /// Answers a [`ClientCommand::ServerInfo`].
+        ServerInfo {
+            /// Seconds since the server started.
+            uptime: u64,
+            /// Users connected, the client included.
+            user_count: u32,
+            /// Version of the server program.
+            version: String,
+            /// The server's message of the day, if it has one.
+            motd: Option<String>,
+        } = 26,
+        /// A file offered by `user_id` to `target_user_id`, sent to both of
+        /// them.
+        FileOffer {
+            transfer_id: TransferId,
+            user_id: UserId,
+            target_user_id: UserId,
+            name: String,
+            size: u64,
+        } = 27,
+        /// Tells the sender of a file that it was accepted, it can start
+        /// sending chunks.
+        FileAccept {
+            transfer_id: TransferId,
+        } = 28,
+        /// The transfer is over without the whole file, declined or
+        /// stopped by the other side or by the server.
+        FileReject {
+            transfer_id: TransferId,
+            reason: String,
+        } = 29,
+        /// Relays a [`ClientCommand::FileChunk`] to the receiver.
+        FileChunk {
+            transfer_id: TransferId,
+            data: Bytes,
+        } = 30,
+        /// Relays a [`ClientCommand::FileDone`] to the receiver.
+        FileDone {
+            transfer_id: TransferId,
+        } = 31,
+        /// Relays a [`ClientCommand::KeyExchange`] to its target.
+        KeyExchange {
+            user_id: UserId,
+            target_user_id: UserId,
+            public_key: Bytes,
+        } = 33,
+        /// Relays a [`ClientCommand::EncryptedWhisper`] to its target and,
+        /// as a confirmation, to its sender.
+        EncryptedWhisper {
+            user_id: UserId,
+            target_user_id: UserId,
+            counter: u64,
+            ciphertext: Bytes,
+        } = 34,
+        /// A [`ClientCommand::OfflineWhisper`] left for the user while they
+        /// were offline, sent when they connect. `name` is the sender's at
+        /// the time, as they may be gone.
+        OfflineWhisper {
+            user_id: UserId,
+            name: String,
+            message: String,
+            /// When the server received it, in seconds since the Unix
+            /// epoch.
+            time: u64,
+        } = 35,
+        /// How far the user read the channel they joined, from their last
+        /// [`ClientCommand::MarkRead`] in it, sent before its history.
+        ReadMarker {
+            channel_id: ChannelId,
+            up_to_msg_id: MsgId,
+        } = 36,
+        /// `user_id` read the whispers the user sent them so far.
+        WhispersRead {
+            user_id: UserId,
+        } = 37,
+        /// All the reactions to a message, sent to its channel when they
+        /// change and after the history replayed to users joining it.
+        ReactionUpdate {
+            msg_id: MsgId,
+            channel_id: ChannelId,
+            /// In the order the emojis were first used, empty when the
+            /// last reaction was taken back.
+            reactions: Vec<Reaction>,
+        } = 38,
+        /// The user is now called `name`, after a [`ClientCommand::Rename`].
+        UserRenamed {
+            user_id: UserId,
+            old_name: String,
+            name: String,
+        } = 39,
+        /// Text for this client alone from the server itself, e.g. a hook
+        /// answering a message.
+        Notice {
+            text: String,
+        } = 40,
+        /// Nobody used the channel for a while, so the server put it away
+        /// with its history, until someone joins it again.
+        RoomArchived {
+            channel_id: ChannelId,
+            name: String,
+        } = 41,
+    }
+}
+
+impl TryFrom<ServerCommand> for MessageRecord {
+    type Error = ServerCommand;
+
+    /// Takes the fields of a [`ServerCommand::Message`], giving back any
+    /// other command.
+    fn try_from(command: ServerCommand) -> Result<Self, Self::Error> {
+        match command {
+            ServerCommand::Message {
                 msg_id,
                 user_id,
+                channel_id,
                 message,
-            } => {
-                3u16.code(w)?;
-                msg_id.code(w)?;
-                user_id.code(w)?;
-                message.code(w)
-            }
+                content_type,
+                quote,
+                time,
+            } => Ok(Self {
+                msg_id,
+                user_id,
+                channel_id,
+                message,
+                content_type,
+                quote,
+                time,
+            }),
+            command => Err(command),
         }
     }
+}
 
-    fn decode(r: &mut impl std::io::Read) -> Result<Self::Owned> {
-        let id = u16::decode(r)?;
-        Ok(match id {
-            0 => Self::Padding,
-            1 => Self::AddUser {
-                user_id: u16::decode(r)?,
-                name: str::decode(r)?,
-            },
-            2 => Self::RemoveUser {
-                user_id: u16::decode(r)?,
-            },
-            3 => Self::Message {
-                msg_id: u16::decode(r)?,
-                user_id: u16::decode(r)?,
-                message: str::decode(r)?,
-            },
-            _ => return Err(Error::from(ErrorKind::InvalidData)),
-        })
+impl From<MessageRecord> for ServerCommand {
+    fn from(record: MessageRecord) -> Self {
+        let MessageRecord {
+            msg_id,
+            user_id,
+            channel_id,
+            message,
+            content_type,
+            quote,
+            time,
+        } = record;
+        Self::Message {
+            msg_id,
+            user_id,
+            channel_id,
+            message,
+            content_type,
+            quote,
+            time,
+        }
     }
+}
 
-    fn coded_size(&self) -> usize {
+impl ClientCommand {
+    /// Short name of the command kind, used for logging and metrics.
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
         match self {
-            Self::Padding => 0u16.coded_size(),
-            Self::AddUser { user_id, name } => {
-                1u16.coded_size() + user_id.coded_size() + name.coded_size()
-            }
-            Self::RemoveUser { user_id } => {
-                2u16.coded_size() + user_id.coded_size()
-            }
-            Self::Message {
-                msg_id,
-                user_id,
-                message,
-            } => {
-                3u16.coded_size()
-                    + msg_id.coded_size()
-                    + user_id.coded_size()
-                    + message.coded_size()
-            }
+            Self::Padding => "padding",
+            Self::Connect { .. } => "connect",
+            Self::Message { .. } => "message",
+            Self::Search { .. } => "search",
+            Self::GetHistory { .. } => "get_history",
+            Self::SetRole { .. } => "set_role",
+            Self::CreateInvite { .. } => "create_invite",
+            Self::RevokeInvite { .. } => "revoke_invite",
+            Self::Join { .. } => "join",
+            Self::Forward { .. } => "forward",
+            Self::Whisper { .. } => "whisper",
+            Self::Pong { .. } => "pong",
+            Self::ListUsers => "list_users",
+            Self::Kick { .. } => "kick",
+            Self::Ban { .. } => "ban",
+            Self::Register { .. } => "register",
+            Self::Login { .. } => "login",
+            Self::SetTopic { .. } => "set_topic",
+            Self::ServerInfo => "server_info",
+            Self::RoomStats => "room_stats",
+            Self::FileOffer { .. } => "file_offer",
+            Self::FileAccept { .. } => "file_accept",
+            Self::FileReject { .. } => "file_reject",
+            Self::FileChunk { .. } => "file_chunk",
+            Self::FileDone { .. } => "file_done",
+            Self::KeyExchange { .. } => "key_exchange",
+            Self::EncryptedWhisper { .. } => "encrypted_whisper",
+            Self::OfflineWhisper { .. } => "offline_whisper",
+            Self::MarkRead { .. } => "mark_read",
+            Self::MarkWhispersRead { .. } => "mark_whispers_read",
+            Self::React { .. } => "react",
+            Self::Rename { .. } => "rename",
+        }
+    }
+}
+
+impl ServerCommand {
+    /// Short name of the command kind, used for logging and metrics.
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Padding => "padding",
+            Self::AddUser { .. } => "add_user",
+            Self::RemoveUser { .. } => "remove_user",
+            Self::Message { .. } => "message",
+            Self::Welcome { .. } => "welcome",
+            Self::NameTaken { .. } => "name_taken",
+            Self::SearchResults { .. } => "search_results",
+            Self::History { .. } => "history",
+            Self::RoleChanged { .. } => "role_changed",
+            Self::PermissionDenied { .. } => "permission_denied",
+            Self::InviteCreated { .. } => "invite_created",
+            Self::InviteRevoked { .. } => "invite_revoked",
+            Self::ConnectRejected { .. } => "connect_rejected",
+            Self::Joined { .. } => "joined",
+            Self::CommandFailed { .. } => "command_failed",
+            Self::Whisper { .. } => "whisper",
+            Self::Ping { .. } => "ping",
+            Self::ServerShutdown { .. } => "server_shutdown",
+            Self::UserList { .. } => "user_list",
+            Self::Users { .. } => "users",
+            Self::Ack { .. } => "ack",
+            Self::Kicked { .. } => "kicked",
+            Self::Banned { .. } => "banned",
+            Self::Registered { .. } => "registered",
+            Self::LoggedIn { .. } => "logged_in",
+            Self::TopicChanged { .. } => "topic_changed",
+            Self::ServerInfo { .. } => "server_info",
+            Self::FileOffer { .. } => "file_offer",
+            Self::FileAccept { .. } => "file_accept",
+            Self::FileReject { .. } => "file_reject",
+            Self::FileChunk { .. } => "file_chunk",
+            Self::FileDone { .. } => "file_done",
+            Self::KeyExchange { .. } => "key_exchange",
+            Self::EncryptedWhisper { .. } => "encrypted_whisper",
+            Self::OfflineWhisper { .. } => "offline_whisper",
+            Self::ReadMarker { .. } => "read_marker",
+            Self::WhispersRead { .. } => "whispers_read",
+            Self::ReactionUpdate { .. } => "reaction_update",
+            Self::UserRenamed { .. } => "user_renamed",
+            Self::Notice { .. } => "notice",
+            Self::RoomArchived { .. } => "room_archived",
         }
     }
 }
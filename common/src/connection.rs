@@ -1,23 +1,85 @@
-use std::io::{Result, Write};
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::marker::PhantomData;
-use std::net::TcpStream;
+use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::ops::{Deref, DerefMut};
 
 use crate::Buffer;
 
 use super::Codec;
 
+/// A byte stream a [`Connection`] runs over, a plain TCP stream or an
+/// encrypted one.
+pub trait Transport: Read + Write + Debug + Send {
+    fn peer_addr(&self) -> Result<SocketAddr>;
+    fn shutdown(&self, how: Shutdown) -> Result<()>;
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Self::peer_addr(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        Self::shutdown(self, how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        Self::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        (**self).shutdown(how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+}
+
 #[derive(Debug)]
 pub struct Connection<Sent: Codec + ?Sized, Received: Codec + ?Sized> {
-    stream: TcpStream,
+    stream: Box<dyn Transport>,
     _sent: PhantomData<Sent>,
     _received: PhantomData<Received>,
 
     buffer: Buffer,
     read_mode: ReadMode,
+    stats: ConnectionStats,
+    max_frame_size: DataSize,
+    /// Frames sent and not yet written to the stream.
+    outgoing: Vec<u8>,
+    /// Bytes at the start of `outgoing` that were already written.
+    written: usize,
 }
 
-type DataSize = u16;
+/// Size of a frame, sent in front of it.
+pub type DataSize = u32;
+
+/// Largest frame sent or received, unless set otherwise with
+/// [`Connection::set_max_frame_size`].
+pub const DEFAULT_MAX_FRAME_SIZE: DataSize = 1 << 20;
+
+/// Running totals of the traffic on a connection, including framing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionStats {
+    /// Bytes written to the stream, not counting the queued ones.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// Bytes sent and still waiting for the stream to take them.
+    pub bytes_queued: u64,
+    /// Flushes that left bytes queued because the stream was full.
+    pub write_stalls: u64,
+}
 
 #[derive(Debug)]
 enum ReadMode {
@@ -29,18 +91,28 @@ enum ReadMode {
 impl<Sent: Codec + ?Sized, Received: Codec + ?Sized>
     Connection<Sent, Received>
 {
-    pub fn new(stream: TcpStream) -> Result<Self> {
+    pub fn new(stream: impl Transport + 'static) -> Result<Self> {
         stream.set_nonblocking(true)?;
         Ok(Self {
-            stream,
+            stream: Box::new(stream),
             _sent: PhantomData,
             _received: PhantomData,
 
             buffer: Buffer::new(),
             read_mode: ReadMode::Parse,
+            stats: ConnectionStats::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            outgoing: Vec::new(),
+            written: 0,
         })
     }
 
+    /// Frames larger than this are not sent, and receiving one is an
+    /// error that leaves the connection unusable.
+    pub fn set_max_frame_size(&mut self, size: DataSize) {
+        self.max_frame_size = size;
+    }
+
     pub fn receive(&mut self) -> Result<Received::Owned> {
         loop {
             match self.read_mode {
@@ -48,11 +120,24 @@ impl<Sent: Codec + ?Sized, Received: Codec + ?Sized>
                     self.buffer.try_fill_from(&mut self.stream)?;
                     let data_size = DataSize::decode(&mut self.buffer)
                         .unwrap_or_else(|_| unreachable!());
+                    if data_size > self.max_frame_size {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!(
+                                "received a frame of {data_size} bytes, over \
+                                 the limit of {}",
+                                self.max_frame_size
+                            ),
+                        ));
+                    }
                     self.buffer.resize(data_size as usize);
+                    self.stats.bytes_received +=
+                        (size_of::<DataSize>() + data_size as usize) as u64;
                     self.read_mode = ReadMode::Data;
                 }
                 ReadMode::Data => {
                     self.buffer.try_fill_from(&mut self.stream)?;
+                    self.stats.frames_received += 1;
                     self.read_mode = ReadMode::Parse;
                 }
                 ReadMode::Parse => {
@@ -66,20 +151,79 @@ impl<Sent: Codec + ?Sized, Received: Codec + ?Sized>
         }
     }
 
+    /// Queues `msg` to be written by [`flush`](Self::flush). Fails with
+    /// [`ErrorKind::InvalidInput`], without queueing anything, if it doesn't
+    /// fit in a frame or has a string or list too long to code.
     pub fn send(&mut self, msg: &Sent) -> Result<()> {
-        let data_size = msg.coded_size() as u16;
-        self.stream.write_all(&data_size.to_be_bytes())?;
-        msg.code(&mut self.stream)
+        let data_size = DataSize::try_from(msg.coded_size())
+            .ok()
+            .filter(|&size| size <= self.max_frame_size)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} bytes don't fit in a frame of at most {}",
+                        msg.coded_size(),
+                        self.max_frame_size
+                    ),
+                )
+            })?;
+        let start = self.outgoing.len();
+        data_size.code(&mut self.outgoing)?;
+        if let Err(e) = msg.code(&mut self.outgoing) {
+            // half a frame would throw the other side off
+            self.outgoing.truncate(start);
+            return Err(Error::new(ErrorKind::InvalidInput, e));
+        }
+        self.stats.frames_sent += 1;
+        Ok(())
+    }
+
+    /// Writes the queued frames, as many bytes as the stream takes. Fails
+    /// with [`ErrorKind::WouldBlock`] if some are left for the next call.
+    pub fn flush(&mut self) -> Result<()> {
+        while self.written < self.outgoing.len() {
+            match self.stream.write(&self.outgoing[self.written..]) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(n) => {
+                    self.written += n;
+                    self.stats.bytes_sent += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    if e.kind() == ErrorKind::WouldBlock {
+                        self.stats.write_stalls += 1;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.outgoing.clear();
+        self.written = 0;
+        self.stream.flush()
+    }
+
+    /// Number of bytes sent and not yet written to the stream.
+    #[must_use]
+    pub fn queued(&self) -> usize {
+        self.outgoing.len() - self.written
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            bytes_queued: self.queued() as u64,
+            ..self.stats
+        }
     }
 }
 
 impl<Sent: Codec + ?Sized, Received: Codec + ?Sized> Deref
     for Connection<Sent, Received>
 {
-    type Target = TcpStream;
+    type Target = dyn Transport;
 
     fn deref(&self) -> &Self::Target {
-        &self.stream
+        &*self.stream
     }
 }
 
@@ -87,6 +231,6 @@ impl<Sent: Codec + ?Sized, Received: Codec + ?Sized> DerefMut
     for Connection<Sent, Received>
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.stream
+        &mut *self.stream
     }
 }
@@ -0,0 +1,92 @@
+use std::fmt::Display;
+use std::io::{Read, Result, Write};
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::Codec;
+
+macro_rules! id_type {
+    ($(#[$attr:meta])* $name:ident($inner:ty)) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[cfg_attr(
+            feature = "serde",
+            derive(Serialize, Deserialize),
+            serde(transparent)
+        )]
+        pub struct $name(pub $inner);
+
+        impl $name {
+            pub const MAX: Self = Self(<$inner>::MAX);
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseIntError;
+
+            fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+                s.parse().map(Self)
+            }
+        }
+
+        impl Codec for $name {
+            fn code(&self, w: &mut impl Write) -> Result<()> {
+                self.0.code(w)
+            }
+
+            fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+                <$inner>::decode(r).map(Self)
+            }
+
+            fn coded_size(&self) -> usize {
+                self.0.coded_size()
+            }
+        }
+    };
+}
+
+id_type!(
+    /// Identifies a connection for as long as it lasts.
+    UserId(u16)
+);
+id_type!(
+    /// Identifies a chat message, increasing with every message sent, so
+    /// wide enough to last as long as the stored history.
+    MsgId(u32)
+);
+id_type!(
+    /// Identifies a channel for as long as the server runs.
+    ChannelId(u16)
+);
+id_type!(
+    /// Identifies a file transfer for as long as it lasts.
+    TransferId(u16)
+);
+
+impl ChannelId {
+    /// The channel every user is in after connecting.
+    pub const LOBBY: Self = Self(0);
+    /// Name of the [`LOBBY`](Self::LOBBY).
+    pub const LOBBY_NAME: &'static str = "general";
+    /// Longest accepted channel name, in bytes.
+    pub const MAX_NAME_LEN: usize = 32;
+
+    /// Whether `name` can be used for a channel: letters, digits, `-` and
+    /// `_`, at most [`MAX_NAME_LEN`](Self::MAX_NAME_LEN) bytes.
+    #[must_use]
+    pub fn valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= Self::MAX_NAME_LEN
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    }
+}
@@ -2,6 +2,12 @@ mod buffer;
 mod codec;
 pub mod commands;
 mod connection;
+mod ids;
+mod memory;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub use buffer::*;
 pub use codec::*;
 pub use connection::*;
+pub use ids::*;
+pub use memory::*;
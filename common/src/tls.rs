@@ -0,0 +1,309 @@
+use std::fmt::{Debug, Write as _};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::ops::DerefMut;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+pub use rustls::{ClientConfig, ServerConfig};
+use rustls::{
+    ClientConnection, ConnectionCommon, DigitallySignedStruct, RootCertStore,
+    ServerConnection, SignatureScheme,
+};
+
+use crate::Transport;
+
+/// Loads a certificate chain and its private key from PEM files.
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect::<std::result::Result<Vec<_>, _>>)
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: {e}", cert.display()),
+            )
+        })?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("{}: {e}", key.display()))
+    })?;
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map(Arc::new)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// What to do with a server certificate that no trusted authority signed,
+/// like the self-signed ones of servers nobody set a real CA up for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unsigned {
+    Refuse,
+    /// Trust it, it is the first one seen from the server.
+    TrustFirst,
+    /// Trust it only if it has this [`fingerprint`], the one seen before.
+    Pinned(String),
+}
+
+/// A certificate a server presented, as its verifier saw it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate {
+    pub fingerprint: String,
+    /// Whether a trusted authority signed it, or it was let through by
+    /// [`Unsigned`].
+    pub signed: bool,
+}
+
+/// Where the verifier of a [`client_config`] leaves the certificate the
+/// server presented, even if it was refused.
+#[derive(Debug, Clone, Default)]
+pub struct SeenCertificate(Arc<Mutex<Option<PeerCertificate>>>);
+
+impl SeenCertificate {
+    /// The certificate, once the handshake got to it.
+    #[must_use]
+    pub fn get(&self) -> Option<PeerCertificate> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn set(&self, certificate: PeerCertificate) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(certificate);
+    }
+}
+
+/// The SHA-256 of a DER encoded certificate as colon separated hex bytes,
+/// like `openssl x509 -fingerprint -sha256` shows it.
+#[must_use]
+pub fn fingerprint(cert: &[u8]) -> String {
+    let mut fingerprint = String::new();
+    for (i, byte) in digest(&SHA256, cert).as_ref().iter().enumerate() {
+        if i > 0 {
+            fingerprint.push(':');
+        }
+        let _ = write!(fingerprint, "{byte:02X}");
+    }
+    fingerprint
+}
+
+/// Checks certificates with webpki, letting unsigned ones through as
+/// [`Unsigned`] says.
+#[derive(Debug)]
+struct Verifier {
+    signed: Arc<WebPkiServerVerifier>,
+    unsigned: Unsigned,
+    seen: SeenCertificate,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self.signed.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        let fingerprint = fingerprint(end_entity);
+        let trusted = match &self.unsigned {
+            Unsigned::Refuse => false,
+            Unsigned::TrustFirst => true,
+            Unsigned::Pinned(pinned) => *pinned == fingerprint,
+        };
+        self.seen.set(PeerCertificate {
+            fingerprint,
+            signed: verified.is_ok(),
+        });
+        match verified {
+            Err(_) if trusted => Ok(ServerCertVerified::assertion()),
+            Err(_) if matches!(self.unsigned, Unsigned::Pinned(_)) => {
+                Err(rustls::Error::General(
+                    "the certificate is not the one pinned for the server"
+                        .to_owned(),
+                ))
+            }
+            verified => verified,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.signed.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.signed.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.signed.supported_verify_schemes()
+    }
+}
+
+/// Trusts the certificates in the PEM file `ca`, or the usual web roots if
+/// there is none, and treats others as `unsigned` says. The certificate the
+/// server presents ends up in the returned [`SeenCertificate`].
+pub fn client_config(
+    ca: Option<&Path>,
+    unsigned: Unsigned,
+) -> Result<(Arc<ClientConfig>, SeenCertificate)> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in CertificateDer::pem_file_iter(ca).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: {e}", ca.display()),
+                )
+            })? {
+                let cert =
+                    cert.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let signed = WebPkiServerVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let seen = SeenCertificate::default();
+    let verifier = Verifier {
+        signed,
+        unsigned,
+        seen: seen.clone(),
+    };
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok((Arc::new(config), seen))
+}
+
+/// Starts a TLS session on an accepted stream. The handshake happens as the
+/// connection is used, so this never blocks.
+pub fn accept(
+    config: Arc<ServerConfig>,
+    stream: TcpStream,
+) -> Result<TlsStream<ServerConnection>> {
+    let session = ServerConnection::new(config).map_err(Error::other)?;
+    Ok(TlsStream { session, stream })
+}
+
+/// Starts a TLS session with the server `host` on a connected stream.
+pub fn connect(
+    config: Arc<ClientConfig>,
+    host: &str,
+    stream: TcpStream,
+) -> Result<TlsStream<ClientConnection>> {
+    let name = ServerName::try_from(host.to_owned())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let session = ClientConnection::new(config, name).map_err(Error::other)?;
+    Ok(TlsStream { session, stream })
+}
+
+/// An encrypted stream that, unlike [`rustls::StreamOwned`], buffers writes
+/// instead of failing while the handshake waits for the peer, so it can be
+/// used in non-blocking mode from the start.
+#[derive(Debug)]
+pub struct TlsStream<C> {
+    session: C,
+    stream: TcpStream,
+}
+
+impl<C, D> TlsStream<C>
+where
+    C: DerefMut<Target = ConnectionCommon<D>>,
+{
+    /// Writes out as much pending TLS data as the socket takes.
+    fn write_pending(&mut self) -> Result<()> {
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl<C, D> Read for TlsStream<C>
+where
+    C: DerefMut<Target = ConnectionCommon<D>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+            if self.session.read_tls(&mut self.stream)? == 0 {
+                return Ok(0);
+            }
+            self.session.process_new_packets().map_err(|e| {
+                // let the peer know why, if the socket takes it
+                let _ = self.write_pending();
+                Error::new(ErrorKind::InvalidData, e)
+            })?;
+            match self.write_pending() {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<C, D> Write for TlsStream<C>
+where
+    C: DerefMut<Target = ConnectionCommon<D>>,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.session.writer().write(buf)?;
+        match self.write_pending() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(written),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.session.writer().flush()?;
+        self.write_pending()
+    }
+}
+
+impl<C, D> Transport for TlsStream<C>
+where
+    C: DerefMut<Target = ConnectionCommon<D>> + Debug + Send,
+{
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+}
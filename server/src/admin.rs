@@ -0,0 +1,259 @@
+//! Offline administration of a [`Store`], used by the server's subcommands.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use common::commands::ServerCommand;
+use common::{ChannelId, Codec};
+
+use crate::storage::{Account, Ban, ChannelRecord, Store, UserRecord};
+use crate::JsonString;
+
+/// Starts every export, followed by the version of its layout.
+const EXPORT_MAGIC: &[u8; 8] = b"TCPCHAT\n";
+/// Layout of the exports written now.
+const EXPORT_VERSION: u16 = 1;
+/// Kinds of the records in an export.
+const ACCOUNT: u16 = 1;
+const USER: u16 = 2;
+const BAN: u16 = 3;
+const CHANNEL: u16 = 4;
+const MESSAGE: u16 = 5;
+
+/// Looks up the id of the channel called `name`.
+pub fn find_channel(
+    store: &dyn Store,
+    name: &str,
+) -> Result<Option<ChannelId>> {
+    if name == ChannelId::LOBBY_NAME {
+        return Ok(Some(ChannelId::LOBBY));
+    }
+    Ok(store
+        .list_channels()?
+        .into_iter()
+        .find(|c| c.name == name)
+        .map(|c| c.channel_id))
+}
+
+/// Writes every stored message of `channel_id` to `w` as a JSON line,
+/// oldest first, returning how many there were.
+pub fn export_history(
+    store: &dyn Store,
+    channel_id: ChannelId,
+    w: &mut impl Write,
+) -> Result<usize> {
+    let mut count = 0;
+    for message in store.load_history(usize::MAX)? {
+        let ServerCommand::Message {
+            msg_id,
+            user_id,
+            channel_id: id,
+            message,
+            quote,
+            time,
+            ..
+        } = message
+        else {
+            continue;
+        };
+        if id != channel_id {
+            continue;
+        }
+        write!(
+            w,
+            "{{\"time\":{time},\"msg_id\":{msg_id},\"user_id\":{user_id},"
+        )?;
+        if let Some(quote) = quote {
+            write!(w, "\"quote\":{},", quote.msg_id)?;
+        }
+        writeln!(w, "\"message\":{}}}", JsonString(&message))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Writes the accounts, stored roles, bans, channels and messages in
+/// `store` to `w`, for [`import`] to load into another store, returning
+/// how many records there were. Invites and offline whispers are left out.
+///
+/// An export starts with [`EXPORT_MAGIC`] and its version as a big-endian
+/// `u16`. Records follow until the end, each as its kind and size, a
+/// big-endian `u16` and `u32`, then the record coded the way a
+/// [`FileStore`](crate::storage::FileStore) keeps it, or a message the way
+/// it is sent to clients. Messages come last, oldest first.
+pub fn export(store: &dyn Store, w: &mut impl Write) -> Result<usize> {
+    w.write_all(EXPORT_MAGIC)?;
+    EXPORT_VERSION.code(w)?;
+    let mut count = 0;
+    for account in store.list_accounts()? {
+        write_record(w, ACCOUNT, &account)?;
+        count += 1;
+    }
+    for user in store.list_users()? {
+        write_record(w, USER, &user)?;
+        count += 1;
+    }
+    for ban in store.list_bans()? {
+        write_record(w, BAN, &ban)?;
+        count += 1;
+    }
+    for channel in store.list_channels()? {
+        write_record(w, CHANNEL, &channel)?;
+        count += 1;
+    }
+    for message in store.load_history(usize::MAX)? {
+        write_record(w, MESSAGE, &message)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn write_record<T: Codec + ?Sized>(
+    w: &mut impl Write,
+    kind: u16,
+    record: &T,
+) -> Result<()> {
+    let size = u32::try_from(record.coded_size())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    kind.code(w)?;
+    size.code(w)?;
+    record.code(w)
+}
+
+/// Loads an [`export`] read from `r` into `store`, which must not have
+/// any accounts, channels or messages yet so that no ids clash, returning
+/// how many records there were.
+pub fn import(store: &mut dyn Store, r: &mut impl Read) -> Result<usize> {
+    if !store.list_accounts()?.is_empty()
+        || !store.list_channels()?.is_empty()
+        || !store.load_history(1)?.is_empty()
+    {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "can only import into an empty store",
+        ));
+    }
+    let mut magic = [0; EXPORT_MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    if &magic != EXPORT_MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "this is not a tcpchat export",
+        ));
+    }
+    match u16::decode(r)? {
+        EXPORT_VERSION => (),
+        version => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown export version {version}"),
+            ))
+        }
+    }
+    let mut count = 0;
+    loop {
+        let kind = match u16::decode(r) {
+            Ok(kind) => kind,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let size = u32::decode(r)?;
+        let mut record = vec![0; size as usize];
+        r.read_exact(&mut record)?;
+        let record = &mut record.as_slice();
+        match kind {
+            ACCOUNT => store.put_account(&Account::decode(record)?)?,
+            USER => store.put_user(&UserRecord::decode(record)?)?,
+            BAN => store.add_ban(&Ban::decode(record)?)?,
+            CHANNEL => store.put_channel(&ChannelRecord::decode(record)?)?,
+            MESSAGE => store.append_message(&ServerCommand::decode(record)?)?,
+            kind => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown export record kind {kind}"),
+                ))
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::commands::{ContentType, Role};
+    use common::{MsgId, UserId};
+
+    use super::*;
+    use crate::storage::MemoryStore;
+    use crate::PasswordHash;
+
+    fn message(msg_id: u32, text: &str) -> ServerCommand {
+        ServerCommand::Message {
+            msg_id: MsgId(msg_id),
+            user_id: UserId(1),
+            channel_id: ChannelId(2),
+            message: text.to_owned(),
+            content_type: ContentType::Plain,
+            quote: None,
+            time: 1000,
+        }
+    }
+
+    #[test]
+    fn imports_load_what_was_exported() {
+        let mut store = MemoryStore::new();
+        store
+            .put_account(&Account {
+                name: "alice".to_owned(),
+                user_id: UserId(1),
+                password: PasswordHash::new("hunter2").unwrap(),
+                role: Role::Admin,
+            })
+            .unwrap();
+        let user = UserRecord {
+            name: "bob".to_owned(),
+            role: Role::Moderator,
+        };
+        store.put_user(&user).unwrap();
+        store.add_ban(&Ban::Name("mallory".to_owned())).unwrap();
+        let channel = ChannelRecord {
+            name: "rust".to_owned(),
+            channel_id: ChannelId(2),
+            topic: "crabs".to_owned(),
+            archived: true,
+        };
+        store.put_channel(&channel).unwrap();
+        for (msg_id, text) in [(1, "first"), (2, "second")] {
+            store.append_message(&message(msg_id, text)).unwrap();
+        }
+
+        let mut exported = vec![];
+        assert_eq!(export(&store, &mut exported).unwrap(), 6);
+        let mut copy = MemoryStore::new();
+        assert_eq!(import(&mut copy, &mut exported.as_slice()).unwrap(), 6);
+        let account = copy.get_account("alice").unwrap().unwrap();
+        assert_eq!(account.user_id, UserId(1));
+        assert!(account.password.verify("hunter2"));
+        assert_eq!(copy.list_users().unwrap(), [user]);
+        assert_eq!(copy.list_bans().unwrap(), store.list_bans().unwrap());
+        assert_eq!(copy.list_channels().unwrap(), [channel]);
+        let texts: Vec<_> = copy
+            .load_history(10)
+            .unwrap()
+            .into_iter()
+            .filter_map(|m| match m {
+                ServerCommand::Message {
+                    msg_id, message, ..
+                } => Some((msg_id, message)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            [(MsgId(1), "first".to_owned()), (MsgId(2), "second".to_owned())]
+        );
+
+        let error = import(&mut copy, &mut exported.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+    }
+}
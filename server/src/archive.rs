@@ -0,0 +1,228 @@
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::{MsgId, UserId};
+
+/// A chat message as written to the archive.
+///
+/// Records are JSON objects, one per line:
+///
+/// ```text
+/// {"time":1718000000,"msg_id":7,"user_id":3,"name":"alice","message":"hi"}
+/// ```
+///
+/// `time` is in seconds since the Unix epoch.
+#[derive(Debug, Clone)]
+pub struct ArchiveRecord {
+    pub time: SystemTime,
+    pub msg_id: MsgId,
+    pub user_id: UserId,
+    pub name: String,
+    /// Name of the channel the message was sent to.
+    pub channel: String,
+    pub message: String,
+}
+
+impl Display for ArchiveRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        write!(
+            f,
+            "{{\"time\":{time},\"msg_id\":{},\"user_id\":{},\"name\":",
+            self.msg_id, self.user_id
+        )?;
+        write_json_string(f, &self.name)?;
+        f.write_str(",\"channel\":")?;
+        write_json_string(f, &self.channel)?;
+        f.write_str(",\"message\":")?;
+        write_json_string(f, &self.message)?;
+        f.write_str("}")
+    }
+}
+
+/// Displays a string as a quoted and escaped JSON string.
+pub(crate) struct JsonString<'a>(pub &'a str);
+
+impl Display for JsonString<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_json_string(f, self.0)
+    }
+}
+
+fn write_json_string(
+    f: &mut std::fmt::Formatter<'_>,
+    s: &str,
+) -> std::fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Where archived messages go.
+#[derive(Debug, Clone)]
+pub enum ArchiveSink {
+    /// Append records to a file.
+    File(PathBuf),
+    /// POST every record to a URL, see [`webhook`].
+    #[cfg(feature = "webhook")]
+    Webhook(String),
+}
+
+impl FromStr for ArchiveSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", path)) => Ok(Self::File(path.into())),
+            #[cfg(feature = "webhook")]
+            Some(("http" | "https", _)) => Ok(Self::Webhook(s.to_owned())),
+            #[cfg(not(feature = "webhook"))]
+            Some(("http" | "https", _)) => {
+                Err("the server was built without webhook support".to_owned())
+            }
+            _ => Err(format!("expected `file:<path>` or a URL, got `{s}`")),
+        }
+    }
+}
+
+/// An open [`ArchiveSink`].
+#[derive(Debug)]
+pub enum Archiver {
+    File(BufWriter<File>),
+    #[cfg(feature = "webhook")]
+    Webhook(webhook::Webhook),
+}
+
+impl Archiver {
+    /// Opens `sink`; a webhook sends records it can't deliver to
+    /// `dead_letter`.
+    #[cfg_attr(not(feature = "webhook"), allow(unused_variables))]
+    pub fn open(sink: &ArchiveSink, dead_letter: &Path) -> Result<Self> {
+        Ok(match sink {
+            ArchiveSink::File(path) => Self::File(BufWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            #[cfg(feature = "webhook")]
+            ArchiveSink::Webhook(url) => Self::Webhook(webhook::Webhook::new(
+                url.clone(),
+                dead_letter.to_owned(),
+            )),
+        })
+    }
+
+    /// Archives a record. Files are written and flushed before returning,
+    /// webhooks deliver in the background.
+    pub fn archive(&mut self, record: &ArchiveRecord) -> Result<()> {
+        match self {
+            Self::File(file) => {
+                writeln!(file, "{record}")?;
+                file.flush()
+            }
+            #[cfg(feature = "webhook")]
+            Self::Webhook(webhook) => {
+                webhook.send(record.to_string());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "webhook")]
+pub mod webhook {
+    //! Delivery of archive records over HTTP(S), also used to bridge
+    //! messages with [`Webhooks`](crate::Webhooks).
+    //!
+    //! Every record is POSTed on its own as `application/json`. Failed
+    //! deliveries are retried with exponential backoff, and records that
+    //! still fail are appended to a dead-letter file in the same format as
+    //! a file archive.
+
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+    use std::time::Duration;
+
+    use log::{error, warn};
+
+    const ATTEMPTS: u32 = 4;
+    const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+    #[derive(Debug)]
+    pub struct Webhook {
+        records: Sender<String>,
+    }
+
+    impl Webhook {
+        #[must_use]
+        pub fn new(url: String, dead_letter: PathBuf) -> Self {
+            let (records, receiver) = channel::<String>();
+            thread::spawn(move || {
+                for record in receiver {
+                    if !deliver(&url, &record) {
+                        write_dead_letter(&dead_letter, &record);
+                    }
+                }
+            });
+            Self { records }
+        }
+
+        pub fn send(&self, record: String) {
+            // the worker only stops once we are dropped
+            let _ = self.records.send(record);
+        }
+    }
+
+    fn deliver(url: &str, record: &str) -> bool {
+        let mut delay = FIRST_RETRY;
+        for attempt in 1..=ATTEMPTS {
+            match ureq::post(url)
+                .set("Content-Type", "application/json")
+                .send_string(record)
+            {
+                Ok(_) => return true,
+                Err(e) => {
+                    warn!("Delivery attempt {attempt} to {url} failed: {e}");
+                }
+            }
+            if attempt < ATTEMPTS {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+        false
+    }
+
+    fn write_dead_letter(path: &Path, record: &str) {
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{record}"));
+        if let Err(e) = written {
+            error!(
+                "Failed to write to the dead-letter file {}, record lost: \
+                 {record} ({e})",
+                path.display()
+            );
+        }
+    }
+}
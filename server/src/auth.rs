@@ -0,0 +1,563 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::Wake;
+
+/// How long an [`AuthProvider`] asking elsewhere may keep a user waiting
+/// before they are turned away.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Decides who may connect under which name.
+///
+/// Providers are asked on every `Connect` and rename, one name at a time on
+/// the thread of an [`AuthWorker`], for at most [`AUTH_TIMEOUT`] when they
+/// ask another program.
+pub trait AuthProvider: Debug + Send {
+    /// Checks whether `credential` lets its holder use `name`. An error
+    /// means the check itself failed, the user is turned away either way.
+    fn authenticate(
+        &mut self,
+        name: &str,
+        credential: Option<&str>,
+    ) -> Result<bool>;
+
+    /// Whether letting a credential in means it was checked against the
+    /// name, so that the name can be trusted with a stored role.
+    fn verifies(&self) -> bool {
+        true
+    }
+}
+
+/// A name for an [`AuthWorker`] to check.
+#[derive(Debug)]
+struct AuthRequest {
+    ticket: u64,
+    name: String,
+    credential: Option<String>,
+}
+
+/// Runs an [`AuthProvider`] on a thread of its own, so that a slow check
+/// only holds up the client waiting for it.
+#[derive(Debug)]
+pub struct AuthWorker {
+    requests: Sender<AuthRequest>,
+    results: Receiver<(u64, Result<bool>)>,
+    verifies: bool,
+}
+
+impl AuthWorker {
+    /// Starts asking `provider`, calling `wake` whenever it answered.
+    #[must_use]
+    pub fn spawn(mut provider: Box<dyn AuthProvider>, wake: Wake) -> Self {
+        let verifies = provider.verifies();
+        let (requests, inbox) = mpsc::channel::<AuthRequest>();
+        let (outbox, results) = mpsc::channel();
+        thread::spawn(move || {
+            // ends when the server is dropped
+            for request in inbox {
+                let result = provider
+                    .authenticate(&request.name, request.credential.as_deref());
+                if outbox.send((request.ticket, result)).is_err() {
+                    break;
+                }
+                wake();
+            }
+        });
+        Self {
+            requests,
+            results,
+            verifies,
+        }
+    }
+
+    /// See [`AuthProvider::verifies`].
+    #[must_use]
+    pub const fn verifies(&self) -> bool {
+        self.verifies
+    }
+
+    /// Asks whether `credential` lets its holder use `name`, answered by
+    /// [`poll`](Self::poll) with `ticket`.
+    pub fn request(
+        &self,
+        ticket: u64,
+        name: &str,
+        credential: Option<&str>,
+    ) -> Result<()> {
+        self.requests
+            .send(AuthRequest {
+                ticket,
+                name: name.to_owned(),
+                credential: credential.map(str::to_owned),
+            })
+            .map_err(|_| Error::other("the auth thread stopped"))
+    }
+
+    /// Returns the next answer with the ticket it was asked with, if any
+    /// arrived.
+    pub fn poll(&self) -> Option<(u64, Result<bool>)> {
+        self.results.try_recv().ok()
+    }
+}
+
+/// Lets anyone use any name that is not taken.
+#[derive(Debug)]
+pub struct AllowAll;
+
+impl AuthProvider for AllowAll {
+    fn authenticate(&mut self, _: &str, _: Option<&str>) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn verifies(&self) -> bool {
+        false
+    }
+}
+
+const PBKDF2: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+// logins are checked on the server loop and `local:` accounts hold up the
+// auth thread, so this is kept modest; existing entries keep the count
+// they were hashed with
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// A salted password hash, `iterations:salt:hash` with hex salt and hash.
+#[derive(Debug, Clone)]
+pub struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Hashes `password` with a fresh random salt.
+    pub fn new(password: &str) -> Result<Self> {
+        let mut salt = vec![0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| Error::other("no random numbers for the salt"))?;
+        let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+        let mut hash = vec![0; HASH_LEN];
+        pbkdf2::derive(
+            PBKDF2,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+        Ok(Self {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    /// Compares in constant time.
+    #[must_use]
+    pub fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            PBKDF2,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+impl std::fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.iterations)?;
+        for byte in &self.salt {
+            write!(f, "{byte:02x}")?;
+        }
+        f.write_str(":")?;
+        for byte in &self.hash {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (Some(iterations), Some(salt), Some(hash), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("expected `iterations:salt:hash`".to_owned());
+        };
+        Ok(Self {
+            iterations: iterations
+                .parse()
+                .map_err(|e| format!("invalid iteration count: {e}"))?,
+            salt: parse_hex(salt)?,
+            hash: parse_hex(hash)?,
+        })
+    }
+}
+
+fn parse_hex(s: &str) -> std::result::Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("invalid hex `{s}`"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|_| format!("invalid hex `{s}`"))
+        })
+        .collect()
+}
+
+/// Accounts from a file of `name:iterations:salt:hash` lines, as printed
+/// by `--hash-password`.
+#[derive(Debug)]
+pub struct LocalAccounts {
+    accounts: HashMap<String, PasswordHash>,
+}
+
+impl LocalAccounts {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut accounts = HashMap::new();
+        for (line_no, line) in read_lines(path)? {
+            let parsed = line
+                .split_once(':')
+                .ok_or_else(|| "expected `name:hash`".to_owned())
+                .and_then(|(name, hash)| Ok((name, hash.parse()?)));
+            match parsed {
+                Ok((name, hash)) => {
+                    accounts.insert(name.to_owned(), hash);
+                }
+                Err(e) => warn!("{}:{line_no}: {e}", path.display()),
+            }
+        }
+        Ok(Self { accounts })
+    }
+}
+
+impl AuthProvider for LocalAccounts {
+    fn authenticate(
+        &mut self,
+        name: &str,
+        credential: Option<&str>,
+    ) -> Result<bool> {
+        Ok(self
+            .accounts
+            .get(name)
+            .zip(credential)
+            .is_some_and(|(hash, password)| hash.verify(password)))
+    }
+}
+
+/// Lets in anyone holding one of a fixed set of tokens, read from a file
+/// with one token per line.
+#[derive(Debug)]
+pub struct StaticTokens {
+    tokens: HashSet<String>,
+}
+
+impl StaticTokens {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            tokens: read_lines(path)?.map(|(_, line)| line).collect(),
+        })
+    }
+}
+
+impl AuthProvider for StaticTokens {
+    fn authenticate(
+        &mut self,
+        _: &str,
+        credential: Option<&str>,
+    ) -> Result<bool> {
+        Ok(credential.is_some_and(|token| self.tokens.contains(token)))
+    }
+}
+
+/// Asks a shell command, which gets the name in `$TCPCHAT_NAME` and the
+/// credential on its stdin, and lets the user in if it exits successfully.
+/// A command still running after [`AUTH_TIMEOUT`] is killed, what it
+/// prints to stderr is logged.
+#[derive(Debug)]
+pub struct CommandVerifier {
+    command: String,
+}
+
+impl AuthProvider for CommandVerifier {
+    fn authenticate(
+        &mut self,
+        name: &str,
+        credential: Option<&str>,
+    ) -> Result<bool> {
+        let output = run_with_timeout(
+            Command::new("sh")
+                .arg("-c")
+                .arg(&self.command)
+                .env("TCPCHAT_NAME", name),
+            credential.unwrap_or_default(),
+            AUTH_TIMEOUT,
+        )?;
+        if !output.stderr.trim().is_empty() {
+            warn!("Auth command said: {}", output.stderr.trim_end());
+        }
+        Ok(output.success)
+    }
+}
+
+#[cfg(feature = "webhook")]
+mod http {
+    use std::io::{Error, Result};
+
+    use super::{AuthProvider, AUTH_TIMEOUT};
+    use crate::JsonString;
+
+    /// POSTs `{"name": ..., "credential": ...}` to a URL, which accepts
+    /// with a 2xx status and rejects with 401 or 403, giving up after
+    /// [`AUTH_TIMEOUT`].
+    #[derive(Debug)]
+    pub struct HttpVerifier {
+        url: String,
+    }
+
+    impl HttpVerifier {
+        #[must_use]
+        pub const fn new(url: String) -> Self {
+            Self { url }
+        }
+    }
+
+    impl AuthProvider for HttpVerifier {
+        fn authenticate(
+            &mut self,
+            name: &str,
+            credential: Option<&str>,
+        ) -> Result<bool> {
+            let body = format!(
+                "{{\"name\":{},\"credential\":{}}}",
+                JsonString(name),
+                JsonString(credential.unwrap_or_default())
+            );
+            match ureq::post(&self.url)
+                .timeout(AUTH_TIMEOUT)
+                .set("Content-Type", "application/json")
+                .send_string(&body)
+            {
+                Ok(_) => Ok(true),
+                Err(ureq::Error::Status(401 | 403, _)) => Ok(false),
+                Err(e) => Err(Error::other(e)),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "webhook")]
+pub use http::HttpVerifier;
+
+/// Which [`AuthProvider`] to use.
+#[derive(Debug, Clone, Default)]
+pub enum AuthConfig {
+    #[default]
+    None,
+    Local(PathBuf),
+    Tokens(PathBuf),
+    Command(String),
+    #[cfg(feature = "webhook")]
+    Http(String),
+}
+
+impl AuthConfig {
+    pub fn open(&self) -> Result<Box<dyn AuthProvider>> {
+        Ok(match self {
+            Self::None => Box::new(AllowAll),
+            Self::Local(path) => Box::new(LocalAccounts::load(path)?),
+            Self::Tokens(path) => Box::new(StaticTokens::load(path)?),
+            Self::Command(command) => Box::new(CommandVerifier {
+                command: command.clone(),
+            }),
+            #[cfg(feature = "webhook")]
+            Self::Http(url) => Box::new(HttpVerifier::new(url.clone())),
+        })
+    }
+}
+
+impl FromStr for AuthConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(Self::None),
+            Some(("local", path)) => Ok(Self::Local(path.into())),
+            Some(("tokens", path)) => Ok(Self::Tokens(path.into())),
+            Some(("command", command)) => Ok(Self::Command(command.to_owned())),
+            #[cfg(feature = "webhook")]
+            Some(("http" | "https", _)) => Ok(Self::Http(s.to_owned())),
+            #[cfg(not(feature = "webhook"))]
+            Some(("http" | "https", _)) => {
+                Err("the server was built without webhook support".to_owned())
+            }
+            _ => Err(format!(
+                "expected `none`, `local:<path>`, `tokens:<path>`, \
+                 `command:<command>` or a URL, got `{s}`"
+            )),
+        }
+    }
+}
+
+/// Non-empty, non-comment lines of a file, trimmed, with their line
+/// numbers.
+pub(crate) fn read_lines(
+    path: &Path,
+) -> Result<impl Iterator<Item = (usize, String)>> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        Error::new(e.kind(), format!("{}: {e}", path.display()))
+    })?;
+    Ok(text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim().to_owned()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .into_iter())
+}
+
+/// How a program run by [`run_with_timeout`] exited and what it printed.
+pub(crate) struct ProcessOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs `command` with `input` on its stdin, killing it if it hasn't
+/// exited after `timeout`, which fails with [`ErrorKind::TimedOut`].
+pub(crate) fn run_with_timeout(
+    command: &mut Command,
+    input: &str,
+    timeout: Duration,
+) -> Result<ProcessOutput> {
+    let deadline = Instant::now() + timeout;
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = input.to_owned();
+        // the program may well exit without reading it, or read it slowly
+        thread::spawn(move || {
+            let _ = stdin.write_all(input.as_bytes());
+        });
+    }
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("still running after {timeout:?}, killed"),
+            ));
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    // whatever it started may still hold the pipes open
+    let read = |output: Receiver<Vec<u8>>| {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let bytes = output.recv_timeout(left).unwrap_or_default();
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    Ok(ProcessOutput {
+        success: status.success(),
+        stdout: read(stdout),
+        stderr: read(stderr),
+    })
+}
+
+/// Reads `pipe` to its end on another thread, so a full pipe doesn't
+/// stall the program writing to it.
+fn read_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    if let Some(mut pipe) = pipe {
+        thread::spawn(move || {
+            let mut bytes = vec![];
+            let _ = pipe.read_to_end(&mut bytes);
+            let _ = sender.send(bytes);
+        });
+    }
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn programs_get_input_and_give_output() {
+        let output = run_with_timeout(
+            &mut sh("tr a-z A-Z; echo oops >&2; exit 3"),
+            "hello",
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert!(!output.success);
+        assert_eq!(output.stdout, "HELLO");
+        assert_eq!(output.stderr, "oops\n");
+    }
+
+    #[test]
+    fn programs_running_too_long_are_killed() {
+        let start = Instant::now();
+        let e = run_with_timeout(
+            &mut sh("sleep 10"),
+            "",
+            Duration::from_millis(100),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn commands_taking_too_long_turn_the_user_away() {
+        let mut verifier = CommandVerifier {
+            command: "sleep 10".to_owned(),
+        };
+        assert!(verifier.authenticate("alice", None).is_err());
+        let mut verifier = CommandVerifier {
+            command: "read pass; [ \"$pass\" = \"$TCPCHAT_NAME\" ]"
+                .to_owned(),
+        };
+        assert!(verifier.authenticate("alice", Some("alice")).unwrap());
+        assert!(!verifier.authenticate("alice", Some("bob")).unwrap());
+    }
+}
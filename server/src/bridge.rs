@@ -0,0 +1,441 @@
+//! Sharing broadcasts between server instances through an external
+//! pub/sub, so that users connected to different instances can talk in
+//! the same rooms.
+//!
+//! Every broadcast is published with the name of the room it went to and
+//! the id of the instance it came from. Instances ignore what they
+//! published themselves, and deliver each message id of another instance
+//! at most once.
+//!
+//! User, message and channel ids are handed out by each instance on its
+//! own, only room names mean the same thing everywhere. The users and
+//! messages of other instances get ids of this one, kept in [`RemoteIds`].
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use log::warn;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use common::commands::ServerCommand;
+use common::{Codec, MsgId, UserId};
+
+/// Called from a background thread when a [`PubSub`] received something, so
+/// the server stops waiting for its clients and polls it.
+pub type Wake = Arc<dyn Fn() + Send + Sync>;
+
+/// A topic shared by all instances.
+pub trait PubSub: Debug {
+    /// Sends `payload` to every subscriber, in the background.
+    fn publish(&mut self, payload: Vec<u8>);
+    /// Returns the next payload received, if any arrived.
+    fn poll(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Which [`PubSub`] the [`Bridge`] uses, if any.
+#[derive(Debug, Clone, Default)]
+pub enum BridgeConfig {
+    #[default]
+    None,
+    /// A Redis channel, see [`redis`].
+    #[cfg(feature = "redis")]
+    Redis { addr: String, channel: String },
+}
+
+impl BridgeConfig {
+    #[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+    pub fn open(&self, wake: Wake) -> Result<Option<Bridge>> {
+        Ok(match self {
+            Self::None => None,
+            #[cfg(feature = "redis")]
+            Self::Redis { addr, channel } => Some(Bridge::new(Box::new(
+                redis::Redis::new(addr.clone(), channel.clone(), wake),
+            ))?),
+        })
+    }
+}
+
+impl FromStr for BridgeConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once("://") {
+            None if s == "none" => Ok(Self::None),
+            #[cfg(feature = "redis")]
+            Some(("redis", rest)) => {
+                let (addr, channel) =
+                    rest.split_once('/').unwrap_or((rest, "tcpchat"));
+                let addr = if addr.contains(':') {
+                    addr.to_owned()
+                } else {
+                    format!("{addr}:6379")
+                };
+                Ok(Self::Redis {
+                    addr,
+                    channel: channel.to_owned(),
+                })
+            }
+            #[cfg(not(feature = "redis"))]
+            Some(("redis", _)) => {
+                Err("the server was built without redis support".to_owned())
+            }
+            _ => Err(format!(
+                "expected `none` or `redis://<host>[:<port>][/<channel>]`, \
+                 got `{s}`"
+            )),
+        }
+    }
+}
+
+/// Number of message ids remembered to drop repeated deliveries, and to
+/// find the local ids of quoted and reacted to messages.
+const DELIVERED_CAPACITY: usize = 4096;
+
+/// The connection of one instance to the others.
+#[derive(Debug)]
+pub struct Bridge {
+    /// Random id of this instance.
+    origin: String,
+    pubsub: Box<dyn PubSub>,
+    /// Messages of other instances that were delivered, oldest first.
+    delivered: VecDeque<(String, MsgId)>,
+    delivered_set: HashSet<(String, MsgId)>,
+}
+
+impl Bridge {
+    pub fn new(pubsub: Box<dyn PubSub>) -> Result<Self> {
+        let mut origin = [0; 8];
+        SystemRandom::new()
+            .fill(&mut origin)
+            .map_err(|_| Error::other("no random numbers for the origin"))?;
+        Ok(Self {
+            origin: origin.iter().map(|b| format!("{b:02x}")).collect(),
+            pubsub,
+            delivered: VecDeque::new(),
+            delivered_set: HashSet::new(),
+        })
+    }
+
+    /// Publishes a broadcast of this instance to the room called `room`,
+    /// or to everyone if it's `None`.
+    pub fn publish(&mut self, room: Option<&str>, command: &ServerCommand) {
+        let mut payload = vec![];
+        let coded = self
+            .origin
+            .code(&mut payload)
+            .and_then(|()| room.map(str::to_owned).code(&mut payload))
+            .and_then(|()| command.code(&mut payload));
+        match coded {
+            Ok(()) => self.pubsub.publish(payload),
+            Err(e) => {
+                warn!("Failed to code {} for the bridge: {e}", command.name())
+            }
+        }
+    }
+
+    /// Returns the next broadcast of another instance, with the id of that
+    /// instance and the name of its room, skipping our own and messages
+    /// that were already delivered.
+    pub fn poll(&mut self) -> Option<(String, Option<String>, ServerCommand)> {
+        loop {
+            let payload = self.pubsub.poll()?;
+            let (origin, room, command) = match decode(&payload) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    warn!("Ignoring a broadcast from the bridge: {e}");
+                    continue;
+                }
+            };
+            if origin == self.origin {
+                continue;
+            }
+            if let ServerCommand::Message { msg_id, .. } = &command {
+                if !self.first_delivery(origin.clone(), *msg_id) {
+                    continue;
+                }
+            }
+            return Some((origin, room, command));
+        }
+    }
+
+    fn first_delivery(&mut self, origin: String, msg_id: MsgId) -> bool {
+        let key = (origin, msg_id);
+        if self.delivered_set.contains(&key) {
+            return false;
+        }
+        if self.delivered.len() == DELIVERED_CAPACITY {
+            if let Some(oldest) = self.delivered.pop_front() {
+                self.delivered_set.remove(&oldest);
+            }
+        }
+        self.delivered.push_back(key.clone());
+        self.delivered_set.insert(key);
+        true
+    }
+}
+
+/// The ids this instance gave to the users and messages of the others, by
+/// the instance and their id there, which may well be taken here.
+#[derive(Debug, Default)]
+pub struct RemoteIds {
+    users: HashMap<(String, UserId), UserId>,
+    /// The values of `users`.
+    local_users: HashSet<UserId>,
+    messages: HashMap<(String, MsgId), MsgId>,
+    /// The keys of `messages`, oldest first.
+    message_order: VecDeque<(String, MsgId)>,
+}
+
+impl RemoteIds {
+    #[must_use]
+    pub fn user(&self, origin: &str, user_id: UserId) -> Option<UserId> {
+        self.users.get(&(origin.to_owned(), user_id)).copied()
+    }
+
+    pub fn add_user(&mut self, origin: &str, user_id: UserId, local: UserId) {
+        self.users.insert((origin.to_owned(), user_id), local);
+        self.local_users.insert(local);
+    }
+
+    /// Forgets a user that left, so its local id can be handed out again.
+    pub fn remove_user(&mut self, origin: &str, user_id: UserId) {
+        if let Some(local) = self.users.remove(&(origin.to_owned(), user_id))
+        {
+            self.local_users.remove(&local);
+        }
+    }
+
+    /// Whether `user_id` was given to a user of another instance.
+    #[must_use]
+    pub fn has_user(&self, user_id: UserId) -> bool {
+        self.local_users.contains(&user_id)
+    }
+
+    #[must_use]
+    pub fn message(&self, origin: &str, msg_id: MsgId) -> Option<MsgId> {
+        self.messages.get(&(origin.to_owned(), msg_id)).copied()
+    }
+
+    /// Remembers the local id of a message, forgetting the oldest one past
+    /// [`DELIVERED_CAPACITY`].
+    pub fn add_message(&mut self, origin: &str, msg_id: MsgId, local: MsgId) {
+        if self.message_order.len() == DELIVERED_CAPACITY {
+            if let Some(oldest) = self.message_order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+        let key = (origin.to_owned(), msg_id);
+        self.message_order.push_back(key.clone());
+        self.messages.insert(key, local);
+    }
+}
+
+fn decode(
+    mut payload: &[u8],
+) -> Result<(String, Option<String>, ServerCommand)> {
+    let r = &mut payload;
+    let envelope = (
+        str::decode(r)?,
+        Option::decode(r)?,
+        ServerCommand::decode(r)?,
+    );
+    if !r.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "trailing bytes"));
+    }
+    Ok(envelope)
+}
+
+#[cfg(feature = "redis")]
+pub mod redis {
+    //! A [`PubSub`] over Redis `PUBLISH` and `SUBSCRIBE`.
+    //!
+    //! Publishing and subscribing each use their own connection in a
+    //! background thread, which reconnects after errors. Broadcasts
+    //! published while Redis is unreachable are lost.
+
+    use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::time::Duration;
+
+    use log::{info, warn};
+
+    use super::{PubSub, Wake};
+
+    const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+    #[derive(Debug)]
+    pub struct Redis {
+        published: Sender<Vec<u8>>,
+        received: Receiver<Vec<u8>>,
+    }
+
+    impl Redis {
+        #[must_use]
+        pub fn new(addr: String, channel_name: String, wake: Wake) -> Self {
+            let (published, to_publish) = channel::<Vec<u8>>();
+            let (to_receive, received) = channel();
+            let publisher_addr = addr.clone();
+            let publisher_channel = channel_name.clone();
+            thread::spawn(move || {
+                publish_all(&publisher_addr, &publisher_channel, &to_publish);
+            });
+            thread::spawn(move || loop {
+                match subscribe(&addr, &channel_name, &to_receive, &*wake) {
+                    // the server is gone
+                    Ok(()) => return,
+                    Err(e) => warn!("Bridge subscription failed: {e}"),
+                }
+                thread::sleep(RECONNECT_DELAY);
+            });
+            Self {
+                published,
+                received,
+            }
+        }
+    }
+
+    impl PubSub for Redis {
+        fn publish(&mut self, payload: Vec<u8>) {
+            // the worker only stops once we are dropped
+            let _ = self.published.send(payload);
+        }
+
+        fn poll(&mut self) -> Option<Vec<u8>> {
+            self.received.try_recv().ok()
+        }
+    }
+
+    fn publish_all(
+        addr: &str,
+        channel_name: &str,
+        payloads: &Receiver<Vec<u8>>,
+    ) {
+        let mut connection = None::<BufReader<TcpStream>>;
+        for payload in payloads {
+            // a connection that broke while idle only fails on use, so
+            // every payload gets a second try on a fresh one
+            for attempt in 1..=2 {
+                let published = match &mut connection {
+                    Some(c) => publish(c, channel_name, &payload),
+                    None => TcpStream::connect(addr).and_then(|stream| {
+                        let c = connection.insert(BufReader::new(stream));
+                        publish(c, channel_name, &payload)
+                    }),
+                };
+                match published {
+                    Ok(()) => break,
+                    Err(e) => {
+                        warn!("Bridge publish attempt {attempt} failed: {e}");
+                        connection = None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn publish(
+        connection: &mut BufReader<TcpStream>,
+        channel_name: &str,
+        payload: &[u8],
+    ) -> Result<()> {
+        write_command(
+            connection.get_mut(),
+            &[b"PUBLISH", channel_name.as_bytes(), payload],
+        )?;
+        read_value(connection).map(drop)
+    }
+
+    /// Forwards the payloads published on `channel_name` until `payloads`
+    /// is closed.
+    fn subscribe(
+        addr: &str,
+        channel_name: &str,
+        payloads: &Sender<Vec<u8>>,
+        wake: &(dyn Fn() + Send + Sync),
+    ) -> Result<()> {
+        let stream = TcpStream::connect(addr)?;
+        write_command(&mut &stream, &[b"SUBSCRIBE", channel_name.as_bytes()])?;
+        info!("Bridge subscribed to {channel_name} on {addr}");
+        let mut r = BufReader::new(stream);
+        loop {
+            let Value::Array(parts) = read_value(&mut r)? else {
+                continue;
+            };
+            if let [Value::Data(kind), _, Value::Data(payload)] =
+                parts.as_slice()
+            {
+                if kind == b"message" {
+                    if payloads.send(payload.clone()).is_err() {
+                        return Ok(());
+                    }
+                    wake();
+                }
+            }
+        }
+    }
+
+    /// Writes `args` as a Redis command, in one piece.
+    fn write_command(w: &mut impl Write, args: &[&[u8]]) -> Result<()> {
+        let mut command = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            write!(command, "${}\r\n", arg.len())?;
+            command.extend_from_slice(arg);
+            command.extend_from_slice(b"\r\n");
+        }
+        w.write_all(&command)
+    }
+
+    /// A reply in the Redis protocol, with simple strings, integers and
+    /// bulk strings all read as [`Value::Data`].
+    enum Value {
+        Data(Vec<u8>),
+        Nil,
+        Array(Vec<Value>),
+    }
+
+    fn read_value(r: &mut impl BufRead) -> Result<Value> {
+        let mut line = vec![];
+        r.read_until(b'\n', &mut line)?;
+        if line.pop() != Some(b'\n') || line.pop() != Some(b'\r') {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+        let Some((&kind, rest)) = line.split_first() else {
+            return Err(Error::from(ErrorKind::InvalidData));
+        };
+        let length = || -> Result<i64> {
+            std::str::from_utf8(rest)
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| Error::from(ErrorKind::InvalidData))
+        };
+        Ok(match kind {
+            b'+' | b':' => Value::Data(rest.to_owned()),
+            b'-' => {
+                return Err(Error::other(
+                    String::from_utf8_lossy(rest).into_owned(),
+                ))
+            }
+            b'$' => match usize::try_from(length()?) {
+                Ok(len) => {
+                    let mut data = vec![0; len + 2];
+                    r.read_exact(&mut data)?;
+                    data.truncate(len);
+                    Value::Data(data)
+                }
+                Err(_) => Value::Nil,
+            },
+            b'*' => match usize::try_from(length()?) {
+                Ok(len) => Value::Array(
+                    (0..len).map(|_| read_value(r)).collect::<Result<_>>()?,
+                ),
+                Err(_) => Value::Nil,
+            },
+            _ => return Err(Error::from(ErrorKind::InvalidData)),
+        })
+    }
+}
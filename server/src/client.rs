@@ -1,28 +1,96 @@
-use std::io::{Error, ErrorKind, Result, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
 
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 
-use common::commands::{ClientCommand, ServerCommand};
-use common::Connection;
+use common::commands::{ClientCommand, Role, ServerCommand};
+use common::{
+    ChannelId, Connection, ConnectionStats, DataSize, Transport, UserId,
+};
+
+use crate::{RateLimiter, RateLimits, Verdict};
+
+/// How the server knows that a client is who its name says.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Identity {
+    /// Nobody checked, anyone could have taken the name.
+    #[default]
+    Anonymous,
+    /// The authentication provider checked a credential for the name.
+    Verified,
+    /// The client logged in to an account.
+    Account,
+}
 
 #[derive(Debug)]
 pub struct Client {
     addr: SocketAddr,
     connection: Connection<ServerCommand, ClientCommand>,
     connected: bool,
-    user_id: u16,
+    user_id: UserId,
+    /// Index of the listener that accepted the client.
+    listener: usize,
+    name: Option<String>,
+    /// What the client connected with, checked again when it renames.
+    credential: Option<String>,
+    identity: Identity,
+    role: Role,
+    /// Ticket of the name it waits on the auth thread to check, if any.
+    auth_ticket: Option<u64>,
+    /// Users whose whispers the client hasn't marked read yet.
+    unread_whispers: HashSet<UserId>,
+    /// Only messages sent to this channel are forwarded to the client.
+    channel: ChannelId,
+    /// Bytes transferred when the traffic was last sampled.
+    traffic_sample: u64,
+    /// Token of the latest ping and when it was sent.
+    last_ping: (u16, Instant),
+    /// Pings sent in a row without the client answering.
+    unanswered_pings: u32,
+    /// When the client connected.
+    joined: SystemTime,
+    rate: RateLimiter,
+    /// Why the client was disconnected, as reported in the metrics.
+    disconnect_reason: Option<&'static str>,
 }
 
 impl Client {
-    pub fn new(stream: TcpStream, user_id: u16) -> Result<Self> {
+    pub fn new(
+        stream: impl Transport + 'static,
+        user_id: UserId,
+        listener: usize,
+        max_frame_size: DataSize,
+        rate_limits: &RateLimits,
+    ) -> Result<Self> {
+        let mut connection = Connection::new(stream)?;
+        connection.set_max_frame_size(max_frame_size);
         let this = Self {
-            addr: stream.peer_addr()?,
-            connection: Connection::new(stream)?,
+            addr: connection.peer_addr()?,
+            connection,
             connected: true,
             user_id,
+            listener,
+            name: None,
+            credential: None,
+            identity: Identity::Anonymous,
+            role: Role::User,
+            auth_ticket: None,
+            unread_whispers: HashSet::new(),
+            channel: ChannelId::LOBBY,
+            traffic_sample: 0,
+            last_ping: (0, Instant::now()),
+            unanswered_pings: 0,
+            joined: SystemTime::now(),
+            rate: RateLimiter::new(rate_limits),
+            disconnect_reason: None,
         };
-        info!("Client connected: {}", this.addr);
+        info!(
+            event = "client_connected", addr:% = this.addr,
+            user_id = user_id.0;
+            "Client connected: {}", this.addr
+        );
         Ok(this)
     }
 
@@ -37,7 +105,7 @@ impl Client {
             }
             Err(e) if e.kind() == ErrorKind::WouldBlock => None,
             Err(e) => {
-                self.disconnect(Some(e));
+                self.disconnect_on_error(e);
                 None
             }
         }
@@ -51,7 +119,10 @@ impl Client {
         match self.connection.send(message) {
             Ok(()) => (),
             Err(e) if e.kind() == ErrorKind::WouldBlock => (),
-            Err(e) => self.disconnect(Some(e)),
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                warn!("Not sending {} to {}: {e}", message.name(), self.addr);
+            }
+            Err(e) => self.disconnect_on_error(e),
         }
     }
 
@@ -63,35 +134,201 @@ impl Client {
         match self.connection.flush() {
             Ok(()) => (),
             Err(e) if e.kind() == ErrorKind::WouldBlock => (),
-            Err(e) => self.disconnect(Some(e)),
+            Err(e) => self.disconnect_on_error(e),
         }
     }
 
-    fn disconnect(&mut self, reason: Option<Error>) {
+    fn disconnect_on_error(&mut self, e: Error) {
+        let reason = match e.kind() {
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => "closed",
+            _ => "error",
+        };
+        self.disconnect(reason, Some(e));
+    }
+
+    /// Closes the connection, `reason` being a short label for the
+    /// metrics like `ping_timeout`, with `error` telling more in the log.
+    pub fn disconnect(&mut self, reason: &'static str, error: Option<Error>) {
         if !self.connected {
             return;
         }
-        if let Some(e) = reason {
+        self.disconnect_reason = Some(reason);
+        if let Some(e) = error {
             info!(
+                event = "client_disconnected", addr:% = self.addr,
+                user_id = self.user_id.0, reason;
                 "Disconnecting client {}, error kind: {}, reason: {}",
                 self.addr,
                 e.kind(),
                 e
             );
         } else {
-            info!("Disconnecting client {}, no reason", self.addr);
+            info!(
+                event = "client_disconnected", addr:% = self.addr,
+                user_id = self.user_id.0, reason;
+                "Disconnecting client {}, no reason", self.addr
+            );
         }
         let _ = self.connection.shutdown(std::net::Shutdown::Both);
         self.connected = false;
     }
 
+    /// Whether `interval` passed since the last ping.
+    #[must_use]
+    pub fn ping_due(&self, interval: Duration) -> bool {
+        self.last_ping.1.elapsed() >= interval
+    }
+
+    /// Time left until [`ping_due`](Self::ping_due).
+    #[must_use]
+    pub fn until_ping(&self, interval: Duration) -> Duration {
+        interval.saturating_sub(self.last_ping.1.elapsed())
+    }
+
+    pub fn ping(&mut self) {
+        let token = self.last_ping.0.wrapping_add(1);
+        self.last_ping = (token, Instant::now());
+        self.unanswered_pings += 1;
+        self.send(&ServerCommand::Ping { token });
+        self.flush();
+    }
+
+    /// Takes note of an answer to a ping, only the latest one counts.
+    pub fn pong(&mut self, token: u16) {
+        if token == self.last_ping.0 {
+            self.unanswered_pings = 0;
+        }
+    }
+
+    /// Number of pings in a row the client did not answer, counting the
+    /// latest one that may still be answered.
+    #[must_use]
+    pub const fn unanswered_pings(&self) -> u32 {
+        self.unanswered_pings
+    }
+
+    /// Charges a command received from the client to its rate limits.
+    pub fn check_rate(&mut self, command: &ClientCommand) -> Verdict {
+        self.rate.check(command)
+    }
+
     #[must_use]
     pub const fn connected(&self) -> bool {
         self.connected
     }
 
+    /// Why the client was disconnected, `None` while it is connected.
+    #[must_use]
+    pub const fn disconnect_reason(&self) -> Option<&'static str> {
+        self.disconnect_reason
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> ConnectionStats {
+        self.connection.stats()
+    }
+
+    /// Returns the bytes sent and received since the last call.
+    pub fn sample_traffic(&mut self) -> u64 {
+        let stats = self.connection.stats();
+        let total = stats.bytes_sent + stats.bytes_received;
+        let traffic = total - self.traffic_sample;
+        self.traffic_sample = total;
+        traffic
+    }
+
+    #[must_use]
+    pub const fn joined(&self) -> SystemTime {
+        self.joined
+    }
+
+    #[must_use]
+    pub const fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
     #[must_use]
-    pub const fn user_id(&self) -> u16 {
+    pub const fn user_id(&self) -> UserId {
         self.user_id
     }
+
+    /// Gives the client its id, once it connects or logs in to an account.
+    pub fn set_user_id(&mut self, user_id: UserId) {
+        self.user_id = user_id;
+    }
+
+    #[must_use]
+    pub const fn listener(&self) -> usize {
+        self.listener
+    }
+
+    /// The name the client connected with, `None` until a `Connect` was
+    /// accepted.
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = Some(name);
+    }
+
+    #[must_use]
+    pub fn credential(&self) -> Option<&str> {
+        self.credential.as_deref()
+    }
+
+    pub fn set_credential(&mut self, credential: Option<String>) {
+        self.credential = credential;
+    }
+
+    #[must_use]
+    pub const fn auth_ticket(&self) -> Option<u64> {
+        self.auth_ticket
+    }
+
+    pub fn set_auth_ticket(&mut self, ticket: Option<u64>) {
+        self.auth_ticket = ticket;
+    }
+
+    /// Notes that `user_id` whispered to the client.
+    pub fn add_unread_whisper(&mut self, user_id: UserId) {
+        self.unread_whispers.insert(user_id);
+    }
+
+    /// Marks the whispers from `user_id` read, returning whether there
+    /// were any.
+    pub fn read_whispers(&mut self, user_id: UserId) -> bool {
+        self.unread_whispers.remove(&user_id)
+    }
+
+    #[must_use]
+    pub const fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    #[must_use]
+    pub const fn identity(&self) -> Identity {
+        self.identity
+    }
+
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.identity = identity;
+    }
+
+    #[must_use]
+    pub const fn channel(&self) -> ChannelId {
+        self.channel
+    }
+
+    pub fn set_channel(&mut self, channel: ChannelId) {
+        self.channel = channel;
+    }
 }
@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use common::DataSize;
+
+use crate::{
+    ArchiveSink, AuthConfig, BridgeConfig, HookConfig, LoadLimits, Permissions,
+    RateLimits, WebhookConfig,
+};
+
+/// Settings of a [`Server`](crate::Server) that are not tied to its
+/// listening address.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Names that are given the admin role when they connect, once they
+    /// logged in to the account or the authentication checked them.
+    pub admins: Vec<String>,
+    pub permissions: Permissions,
+    /// Only let users with an invite token (or admins) connect.
+    pub invite_only: bool,
+    /// Users must send this to connect, whatever their name.
+    pub password: Option<String>,
+    /// Who may connect under which name.
+    pub auth: AuthConfig,
+    /// Number of recent messages sent to users joining a channel.
+    pub history_size: usize,
+    /// Shown to users when they connect.
+    pub motd: Option<String>,
+    /// How often clients are pinged, never if `None`.
+    pub ping_interval: Option<Duration>,
+    /// Clients missing this many pings in a row are disconnected.
+    pub max_missed_pings: u32,
+    /// Channels nobody was in or posted to for this long are archived,
+    /// never if `None`.
+    pub archive_rooms_after: Option<Duration>,
+    pub load_limits: LoadLimits,
+    /// How fast each client may send commands.
+    pub rate_limits: RateLimits,
+    /// Every message is archived to all of these before it is broadcast.
+    pub archive: Vec<ArchiveSink>,
+    /// Where webhook sinks put records they failed to deliver.
+    pub archive_dead_letter: PathBuf,
+    /// Pub/sub shared with other instances serving the same rooms.
+    pub bridge: BridgeConfig,
+    /// Bridges to other chat networks over HTTP.
+    pub webhooks: WebhookConfig,
+    /// Plugins called on connects, messages and disconnects, in this
+    /// order; more can be added with [`Server::add_hook`](crate::Server).
+    pub hooks: Vec<HookConfig>,
+    /// Encrypt connections with this certificate, plain TCP if `None`.
+    pub tls: Option<TlsConfig>,
+    /// Threads reading from and writing to clients, when there are enough
+    /// of them.
+    pub io_threads: usize,
+    /// Largest frame sent to or accepted from clients, in bytes.
+    pub max_frame_size: DataSize,
+    /// Clients that leave more bytes than this waiting to be written to
+    /// them are disconnected, never if `None`.
+    pub max_queued_bytes: Option<u64>,
+    /// Largest file users may send each other, in bytes, no transfers at
+    /// all if `None`.
+    pub max_file_size: Option<u64>,
+}
+
+/// PEM files of the certificate chain and private key the server presents
+/// to clients.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
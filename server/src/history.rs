@@ -0,0 +1,122 @@
+use std::collections::{HashMap, VecDeque};
+
+use common::commands::{Reaction, ServerCommand};
+use common::{ChannelId, MsgId, UserId};
+
+/// A bounded log of the most recent chat messages.
+#[derive(Debug)]
+pub struct History {
+    messages: VecDeque<ServerCommand>,
+    capacity: usize,
+    /// Reactions to the kept messages that have any.
+    reactions: HashMap<MsgId, Vec<Reaction>>,
+}
+
+impl History {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            reactions: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, message: ServerCommand) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            if let Some(ServerCommand::Message { msg_id, .. }) =
+                self.messages.pop_front()
+            {
+                self.reactions.remove(&msg_id);
+            }
+        }
+        self.messages.push_back(message);
+    }
+
+    /// Returns the message with `msg_id`, if it is still kept.
+    #[must_use]
+    pub fn get(&self, msg_id: MsgId) -> Option<&ServerCommand> {
+        let index = self.messages.partition_point(|m| {
+            matches!(m, ServerCommand::Message { msg_id: id, .. } if *id < msg_id)
+        });
+        self.messages.get(index).filter(|m| {
+            matches!(m, ServerCommand::Message { msg_id: id, .. } if *id == msg_id)
+        })
+    }
+
+    /// Returns the reactions to the message with `msg_id`.
+    #[must_use]
+    pub fn reactions(&self, msg_id: MsgId) -> &[Reaction] {
+        self.reactions.get(&msg_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Adds the reaction of `user_id` with `emoji` to the message with
+    /// `msg_id`, or takes it back if they already reacted with it. Returns
+    /// the reactions to the message, `None` if it is not kept.
+    pub fn toggle_reaction(
+        &mut self,
+        msg_id: MsgId,
+        user_id: UserId,
+        emoji: String,
+    ) -> Option<Vec<Reaction>> {
+        self.get(msg_id)?;
+        let reactions = self.reactions.entry(msg_id).or_default();
+        match reactions.iter().position(|r| r.emoji == emoji) {
+            Some(index) => {
+                let user_ids = &mut reactions[index].user_ids;
+                match user_ids.iter().position(|&id| id == user_id) {
+                    Some(position) => {
+                        user_ids.remove(position);
+                        if user_ids.is_empty() {
+                            reactions.remove(index);
+                        }
+                    }
+                    None => user_ids.push(user_id),
+                }
+            }
+            None => reactions.push(Reaction {
+                emoji,
+                user_ids: vec![user_id],
+            }),
+        }
+        let reactions = reactions.clone();
+        if reactions.is_empty() {
+            self.reactions.remove(&msg_id);
+        }
+        Some(reactions)
+    }
+
+    /// Returns the latest `limit` messages in `channel_id` older than
+    /// `msg_id`, oldest first.
+    #[must_use]
+    pub fn before(
+        &self,
+        channel_id: ChannelId,
+        msg_id: MsgId,
+        limit: usize,
+    ) -> Vec<ServerCommand> {
+        let end = self.messages.partition_point(|m| {
+            matches!(m, ServerCommand::Message { msg_id: id, .. } if *id < msg_id)
+        });
+        let mut found: Vec<_> = self
+            .messages
+            .range(..end)
+            .rev()
+            .filter(|m| in_channel(m, channel_id))
+            .take(limit)
+            .cloned()
+            .collect();
+        found.reverse();
+        found
+    }
+}
+
+fn in_channel(message: &ServerCommand, channel_id: ChannelId) -> bool {
+    matches!(
+        message,
+        ServerCommand::Message { channel_id: id, .. } if *id == channel_id
+    )
+}
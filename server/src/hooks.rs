@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use common::UserId;
+use log::warn;
+
+use crate::auth::{read_lines, run_with_timeout, ProcessOutput};
+use crate::Wake;
+
+/// How long a [`ScriptHook`] may run before it is killed.
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a [`Hook`] makes of a chat message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookVerdict {
+    /// Let the message through as it is.
+    Accept,
+    /// Send this text instead.
+    Replace(String),
+    /// Drop the message, telling the sender this reason.
+    Reject(String),
+}
+
+/// Extends the server, e.g. to filter or log messages, without changes to
+/// its loop.
+///
+/// Hooks are called on a thread of their own, in the order they were
+/// added, and only hold up the messages waiting for them while they run.
+/// Every method does nothing by default.
+pub trait Hook: Debug + Send {
+    /// A user connected under `name`; renames don't count.
+    fn on_connect(&mut self, _user_id: UserId, _name: &str) {}
+
+    /// A user sent `message` to `channel`, or whispered it to `@` and the
+    /// recipient's name; forwards pass the text they quote. A replaced
+    /// message is what the hooks after this one see. Text pushed to
+    /// `replies` is sent back to the user alone, whatever the verdict.
+    fn on_message(
+        &mut self,
+        _user_id: UserId,
+        _channel: &str,
+        _message: &str,
+        _replies: &mut Vec<String>,
+    ) -> HookVerdict {
+        HookVerdict::Accept
+    }
+
+    /// A user that connected as `name` is gone.
+    fn on_disconnect(&mut self, _user_id: UserId, _name: &str) {}
+}
+
+/// What the hook thread is asked to do, in order.
+enum HookJob {
+    Add(Box<dyn Hook>),
+    Connect(UserId, String),
+    Message {
+        ticket: u64,
+        user_id: UserId,
+        channel: String,
+        message: String,
+    },
+    Disconnect(UserId, String),
+}
+
+/// What the hooks made of a message passed with
+/// [`Hooks::on_message`].
+#[derive(Debug)]
+pub struct HookedMessage {
+    pub ticket: u64,
+    /// The text to send, or the reason of the first hook that rejected
+    /// it.
+    pub verdict: std::result::Result<String, String>,
+    /// What the hooks sent back to the user.
+    pub replies: Vec<String>,
+}
+
+/// The hooks of a server, called one after the other on a thread of their
+/// own.
+#[derive(Debug)]
+pub struct Hooks {
+    len: usize,
+    jobs: Sender<HookJob>,
+    messages: Receiver<HookedMessage>,
+}
+
+impl Hooks {
+    /// Starts the hook thread, calling `wake` whenever a message passed
+    /// through the hooks.
+    #[must_use]
+    pub fn spawn(wake: Wake) -> Self {
+        let (jobs, inbox) = channel();
+        let (outbox, messages) = channel();
+        thread::spawn(move || run_hooks(&inbox, &outbox, &wake));
+        Self {
+            len: 0,
+            jobs,
+            messages,
+        }
+    }
+
+    /// Whether there are no hooks, so messages need not wait for them.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn add(&mut self, hook: Box<dyn Hook>) {
+        self.len += 1;
+        self.send(HookJob::Add(hook));
+    }
+
+    pub fn on_connect(&self, user_id: UserId, name: &str) {
+        self.send(HookJob::Connect(user_id, name.to_owned()));
+    }
+
+    /// Passes `message` through every hook, the outcome coming back from
+    /// [`poll`](Self::poll) with `ticket`. Fails if the hook thread
+    /// stopped.
+    pub fn on_message(
+        &self,
+        ticket: u64,
+        user_id: UserId,
+        channel: &str,
+        message: String,
+    ) -> Result<()> {
+        self.jobs
+            .send(HookJob::Message {
+                ticket,
+                user_id,
+                channel: channel.to_owned(),
+                message,
+            })
+            .map_err(|_| Error::other("the hook thread stopped"))
+    }
+
+    pub fn on_disconnect(&self, user_id: UserId, name: &str) {
+        self.send(HookJob::Disconnect(user_id, name.to_owned()));
+    }
+
+    /// Returns the next message that passed through the hooks, if any.
+    pub fn poll(&self) -> Option<HookedMessage> {
+        self.messages.try_recv().ok()
+    }
+
+    fn send(&self, job: HookJob) {
+        if self.jobs.send(job).is_err() {
+            warn!("The hook thread stopped");
+        }
+    }
+}
+
+/// Does the jobs of the hook thread until the server is dropped.
+fn run_hooks(
+    jobs: &Receiver<HookJob>,
+    messages: &Sender<HookedMessage>,
+    wake: &Wake,
+) {
+    let mut hooks: Vec<Box<dyn Hook>> = vec![];
+    for job in jobs {
+        match job {
+            HookJob::Add(hook) => hooks.push(hook),
+            HookJob::Connect(user_id, name) => {
+                for hook in &mut hooks {
+                    hook.on_connect(user_id, &name);
+                }
+            }
+            HookJob::Message {
+                ticket,
+                user_id,
+                channel,
+                mut message,
+            } => {
+                let mut replies = vec![];
+                let mut verdict = Ok(());
+                for hook in &mut hooks {
+                    match hook.on_message(
+                        user_id,
+                        &channel,
+                        &message,
+                        &mut replies,
+                    ) {
+                        HookVerdict::Accept => (),
+                        HookVerdict::Replace(text) => message = text,
+                        HookVerdict::Reject(reason) => {
+                            verdict = Err(reason);
+                            break;
+                        }
+                    }
+                }
+                let hooked = HookedMessage {
+                    ticket,
+                    verdict: verdict.map(|()| message),
+                    replies,
+                };
+                if messages.send(hooked).is_err() {
+                    return;
+                }
+                wake();
+            }
+            HookJob::Disconnect(user_id, name) => {
+                for hook in &mut hooks {
+                    hook.on_disconnect(user_id, &name);
+                }
+            }
+        }
+    }
+}
+
+/// Masks the words of a list in messages with `*`, matching whole words
+/// whatever their case.
+#[derive(Debug)]
+pub struct ProfanityFilter {
+    /// In lowercase.
+    words: HashSet<String>,
+}
+
+impl ProfanityFilter {
+    /// Reads the words from a file, one per line, `#` starting comments.
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            words: read_lines(path)?
+                .map(|(_, word)| word.to_lowercase())
+                .collect(),
+        })
+    }
+}
+
+impl Hook for ProfanityFilter {
+    fn on_message(
+        &mut self,
+        _: UserId,
+        _: &str,
+        message: &str,
+        _: &mut Vec<String>,
+    ) -> HookVerdict {
+        let mut filtered = String::with_capacity(message.len());
+        let mut masked = false;
+        let mut rest = message;
+        while !rest.is_empty() {
+            let word_len = rest
+                .find(|c: char| !c.is_alphanumeric())
+                .unwrap_or(rest.len());
+            if word_len == 0 {
+                let c = rest.chars().next().unwrap_or_default();
+                filtered.push(c);
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+            let (word, after) = rest.split_at(word_len);
+            if self.words.contains(&word.to_lowercase()) {
+                filtered.extend(word.chars().map(|_| '*'));
+                masked = true;
+            } else {
+                filtered.push_str(word);
+            }
+            rest = after;
+        }
+        if masked {
+            HookVerdict::Replace(filtered)
+        } else {
+            HookVerdict::Accept
+        }
+    }
+}
+
+/// Runs an executable for every event, so the server can be customized
+/// without rebuilding it.
+///
+/// The event is in `$TCPCHAT_EVENT` (`connect`, `message` or
+/// `disconnect`) and the user's id in `$TCPCHAT_USER_ID`. Connects and
+/// disconnects also get the name in `$TCPCHAT_NAME`, messages the channel
+/// in `$TCPCHAT_CHANNEL` and the text on stdin.
+/// A message is let through if the script exits successfully, replaced by
+/// what it printed if anything, and rejected otherwise, with what it
+/// printed as the reason. Each line it prints to stderr is sent back to the
+/// user as a reply. Scripts that can't be run, or are killed for running
+/// longer than [`SCRIPT_TIMEOUT`], let messages through. They run with the
+/// rights of the server, trusted like its configuration.
+#[derive(Debug)]
+pub struct ScriptHook {
+    path: PathBuf,
+}
+
+impl ScriptHook {
+    /// Every file in `dir` but hidden ones, in the order of their names.
+    pub fn load_dir(dir: &Path) -> Result<Vec<Self>> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file()
+                && !entry.file_name().to_string_lossy().starts_with('.')
+            {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        Ok(paths.into_iter().map(|path| Self { path }).collect())
+    }
+
+    /// Runs the script for `event`.
+    fn run(
+        &self,
+        event: &str,
+        user_id: UserId,
+        vars: &[(&str, &str)],
+        input: &str,
+    ) -> Result<ProcessOutput> {
+        run_with_timeout(
+            Command::new(&self.path)
+                .env("TCPCHAT_EVENT", event)
+                .env("TCPCHAT_USER_ID", user_id.to_string())
+                .envs(vars.iter().copied()),
+            input,
+            SCRIPT_TIMEOUT,
+        )
+    }
+
+    fn notify(&self, event: &str, user_id: UserId, name: &str) {
+        if let Err(e) = self.run(event, user_id, &[("TCPCHAT_NAME", name)], "")
+        {
+            warn!("Failed to run {}: {e}", self.path.display());
+        }
+    }
+}
+
+impl Hook for ScriptHook {
+    fn on_connect(&mut self, user_id: UserId, name: &str) {
+        self.notify("connect", user_id, name);
+    }
+
+    fn on_message(
+        &mut self,
+        user_id: UserId,
+        channel: &str,
+        message: &str,
+        replies: &mut Vec<String>,
+    ) -> HookVerdict {
+        let output = match self.run(
+            "message",
+            user_id,
+            &[("TCPCHAT_CHANNEL", channel)],
+            message,
+        ) {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("Failed to run {}: {e}", self.path.display());
+                return HookVerdict::Accept;
+            }
+        };
+        replies.extend(
+            output
+                .stderr
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(str::to_owned),
+        );
+        let text = output.stdout.trim_end().to_owned();
+        match (output.success, text.is_empty()) {
+            (true, true) => HookVerdict::Accept,
+            (true, false) => HookVerdict::Replace(text),
+            (false, true) => {
+                HookVerdict::Reject("The message was rejected".to_owned())
+            }
+            (false, false) => HookVerdict::Reject(text),
+        }
+    }
+
+    fn on_disconnect(&mut self, user_id: UserId, name: &str) {
+        self.notify("disconnect", user_id, name);
+    }
+}
+
+/// [`Hook`]s to add to the server.
+#[derive(Debug, Clone)]
+pub enum HookConfig {
+    /// A [`ProfanityFilter`] with the words of this file.
+    ProfanityFilter(PathBuf),
+    /// A [`ScriptHook`] for each file in this directory.
+    Scripts(PathBuf),
+}
+
+impl HookConfig {
+    pub fn open(&self) -> Result<Vec<Box<dyn Hook>>> {
+        Ok(match self {
+            Self::ProfanityFilter(path) => {
+                vec![Box::new(ProfanityFilter::load(path)?)]
+            }
+            Self::Scripts(dir) => ScriptHook::load_dir(dir)?
+                .into_iter()
+                .map(|hook| Box::new(hook) as Box<dyn Hook>)
+                .collect(),
+        })
+    }
+}
+
+impl FromStr for HookConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("profanity", path)) => Ok(Self::ProfanityFilter(path.into())),
+            Some(("scripts", dir)) => Ok(Self::Scripts(dir.into())),
+            _ => Err(format!(
+                "expected `profanity:<words file>` or `scripts:<directory>`, \
+                 got `{s}`"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Instant;
+
+    use super::*;
+
+    /// A hook running a shell script with `body`.
+    fn script(name: &str, body: &str) -> ScriptHook {
+        let dir = std::env::temp_dir()
+            .join(format!("tcpchat-hooks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .unwrap();
+        ScriptHook { path }
+    }
+
+    #[test]
+    fn scripts_replace_reject_and_reply() {
+        let mut hook = script(
+            "shout",
+            "echo \"shouting in $TCPCHAT_CHANNEL\" >&2\n\
+             [ \"$(cat)\" = quiet ] && exit 1\n\
+             echo LOUD",
+        );
+        let mut replies = vec![];
+        assert_eq!(
+            hook.on_message(UserId(1), "lobby", "hi", &mut replies),
+            HookVerdict::Replace("LOUD".to_owned())
+        );
+        assert_eq!(
+            hook.on_message(UserId(1), "lobby", "quiet", &mut replies),
+            HookVerdict::Reject("The message was rejected".to_owned())
+        );
+        assert_eq!(replies, ["shouting in lobby", "shouting in lobby"]);
+    }
+
+    #[test]
+    fn scripts_running_too_long_are_killed() {
+        let mut hook = script("slow", "sleep 10; exit 1");
+        let start = Instant::now();
+        assert_eq!(
+            hook.on_message(UserId(1), "lobby", "hi", &mut vec![]),
+            HookVerdict::Accept
+        );
+        assert!(start.elapsed() < SCRIPT_TIMEOUT * 2);
+    }
+}
@@ -0,0 +1,77 @@
+use std::hash::{BuildHasher, RandomState};
+use std::io::Result;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::storage::{Invite, Store};
+
+/// Seconds since the Unix epoch, which invites expire in.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Creates a token that lets new users connect to an invite-only server
+/// `uses` times (unlimited if `None`) until `valid_for` has passed
+/// (forever if `None`), and keeps it in `store`.
+pub fn create_invite(
+    store: &mut dyn Store,
+    uses: Option<u16>,
+    valid_for: Option<Duration>,
+) -> Result<String> {
+    let token = loop {
+        // RandomState is seeded randomly for every instance
+        let token =
+            format!("{:016x}", RandomState::new().hash_one(Instant::now()));
+        if store.get_invite(&token)?.is_none() {
+            break token;
+        }
+    };
+    store.put_invite(&Invite {
+        token: token.clone(),
+        uses_left: uses,
+        expires: valid_for.map(|d| now().saturating_add(d.as_secs())),
+    })?;
+    Ok(token)
+}
+
+/// Returns the invite with `token` if it can still be redeemed, removing
+/// it from `store` if it expired.
+fn valid_invite(store: &mut dyn Store, token: &str) -> Result<Option<Invite>> {
+    let Some(invite) = store.get_invite(token)? else {
+        return Ok(None);
+    };
+    if invite.expires.is_some_and(|expires| expires <= now()) {
+        store.delete_invite(token)?;
+        return Ok(None);
+    }
+    Ok(Some(invite))
+}
+
+/// Whether `token` would let a user in, without using it up.
+pub fn check_invite(store: &mut dyn Store, token: &str) -> Result<bool> {
+    Ok(valid_invite(store, token)?.is_some())
+}
+
+/// Uses up one redemption of `token`, returning whether it was valid.
+pub fn redeem_invite(store: &mut dyn Store, token: &str) -> Result<bool> {
+    let Some(mut invite) = valid_invite(store, token)? else {
+        return Ok(false);
+    };
+    match &mut invite.uses_left {
+        Some(0 | 1) => {
+            store.delete_invite(token)?;
+        }
+        Some(uses_left) => {
+            *uses_left -= 1;
+            store.put_invite(&invite)?;
+        }
+        None => (),
+    }
+    Ok(true)
+}
+
+/// Invalidates `token`, returning whether it existed.
+pub fn revoke_invite(store: &mut dyn Store, token: &str) -> Result<bool> {
+    store.delete_invite(token)
+}
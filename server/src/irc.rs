@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr};
+
+use log::debug;
+
+use common::commands::{ClientCommand, ContentType, ServerCommand};
+use common::{Codec, DataSize, Transport, UserId};
+
+/// Name the server goes by in the prefix of its own replies.
+const SERVER_NAME: &str = "tcpchat";
+/// Bytes written and not yet taken by the peer past which writes are
+/// refused, so the backlog stays with the
+/// [`Connection`](common::Connection), which counts it as queued.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+const RPL_WELCOME: u16 = 1;
+const RPL_TOPIC: u16 = 332;
+const RPL_NAMREPLY: u16 = 353;
+const RPL_ENDOFNAMES: u16 = 366;
+const RPL_MOTD: u16 = 372;
+const RPL_MOTDSTART: u16 = 375;
+const RPL_ENDOFMOTD: u16 = 376;
+const ERR_CANNOTSENDTOCHAN: u16 = 404;
+const ERR_UNKNOWNCOMMAND: u16 = 421;
+const ERR_NOMOTD: u16 = 422;
+const ERR_NONICKNAMEGIVEN: u16 = 431;
+const ERR_NICKNAMEINUSE: u16 = 433;
+const ERR_NOTREGISTERED: u16 = 451;
+const ERR_NEEDMOREPARAMS: u16 = 461;
+
+/// The server side of a connection from a stock IRC client.
+///
+/// Understands a small part of IRC: `NICK` connects or renames, `JOIN`
+/// moves to a channel, `PRIVMSG` talks in it or whispers to a nick, even
+/// an offline one with an account, and `PASS`, `PING`, `PONG` and `QUIT`
+/// do what they do on IRC. Lines read are turned into commands in the
+/// framing of the raw protocol, and the commands written back into lines,
+/// so a [`Connection`](common::Connection) can run over this like over a
+/// TCP stream. Replies the IRC client has no use for are dropped.
+#[derive(Debug)]
+pub struct Irc<T> {
+    inner: T,
+    /// Bytes read from the peer and not yet parsed.
+    raw: Vec<u8>,
+    /// Commands received, in the framing of the raw protocol.
+    incoming: Vec<u8>,
+    /// Commands written, in the framing of the raw protocol.
+    pending: Vec<u8>,
+    /// Bytes to write to the peer.
+    outgoing: Vec<u8>,
+    /// Longest line accepted, like a frame of the raw protocol.
+    max_line_len: usize,
+    /// Server password given with `PASS`, sent with the first `NICK`.
+    password: Option<String>,
+    /// The nick the server accepted, or the one asked for before that.
+    nick: Option<String>,
+    /// Set once the server welcomed the user.
+    user_id: Option<UserId>,
+    /// Names of the users online, the client included.
+    users: HashMap<UserId, String>,
+    /// Name of the channel the user is in, without the `#`.
+    channel: Option<String>,
+    /// The client quit, reads end once the commands before are taken.
+    closed: bool,
+}
+
+impl<T: Transport> Irc<T> {
+    /// Waits for the client on `inner` to register with `NICK`.
+    pub fn accept(inner: T, max_line_len: DataSize) -> Self {
+        Self {
+            inner,
+            raw: Vec::new(),
+            incoming: Vec::new(),
+            pending: Vec::new(),
+            outgoing: Vec::new(),
+            max_line_len: max_line_len as usize,
+            password: None,
+            nick: None,
+            user_id: None,
+            users: HashMap::new(),
+            channel: None,
+            closed: false,
+        }
+    }
+
+    /// Handles the complete lines among the bytes read.
+    fn parse_lines(&mut self) -> Result<()> {
+        while let Some(end) = self.raw.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.raw.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if let Some((command, params)) = parse_line(line) {
+                self.handle_line(&command, params);
+            }
+            if self.closed {
+                return Ok(());
+            }
+        }
+        if self.raw.len() > self.max_line_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "IRC line too long",
+            ));
+        }
+        Ok(())
+    }
+
+    fn handle_line(&mut self, command: &str, mut params: Vec<String>) {
+        let registered = self.user_id.is_some();
+        match command {
+            "CAP" => match params.first().map(String::as_str) {
+                Some("LS") => self.push_line("CAP * LS :"),
+                Some("REQ") => {
+                    let caps = params.pop().unwrap_or_default();
+                    self.push_line(&format!("CAP * NAK :{caps}"));
+                }
+                _ => (),
+            },
+            "PASS" => self.password = params.pop(),
+            "NICK" => {
+                let Some(name) = params.into_iter().next() else {
+                    return self.push_reply(
+                        ERR_NONICKNAMEGIVEN,
+                        &[],
+                        "No nickname given",
+                    );
+                };
+                if registered {
+                    self.push_command(&ClientCommand::Rename {
+                        new_name: name,
+                    });
+                } else {
+                    self.nick = Some(name.clone());
+                    self.push_command(&ClientCommand::Connect {
+                        name,
+                        invite: None,
+                        credential: None,
+                        password: self.password.clone(),
+                    });
+                }
+            }
+            "USER" | "MODE" => (),
+            "PING" => {
+                let token = params.pop().unwrap_or_default();
+                self.push_line(&format!(
+                    ":{SERVER_NAME} PONG {SERVER_NAME} :{token}"
+                ));
+            }
+            "PONG" => {
+                if let Some(token) =
+                    params.pop().and_then(|token| token.parse().ok())
+                {
+                    self.push_command(&ClientCommand::Pong { token });
+                }
+            }
+            "QUIT" => {
+                self.push_line("ERROR :Closing link");
+                self.closed = true;
+            }
+            _ if !registered => {
+                self.push_reply(
+                    ERR_NOTREGISTERED,
+                    &[],
+                    "You have not registered",
+                );
+            }
+            "JOIN" => {
+                let Some(channels) = params.first() else {
+                    return self.push_reply(
+                        ERR_NEEDMOREPARAMS,
+                        &[command],
+                        "Not enough parameters",
+                    );
+                };
+                // a user is in one channel at a time
+                let name = channels.split(',').next().unwrap_or_default();
+                self.push_command(&ClientCommand::Join {
+                    name: name.trim_start_matches('#').to_owned(),
+                });
+            }
+            "PRIVMSG" => {
+                let [target, message] = &params[..] else {
+                    return self.push_reply(
+                        ERR_NEEDMOREPARAMS,
+                        &[command],
+                        "Not enough parameters",
+                    );
+                };
+                self.privmsg(target, message);
+            }
+            _ => self.push_reply(
+                ERR_UNKNOWNCOMMAND,
+                &[command],
+                "Unknown command",
+            ),
+        }
+    }
+
+    /// Sends `message` to the user's channel or whispers it to a nick.
+    fn privmsg(&mut self, target: &str, message: &str) {
+        if let Some(channel) = target.strip_prefix('#') {
+            if self.channel.as_deref() != Some(channel) {
+                return self.push_reply(
+                    ERR_CANNOTSENDTOCHAN,
+                    &[target],
+                    "Cannot send to channel",
+                );
+            }
+            return self.push_command(&ClientCommand::Message {
+                message: message.to_owned(),
+                content_type: ContentType::Plain,
+                quote: None,
+            });
+        }
+        let target_user_id = self
+            .users
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(target))
+            .map(|(&user_id, _)| user_id);
+        // the server keeps messages for registered users who are offline,
+        // and tells if there is no such account
+        self.push_command(&match target_user_id {
+            Some(target_user_id) => ClientCommand::Whisper {
+                target_user_id,
+                message: message.to_owned(),
+            },
+            None => ClientCommand::OfflineWhisper {
+                name: target.to_owned(),
+                message: message.to_owned(),
+            },
+        });
+    }
+
+    /// Turns the complete commands written into lines.
+    fn translate_pending(&mut self) -> Result<()> {
+        const PREFIX: usize = size_of::<DataSize>();
+        while self.pending.len() >= PREFIX {
+            let size = DataSize::from_be_bytes(
+                self.pending[..PREFIX].try_into().unwrap(),
+            );
+            let end = PREFIX + size as usize;
+            if self.pending.len() < end {
+                return Ok(());
+            }
+            let command =
+                ServerCommand::decode(&mut &self.pending[PREFIX..end]);
+            self.pending.drain(..end);
+            self.translate(command?);
+        }
+        Ok(())
+    }
+
+    fn translate(&mut self, command: ServerCommand) {
+        match command {
+            ServerCommand::Welcome { user_id, motd } => {
+                if self.user_id.replace(user_id).is_some() {
+                    return;
+                }
+                let nick = self.nick().to_owned();
+                self.users.insert(user_id, nick.clone());
+                self.push_reply(
+                    RPL_WELCOME,
+                    &[],
+                    &format!("Welcome to {SERVER_NAME}, {nick}"),
+                );
+                let Some(motd) = motd else {
+                    return self.push_reply(
+                        ERR_NOMOTD,
+                        &[],
+                        "MOTD File is missing",
+                    );
+                };
+                self.push_reply(RPL_MOTDSTART, &[], "Message of the day");
+                for line in split_lines(&motd) {
+                    self.push_reply(RPL_MOTD, &[], line);
+                }
+                self.push_reply(RPL_ENDOFMOTD, &[], "End of MOTD");
+            }
+            ServerCommand::UserList { users } => self.users.extend(users),
+            ServerCommand::AddUser { user_id, name } => {
+                let old = self.users.insert(user_id, name.clone());
+                if let Some(old) = old.filter(|old| *old != name) {
+                    self.push_line(&format!(":{} NICK :{name}", mask(&old)));
+                }
+                if Some(user_id) == self.user_id {
+                    self.nick = Some(name);
+                }
+            }
+            ServerCommand::UserRenamed {
+                user_id,
+                old_name,
+                name,
+            } => {
+                self.users.insert(user_id, name.clone());
+                self.push_line(&format!(":{} NICK :{name}", mask(&old_name)));
+                if Some(user_id) == self.user_id {
+                    self.nick = Some(name);
+                }
+            }
+            ServerCommand::RemoveUser { user_id } => {
+                if let Some(name) = self.users.remove(&user_id) {
+                    self.push_line(&format!(":{} QUIT :Quit", mask(&name)));
+                }
+            }
+            ServerCommand::Joined { name, .. } => {
+                let own = mask(self.nick());
+                if let Some(old) = self.channel.take() {
+                    self.push_line(&format!(":{own} PART #{old}"));
+                }
+                self.push_line(&format!(":{own} JOIN #{name}"));
+                let channel = format!("#{name}");
+                let nick = self.nick().to_owned();
+                self.push_reply(RPL_NAMREPLY, &["=", &channel], &nick);
+                self.push_reply(
+                    RPL_ENDOFNAMES,
+                    &[&channel],
+                    "End of NAMES list",
+                );
+                self.channel = Some(name);
+            }
+            ServerCommand::TopicChanged { user_id, topic, .. } => {
+                let Some(channel) = self.channel.clone() else {
+                    return;
+                };
+                match user_id.and_then(|user_id| self.users.get(&user_id)) {
+                    Some(name) => {
+                        let line = format!(
+                            ":{} TOPIC #{channel} :{topic}",
+                            mask(name)
+                        );
+                        self.push_line(&line);
+                    }
+                    None => self.push_reply(
+                        RPL_TOPIC,
+                        &[&format!("#{channel}")],
+                        &topic,
+                    ),
+                }
+            }
+            ServerCommand::Message {
+                user_id, message, ..
+            } if Some(user_id) != self.user_id => {
+                if let Some(channel) = self.channel.clone() {
+                    self.push_privmsg(
+                        user_id,
+                        &format!("#{channel}"),
+                        &message,
+                    );
+                }
+            }
+            ServerCommand::Whisper {
+                user_id, message, ..
+            } if Some(user_id) != self.user_id => {
+                let nick = self.nick().to_owned();
+                self.push_privmsg(user_id, &nick, &message);
+            }
+            ServerCommand::OfflineWhisper { name, message, .. } => {
+                let nick = self.nick().to_owned();
+                for line in split_lines(&message) {
+                    let from = mask(&name);
+                    self.push_line(&format!(":{from} PRIVMSG {nick} :{line}"));
+                }
+            }
+            ServerCommand::NameTaken { name, .. } => self.push_reply(
+                ERR_NICKNAMEINUSE,
+                &[&name],
+                "Nickname is already in use",
+            ),
+            ServerCommand::ConnectRejected { reason }
+            | ServerCommand::ServerShutdown { reason } => {
+                self.push_line(&format!("ERROR :{reason}"));
+            }
+            ServerCommand::Kicked {
+                user_id, reason, ..
+            }
+            | ServerCommand::Banned {
+                user_id, reason, ..
+            } if Some(user_id) == self.user_id => {
+                self.push_line(&format!("ERROR :{reason}"));
+            }
+            ServerCommand::CommandFailed { command, reason } => {
+                self.push_notice(&format!("{command} failed: {reason}"));
+            }
+            ServerCommand::Notice { text } => {
+                for line in split_lines(&text) {
+                    self.push_notice(line);
+                }
+            }
+            ServerCommand::PermissionDenied { command, required } => {
+                self.push_notice(&format!(
+                    "You need to be {required} to use {command}"
+                ));
+            }
+            ServerCommand::Ping { token } => {
+                self.push_line(&format!("PING :{token}"));
+            }
+            _ => (),
+        }
+    }
+
+    /// The client's nick, `*` before it has one as in IRC replies.
+    fn nick(&self) -> &str {
+        self.nick.as_deref().unwrap_or("*")
+    }
+
+    fn push_privmsg(&mut self, user_id: UserId, target: &str, message: &str) {
+        let from = match self.users.get(&user_id) {
+            Some(name) => mask(name),
+            None => format!("user{user_id}"),
+        };
+        // a line can't hold a line break
+        for line in split_lines(message) {
+            self.push_line(&format!(":{from} PRIVMSG {target} :{line}"));
+        }
+    }
+
+    fn push_notice(&mut self, text: &str) {
+        let line = format!(":{SERVER_NAME} NOTICE {} :{text}", self.nick());
+        self.push_line(&line);
+    }
+
+    /// Queues a numeric reply to the client, with its nick first.
+    fn push_reply(&mut self, code: u16, params: &[&str], text: &str) {
+        let mut line = format!(":{SERVER_NAME} {code:03} {}", self.nick());
+        for param in params {
+            line.push(' ');
+            line.push_str(param);
+        }
+        line.push_str(" :");
+        line.push_str(text);
+        self.push_line(&line);
+    }
+
+    /// Queues a line to the client. Line breaks and NULs, which could
+    /// only come from the names and texts put in it, are replaced, so they
+    /// can't end the line early and smuggle in a command of their own.
+    fn push_line(&mut self, line: &str) {
+        for c in line.chars() {
+            let c = if matches!(c, '\r' | '\n' | '\0') { ' ' } else { c };
+            let mut buf = [0; 4];
+            self.outgoing.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+        self.outgoing.extend_from_slice(b"\r\n");
+    }
+
+    /// Queues `command` to be read by the server.
+    #[allow(clippy::cast_possible_truncation)]
+    fn push_command(&mut self, command: &ClientCommand) {
+        let size = command.coded_size() as DataSize;
+        self.incoming.extend_from_slice(&size.to_be_bytes());
+        command
+            .code(&mut self.incoming)
+            .unwrap_or_else(|_| unreachable!());
+    }
+
+    /// Writes out as much of the outgoing bytes as the stream takes.
+    fn write_outgoing(&mut self) -> Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.inner.write(&self.outgoing)? {
+                0 => return Err(Error::from(ErrorKind::WriteZero)),
+                n => drop(self.outgoing.drain(..n)),
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+/// Splits an IRC line into its command, in upper case, and parameters,
+/// skipping tags and the prefix.
+fn parse_line(line: &str) -> Option<(String, Vec<String>)> {
+    let mut rest = line;
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ')?.1;
+    }
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ')?.1;
+    }
+    let (middle, trailing) = match rest.split_once(" :") {
+        Some((middle, trailing)) => (middle, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = middle.split(' ').filter(|word| !word.is_empty());
+    let command = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<String> = words.map(str::to_owned).collect();
+    params.extend(trailing.map(str::to_owned));
+    debug!("IRC command {command} {params:?}");
+    Some((command, params))
+}
+
+/// The lines of a text, broken at any `\r` or `\n`, unlike
+/// [`str::lines`] which leaves a lone `\r` in.
+fn split_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split(['\r', '\n']).filter(|line| !line.is_empty())
+}
+
+/// The prefix of lines from the user with `name`.
+fn mask(name: &str) -> String {
+    format!("{name}!{name}@{SERVER_NAME}")
+}
+
+impl<T: Transport> Read for Irc<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            if !self.incoming.is_empty() {
+                let n = buf.len().min(self.incoming.len());
+                buf[..n].copy_from_slice(&self.incoming[..n]);
+                self.incoming.drain(..n);
+                return Ok(n);
+            }
+            if self.closed {
+                return Ok(0);
+            }
+            let mut chunk = [0; 4096];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.raw.extend_from_slice(&chunk[..n]);
+            self.parse_lines()?;
+            match self.write_outgoing() {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<T: Transport> Write for Irc<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.write_outgoing() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+            _ => (),
+        }
+        if self.pending.len() + self.outgoing.len() >= MAX_BUFFERED {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        self.pending.extend_from_slice(buf);
+        self.translate_pending()?;
+        match self.write_outgoing() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_outgoing()
+    }
+}
+
+impl<T: Transport> Transport for Irc<T> {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use common::{ChannelId, MsgId};
+
+    use super::*;
+
+    #[test]
+    fn line_breaks_in_fields_stay_in_one_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap());
+        let mut irc = Irc::accept(stream.unwrap(), 512);
+        irc.push_reply(RPL_TOPIC, &["#general"], "hi\r\nKILL x :y\0z\rw");
+        let out = String::from_utf8(irc.outgoing).unwrap();
+        assert_eq!(out.matches("\r\n").count(), 1);
+        assert!(!out.trim_end().contains(['\r', '\n', '\0']));
+    }
+
+    #[test]
+    fn a_slow_peer_leaves_the_backlog_to_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut irc = Irc::accept(stream, 512);
+        irc.channel = Some("general".to_owned());
+        irc.users.insert(UserId(1), "alice".to_owned());
+        let message = ServerCommand::Message {
+            msg_id: MsgId(1),
+            user_id: UserId(1),
+            channel_id: ChannelId::LOBBY,
+            message: "x".repeat(400),
+            content_type: ContentType::Plain,
+            quote: None,
+            time: 0,
+        };
+        let mut command = vec![];
+        message.code(&mut command).unwrap();
+        let size = DataSize::try_from(command.len()).unwrap();
+        command.splice(0..0, size.to_be_bytes());
+        let refused = (0..100_000).find_map(|_| irc.write(&command).err());
+        assert_eq!(refused.map(|e| e.kind()), Some(ErrorKind::WouldBlock));
+        let buffered = irc.pending.len() + irc.outgoing.len();
+        assert!(buffered < MAX_BUFFERED + 1024);
+    }
+
+    #[test]
+    fn lone_carriage_return_splits_messages() {
+        let lines: Vec<_> = split_lines("a\rb\r\nc\n").collect();
+        assert_eq!(lines, ["a", "b", "c"]);
+    }
+}
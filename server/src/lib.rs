@@ -1,5 +1,60 @@
+pub mod admin;
+
+mod archive;
+pub use archive::*;
+
+mod auth;
+pub use auth::*;
+
+mod bridge;
+pub use bridge::*;
+
 mod client;
 pub use client::*;
 
+mod config;
+pub use config::*;
+
+mod history;
+pub use history::*;
+
+mod hooks;
+pub use hooks::*;
+
+mod invites;
+pub use invites::*;
+
+mod irc;
+pub use irc::*;
+
+mod listener;
+pub use listener::*;
+
+mod load;
+pub use load::*;
+
+mod logging;
+pub use logging::*;
+
+mod metrics;
+pub use metrics::*;
+
+mod permissions;
+pub use permissions::*;
+
+mod rate_limit;
+pub use rate_limit::*;
+
 mod server;
 pub use server::*;
+
+pub mod storage;
+
+mod transfers;
+pub use transfers::*;
+
+mod webhooks;
+pub use webhooks::*;
+
+mod websocket;
+pub use websocket::*;
@@ -0,0 +1,117 @@
+use std::fmt::Display;
+use std::io::Result;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::str::FromStr;
+
+/// An address to accept clients on, see its [`FromStr`] impl for the
+/// syntax.
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    /// Label used in logs and metrics.
+    pub name: String,
+    pub addr: SocketAddr,
+    /// Most clients connected through this listener at the same time.
+    pub max_connections: Option<usize>,
+    pub protocol: Protocol,
+}
+
+/// What clients of a listener speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// The chat protocol over TCP.
+    #[default]
+    Raw,
+    /// The chat protocol in WebSocket messages, see
+    /// [`WebSocket`](crate::WebSocket).
+    WebSocket,
+    /// A subset of IRC, see [`Irc`](crate::Irc).
+    Irc,
+}
+
+impl Protocol {
+    const fn scheme(self) -> &'static str {
+        match self {
+            Self::Raw => "",
+            Self::WebSocket => "ws://",
+            Self::Irc => "irc://",
+        }
+    }
+}
+
+impl Display for ListenerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = self.protocol.scheme();
+        write!(f, "{}={scheme}{}", self.name, self.addr)?;
+        if let Some(max) = self.max_connections {
+            write!(f, "/{max}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `[NAME=][ws://|irc://]ADDRESS[/MAX_CONNECTIONS]`, e.g.
+/// `lan=0.0.0.0:6969/50`. The name defaults to the address, `ws://` makes
+/// it a WebSocket listener and `irc://` an IRC one.
+impl FromStr for ListenerConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once('=')
+            .map_or((None, s), |(n, r)| (Some(n.to_owned()), r));
+        let (addr, max_connections) = match rest.split_once('/') {
+            Some((addr, max)) => (
+                addr,
+                Some(max.parse().map_err(|_| {
+                    format!("invalid connection limit `{max}`")
+                })?),
+            ),
+            None => (rest, None),
+        };
+        let (addr, protocol) = [Protocol::WebSocket, Protocol::Irc]
+            .into_iter()
+            .find_map(|protocol| {
+                Some((addr.strip_prefix(protocol.scheme())?, protocol))
+            })
+            .unwrap_or((addr, Protocol::Raw));
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|e| format!("invalid address `{addr}`: {e}"))?;
+        Ok(Self {
+            name: name.unwrap_or_else(|| addr.to_string()),
+            addr,
+            max_connections,
+            protocol,
+        })
+    }
+}
+
+/// A bound, non-blocking listening socket.
+#[derive(Debug)]
+pub struct Listener {
+    pub config: ListenerConfig,
+    socket: TcpListener,
+}
+
+impl Listener {
+    pub fn bind(config: ListenerConfig) -> Result<Self> {
+        let socket = TcpListener::bind(config.addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { config, socket })
+    }
+
+    pub fn accept(&self) -> Result<TcpStream> {
+        self.socket.accept().map(|(stream, _)| stream)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use log::{info, warn};
+
+/// Thresholds past which the server considers itself overloaded. Shedding
+/// is disabled when neither limit is set.
+#[derive(Debug, Clone, Default)]
+pub struct LoadLimits {
+    /// Longest acceptable server tick.
+    pub max_tick: Option<Duration>,
+    /// Most broadcasts that may pile up in one tick.
+    pub max_queue: Option<usize>,
+    /// Consecutive ticks over (or back under) the limits before shedding
+    /// starts (or stops).
+    pub ticks: u32,
+    /// Disconnect the client with the most traffic on every overloaded
+    /// tick while shedding, instead of only refusing new connections.
+    pub disconnect_heaviest: bool,
+}
+
+/// Decides when the server stops taking on more work.
+#[derive(Debug)]
+pub struct LoadShedder {
+    limits: LoadLimits,
+    /// Consecutive ticks on the other side of the limits than `shedding`
+    /// suggests.
+    streak: u32,
+    shedding: bool,
+}
+
+impl LoadShedder {
+    #[must_use]
+    pub const fn new(limits: LoadLimits) -> Self {
+        Self {
+            limits,
+            streak: 0,
+            shedding: false,
+        }
+    }
+
+    /// Records how a tick went, returning whether clients should be
+    /// disconnected to recover.
+    pub fn record(&mut self, tick: Duration, queue: usize) -> bool {
+        let overloaded = self.limits.max_tick.is_some_and(|max| tick > max)
+            || self.limits.max_queue.is_some_and(|max| queue > max);
+        if overloaded == self.shedding {
+            self.streak = 0;
+        } else {
+            self.streak += 1;
+            if self.streak >= self.limits.ticks {
+                self.streak = 0;
+                self.shedding = overloaded;
+                if overloaded {
+                    warn!(
+                        "Server overloaded (tick {}us, {queue} queued), \
+                         refusing new connections",
+                        tick.as_micros()
+                    );
+                } else {
+                    info!("Load is back to normal, accepting connections");
+                }
+            }
+        }
+        self.shedding && overloaded && self.limits.disconnect_heaviest
+    }
+
+    #[must_use]
+    pub const fn shedding(&self) -> bool {
+        self.shedding
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{Result, Write};
+use std::str::FromStr;
+
+use env_logger::fmt::Formatter;
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+
+use crate::JsonString;
+
+/// How the server writes its log, filtered by `RUST_LOG` either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Colored lines for people to read.
+    #[default]
+    Pretty,
+    /// One JSON object per line, for journald or ELK pipelines:
+    ///
+    /// ```text
+    /// {"timestamp":"2024-06-10T08:13:20.123Z","level":"INFO","target":"server::client","message":"Client connected: 127.0.0.1:50000","event":"client_connected","addr":"127.0.0.1:50000"}
+    /// ```
+    ///
+    /// with the structured fields of the record, like `addr`, `user_id`
+    /// and `event`, after the message.
+    Json,
+}
+
+impl LogFormat {
+    /// Installs the global logger, it can only be done once.
+    pub fn init(self) {
+        match self {
+            Self::Pretty => pretty_env_logger::init(),
+            Self::Json => env_logger::Builder::from_default_env()
+                .format(write_json)
+                .init(),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("expected `pretty` or `json`, got `{s}`")),
+        }
+    }
+}
+
+fn write_json(buf: &mut Formatter, record: &Record) -> Result<()> {
+    write!(buf, "{{\"timestamp\":\"{}\"", buf.timestamp_millis())?;
+    write!(buf, ",\"level\":\"{}\"", record.level())?;
+    write!(buf, ",\"target\":{}", JsonString(record.target()))?;
+    let message = record.args().to_string();
+    write!(buf, ",\"message\":{}", JsonString(&message))?;
+    let mut fields = JsonFields {
+        buf,
+        result: Ok(()),
+    };
+    // the visitor only fails by passing on a write error
+    let _ = record.key_values().visit(&mut fields);
+    fields.result?;
+    buf.write_all(b"}\n")
+}
+
+/// Writes the key-value pairs of a record as JSON members.
+struct JsonFields<'a> {
+    buf: &'a mut Formatter,
+    result: Result<()>,
+}
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> std::result::Result<(), kv::Error> {
+        if let Err(e) = write_json_member(self.buf, &key, &value) {
+            self.result = Err(e);
+            return Err(kv::Error::msg("failed to write the log"));
+        }
+        Ok(())
+    }
+}
+
+fn write_json_member(
+    buf: &mut Formatter,
+    key: &Key,
+    value: &Value,
+) -> Result<()> {
+    write!(buf, ",{}:", JsonString(key.as_str()))?;
+    // numbers and booleans keep their JSON type, anything else is text
+    if let Some(n) = value.to_u64() {
+        write!(buf, "{n}")
+    } else if let Some(n) = value.to_i64() {
+        write!(buf, "{n}")
+    } else if let Some(b) = value.to_bool() {
+        write!(buf, "{b}")
+    } else {
+        write!(buf, "{}", JsonString(&value.to_string()))
+    }
+}
@@ -1,30 +1,489 @@
-use std::io::Result;
+use std::fs::File;
+use std::io::{
+    stdin, stdout, BufReader, BufWriter, Error, ErrorKind, Result, Write,
+};
 use std::net::{IpAddr, Ipv4Addr};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
-use log::trace;
+use clap::{Parser, Subcommand};
+use log::{info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
 
-use server::Server;
+use common::commands::Role;
+use common::{ChannelId, DEFAULT_MAX_FRAME_SIZE};
+use server::admin;
+use server::storage::{
+    Ban, HistoryFileStore, MessageLog, Rotation, Store, StoreConfig,
+};
+use server::{
+    parse_requirement, ArchiveSink, AuthConfig, BridgeConfig, Config,
+    HookConfig, ListenerConfig, LoadLimits, LogFormat, MetricsEndpoint,
+    PasswordHash, Permission, Permissions, Protocol, RateLimits, Server,
+    TlsConfig, WebhookConfig,
+};
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
     addr: IpAddr,
     #[arg(short, long, default_value_t = 6969)]
     port: u16,
+    /// Accept clients on `[NAME=][ws://|irc://]ADDRESS[/MAX_CONNECTIONS]`
+    /// instead of --addr and --port; may be repeated
+    #[arg(long = "listen", value_name = "LISTENER")]
+    listeners: Vec<ListenerConfig>,
+    /// Also accept WebSocket clients on this port of --addr
+    #[arg(long, value_name = "PORT")]
+    ws_port: Option<u16>,
+    /// Also accept IRC clients on this port of --addr
+    #[arg(long, value_name = "PORT")]
+    irc_port: Option<u16>,
+    /// Periodically write Prometheus metrics to this file
+    #[arg(long)]
+    metrics_file: Option<PathBuf>,
+    /// How to write the log: `pretty` or `json` (one object per line)
+    #[arg(long, value_name = "FORMAT", default_value = "pretty")]
+    log_format: LogFormat,
+    /// Serve Prometheus metrics over HTTP at /metrics on this port of
+    /// --addr
+    #[arg(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+    /// Give the admin role to the account with this name once logged in,
+    /// or to the name once `--auth` checked its credential
+    #[arg(long = "admin", value_name = "NAME")]
+    admins: Vec<String>,
+    /// Override the role needed for a permission, e.g. `set_role=moderator`
+    #[arg(long = "require", value_name = "PERMISSION=ROLE", value_parser = parse_requirement)]
+    requirements: Vec<(Permission, Role)>,
+    /// Only let users with an invite token (or admins) connect
+    #[arg(long)]
+    invite_only: bool,
+    /// Only let users that know this password connect
+    #[arg(long, value_name = "PASSWORD")]
+    password: Option<String>,
+    /// Who may connect: `none`, `local:<accounts file>`, `tokens:<token
+    /// file>`, `command:<shell command>` or an http(s) URL (with the
+    /// `webhook` feature)
+    #[arg(long, value_name = "PROVIDER", default_value = "none")]
+    auth: AuthConfig,
+    /// Number of recent messages sent to users when they join a channel
+    #[arg(long, value_name = "MESSAGES", default_value_t = 50)]
+    history_size: usize,
+    /// A message of the day shown to users when they connect
+    #[arg(long, value_name = "TEXT")]
+    motd: Option<String>,
+    /// Threads reading from and writing to clients, used once there are
+    /// hundreds of them
+    #[arg(
+        long,
+        value_name = "THREADS",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    io_threads: u16,
+    /// Disconnect clients sending frames larger than this many bytes; at
+    /// least 65536 so history replies fit
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = DEFAULT_MAX_FRAME_SIZE,
+        value_parser = clap::value_parser!(u32).range(65536..)
+    )]
+    max_frame_size: u32,
+    /// Disconnect clients that don't read fast enough to keep fewer bytes
+    /// than this waiting for them, 0 to never disconnect them
+    #[arg(long, value_name = "BYTES", default_value_t = 16 << 20)]
+    max_queued_bytes: u64,
+    /// Largest file users may send each other, 0 to disable file transfers
+    #[arg(long, value_name = "BYTES", default_value_t = 100 << 20)]
+    max_file_size: u64,
+    /// Throttle clients sending more chat messages than this per second on
+    /// average, 0 for no limit
+    #[arg(long, value_name = "MESSAGES", default_value_t = 5.0)]
+    max_messages_per_sec: f64,
+    /// Throttle clients sending more bytes than this per second on average,
+    /// 0 for no limit
+    #[arg(long, value_name = "BYTES", default_value_t = 65536.0)]
+    max_bytes_per_sec: f64,
+    /// Mute clients that keep going over the rate limits for this long, 0
+    /// to only throttle them
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    flood_mute_secs: u64,
+    /// Ping clients this often, 0 to never ping them
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    ping_interval: u64,
+    /// Disconnect clients that miss this many pings in a row
+    #[arg(
+        long,
+        value_name = "PINGS",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_missed_pings: u32,
+    /// Archive channels nobody was in or posted to for this long, until
+    /// someone joins them again, 0 to keep them all
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    archive_rooms_after: u64,
+    /// Read a password from stdin and print a line for a `local:` accounts
+    /// file, then exit
+    #[arg(long, value_name = "NAME")]
+    hash_password: Option<String>,
+    /// Where to keep messages, roles and bans: `memory`, `file:<dir>` or
+    /// `sqlite:<path>`
+    #[arg(long, default_value = "memory")]
+    store: StoreConfig,
+    /// Keep messages in this append-only file instead of the --store
+    #[arg(long, value_name = "PATH")]
+    history_file: Option<PathBuf>,
+    /// Start a new --history-file once it grows past this many MiB
+    #[arg(long, value_name = "MIB", default_value_t = 16)]
+    history_max_size: u64,
+    /// Number of rotated --history-file files kept
+    #[arg(long, value_name = "FILES", default_value_t = 4)]
+    history_keep: usize,
+    /// Refuse new connections when ticks take longer than this many
+    /// milliseconds
+    #[arg(long, value_name = "MS")]
+    shed_tick_ms: Option<u64>,
+    /// Refuse new connections when more broadcasts than this pile up in a
+    /// tick
+    #[arg(long, value_name = "COMMANDS")]
+    shed_queue: Option<usize>,
+    /// Consecutive ticks over (or under) the limits before shedding starts
+    /// (or stops)
+    #[arg(long, value_name = "TICKS", default_value_t = 5)]
+    shed_after: u32,
+    /// While overloaded, also disconnect the client with the most traffic
+    #[arg(long)]
+    shed_disconnect: bool,
+    /// Archive every message before broadcasting it, to `file:<path>` or to
+    /// an http(s) URL (with the `webhook` feature); may be repeated
+    #[arg(long, value_name = "SINK")]
+    archive: Vec<ArchiveSink>,
+    /// Where undeliverable webhook archive records are appended
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "archive-dead-letter.jsonl"
+    )]
+    archive_dead_letter: PathBuf,
+    /// Share rooms with other servers through `redis://<host>[:<port>][/<channel>]`
+    /// (with the `redis` feature), or `none`
+    #[arg(long, value_name = "PUBSUB", default_value = "none")]
+    bridge: BridgeConfig,
+    /// POST every message as JSON to this http(s) URL, to bridge rooms to
+    /// other chat networks (with the `webhook` feature); may be repeated
+    #[arg(long = "webhook", value_name = "URL")]
+    webhooks: Vec<String>,
+    /// Where undeliverable --webhook messages are appended
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "webhook-dead-letter.jsonl"
+    )]
+    webhook_dead_letter: PathBuf,
+    /// Accept bridged messages as JSON POSTed to /messages on this port of
+    /// --addr
+    #[arg(long, value_name = "PORT")]
+    webhook_port: Option<u16>,
+    /// Bridges must send this as a bearer token to --webhook-port
+    #[arg(long, value_name = "TOKEN")]
+    webhook_token: Option<String>,
+    /// Add a plugin: `profanity:<words file>` masks the listed words in
+    /// messages, `scripts:<directory>` runs the files in it on every
+    /// connect, message and disconnect; may be repeated
+    #[arg(long, value_name = "HOOK")]
+    hook: Vec<HookConfig>,
+    /// Encrypt connections with the certificate chain in this PEM file
+    /// (with the `tls` feature)
+    #[arg(long, value_name = "PATH", requires = "key")]
+    cert: Option<PathBuf>,
+    /// PEM file with the private key of --cert
+    #[arg(long, value_name = "PATH", requires = "cert")]
+    key: Option<PathBuf>,
 }
 
+/// Offline administration; without one the server is started.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the users in the store
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Read the messages in the store
+    #[command(subcommand)]
+    History(HistoryCommand),
+    /// Write the accounts, roles, bans, rooms and messages in the store to
+    /// a file that `import` loads into another store
+    Export {
+        #[arg(long, value_name = "PATH")]
+        out: PathBuf,
+    },
+    /// Load a file written by `export` into an empty store
+    Import {
+        #[arg(long, value_name = "PATH")]
+        from: PathBuf,
+    },
+    /// Validate the configuration given by the other options
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum UserCommand {
+    /// List the users with a stored role, registered and banned names
+    List,
+    /// Stop a name from connecting
+    Ban { name: String },
+    /// Let a banned name connect again
+    Unban { name: String },
+    /// Delete the account of a registered name, freeing it for anyone
+    Unregister { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum HistoryCommand {
+    /// Print the messages of a room as JSON lines, oldest first
+    Export {
+        #[arg(long, default_value = ChannelId::LOBBY_NAME)]
+        room: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Open the store, authentication and archives and bind the listeners,
+    /// then exit
+    Check,
+}
+
+const METRICS_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the metrics served over HTTP are refreshed.
+const METRICS_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
 fn main() -> Result<()> {
-    pretty_env_logger::init();
     let args = Args::parse();
-    let mut server = Server::new((args.addr, args.port))?;
-    loop {
+    args.log_format.init();
+    if let Some(name) = args.hash_password {
+        let mut password = String::new();
+        stdin().read_line(&mut password)?;
+        let password = password.trim_end_matches(['\r', '\n']);
+        println!("{name}:{}", PasswordHash::new(password)?);
+        return Ok(());
+    }
+    let mut permissions = Permissions::default();
+    for (permission, role) in args.requirements {
+        permissions.set(permission, role);
+    }
+    let config = Config {
+        admins: args.admins,
+        permissions,
+        invite_only: args.invite_only,
+        password: args.password,
+        auth: args.auth,
+        history_size: args.history_size,
+        motd: args.motd,
+        ping_interval: (args.ping_interval > 0)
+            .then(|| Duration::from_secs(args.ping_interval)),
+        max_missed_pings: args.max_missed_pings,
+        archive_rooms_after: (args.archive_rooms_after > 0)
+            .then(|| Duration::from_secs(args.archive_rooms_after)),
+        load_limits: LoadLimits {
+            max_tick: args.shed_tick_ms.map(Duration::from_millis),
+            max_queue: args.shed_queue,
+            ticks: args.shed_after,
+            disconnect_heaviest: args.shed_disconnect,
+        },
+        rate_limits: RateLimits {
+            messages_per_sec: (args.max_messages_per_sec > 0.0)
+                .then_some(args.max_messages_per_sec),
+            bytes_per_sec: (args.max_bytes_per_sec > 0.0)
+                .then_some(args.max_bytes_per_sec),
+            mute: Duration::from_secs(args.flood_mute_secs),
+        },
+        archive: args.archive,
+        archive_dead_letter: args.archive_dead_letter,
+        bridge: args.bridge,
+        webhooks: WebhookConfig {
+            urls: args.webhooks,
+            dead_letter: args.webhook_dead_letter,
+            inbound: args.webhook_port.map(|port| (args.addr, port).into()),
+            token: args.webhook_token,
+        },
+        hooks: args.hook,
+        io_threads: args.io_threads.into(),
+        max_frame_size: args.max_frame_size,
+        max_queued_bytes: (args.max_queued_bytes > 0)
+            .then_some(args.max_queued_bytes),
+        max_file_size: (args.max_file_size > 0).then_some(args.max_file_size),
+        tls: args
+            .cert
+            .zip(args.key)
+            .map(|(cert, key)| TlsConfig { cert, key }),
+    };
+    let offline_history = matches!(args.command, Some(Command::History(_)))
+        && args.history_file.is_none();
+    let offline_store = matches!(
+        args.command,
+        Some(Command::User(_) | Command::Export { .. } | Command::Import { .. })
+    );
+    if (offline_history || offline_store)
+        && matches!(args.store, StoreConfig::Memory)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "offline administration needs a persistent --store",
+        ));
+    }
+    let mut store = args.store.open()?;
+    if let Some(path) = &args.history_file {
+        let rotation = Rotation {
+            max_size: args.history_max_size.saturating_mul(1 << 20),
+            keep: args.history_keep,
+        };
+        let log = MessageLog::open(path, Some(rotation))?;
+        store = Box::new(HistoryFileStore::new(log, store));
+    }
+    let mut listeners = args.listeners;
+    if listeners.is_empty() {
+        listeners.push(ListenerConfig {
+            name: "default".to_owned(),
+            addr: (args.addr, args.port).into(),
+            max_connections: None,
+            protocol: Protocol::Raw,
+        });
+    }
+    if let Some(port) = args.ws_port {
+        listeners.push(ListenerConfig {
+            name: "websocket".to_owned(),
+            addr: (args.addr, port).into(),
+            max_connections: None,
+            protocol: Protocol::WebSocket,
+        });
+    }
+    if let Some(port) = args.irc_port {
+        listeners.push(ListenerConfig {
+            name: "irc".to_owned(),
+            addr: (args.addr, port).into(),
+            max_connections: None,
+            protocol: Protocol::Irc,
+        });
+    }
+    match args.command {
+        Some(Command::User(command)) => return user(store.as_mut(), command),
+        Some(Command::History(HistoryCommand::Export { room })) => {
+            let channel_id = admin::find_channel(store.as_ref(), &room)?
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("there is no room `{room}`"),
+                    )
+                })?;
+            let count = admin::export_history(
+                store.as_ref(),
+                channel_id,
+                &mut stdout(),
+            )?;
+            info!("Exported {count} messages");
+            return Ok(());
+        }
+        Some(Command::Export { out }) => {
+            let mut w = BufWriter::new(File::create(&out)?);
+            let count = admin::export(store.as_ref(), &mut w)?;
+            w.flush()?;
+            info!("Exported {count} records to {}", out.display());
+            return Ok(());
+        }
+        Some(Command::Import { from }) => {
+            let mut r = BufReader::new(File::open(&from)?);
+            let count = admin::import(store.as_mut(), &mut r)?;
+            info!("Imported {count} records from {}", from.display());
+            return Ok(());
+        }
+        Some(Command::Config(ConfigCommand::Check)) => {
+            Server::new(listeners, config, store)?;
+            println!("Configuration OK");
+            return Ok(());
+        }
+        None => (),
+    }
+    let mut server = Server::new(listeners, config, store)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+    let metrics_endpoint = args
+        .metrics_port
+        .map(|port| MetricsEndpoint::serve((args.addr, port).into()))
+        .transpose()?;
+    let mut metrics_written = Instant::now();
+    let mut metrics_snapshot = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
         server.update()?;
-        if server.inactivity != 0 {
-            let sleep_time = server.inactivity.min(25) * 10;
-            trace!("Server is inactive, sleeping for {}ms", sleep_time);
-            std::thread::sleep(Duration::from_millis(sleep_time));
+        if let Some(path) = &args.metrics_file {
+            if metrics_written.elapsed() >= METRICS_INTERVAL {
+                metrics_written = Instant::now();
+                if let Err(e) = server.metrics().write_to(path) {
+                    warn!("Failed to write metrics to {}: {e}", path.display());
+                }
+            }
+        }
+        if let Some(endpoint) = &metrics_endpoint {
+            if metrics_snapshot.elapsed() >= METRICS_SNAPSHOT_INTERVAL {
+                metrics_snapshot = Instant::now();
+                endpoint.update(server.metrics());
+            }
+        }
+        let until_metrics = match &args.metrics_file {
+            Some(_) => {
+                METRICS_INTERVAL.saturating_sub(metrics_written.elapsed())
+            }
+            None => Duration::MAX,
+        };
+        let until_snapshot = match &metrics_endpoint {
+            Some(_) => METRICS_SNAPSHOT_INTERVAL
+                .saturating_sub(metrics_snapshot.elapsed()),
+            None => Duration::MAX,
+        };
+        server.wait(until_metrics.min(until_snapshot))?;
+    }
+    info!("Shutting down");
+    server.shutdown("The server was stopped");
+    Ok(())
+}
+
+fn user(store: &mut dyn Store, command: UserCommand) -> Result<()> {
+    match command {
+        UserCommand::List => {
+            let bans = store.list_bans()?;
+            for user in store.list_users()? {
+                println!("{}\t{}", user.name, user.role);
+            }
+            for account in store.list_accounts()? {
+                println!(
+                    "{}\t{}, registered as {}",
+                    account.name, account.role, account.user_id
+                );
+            }
+            for ban in bans {
+                println!("{ban}\tbanned");
+            }
+        }
+        UserCommand::Ban { name } => store.add_ban(&Ban::Name(name))?,
+        UserCommand::Unban { name } => {
+            if !store.remove_ban(&Ban::Name(name.clone()))? {
+                warn!("'{name}' was not banned");
+            }
+        }
+        UserCommand::Unregister { name } => {
+            if !store.delete_account(&name)? {
+                warn!("'{name}' was not registered");
+            }
         }
     }
+    Ok(())
 }
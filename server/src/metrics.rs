@@ -0,0 +1,612 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::fs;
+use std::io::{BufRead, BufReader, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{ConnectionStats, UserId};
+use log::{info, warn};
+
+/// Time windows over which chat activity is reported.
+const ACTIVITY_WINDOWS: [(&str, Duration); 3] = [
+    ("1m", Duration::from_secs(60)),
+    ("15m", Duration::from_secs(15 * 60)),
+    ("1h", Duration::from_secs(60 * 60)),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Received => "received",
+            Self::Sent => "sent",
+        })
+    }
+}
+
+/// Upper bounds of the tick duration histogram buckets, in seconds.
+const TICK_BUCKETS: [f64; 6] = [0.0001, 0.001, 0.005, 0.01, 0.05, 0.1];
+
+/// Connection counters of one listener.
+#[derive(Debug, Default)]
+struct ListenerMetrics {
+    connections: usize,
+    /// Bytes waiting to be written to the listener's clients.
+    queued_bytes: u64,
+    accepted: u64,
+    refused: u64,
+}
+
+/// Activity in one room.
+#[derive(Debug, Default)]
+struct RoomMetrics {
+    messages: u64,
+    /// Send time and author of the messages in the largest window.
+    recent_messages: VecDeque<(Instant, UserId)>,
+    users: usize,
+    peak_users: usize,
+    /// When the number of users in the room changed and to what, since
+    /// the last change before the largest window.
+    user_changes: VecDeque<(Instant, usize)>,
+}
+
+impl RoomMetrics {
+    fn set_users(&mut self, users: usize) {
+        if users == self.users && !self.user_changes.is_empty() {
+            return;
+        }
+        self.users = users;
+        self.peak_users = self.peak_users.max(users);
+        let now = Instant::now();
+        // the change before the window tells how many were there when it
+        // started
+        while self
+            .user_changes
+            .get(1)
+            .is_some_and(|(t, _)| now - *t > max_window())
+        {
+            self.user_changes.pop_front();
+        }
+        self.user_changes.push_back((now, users));
+    }
+
+    /// Returns the most users that were in the room at once in the last
+    /// `window`.
+    fn peak_users(&self, window: Duration) -> usize {
+        let now = Instant::now();
+        let mut peak = self.users;
+        for (t, users) in self.user_changes.iter().rev() {
+            peak = peak.max(*users);
+            if now - *t > window {
+                break;
+            }
+        }
+        peak
+    }
+}
+
+/// Server counters, rendered in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    commands: BTreeMap<(&'static str, Direction), u64>,
+    /// Send time and author of the messages in the largest window.
+    recent_messages: VecDeque<(Instant, UserId)>,
+    messages: u64,
+    users: usize,
+    peak_users: usize,
+    /// Keyed by channel name.
+    rooms: BTreeMap<String, RoomMetrics>,
+    /// Clients disconnected for not reading fast enough.
+    evictions: u64,
+    /// Keyed by listener name.
+    listeners: BTreeMap<String, ListenerMetrics>,
+    /// Keyed by the reason given to [`Client::disconnect`].
+    ///
+    /// [`Client::disconnect`]: crate::Client::disconnect
+    disconnects: BTreeMap<&'static str, u64>,
+    /// Bytes received and sent by clients that are gone.
+    departed_bytes: (u64, u64),
+    /// Bytes received and sent by the clients connected now.
+    connected_bytes: (u64, u64),
+    /// Ticks that took at most the matching [`TICK_BUCKETS`] bound, the
+    /// last one counting the slower ticks.
+    tick_buckets: [u64; TICK_BUCKETS.len() + 1],
+    tick_seconds: f64,
+}
+
+impl Metrics {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count_command(&mut self, command: &'static str, dir: Direction) {
+        self.count_commands(command, dir, 1);
+    }
+
+    pub fn count_commands(
+        &mut self,
+        command: &'static str,
+        dir: Direction,
+        count: u64,
+    ) {
+        *self.commands.entry((command, dir)).or_default() += count;
+    }
+
+    /// Counts a message `user_id` sent to the channel called `room`.
+    pub fn record_message(&mut self, user_id: UserId, room: &str) {
+        push_message(&mut self.recent_messages, user_id);
+        self.messages += 1;
+        let room = self.room(room);
+        push_message(&mut room.recent_messages, user_id);
+        room.messages += 1;
+    }
+
+    pub fn set_users(&mut self, users: usize) {
+        self.users = users;
+        self.peak_users = self.peak_users.max(users);
+    }
+
+    /// Sets the number of users in the channel called `room`.
+    pub fn set_room_users(&mut self, room: &str, users: usize) {
+        self.room(room).set_users(users);
+    }
+
+    /// Stops reporting the channel called `room`, e.g. once it was
+    /// archived.
+    pub fn remove_room(&mut self, room: &str) {
+        self.rooms.remove(room);
+    }
+
+    fn room(&mut self, name: &str) -> &mut RoomMetrics {
+        self.rooms.entry(name.to_owned()).or_default()
+    }
+
+    /// Describes the activity of every room, one line each, for the
+    /// operators asking with [`ClientCommand::RoomStats`].
+    ///
+    /// [`ClientCommand::RoomStats`]: common::commands::ClientCommand
+    #[must_use]
+    pub fn room_stats(&self) -> String {
+        let mut stats = vec![];
+        for (name, room) in &self.rooms {
+            let mut line = format!(
+                "#{name}: {} messages, {} users now, {} at most",
+                room.messages, room.users, room.peak_users
+            );
+            for (window, duration) in ACTIVITY_WINDOWS {
+                let (messages, senders) =
+                    window_activity(&room.recent_messages, duration);
+                let peak = room.peak_users(duration);
+                line.push_str(&format!(
+                    "; {window}: {messages} messages from {senders} users, \
+                     {peak} at most"
+                ));
+            }
+            stats.push(line);
+        }
+        if stats.is_empty() {
+            return "No rooms yet".to_owned();
+        }
+        stats.join("\n")
+    }
+
+    pub fn count_eviction(&mut self) {
+        self.evictions += 1;
+    }
+
+    /// Counts a client disconnected because of `reason`, keeping the bytes
+    /// it transferred in the totals.
+    pub fn count_disconnect(
+        &mut self,
+        reason: &'static str,
+        stats: &ConnectionStats,
+    ) {
+        *self.disconnects.entry(reason).or_default() += 1;
+        self.departed_bytes.0 += stats.bytes_received;
+        self.departed_bytes.1 += stats.bytes_sent;
+    }
+
+    /// Sets the bytes received and sent by the clients still connected.
+    pub fn set_connected_bytes(&mut self, received: u64, sent: u64) {
+        self.connected_bytes = (received, sent);
+    }
+
+    pub fn record_tick(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = TICK_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(TICK_BUCKETS.len());
+        self.tick_buckets[bucket] += 1;
+        self.tick_seconds += secs;
+    }
+
+    /// Counts a connection accepted (or refused for being over the limit)
+    /// by the listener called `listener`.
+    pub fn count_connection(&mut self, listener: &str, accepted: bool) {
+        let metrics = self.listener(listener);
+        if accepted {
+            metrics.accepted += 1;
+        } else {
+            metrics.refused += 1;
+        }
+    }
+
+    /// Sets the number of clients connected through `listener` and the
+    /// bytes queued for them.
+    pub fn set_connections(
+        &mut self,
+        listener: &str,
+        connections: usize,
+        queued_bytes: u64,
+    ) {
+        let metrics = self.listener(listener);
+        metrics.connections = connections;
+        metrics.queued_bytes = queued_bytes;
+    }
+
+    fn listener(&mut self, name: &str) -> &mut ListenerMetrics {
+        self.listeners.entry(name.to_owned()).or_default()
+    }
+
+    fn activity(&self, window: Duration) -> (usize, usize) {
+        window_activity(&self.recent_messages, window)
+    }
+
+    /// Writes the metrics to `path`, replacing it atomically so a scraper
+    /// (e.g. the node exporter textfile collector) never sees a partial
+    /// file.
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_string())?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// The largest of the [`ACTIVITY_WINDOWS`].
+fn max_window() -> Duration {
+    ACTIVITY_WINDOWS[ACTIVITY_WINDOWS.len() - 1].1
+}
+
+/// Adds a message of `user_id` sent now to `recent`, forgetting those
+/// older than the largest window.
+fn push_message(recent: &mut VecDeque<(Instant, UserId)>, user_id: UserId) {
+    let now = Instant::now();
+    while recent.front().is_some_and(|(t, _)| now - *t > max_window()) {
+        recent.pop_front();
+    }
+    recent.push_back((now, user_id));
+}
+
+/// Returns the number of messages in `recent` and of distinct senders in
+/// the last `window`.
+fn window_activity(
+    recent: &VecDeque<(Instant, UserId)>,
+    window: Duration,
+) -> (usize, usize) {
+    let now = Instant::now();
+    let recent = recent.iter().rev().take_while(|(t, _)| now - *t <= window);
+    let mut count = 0;
+    let mut senders = HashSet::new();
+    for (_, user_id) in recent {
+        count += 1;
+        senders.insert(user_id);
+    }
+    (count, senders.len())
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "# HELP tcpchat_commands_total Protocol commands processed."
+        )?;
+        writeln!(f, "# TYPE tcpchat_commands_total counter")?;
+        for ((command, direction), count) in &self.commands {
+            writeln!(
+                f,
+                "tcpchat_commands_total{{command=\"{command}\",\
+                 direction=\"{direction}\"}} {count}"
+            )?;
+        }
+        writeln!(f, "# HELP tcpchat_messages_total Chat messages sent.")?;
+        writeln!(f, "# TYPE tcpchat_messages_total counter")?;
+        writeln!(f, "tcpchat_messages_total {}", self.messages)?;
+        writeln!(
+            f,
+            "# HELP tcpchat_messages_per_second Chat messages sent per second \
+             over the last minute."
+        )?;
+        writeln!(f, "# TYPE tcpchat_messages_per_second gauge")?;
+        let (last_minute, _) = self.activity(ACTIVITY_WINDOWS[0].1);
+        #[allow(clippy::cast_precision_loss)]
+        let per_second =
+            last_minute as f64 / ACTIVITY_WINDOWS[0].1.as_secs_f64();
+        writeln!(f, "tcpchat_messages_per_second {per_second}")?;
+        writeln!(f, "# HELP tcpchat_users Users currently connected.")?;
+        writeln!(f, "# TYPE tcpchat_users gauge")?;
+        writeln!(f, "tcpchat_users {}", self.users)?;
+        writeln!(
+            f,
+            "# HELP tcpchat_users_peak Most users connected at the same time."
+        )?;
+        writeln!(f, "# TYPE tcpchat_users_peak gauge")?;
+        writeln!(f, "tcpchat_users_peak {}", self.peak_users)?;
+        writeln!(
+            f,
+            "# HELP tcpchat_evictions_total Clients disconnected for not \
+             reading fast enough."
+        )?;
+        writeln!(f, "# TYPE tcpchat_evictions_total counter")?;
+        writeln!(f, "tcpchat_evictions_total {}", self.evictions)?;
+        writeln!(
+            f,
+            "# HELP tcpchat_disconnects_total Clients disconnected, by reason."
+        )?;
+        writeln!(f, "# TYPE tcpchat_disconnects_total counter")?;
+        for (reason, count) in &self.disconnects {
+            writeln!(
+                f,
+                "tcpchat_disconnects_total{{reason=\"{reason}\"}} {count}"
+            )?;
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_bytes_total Bytes transferred with clients."
+        )?;
+        writeln!(f, "# TYPE tcpchat_bytes_total counter")?;
+        for (direction, bytes) in [
+            (
+                Direction::Received,
+                self.departed_bytes.0 + self.connected_bytes.0,
+            ),
+            (
+                Direction::Sent,
+                self.departed_bytes.1 + self.connected_bytes.1,
+            ),
+        ] {
+            writeln!(
+                f,
+                "tcpchat_bytes_total{{direction=\"{direction}\"}} {bytes}"
+            )?;
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_tick_duration_seconds Time taken by server ticks."
+        )?;
+        writeln!(f, "# TYPE tcpchat_tick_duration_seconds histogram")?;
+        let mut ticks = 0;
+        for (bound, count) in TICK_BUCKETS.iter().zip(&self.tick_buckets) {
+            ticks += count;
+            writeln!(
+                f,
+                "tcpchat_tick_duration_seconds_bucket{{le=\"{bound}\"}} \
+                 {ticks}"
+            )?;
+        }
+        ticks += self.tick_buckets[TICK_BUCKETS.len()];
+        writeln!(
+            f,
+            "tcpchat_tick_duration_seconds_bucket{{le=\"+Inf\"}} {ticks}"
+        )?;
+        writeln!(f, "tcpchat_tick_duration_seconds_sum {}", self.tick_seconds)?;
+        writeln!(f, "tcpchat_tick_duration_seconds_count {ticks}")?;
+        writeln!(
+            f,
+            "# HELP tcpchat_connections Clients connected through a listener."
+        )?;
+        writeln!(f, "# TYPE tcpchat_connections gauge")?;
+        for (listener, metrics) in &self.listeners {
+            writeln!(
+                f,
+                "tcpchat_connections{{listener=\"{listener}\"}} {}",
+                metrics.connections
+            )?;
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_queued_bytes Bytes waiting for slow clients of a \
+             listener."
+        )?;
+        writeln!(f, "# TYPE tcpchat_queued_bytes gauge")?;
+        for (listener, metrics) in &self.listeners {
+            writeln!(
+                f,
+                "tcpchat_queued_bytes{{listener=\"{listener}\"}} {}",
+                metrics.queued_bytes
+            )?;
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_connections_total Connections accepted or refused \
+             by a listener."
+        )?;
+        writeln!(f, "# TYPE tcpchat_connections_total counter")?;
+        for (listener, metrics) in &self.listeners {
+            for (result, count) in
+                [("accepted", metrics.accepted), ("refused", metrics.refused)]
+            {
+                writeln!(
+                    f,
+                    "tcpchat_connections_total{{listener=\"{listener}\",\
+                     result=\"{result}\"}} {count}"
+                )?;
+            }
+        }
+        let activity =
+            ACTIVITY_WINDOWS.map(|(name, d)| (name, self.activity(d)));
+        writeln!(
+            f,
+            "# HELP tcpchat_window_messages Chat messages sent in the window."
+        )?;
+        writeln!(f, "# TYPE tcpchat_window_messages gauge")?;
+        for (window, (messages, _)) in activity {
+            writeln!(
+                f,
+                "tcpchat_window_messages{{window=\"{window}\"}} {messages}"
+            )?;
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_window_active_users Users who sent a message in \
+             the window."
+        )?;
+        writeln!(f, "# TYPE tcpchat_window_active_users gauge")?;
+        for (window, (_, senders)) in activity {
+            writeln!(
+                f,
+                "tcpchat_window_active_users{{window=\"{window}\"}} {senders}"
+            )?;
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_room_messages_total Chat messages sent to a room."
+        )?;
+        writeln!(f, "# TYPE tcpchat_room_messages_total counter")?;
+        for (room, metrics) in &self.rooms {
+            writeln!(
+                f,
+                "tcpchat_room_messages_total{{room=\"{room}\"}} {}",
+                metrics.messages
+            )?;
+        }
+        writeln!(f, "# HELP tcpchat_room_users Users currently in a room.")?;
+        writeln!(f, "# TYPE tcpchat_room_users gauge")?;
+        for (room, metrics) in &self.rooms {
+            writeln!(
+                f,
+                "tcpchat_room_users{{room=\"{room}\"}} {}",
+                metrics.users
+            )?;
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_room_window_messages Chat messages sent to a room \
+             in the window."
+        )?;
+        writeln!(f, "# TYPE tcpchat_room_window_messages gauge")?;
+        for (room, metrics) in &self.rooms {
+            for (window, duration) in ACTIVITY_WINDOWS {
+                let (messages, _) =
+                    window_activity(&metrics.recent_messages, duration);
+                writeln!(
+                    f,
+                    "tcpchat_room_window_messages{{room=\"{room}\",\
+                     window=\"{window}\"}} {messages}"
+                )?;
+            }
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_room_window_active_users Users who sent a message \
+             to a room in the window."
+        )?;
+        writeln!(f, "# TYPE tcpchat_room_window_active_users gauge")?;
+        for (room, metrics) in &self.rooms {
+            for (window, duration) in ACTIVITY_WINDOWS {
+                let (_, senders) =
+                    window_activity(&metrics.recent_messages, duration);
+                writeln!(
+                    f,
+                    "tcpchat_room_window_active_users{{room=\"{room}\",\
+                     window=\"{window}\"}} {senders}"
+                )?;
+            }
+        }
+        writeln!(
+            f,
+            "# HELP tcpchat_room_window_users_peak Most users in a room at the \
+             same time in the window."
+        )?;
+        writeln!(f, "# TYPE tcpchat_room_window_users_peak gauge")?;
+        for (room, metrics) in &self.rooms {
+            for (window, duration) in ACTIVITY_WINDOWS {
+                writeln!(
+                    f,
+                    "tcpchat_room_window_users_peak{{room=\"{room}\",\
+                     window=\"{window}\"}} {}",
+                    metrics.peak_users(duration)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves the latest [`Metrics`] snapshot over HTTP for Prometheus to
+/// scrape, from a thread of its own so a slow scraper can't stall the
+/// server.
+#[derive(Debug)]
+pub struct MetricsEndpoint {
+    snapshot: Arc<Mutex<String>>,
+}
+
+impl MetricsEndpoint {
+    /// Starts answering `GET /metrics` on `addr`.
+    pub fn serve(addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Serving metrics on http://{addr}/metrics");
+        let snapshot = Arc::new(Mutex::new(String::new()));
+        let shared = Arc::clone(&snapshot);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| {
+                    let snapshot = shared
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .clone();
+                    respond(stream, &snapshot)
+                });
+                if let Err(e) = result {
+                    warn!("Failed to serve metrics: {e}");
+                }
+            }
+        });
+        Ok(Self { snapshot })
+    }
+
+    /// Replaces what scrapers get with the current `metrics`.
+    pub fn update(&self, metrics: &Metrics) {
+        *self.snapshot.lock().unwrap_or_else(PoisonError::into_inner) =
+            metrics.to_string();
+    }
+}
+
+/// Answers one HTTP request, closing the connection afterwards.
+fn respond(stream: TcpStream, snapshot: &str) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers don't matter, but are read so the client sees no reset
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", snapshot),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n"),
+        _ => ("405 Method Not Allowed", "Method not allowed\n"),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
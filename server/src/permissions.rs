@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use common::commands::Role;
+
+/// Actions that are restricted to some roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    SetRole,
+    Invite,
+    Kick,
+    Ban,
+    Topic,
+    Stats,
+}
+
+impl Permission {
+    pub const ALL: [Self; 6] = [
+        Self::SetRole,
+        Self::Invite,
+        Self::Kick,
+        Self::Ban,
+        Self::Topic,
+        Self::Stats,
+    ];
+
+    const fn default_role(self) -> Role {
+        match self {
+            Self::SetRole | Self::Invite | Self::Stats => Role::Admin,
+            Self::Kick | Self::Ban | Self::Topic => Role::Moderator,
+        }
+    }
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::SetRole => "set_role",
+            Self::Invite => "invite",
+            Self::Kick => "kick",
+            Self::Ban => "ban",
+            Self::Topic => "topic",
+            Self::Stats => "stats",
+        })
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|p| p.to_string() == s)
+            .ok_or_else(|| format!("unknown permission `{s}`"))
+    }
+}
+
+/// The minimum role required for each permission.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    overrides: HashMap<Permission, Role>,
+}
+
+impl Permissions {
+    #[must_use]
+    pub fn required(&self, permission: Permission) -> Role {
+        self.overrides
+            .get(&permission)
+            .copied()
+            .unwrap_or_else(|| permission.default_role())
+    }
+
+    #[must_use]
+    pub fn allows(&self, role: Role, permission: Permission) -> bool {
+        role >= self.required(permission)
+    }
+
+    pub fn set(&mut self, permission: Permission, role: Role) {
+        self.overrides.insert(permission, role);
+    }
+}
+
+/// Parses a `<permission>=<role>` pair, as given on the command line.
+pub fn parse_requirement(s: &str) -> Result<(Permission, Role), String> {
+    let (permission, role) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<permission>=<role>`, got `{s}`"))?;
+    Ok((permission.parse()?, role.parse()?))
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use common::commands::ClientCommand;
+use common::Codec;
+
+/// A burst may use up this many seconds worth of a rate at once.
+const BURST_SECS: f64 = 5.0;
+/// Commands over the limits in a row before the client is muted.
+const MUTE_STRIKES: u32 = 5;
+/// Commands over the limits in a row before the client is disconnected.
+const DISCONNECT_STRIKES: u32 = 20;
+/// Time without going over the limits after which strikes are forgotten.
+const STRIKE_MEMORY: Duration = Duration::from_secs(10);
+/// Logins, registrations and connects per second from one connection,
+/// whatever the [`RateLimits`], since each may hash a password on the
+/// server loop.
+const LOGINS_PER_SEC: f64 = 0.5;
+/// The same from all the connections of an address together.
+const ADDRESS_LOGINS_PER_SEC: f64 = 2.0;
+/// Addresses a [`LoginLimiter`] tracks before forgetting the ones that
+/// are back to their full allowance.
+const MAX_TRACKED_ADDRESSES: usize = 4096;
+
+/// How fast a client may send commands. Limiting is disabled when neither
+/// rate is set, except for logins, see [`is_login`].
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    /// Chat messages, whispers and forwards per second.
+    pub messages_per_sec: Option<f64>,
+    /// Bytes of commands per second.
+    pub bytes_per_sec: Option<f64>,
+    /// How long a client that keeps going over the limits can't send
+    /// messages, never muted if zero.
+    pub mute: Duration,
+}
+
+/// What to do with a command, see [`RateLimiter::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Drop the command and warn the client.
+    Throttled,
+    /// Drop the command, the client is muted for this much longer.
+    Muted(Duration),
+    /// The client kept flooding, disconnect it.
+    Disconnect,
+}
+
+/// Refills at `rate` per second, up to [`BURST_SECS`] worth.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: Self::capacity(rate),
+            last: Instant::now(),
+        }
+    }
+
+    fn capacity(rate: f64) -> f64 {
+        (rate * BURST_SECS).max(1.0)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = (now - self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(Self::capacity(self.rate));
+        self.last = now;
+    }
+
+    fn full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= Self::capacity(self.rate)
+    }
+
+    fn take(&mut self, amount: f64) -> bool {
+        self.refill();
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// Keeps a client to its [`RateLimits`], muting and then disconnecting it
+/// if it doesn't slow down.
+#[derive(Debug)]
+pub struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    logins: TokenBucket,
+    mute: Duration,
+    /// Commands over the limits since the client last behaved.
+    strikes: u32,
+    last_strike: Instant,
+    muted_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(limits: &RateLimits) -> Self {
+        Self {
+            messages: limits.messages_per_sec.map(TokenBucket::new),
+            bytes: limits.bytes_per_sec.map(TokenBucket::new),
+            logins: TokenBucket::new(LOGINS_PER_SEC),
+            mute: limits.mute,
+            strikes: 0,
+            last_strike: Instant::now(),
+            muted_until: None,
+        }
+    }
+
+    /// Charges `command` to the limits and decides whether it goes
+    /// through. Pongs always do, so that throttled clients aren't also
+    /// taken for gone.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn check(&mut self, command: &ClientCommand) -> Verdict {
+        if matches!(command, ClientCommand::Pong { .. }) {
+            return Verdict::Allow;
+        }
+        let now = Instant::now();
+        if now - self.last_strike > STRIKE_MEMORY {
+            self.strikes = 0;
+        }
+        let is_message = matches!(
+            command,
+            ClientCommand::Message { .. }
+                | ClientCommand::Whisper { .. }
+                | ClientCommand::Forward { .. }
+        );
+        // files are bounded by the offer the receiver accepted instead
+        let is_chunk = matches!(command, ClientCommand::FileChunk { .. });
+        let is_login = is_login(command);
+        let within = (is_chunk
+            || self
+                .bytes
+                .as_mut()
+                .is_none_or(|b| b.take(command.coded_size() as f64)))
+            && (!is_message
+                || self.messages.as_mut().is_none_or(|b| b.take(1.0)))
+            && (!is_login || self.logins.take(1.0));
+        let muted_for = self
+            .muted_until
+            .filter(|&until| is_message && until > now)
+            .map(|until| until - now);
+        if within && muted_for.is_none() {
+            return Verdict::Allow;
+        }
+        if !within {
+            self.strikes += 1;
+            self.last_strike = now;
+        }
+        if self.strikes >= DISCONNECT_STRIKES {
+            return Verdict::Disconnect;
+        }
+        if let Some(left) = muted_for {
+            return Verdict::Muted(left);
+        }
+        if self.strikes >= MUTE_STRIKES && !self.mute.is_zero() {
+            self.muted_until = Some(now + self.mute);
+            return Verdict::Muted(self.mute);
+        }
+        Verdict::Throttled
+    }
+}
+
+/// Whether `command` is an attempt to log in, register or connect, which
+/// are limited to a few per second even if nothing else is.
+#[must_use]
+pub const fn is_login(command: &ClientCommand) -> bool {
+    matches!(
+        command,
+        ClientCommand::Login { .. }
+            | ClientCommand::Register { .. }
+            | ClientCommand::Connect { .. }
+    )
+}
+
+/// Limits the logins from each address, across all its connections, so
+/// opening more of them doesn't help guessing passwords.
+#[derive(Debug, Default)]
+pub struct LoginLimiter {
+    addresses: HashMap<IpAddr, TokenBucket>,
+}
+
+impl LoginLimiter {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Charges a login from `ip`, returning whether it is allowed.
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        if self.addresses.len() >= MAX_TRACKED_ADDRESSES {
+            self.addresses.retain(|_, bucket| !bucket.full());
+        }
+        self.addresses
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(ADDRESS_LOGINS_PER_SEC))
+            .take(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login() -> ClientCommand {
+        ClientCommand::Login {
+            name: "alice".to_owned(),
+            password: "guess".to_owned(),
+        }
+    }
+
+    #[test]
+    fn logins_are_limited_without_any_limits_set() {
+        let mut limiter = RateLimiter::new(&RateLimits::default());
+        let allowed = (0..10)
+            .take_while(|_| limiter.check(&login()) == Verdict::Allow)
+            .count();
+        assert!((1..10).contains(&allowed), "{allowed} logins allowed");
+        let message = ClientCommand::Message {
+            message: "hi".to_owned(),
+            content_type: common::commands::ContentType::Plain,
+            quote: None,
+        };
+        assert_eq!(limiter.check(&message), Verdict::Allow);
+    }
+
+    #[test]
+    fn logins_are_limited_per_address() {
+        let mut limiter = LoginLimiter::new();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let allowed = (0..100).take_while(|_| limiter.check(ip)).count();
+        assert!((1..100).contains(&allowed), "{allowed} logins allowed");
+        assert!(limiter.check(IpAddr::from([192, 0, 2, 2])));
+    }
+}
@@ -1,119 +1,531 @@
-use std::io::{ErrorKind, Result};
-use std::net::{TcpListener, ToSocketAddrs};
-use std::time::Instant;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpStream};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{panic, thread};
 
-use log::{info, trace};
+use log::{error, info, trace, warn};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use ring::digest::{digest, SHA256};
 
-use crate::Client;
-use common::commands::{ClientCommand, ServerCommand};
+use crate::storage::{
+    Account, Ban, ChannelRecord, OfflineWhisper, Store, UserRecord,
+};
+use crate::{
+    check_invite, create_invite, is_login, redeem_invite, revoke_invite,
+    ArchiveRecord, Archiver, AuthWorker, Bridge, Client, Config, Direction,
+    History, Hook, Hooks, Identity, InboundMessage, Irc, Listener,
+    ListenerConfig, LoadShedder, LoginLimiter, Metrics, PasswordHash,
+    Permission, Protocol, RemoteIds, Transfers, Verdict, Wake, WebSocket,
+    Webhooks,
+};
+use common::commands::{
+    ClientCommand, ContentType, MessageRecord, Quote, Role, ServerCommand,
+    UserInfo,
+};
+use common::{Bytes, ChannelId, Codec, MsgId, TransferId, Transport, UserId};
 
 #[derive(Debug)]
-struct IdGen {
-    id: u16,
+struct IdGen<T> {
+    id: T,
 }
 
-impl IdGen {
-    fn new() -> Self {
-        Self { id: 0 }
+impl<T: Copy + Into<u64> + TryFrom<u64>> IdGen<T> {
+    /// Continues after the ids that were already handed out.
+    fn starting_after(id: T) -> Self {
+        Self { id }
     }
 
-    fn get(&mut self) -> u16 {
-        self.id += 1;
-        self.id
+    /// The next id, `None` once they are all used up.
+    fn get(&mut self) -> Option<T> {
+        self.id = T::try_from(self.id.into() + 1).ok()?;
+        Some(self.id)
+    }
+}
+
+/// What a client asked for that needs its name checked first.
+#[derive(Debug)]
+enum PendingAuth {
+    Connect {
+        name: String,
+        invite: Option<String>,
+        credential: Option<String>,
+    },
+    Rename {
+        new_name: String,
+    },
+}
+
+/// What to do with a message once it passed through the hooks.
+#[derive(Debug)]
+enum Hooked {
+    /// Sent by the client with `user_id`.
+    Command {
+        user_id: UserId,
+        command: HookedCommand,
+    },
+    /// Posted by a bridge under `name`.
+    Bridged {
+        user_id: UserId,
+        name: String,
+        channel_id: ChannelId,
+    },
+}
+
+/// A command of a client that waits for the hooks.
+#[derive(Debug)]
+enum HookedCommand {
+    Message {
+        channel_id: ChannelId,
+        content_type: ContentType,
+        quote: Option<Quote>,
+    },
+    /// The hooks get the text of the quote.
+    Forward {
+        channel_id: ChannelId,
+        quote: Quote,
+    },
+    Whisper {
+        target_user_id: UserId,
+    },
+    OfflineWhisper {
+        target_user_id: UserId,
+        name: String,
+    },
+}
+
+impl Hooked {
+    const fn user_id(&self) -> UserId {
+        match self {
+            Self::Command { user_id, .. } | Self::Bridged { user_id, .. } => {
+                *user_id
+            }
+        }
+    }
+}
+
+impl HookedCommand {
+    const fn name(&self) -> &'static str {
+        match self {
+            Self::Message { .. } => "message",
+            Self::Forward { .. } => "forward",
+            Self::Whisper { .. } => "whisper",
+            Self::OfflineWhisper { .. } => "offline_whisper",
+        }
+    }
+}
+
+impl PendingAuth {
+    /// The name to check.
+    fn name(&self) -> &str {
+        match self {
+            Self::Connect { name, .. } => name,
+            Self::Rename { new_name } => new_name,
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct Server {
-    listener: TcpListener,
+    listeners: Vec<Listener>,
     clients: Vec<Client>,
-    message_queue: Vec<ServerCommand>,
-    pub inactivity: u64,
-    user_id_gen: IdGen,
-    msg_id_gen: IdGen,
+    /// Commands to send at the end of the tick, to the clients in the
+    /// channel or to everyone if it's `None`.
+    message_queue: Vec<(Option<ChannelId>, ServerCommand)>,
+    /// Readiness of the listeners and the client sockets, see
+    /// [`wait`](Self::wait).
+    poll: Poll,
+    events: Events,
+    /// Set when a tick left work that no socket will report, so the next
+    /// [`wait`](Self::wait) returns right away.
+    busy: bool,
+    user_id_gen: IdGen<u16>,
+    msg_id_gen: IdGen<u32>,
+    channel_id_gen: IdGen<u16>,
+    /// Every channel ever joined and not archived, except the lobby.
+    channels: HashMap<String, ChannelId>,
+    /// Channels nobody used for [`Config::archive_rooms_after`], which
+    /// joining brings back with their id and history.
+    archived_channels: HashMap<String, ChannelId>,
+    /// When each channel was last joined, posted to or seen with someone
+    /// in it.
+    channel_activity: HashMap<ChannelId, Instant>,
+    /// When idle channels were last looked for.
+    last_archive_check: Instant,
+    /// Topics of the channels that have one, the lobby included.
+    topics: HashMap<ChannelId, String>,
+    history: History,
+    metrics: Metrics,
+    config: Config,
+    transfers: Transfers,
+    store: Box<dyn Store>,
+    load: LoadShedder,
+    archivers: Vec<Archiver>,
+    auth: AuthWorker,
+    /// What the clients wait on the auth thread for, by ticket.
+    pending_auth: HashMap<u64, PendingAuth>,
+    last_auth_ticket: u64,
+    hooks: Hooks,
+    /// Messages waiting for the hooks, by ticket.
+    pending_hooks: HashMap<u64, Hooked>,
+    last_hook_ticket: u64,
+    bridge: Option<Bridge>,
+    /// Ids of the users and messages of other instances of the bridge.
+    remote_ids: RemoteIds,
+    webhooks: Webhooks,
+    /// How far each user read each channel, kept for accounts to find
+    /// again when they come back.
+    read_markers: HashMap<(UserId, ChannelId), MsgId>,
+    /// Ids of the users that only post through the webhooks, by name.
+    bridged_users: HashMap<String, UserId>,
+    /// Logins from each address, across its connections.
+    login_limiter: LoginLimiter,
+    /// Client polled first in the current tick.
+    poll_offset: usize,
+    /// When the server started, for the uptime in
+    /// [`ServerCommand::ServerInfo`].
+    started: Instant,
+    /// Accepted connections are encrypted with this, if set.
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<common::tls::ServerConfig>>,
 }
 
+/// Id of the clients until they connect or log in, which no user gets.
+const NO_USER: UserId = UserId(0);
+/// Number of past messages kept for searching, unless more are replayed.
+const HISTORY_SIZE: usize = 1000;
+/// Upper bound on the number of search results sent in one reply.
+const MAX_SEARCH_RESULTS: u16 = 100;
+/// Upper bound on the number of messages sent in one history reply.
+const MAX_HISTORY_CHUNK: u16 = 100;
+/// Largest coded size of the messages in one history reply, or the users
+/// in one user list or listing, leaving room for the frame header.
+const MAX_HISTORY_BYTES: usize = u16::MAX as usize - 64;
+/// Most commands taken from one client in a tick.
+const COMMAND_BUDGET: usize = 4;
+/// Longest accepted user name, in bytes.
+const MAX_NAME_LEN: usize = 32;
+/// Longest accepted chat message, in bytes.
+const MAX_MESSAGE_LEN: usize = 4000;
+/// Most messages of one user waiting for the hooks at once.
+const MAX_HOOKED_PER_USER: usize = 16;
+/// Most whispers kept for an offline account, until it connects.
+const MAX_OFFLINE_WHISPERS: usize = 100;
+/// Bytes the authentication tag adds to an encrypted whisper.
+const AEAD_TAG_LEN: usize = 16;
+/// Size of the public keys of end-to-end encrypted conversations.
+const X25519_KEY_LEN: usize = 32;
+/// Longest accepted name of an offered file, in bytes.
+const MAX_FILE_NAME_LEN: usize = 255;
+/// Characters of a message kept when it is quoted in a reply.
+const QUOTE_SNIPPET_LEN: usize = 100;
+/// Longest emoji accepted in a reaction, in bytes, enough for the ones
+/// made of several characters joined together.
+const MAX_EMOJI_LEN: usize = 32;
+/// Most different emojis a message can be reacted with.
+const MAX_REACTIONS: usize = 20;
+/// Longest channel topic accepted, in bytes.
+const MAX_TOPIC_LEN: usize = 300;
+/// How often channels are checked for being idle long enough to archive.
+const ARCHIVE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Fewest clients worth giving their own I/O thread.
+const MIN_CLIENTS_PER_THREAD: usize = 256;
+/// Longest wait for the sockets, so checks that don't depend on them (like
+/// recovering from load shedding) still happen.
+const MAX_WAIT: Duration = Duration::from_secs(1);
+/// Tokens of the readiness events, which only tell that there is something
+/// to do, a tick goes through everything anyway.
+const LISTENER: Token = Token(0);
+const CLIENT: Token = Token(1);
+const WAKER: Token = Token(2);
+
 impl Server {
-    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let listener = TcpListener::bind(addr)?;
-        listener.set_nonblocking(true)?;
+    pub fn new(
+        listeners: Vec<ListenerConfig>,
+        config: Config,
+        store: Box<dyn Store>,
+    ) -> Result<Self> {
+        let listeners = listeners
+            .into_iter()
+            .map(Listener::bind)
+            .collect::<Result<Vec<_>>>()?;
+        let poll = Poll::new()?;
+        for listener in &listeners {
+            poll.registry().register(
+                &mut SourceFd(&listener.as_raw_fd()),
+                LISTENER,
+                Interest::READABLE,
+            )?;
+        }
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let history_size = HISTORY_SIZE.max(config.history_size);
+        let mut history = History::new(history_size);
+        let mut last_msg_id = MsgId(0);
+        let mut last_channel_id = ChannelId::LOBBY;
+        for message in store.load_history(history_size)? {
+            if let ServerCommand::Message {
+                msg_id, channel_id, ..
+            } = message
+            {
+                last_msg_id = last_msg_id.max(msg_id);
+                last_channel_id = last_channel_id.max(channel_id);
+            }
+            history.push(message);
+        }
+        let records = store.list_channels()?;
+        let topics = records
+            .iter()
+            .filter(|c| !c.topic.is_empty())
+            .map(|c| (c.channel_id, c.topic.clone()))
+            .collect();
+        // the lobby only has a record to keep its topic
+        let (archived, live): (Vec<_>, Vec<_>) = records
+            .into_iter()
+            .filter(|c| c.name != ChannelId::LOBBY_NAME)
+            .partition(|c| c.archived);
+        let channels: HashMap<_, _> =
+            live.into_iter().map(|c| (c.name, c.channel_id)).collect();
+        let archived_channels: HashMap<_, _> =
+            archived.into_iter().map(|c| (c.name, c.channel_id)).collect();
+        // archived channels get their ids back when they are restored
+        if let Some(&id) =
+            channels.values().chain(archived_channels.values()).max()
+        {
+            last_channel_id = last_channel_id.max(id);
+        }
+        let now = Instant::now();
+        let channel_activity =
+            channels.values().map(|&id| (id, now)).collect();
+        // accounts keep their ids, new sessions must not get them
+        let last_user_id = store
+            .list_accounts()?
+            .iter()
+            .map(|a| a.user_id)
+            .max()
+            .unwrap_or(UserId(0));
+        let load = LoadShedder::new(config.load_limits.clone());
+        let archivers = config
+            .archive
+            .iter()
+            .map(|sink| Archiver::open(sink, &config.archive_dead_letter))
+            .collect::<Result<_>>()?;
+        let wake: Wake = Arc::new(move || {
+            // only fails if the poll is gone, then no one is waiting
+            let _ = waker.wake();
+        });
+        let auth = AuthWorker::spawn(config.auth.open()?, Arc::clone(&wake));
+        let mut hooks = Hooks::spawn(Arc::clone(&wake));
+        for hook in &config.hooks {
+            for hook in hook.open()? {
+                hooks.add(hook);
+            }
+        }
+        let bridge = config.bridge.open(Arc::clone(&wake))?;
+        let webhooks = Webhooks::open(&config.webhooks, wake)?;
+        #[cfg(feature = "tls")]
+        let tls = config
+            .tls
+            .as_ref()
+            .map(|tls| common::tls::server_config(&tls.cert, &tls.key))
+            .transpose()?;
+        #[cfg(not(feature = "tls"))]
+        if config.tls.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "the server was built without TLS support",
+            ));
+        }
         let this = Self {
-            listener,
+            listeners,
             clients: Vec::default(),
             message_queue: Vec::default(),
-            inactivity: 0,
-            user_id_gen: IdGen::new(),
-            msg_id_gen: IdGen::new(),
+            poll,
+            events: Events::with_capacity(1024),
+            busy: false,
+            user_id_gen: IdGen::starting_after(last_user_id.0),
+            msg_id_gen: IdGen::starting_after(last_msg_id.0),
+            // also skips ids only found in the history, in case the store
+            // lost the channel names
+            channel_id_gen: IdGen::starting_after(last_channel_id.0),
+            channels,
+            archived_channels,
+            channel_activity,
+            last_archive_check: now,
+            topics,
+            history,
+            metrics: Metrics::new(),
+            config,
+            transfers: Transfers::new(),
+            load,
+            archivers,
+            auth,
+            pending_auth: HashMap::new(),
+            last_auth_ticket: 0,
+            hooks,
+            pending_hooks: HashMap::new(),
+            last_hook_ticket: 0,
+            bridge,
+            remote_ids: RemoteIds::default(),
+            webhooks,
+            read_markers: HashMap::new(),
+            bridged_users: HashMap::new(),
+            login_limiter: LoginLimiter::new(),
+            store,
+            poll_offset: 0,
+            started: Instant::now(),
+            #[cfg(feature = "tls")]
+            tls,
         };
-        info!(
-            "Server started with address {}",
-            this.listener.local_addr()?
-        );
+        for listener in &this.listeners {
+            info!(
+                "Listening on {} ({})",
+                listener.local_addr()?,
+                listener.config.name
+            );
+        }
         Ok(this)
     }
 
+    /// Adds a plugin, called after the ones already there.
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.add(hook);
+    }
+
+    /// Blocks until a socket is ready, something arrives from the bridge,
+    /// a client is due a ping or `timeout` passes, then the next
+    /// [`update`](Self::update) has something to do.
+    pub fn wait(&mut self, timeout: Duration) -> Result<()> {
+        let mut timeout = timeout.min(MAX_WAIT);
+        if let Some(interval) = self.config.ping_interval {
+            for client in &self.clients {
+                timeout = timeout.min(client.until_ping(interval));
+            }
+        }
+        if self.busy {
+            timeout = Duration::ZERO;
+        }
+        trace!("Waiting for at most {}ms", timeout.as_millis());
+        match self.poll.poll(&mut self.events, Some(timeout)) {
+            // a signal, the caller checks whether to stop
+            Err(e) if e.kind() == ErrorKind::Interrupted => Ok(()),
+            result => result,
+        }
+    }
+
     pub fn update(&mut self) -> Result<()> {
         let tick_start = Instant::now();
-        self.inactivity += 1;
+        self.busy = false;
         trace!("Updating server");
 
         let listener_poll_start = Instant::now();
-        self.poll_listener()?;
+        self.poll_listeners()?;
         let listener_poll_elapsed = listener_poll_start.elapsed();
 
         let client_poll_start = Instant::now();
-        self.message_queue.extend(
-            self.clients
-                .iter_mut()
-                .filter_map(|c| c.poll().map(|cc| (c, cc)))
-                .filter_map(|(c, cc)| match cc {
-                    ClientCommand::Padding => None,
-                    ClientCommand::Connect { name } => {
-                        let user_id = c.user_id();
-                        Some(ServerCommand::AddUser { user_id, name })
-                    }
-                    ClientCommand::Message { message } => {
-                        Some(ServerCommand::Message {
-                            msg_id: self.msg_id_gen.get(),
-                            user_id: c.user_id(),
-                            message,
-                        })
-                    }
-                }),
-        );
+        for (index, command) in self.poll_clients() {
+            self.handle_command(index, command);
+        }
+        self.ping_clients();
+        self.finish_authentication();
+        self.finish_hooked();
+        self.exchange_bridged();
+        self.post_webhook_messages();
+        self.archive_idle_channels();
         let client_poll_elapsed = client_poll_start.elapsed();
 
         let message_send_start = Instant::now();
-        // if !self.message_queue.is_empty() {
-        for message in &self.message_queue {
-            self.inactivity = 0;
-            for client in &mut self.clients {
-                client.send(message);
-                client.flush();
-            }
-        }
-        self.message_queue.clear();
-        // }
+        let queue_depth = self.message_queue.len();
+        self.flush_broadcasts();
+        // replies and whatever a slow client couldn't take last time
+        in_parallel(&mut self.clients, self.config.io_threads, |clients| {
+            clients.iter_mut().for_each(Client::flush);
+        });
+        self.evict_slow_consumers();
         let message_send_elapsed = message_send_start.elapsed();
 
         let client_clear_start = Instant::now();
         let prev_clients_len = self.clients.len();
+        let mut departed = vec![];
         self.clients.retain(|c| {
             if c.connected() {
-                true
-            } else {
-                self.message_queue.push(ServerCommand::RemoveUser {
-                    user_id: c.user_id(),
-                });
-                false
+                return true;
+            }
+            if let Some(reason) = c.disconnect_reason() {
+                self.metrics.count_disconnect(reason, &c.stats());
+            }
+            departed.push(c.user_id());
+            if let Some(name) = c.name() {
+                self.hooks.on_disconnect(c.user_id(), name);
+                self.message_queue.push((
+                    None,
+                    ServerCommand::RemoveUser {
+                        user_id: c.user_id(),
+                    },
+                ));
             }
+            false
         });
+        for user_id in departed {
+            self.end_transfers(user_id);
+            if !self
+                .read_markers
+                .keys()
+                .any(|(reader, _)| *reader == user_id)
+            {
+                continue;
+            }
+            // only accounts come back with the same id
+            let accounts = self.store.list_accounts().unwrap_or_else(|e| {
+                warn!("Failed to load accounts: {e}");
+                vec![]
+            });
+            if !accounts.iter().any(|a| a.user_id == user_id) {
+                self.read_markers
+                    .retain(|(reader, _), _| *reader != user_id);
+            }
+        }
         if self.clients.len() != prev_clients_len {
-            self.inactivity = 0;
+            // the departures are broadcast in the next tick
+            self.busy = true;
+        }
+        self.metrics.set_users(
+            self.clients.iter().filter(|c| c.name().is_some()).count(),
+        );
+        let mut room_users = HashMap::<_, usize>::new();
+        for client in self.clients.iter().filter(|c| c.name().is_some()) {
+            *room_users.entry(client.channel()).or_default() += 1;
+        }
+        let rooms = self.channels.iter().map(|(name, &id)| (name.as_str(), id));
+        for (name, channel_id) in
+            rooms.chain([(ChannelId::LOBBY_NAME, ChannelId::LOBBY)])
+        {
+            let users = room_users.get(&channel_id).copied().unwrap_or(0);
+            self.metrics.set_room_users(name, users);
         }
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let clients = self.clients.iter().filter(|c| c.listener() == index);
+            self.metrics.set_connections(
+                &listener.config.name,
+                clients.clone().count(),
+                clients.map(|c| c.stats().bytes_queued).sum(),
+            );
+        }
+        let (received, sent) =
+            self.clients.iter().fold((0, 0), |(received, sent), c| {
+                let stats = c.stats();
+                (received + stats.bytes_received, sent + stats.bytes_sent)
+            });
+        self.metrics.set_connected_bytes(received, sent);
         let client_clear_elapsed = client_clear_start.elapsed();
 
         let tick_elapsed = tick_start.elapsed();
+        self.metrics.record_tick(tick_elapsed);
+        if self.load.record(tick_elapsed, queue_depth) {
+            self.disconnect_heaviest();
+        }
         log::log!(
             match tick_elapsed.as_micros() {
                 100_000.. => log::Level::Warn,
@@ -132,17 +544,3723 @@ impl Server {
         Ok(())
     }
 
-    fn poll_listener(&mut self) -> Result<bool> {
-        match self.listener.accept() {
-            Ok((stream, _)) => {
-                self.inactivity = 0;
-                self.clients
-                    .push(Client::new(stream, self.user_id_gen.get())?);
-                Ok(true)
+    /// Sends what is still queued, tells every client that the server is
+    /// stopping because of `reason` and disconnects them.
+    pub fn shutdown(&mut self, reason: &str) {
+        self.flush_broadcasts();
+        let command = ServerCommand::ServerShutdown {
+            reason: reason.to_owned(),
+        };
+        for client in &mut self.clients {
+            client.send(&command);
+            client.flush();
+            client.disconnect("shutdown", None);
+            self.metrics.count_command(command.name(), Direction::Sent);
+        }
+        self.clients.clear();
+    }
+
+    /// Addresses the listeners are bound to, in the order they were given,
+    /// with the ports picked for those given port 0.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(Listener::local_addr).collect()
+    }
+
+    #[must_use]
+    pub const fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Queues `command` for every client, to be sent at the end of the
+    /// tick.
+    pub fn broadcast_all(&mut self, command: ServerCommand) {
+        self.message_queue.push((None, command));
+    }
+
+    /// Queues `command` for the clients in `channel_id`, to be sent at the
+    /// end of the tick.
+    pub fn broadcast_channel(
+        &mut self,
+        channel_id: ChannelId,
+        command: ServerCommand,
+    ) {
+        self.message_queue.push((Some(channel_id), command));
+    }
+
+    /// Sends `command` to the user with `user_id`, returning whether they
+    /// are connected.
+    pub fn send_to(
+        &mut self,
+        user_id: UserId,
+        command: &ServerCommand,
+    ) -> bool {
+        let Some(index) = self
+            .clients
+            .iter()
+            .position(|c| c.user_id() == user_id && c.name().is_some())
+        else {
+            return false;
+        };
+        self.reply(index, command);
+        true
+    }
+
+    /// Sends `command` to the client at `index`, usually in response to
+    /// something it sent.
+    fn reply(&mut self, index: usize, command: &ServerCommand) {
+        self.clients[index].send(command);
+        self.metrics.count_command(command.name(), Direction::Sent);
+    }
+
+    fn flush_broadcasts(&mut self) {
+        let queue = &self.message_queue;
+        // clients each message was sent to, by chunk
+        let sent =
+            in_parallel(&mut self.clients, self.config.io_threads, |clients| {
+                let sent: Vec<u64> = queue
+                    .iter()
+                    .map(|(channel_id, message)| {
+                        let mut sent = 0;
+                        // clients without a name are not in any channel yet
+                        for client in clients.iter_mut() {
+                            if client.name().is_some()
+                                && channel_id
+                                    .is_none_or(|id| id == client.channel())
+                            {
+                                client.send(message);
+                                sent += 1;
+                            }
+                        }
+                        sent
+                    })
+                    .collect();
+                for client in clients {
+                    client.flush();
+                }
+                sent
+            });
+        for (i, (_, message)) in queue.iter().enumerate() {
+            let count = sent.iter().map(|chunk| chunk[i]).sum();
+            self.metrics
+                .count_commands(message.name(), Direction::Sent, count);
+        }
+        self.message_queue.clear();
+    }
+
+    /// Publishes the broadcasts queued in this tick to the other instances
+    /// and queues theirs, for the rooms that exist here.
+    fn exchange_bridged(&mut self) {
+        let Some(mut bridge) = self.bridge.take() else {
+            return;
+        };
+        for (channel_id, command) in &self.message_queue {
+            let room = channel_id.map(|id| self.channel_name(id));
+            bridge.publish(room, command);
+        }
+        while let Some((origin, room, mut command)) = bridge.poll() {
+            let channel_id = match room {
+                Some(room) => match self.find_channel(&room) {
+                    Some(id) => Some(id),
+                    None => continue,
+                },
+                None => None,
+            };
+            if let (
+                ServerCommand::Message {
+                    channel_id: command_channel_id,
+                    ..
+                }
+                | ServerCommand::TopicChanged {
+                    channel_id: command_channel_id,
+                    ..
+                }
+                | ServerCommand::ReactionUpdate {
+                    channel_id: command_channel_id,
+                    ..
+                },
+                Some(id),
+            ) = (&mut command, channel_id)
+            {
+                *command_channel_id = id;
+            }
+            if self.remap_bridged(&origin, &mut command).is_none() {
+                warn!(
+                    "Dropping a bridged {}, no ids are left for it",
+                    command.name()
+                );
+                continue;
+            }
+            self.message_queue.push((channel_id, command));
+        }
+        self.bridge = Some(bridge);
+    }
+
+    /// Gives the users and messages in a broadcast of the instance `origin`
+    /// the ids they have here, handing out new ones to those seen first.
+    fn remap_bridged(
+        &mut self,
+        origin: &str,
+        command: &mut ServerCommand,
+    ) -> Option<()> {
+        match command {
+            ServerCommand::RemoveUser { user_id } => {
+                let remote = *user_id;
+                *user_id = self.remote_user_id(origin, remote)?;
+                self.remote_ids.remove_user(origin, remote);
+            }
+            ServerCommand::AddUser { user_id, .. }
+            | ServerCommand::UserRenamed { user_id, .. }
+            | ServerCommand::RoleChanged { user_id, .. }
+            | ServerCommand::TopicChanged {
+                user_id: Some(user_id),
+                ..
+            } => *user_id = self.remote_user_id(origin, *user_id)?,
+            ServerCommand::Kicked { user_id, by, .. }
+            | ServerCommand::Banned { user_id, by, .. } => {
+                *user_id = self.remote_user_id(origin, *user_id)?;
+                *by = self.remote_user_id(origin, *by)?;
+            }
+            ServerCommand::Message {
+                msg_id,
+                user_id,
+                quote,
+                ..
+            } => {
+                *msg_id = self.remote_msg_id(origin, *msg_id)?;
+                *user_id = self.remote_user_id(origin, *user_id)?;
+                if let Some(quote) = quote {
+                    quote.msg_id = self.remote_msg_id(origin, quote.msg_id)?;
+                    quote.user_id =
+                        self.remote_user_id(origin, quote.user_id)?;
+                }
+            }
+            ServerCommand::ReactionUpdate {
+                msg_id, reactions, ..
+            } => {
+                *msg_id = self.remote_msg_id(origin, *msg_id)?;
+                for user_id in
+                    reactions.iter_mut().flat_map(|r| &mut r.user_ids)
+                {
+                    *user_id = self.remote_user_id(origin, *user_id)?;
+                }
+            }
+            _ => (),
+        }
+        Some(())
+    }
+
+    /// The id here of user `user_id` of the instance `origin`.
+    fn remote_user_id(
+        &mut self,
+        origin: &str,
+        user_id: UserId,
+    ) -> Option<UserId> {
+        if let Some(local) = self.remote_ids.user(origin, user_id) {
+            return Some(local);
+        }
+        let local = self.allocate_user_id()?;
+        self.remote_ids.add_user(origin, user_id, local);
+        Some(local)
+    }
+
+    /// The id here of message `msg_id` of the instance `origin`.
+    fn remote_msg_id(&mut self, origin: &str, msg_id: MsgId) -> Option<MsgId> {
+        if let Some(local) = self.remote_ids.message(origin, msg_id) {
+            return Some(local);
+        }
+        let local = self.msg_id_gen.get().map(MsgId)?;
+        self.remote_ids.add_message(origin, msg_id, local);
+        Some(local)
+    }
+
+    /// Pings the clients that are due, disconnecting those that missed too
+    /// many pings.
+    fn ping_clients(&mut self) {
+        let Some(interval) = self.config.ping_interval else {
+            return;
+        };
+        let max_missed = self.config.max_missed_pings;
+        for client in &mut self.clients {
+            if !client.ping_due(interval) {
+                continue;
+            }
+            if client.unanswered_pings() >= max_missed {
+                client.disconnect(
+                    "ping_timeout",
+                    Some(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("missed {max_missed} pings"),
+                    )),
+                );
+            } else {
+                client.ping();
+                self.metrics.count_command("ping", Direction::Sent);
+            }
+        }
+    }
+
+    fn handle_command(&mut self, index: usize, command: ClientCommand) {
+        self.metrics
+            .count_command(command.name(), Direction::Received);
+        let reason = match self.clients[index].check_rate(&command) {
+            Verdict::Allow => None,
+            Verdict::Throttled => {
+                Some("You are sending too fast, slow down".to_owned())
+            }
+            Verdict::Muted(left) => {
+                // only the command that got the client muted has all of it
+                if left >= self.config.rate_limits.mute {
+                    warn!(
+                        event = "flooding_muted",
+                        user_id = self.clients[index].user_id().0;
+                        "User {} is flooding, muted for {}s",
+                        self.clients[index].user_id(),
+                        left.as_secs()
+                    );
+                }
+                Some(format!(
+                    "You are muted for flooding, {}s left",
+                    left.as_secs().max(1)
+                ))
+            }
+            Verdict::Disconnect => {
+                // the rest of what it sent before is dropped quietly
+                if !self.clients[index].connected() {
+                    return;
+                }
+                warn!(
+                    event = "flooding_disconnected",
+                    user_id = self.clients[index].user_id().0;
+                    "Disconnecting user {} for flooding",
+                    self.clients[index].user_id()
+                );
+                self.clients[index].disconnect(
+                    "flooding",
+                    Some(Error::new(
+                        ErrorKind::PermissionDenied,
+                        "kept flooding after being muted",
+                    )),
+                );
+                return;
+            }
+        };
+        if let Some(reason) = reason {
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: command.name().to_owned(),
+                    reason,
+                },
+            );
+            return;
+        }
+        if is_login(&command)
+            && !self.login_limiter.check(self.clients[index].addr().ip())
+        {
+            warn!(
+                event = "login_throttled",
+                addr:% = self.clients[index].addr();
+                "Too many logins from {}", self.clients[index].addr().ip()
+            );
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: command.name().to_owned(),
+                    reason: "Too many attempts from your address, try again \
+                             later"
+                        .to_owned(),
+                },
+            );
+            return;
+        }
+        // nothing but getting a name is open to a client without one, or
+        // it could skip the password, invites and bans
+        if self.clients[index].name().is_none()
+            && !matches!(
+                command,
+                ClientCommand::Padding
+                    | ClientCommand::Pong { .. }
+                    | ClientCommand::Connect { .. }
+                    | ClientCommand::Login { .. }
+                    | ClientCommand::Register { .. }
+            )
+        {
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: command.name().to_owned(),
+                    reason: "Connect first".to_owned(),
+                },
+            );
+            return;
+        }
+        match command {
+            ClientCommand::Padding => (),
+            ClientCommand::Pong { token } => self.clients[index].pong(token),
+            ClientCommand::Connect {
+                name,
+                invite,
+                credential,
+                password,
+            } => {
+                // a name is changed with a rename, which checks it the same
+                // way and tells the others
+                let reason = if self.clients[index].name().is_some() {
+                    Some("Already connected, rename instead")
+                } else if self.clients[index].auth_ticket().is_some() {
+                    Some("Already connecting")
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    self.reply(
+                        index,
+                        &ServerCommand::CommandFailed {
+                            command: "connect".to_owned(),
+                            reason: reason.to_owned(),
+                        },
+                    );
+                    return;
+                }
+                if !self.check_password(index, password.as_deref()) {
+                    return;
+                }
+                if let Err(reason) = validate_name(&name) {
+                    self.reply(
+                        index,
+                        &ServerCommand::ConnectRejected {
+                            reason: reason.to_owned(),
+                        },
+                    );
+                    return;
+                }
+                self.authenticate(
+                    index,
+                    PendingAuth::Connect {
+                        name,
+                        invite,
+                        credential,
+                    },
+                );
+            }
+            ClientCommand::Message {
+                message,
+                content_type,
+                quote,
+            } => {
+                // the text itself was checked to be UTF-8 when decoding
+                if message.len() > MAX_MESSAGE_LEN {
+                    warn!(
+                        "Dropping {} byte message from user {}",
+                        message.len(),
+                        self.clients[index].user_id()
+                    );
+                    self.reply(
+                        index,
+                        &ServerCommand::CommandFailed {
+                            command: "message".to_owned(),
+                            reason: format!(
+                                "The message is longer than \
+                                 {MAX_MESSAGE_LEN} bytes"
+                            ),
+                        },
+                    );
+                    return;
+                }
+                let quote = match quote {
+                    Some(msg_id) => {
+                        let Some(quote) = self.quote(index, msg_id, "quote")
+                        else {
+                            return;
+                        };
+                        Some(snippet(quote))
+                    }
+                    None => None,
+                };
+                let channel_id = self.clients[index].channel();
+                let channel = self.channel_name(channel_id).to_owned();
+                let command = HookedCommand::Message {
+                    channel_id,
+                    content_type,
+                    quote,
+                };
+                self.run_hooks(index, &channel, message, command);
+            }
+            ClientCommand::Search {
+                query,
+                before_msg_id,
+                limit,
+            } => self.search(index, query, before_msg_id, limit),
+            ClientCommand::GetHistory {
+                before_msg_id,
+                limit,
+            } => {
+                let limit = limit.min(MAX_HISTORY_CHUNK);
+                let messages = self.history.before(
+                    self.clients[index].channel(),
+                    before_msg_id,
+                    limit.into(),
+                );
+                self.send_history(index, messages);
+            }
+            ClientCommand::SetRole { user_id, role } => {
+                self.set_role(index, user_id, role);
+            }
+            ClientCommand::CreateInvite {
+                uses,
+                valid_minutes,
+            } => {
+                if self.check_permission(index, Permission::Invite) {
+                    let created = create_invite(
+                        &mut *self.store,
+                        Some(uses).filter(|&u| u > 0),
+                        Some(valid_minutes)
+                            .filter(|&m| m > 0)
+                            .map(|m| Duration::from_secs(u64::from(m) * 60)),
+                    );
+                    match created {
+                        Ok(token) => {
+                            info!(
+                                "Invite created by user {}",
+                                self.clients[index].user_id()
+                            );
+                            self.reply(
+                                index,
+                                &ServerCommand::InviteCreated { token },
+                            );
+                        }
+                        Err(e) => {
+                            warn!("Failed to store an invite: {e}");
+                            self.reply(
+                                index,
+                                &ServerCommand::CommandFailed {
+                                    command: "invite".to_owned(),
+                                    reason: "Failed to store the invite"
+                                        .to_owned(),
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            ClientCommand::RevokeInvite { token } => {
+                if self.check_permission(index, Permission::Invite) {
+                    let existed = revoke_invite(&mut *self.store, &token)
+                        .unwrap_or_else(|e| {
+                            warn!("Failed to revoke invite {token}: {e}");
+                            false
+                        });
+                    self.reply(
+                        index,
+                        &ServerCommand::InviteRevoked { token, existed },
+                    );
+                }
+            }
+            ClientCommand::Join { name } => self.join(index, name),
+            ClientCommand::ListUsers => self.list_users(index),
+            ClientCommand::ServerInfo => self.server_info(index),
+            ClientCommand::RoomStats => self.room_stats(index),
+            ClientCommand::FileOffer {
+                target_user_id,
+                name,
+                size,
+            } => self.file_offer(index, target_user_id, name, size),
+            ClientCommand::FileAccept { transfer_id } => {
+                self.file_accept(index, transfer_id);
+            }
+            ClientCommand::FileReject {
+                transfer_id,
+                reason,
+            } => self.file_reject(index, transfer_id, reason),
+            ClientCommand::FileChunk { transfer_id, data } => {
+                self.file_chunk(index, transfer_id, data);
+            }
+            ClientCommand::FileDone { transfer_id } => {
+                self.file_done(index, transfer_id);
+            }
+            ClientCommand::Kick { user_id, reason } => {
+                self.moderate(index, user_id, reason, false);
+            }
+            ClientCommand::Ban { user_id, reason } => {
+                self.moderate(index, user_id, reason, true);
+            }
+            ClientCommand::Register { name, password } => {
+                self.register(index, name, &password);
+            }
+            ClientCommand::Login { name, password } => {
+                self.login(index, name, &password);
+            }
+            ClientCommand::SetTopic { topic } => self.set_topic(index, topic),
+            ClientCommand::Whisper {
+                target_user_id,
+                message,
+            } => self.whisper(index, target_user_id, message),
+            ClientCommand::OfflineWhisper { name, message } => {
+                self.offline_whisper(index, name, message);
+            }
+            ClientCommand::MarkRead { up_to_msg_id } => {
+                let client = &self.clients[index];
+                let key = (client.user_id(), client.channel());
+                let marker =
+                    self.read_markers.entry(key).or_insert(up_to_msg_id);
+                *marker = up_to_msg_id.max(*marker);
+            }
+            ClientCommand::MarkWhispersRead { user_id } => {
+                // only whoever whispered may learn that they were read
+                if !self.clients[index].read_whispers(user_id) {
+                    return;
+                }
+                // receipts don't matter enough to tell if they got lost
+                let reader = self.clients[index].user_id();
+                self.send_to(
+                    user_id,
+                    &ServerCommand::WhispersRead { user_id: reader },
+                );
+            }
+            ClientCommand::React { msg_id, emoji } => {
+                self.react(index, msg_id, emoji);
+            }
+            ClientCommand::Rename { new_name } => {
+                self.rename(index, new_name);
+            }
+            ClientCommand::KeyExchange {
+                target_user_id,
+                public_key,
+            } => self.key_exchange(index, target_user_id, public_key),
+            ClientCommand::EncryptedWhisper {
+                target_user_id,
+                counter,
+                ciphertext,
+            } => self.encrypted_whisper(
+                index,
+                target_user_id,
+                counter,
+                ciphertext,
+            ),
+            ClientCommand::Forward { msg_id, channel } => {
+                let Some(channel_id) = self.find_channel(&channel) else {
+                    self.reply(
+                        index,
+                        &ServerCommand::CommandFailed {
+                            command: "forward".to_owned(),
+                            reason: format!("There is no channel #{channel}"),
+                        },
+                    );
+                    return;
+                };
+                if let Some(quote) = self.quote(index, msg_id, "forward") {
+                    let text = quote.text.clone();
+                    let command = HookedCommand::Forward { channel_id, quote };
+                    self.run_hooks(index, &channel, text, command);
+                }
+            }
+        }
+    }
+
+    /// Relays the offer of a file from the client at `index` to the user
+    /// with `target_user_id`, and back to the sender with the id of the
+    /// transfer.
+    fn file_offer(
+        &mut self,
+        index: usize,
+        target_user_id: UserId,
+        name: String,
+        size: u64,
+    ) {
+        let user_id = self.clients[index].user_id();
+        let online = self
+            .clients
+            .iter()
+            .any(|c| c.user_id() == target_user_id && c.name().is_some());
+        let failure = match self.config.max_file_size {
+            None => Some("File transfers are disabled".to_owned()),
+            Some(max) if size > max => {
+                Some(format!("Files over {max} bytes are not accepted"))
+            }
+            _ if name.is_empty() || name.len() > MAX_FILE_NAME_LEN => {
+                Some("The file name is empty or too long".to_owned())
+            }
+            _ if target_user_id == user_id => {
+                Some("You can't send a file to yourself".to_owned())
+            }
+            _ if !online => {
+                Some(format!("User {target_user_id} is not online"))
+            }
+            _ => None,
+        };
+        let transfer_id = match failure {
+            Some(reason) => Err(reason),
+            None => self
+                .transfers
+                .offer(user_id, target_user_id, size)
+                .ok_or_else(|| {
+                    "You have too many transfers under way".to_owned()
+                }),
+        };
+        let transfer_id = match transfer_id {
+            Ok(transfer_id) => transfer_id,
+            Err(reason) => {
+                self.reply(
+                    index,
+                    &ServerCommand::CommandFailed {
+                        command: "file_offer".to_owned(),
+                        reason,
+                    },
+                );
+                return;
+            }
+        };
+        info!(
+            "User {user_id} offers {size} bytes to user {target_user_id} \
+             as transfer {transfer_id}"
+        );
+        let offer = ServerCommand::FileOffer {
+            transfer_id,
+            user_id,
+            target_user_id,
+            name,
+            size,
+        };
+        self.send_to(target_user_id, &offer);
+        self.reply(index, &offer);
+    }
+
+    /// Tells the sender of a file that the client at `index` accepted it.
+    fn file_accept(&mut self, index: usize, transfer_id: TransferId) {
+        let user_id = self.clients[index].user_id();
+        let Some(transfer) = self
+            .transfers
+            .get_mut(transfer_id)
+            .filter(|t| t.receiver == user_id && !t.accepted)
+        else {
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: "file_accept".to_owned(),
+                    reason: format!("There is no offer {transfer_id}"),
+                },
+            );
+            return;
+        };
+        transfer.accepted = true;
+        let sender = transfer.sender;
+        self.send_to(sender, &ServerCommand::FileAccept { transfer_id });
+    }
+
+    /// Ends a transfer the client at `index` takes part in, telling the
+    /// other side why.
+    fn file_reject(
+        &mut self,
+        index: usize,
+        transfer_id: TransferId,
+        reason: String,
+    ) {
+        let user_id = self.clients[index].user_id();
+        let Some(transfer) = self.transfers.get_mut(transfer_id) else {
+            // the other side may have ended it first
+            return;
+        };
+        let other = if transfer.sender == user_id {
+            transfer.receiver
+        } else if transfer.receiver == user_id {
+            transfer.sender
+        } else {
+            return;
+        };
+        self.transfers.remove(transfer_id);
+        self.send_to(
+            other,
+            &ServerCommand::FileReject {
+                transfer_id,
+                reason,
+            },
+        );
+    }
+
+    /// Relays a chunk of an accepted file from the client at `index`,
+    /// stopping the transfer if it goes over the offered size.
+    fn file_chunk(
+        &mut self,
+        index: usize,
+        transfer_id: TransferId,
+        data: Bytes,
+    ) {
+        let user_id = self.clients[index].user_id();
+        let Some(transfer) = self
+            .transfers
+            .get_mut(transfer_id)
+            .filter(|t| t.sender == user_id && t.accepted)
+        else {
+            // chunks already sent when the transfer was stopped
+            return;
+        };
+        transfer.relayed += data.0.len() as u64;
+        let receiver = transfer.receiver;
+        if transfer.relayed > transfer.size {
+            self.stop_transfer(transfer_id, "The file is larger than offered");
+            return;
+        }
+        self.send_to(receiver, &ServerCommand::FileChunk { transfer_id, data });
+    }
+
+    /// Tells the receiver of a file that the client at `index` sent all
+    /// of it.
+    fn file_done(&mut self, index: usize, transfer_id: TransferId) {
+        let user_id = self.clients[index].user_id();
+        let Some(transfer) = self
+            .transfers
+            .get_mut(transfer_id)
+            .filter(|t| t.sender == user_id && t.accepted)
+        else {
+            return;
+        };
+        if transfer.relayed < transfer.size {
+            self.stop_transfer(transfer_id, "The file is smaller than offered");
+            return;
+        }
+        let receiver = transfer.receiver;
+        self.transfers.remove(transfer_id);
+        self.send_to(receiver, &ServerCommand::FileDone { transfer_id });
+    }
+
+    /// Ends a transfer, telling both sides `reason`.
+    fn stop_transfer(&mut self, transfer_id: TransferId, reason: &str) {
+        let Some(transfer) = self.transfers.remove(transfer_id) else {
+            return;
+        };
+        let reject = ServerCommand::FileReject {
+            transfer_id,
+            reason: reason.to_owned(),
+        };
+        self.send_to(transfer.sender, &reject);
+        self.send_to(transfer.receiver, &reject);
+    }
+
+    /// Ends the transfers of a user that disconnected, telling the other
+    /// side.
+    fn end_transfers(&mut self, user_id: UserId) {
+        for (transfer_id, transfer) in self.transfers.remove_user(user_id) {
+            let other = if transfer.sender == user_id {
+                transfer.receiver
+            } else {
+                transfer.sender
+            };
+            self.send_to(
+                other,
+                &ServerCommand::FileReject {
+                    transfer_id,
+                    reason: format!("User {user_id} disconnected"),
+                },
+            );
+        }
+    }
+
+    /// Sends a private message from the client at `index` to the user with
+    /// `target_user_id`, and back to the sender to confirm it was sent.
+    /// Whispers are not archived, stored or kept in the history.
+    fn whisper(
+        &mut self,
+        index: usize,
+        target_user_id: UserId,
+        message: String,
+    ) {
+        let user_id = self.clients[index].user_id();
+        if message.len() > MAX_MESSAGE_LEN {
+            warn!(
+                "Dropping {} byte whisper from user {user_id}",
+                message.len()
+            );
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: "whisper".to_owned(),
+                    reason: format!(
+                        "The message is longer than {MAX_MESSAGE_LEN} bytes"
+                    ),
+                },
+            );
+            return;
+        }
+        let target = self
+            .clients
+            .iter()
+            .find(|c| c.user_id() == target_user_id)
+            .and_then(Client::name)
+            .map_or_else(|| target_user_id.to_string(), str::to_owned);
+        let command = HookedCommand::Whisper { target_user_id };
+        self.run_hooks(index, &format!("@{target}"), message, command);
+    }
+
+    /// Keeps a whisper from the client at `index` for the offline account
+    /// `name` in the store, to be delivered when it next connects.
+    fn offline_whisper(&mut self, index: usize, name: String, message: String) {
+        let user_id = self.clients[index].user_id();
+        if message.len() > MAX_MESSAGE_LEN {
+            warn!(
+                "Dropping {} byte offline whisper from user {user_id}",
+                message.len()
+            );
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: "offline_whisper".to_owned(),
+                    reason: format!(
+                        "The message is longer than {MAX_MESSAGE_LEN} bytes"
+                    ),
+                },
+            );
+            return;
+        }
+        let fail = |reason: String| ServerCommand::CommandFailed {
+            command: "offline_whisper".to_owned(),
+            reason,
+        };
+        // they may have connected since the sender looked
+        if let Some(target) =
+            self.clients.iter().find(|c| c.name() == Some(&name))
+        {
+            let target_user_id = target.user_id();
+            return self.whisper(index, target_user_id, message);
+        }
+        let account = match self.store.get_account(&name) {
+            Ok(Some(account)) => account,
+            Ok(None) => {
+                let reason = format!("'{name}' is offline and not registered");
+                return self.reply(index, &fail(reason));
+            }
+            Err(e) => {
+                warn!("Failed to load the account '{name}': {e}");
+                let reason = "The account could not be checked".to_owned();
+                return self.reply(index, &fail(reason));
+            }
+        };
+        let waiting = match self.store.count_offline_whispers(account.user_id)
+        {
+            Ok(waiting) => waiting,
+            Err(e) => {
+                warn!("Failed to count the whispers for '{name}': {e}");
+                let reason = "The message could not be kept".to_owned();
+                return self.reply(index, &fail(reason));
+            }
+        };
+        if waiting >= MAX_OFFLINE_WHISPERS {
+            let reason = format!("'{name}' has too many messages waiting");
+            return self.reply(index, &fail(reason));
+        }
+        let channel = format!("@{name}");
+        let command = HookedCommand::OfflineWhisper {
+            target_user_id: account.user_id,
+            name,
+        };
+        self.run_hooks(index, &channel, message, command);
+    }
+
+    /// Passes `message` from the client at `index` to `channel` through
+    /// the hooks, doing `command` with what they made of it once they
+    /// ran.
+    fn run_hooks(
+        &mut self,
+        index: usize,
+        channel: &str,
+        message: String,
+        command: HookedCommand,
+    ) {
+        let user_id = self.clients[index].user_id();
+        let hooked = Hooked::Command { user_id, command };
+        self.queue_hooked(channel, message, hooked);
+    }
+
+    /// Passes `message` to `channel` to the hook thread, finishing
+    /// `hooked` right away if there are no hooks.
+    fn queue_hooked(&mut self, channel: &str, message: String, hooked: Hooked) {
+        if self.hooks.is_empty() {
+            self.finish_hook(hooked, Ok(message), vec![]);
+            return;
+        }
+        let user_id = hooked.user_id();
+        let waiting = self
+            .pending_hooks
+            .values()
+            .filter(|h| h.user_id() == user_id)
+            .count();
+        if waiting >= MAX_HOOKED_PER_USER {
+            let reason = "Too many messages are waiting to be sent".to_owned();
+            self.finish_hook(hooked, Err(reason), vec![]);
+            return;
+        }
+        self.last_hook_ticket += 1;
+        let ticket = self.last_hook_ticket;
+        if let Err(e) = self.hooks.on_message(ticket, user_id, channel, message)
+        {
+            error!("Failed to pass a message to the hooks: {e}");
+            let reason = "The message could not be checked".to_owned();
+            self.finish_hook(hooked, Err(reason), vec![]);
+            return;
+        }
+        self.pending_hooks.insert(ticket, hooked);
+    }
+
+    /// Finishes the messages that passed through the hook thread.
+    fn finish_hooked(&mut self) {
+        while let Some(message) = self.hooks.poll() {
+            if let Some(hooked) = self.pending_hooks.remove(&message.ticket) {
+                self.finish_hook(hooked, message.verdict, message.replies);
+            }
+        }
+    }
+
+    /// Does what `hooked` asks with the text the hooks let through,
+    /// sending the client their `replies`, or tells it why they rejected
+    /// it.
+    fn finish_hook(
+        &mut self,
+        hooked: Hooked,
+        verdict: std::result::Result<String, String>,
+        replies: Vec<String>,
+    ) {
+        let (user_id, command) = match hooked {
+            Hooked::Command { user_id, command } => (user_id, command),
+            // a webhook has nowhere to get replies
+            Hooked::Bridged {
+                user_id,
+                name,
+                channel_id,
+            } => {
+                match verdict {
+                    Ok(message) => {
+                        self.post(
+                            user_id,
+                            name,
+                            channel_id,
+                            message,
+                            ContentType::Plain,
+                            None,
+                        );
+                    }
+                    Err(reason) => {
+                        info!(
+                            "Rejected a bridged message from '{name}': \
+                             {reason}"
+                        );
+                    }
+                }
+                return;
+            }
+        };
+        // the sender may have left while the hooks ran
+        let Some(index) = self
+            .clients
+            .iter()
+            .position(|c| c.user_id() == user_id && c.name().is_some())
+        else {
+            return;
+        };
+        for text in replies {
+            self.reply(index, &ServerCommand::Notice { text });
+        }
+        let message = match verdict {
+            Ok(message) => message,
+            Err(reason) => {
+                self.reply(
+                    index,
+                    &ServerCommand::CommandFailed {
+                        command: command.name().to_owned(),
+                        reason,
+                    },
+                );
+                return;
+            }
+        };
+        match command {
+            HookedCommand::Message {
+                channel_id,
+                content_type,
+                quote,
+            } => {
+                let reply = match self.post_message(
+                    index,
+                    channel_id,
+                    message,
+                    content_type,
+                    quote,
+                ) {
+                    Some(msg_id) => ServerCommand::Ack { msg_id },
+                    None => ServerCommand::CommandFailed {
+                        command: "message".to_owned(),
+                        reason: "The message could not be sent".to_owned(),
+                    },
+                };
+                self.reply(index, &reply);
+            }
+            HookedCommand::Forward { channel_id, quote } => {
+                let quote = Quote {
+                    text: message,
+                    ..quote
+                };
+                // forwards are not acknowledged, the copy shows up in the
+                // other channel
+                self.post_message(
+                    index,
+                    channel_id,
+                    String::new(),
+                    ContentType::Plain,
+                    Some(quote),
+                );
+            }
+            HookedCommand::Whisper { target_user_id } => {
+                let whisper = ServerCommand::Whisper {
+                    user_id,
+                    target_user_id,
+                    message,
+                };
+                self.relay_private(index, target_user_id, &whisper, true);
+            }
+            HookedCommand::OfflineWhisper {
+                target_user_id,
+                name,
+            } => {
+                let whisper = OfflineWhisper {
+                    target_user_id,
+                    user_id,
+                    name: self.clients[index]
+                        .name()
+                        .unwrap_or_default()
+                        .to_owned(),
+                    message,
+                    time: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs()),
+                };
+                if let Err(e) = self.store.push_offline_whisper(&whisper) {
+                    warn!("Failed to keep a whisper for '{name}': {e}");
+                    self.reply(
+                        index,
+                        &ServerCommand::CommandFailed {
+                            command: "offline_whisper".to_owned(),
+                            reason: "The message could not be kept".to_owned(),
+                        },
+                    );
+                }
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
-            // HACK: this error might not be fatal
-            Err(e) => Err(e),
         }
     }
+
+    /// Relays a public key for an end-to-end encrypted conversation from
+    /// the client at `index` to the user with `target_user_id`.
+    fn key_exchange(
+        &mut self,
+        index: usize,
+        target_user_id: UserId,
+        public_key: Bytes,
+    ) {
+        let user_id = self.clients[index].user_id();
+        if public_key.0.len() != X25519_KEY_LEN {
+            warn!(
+                "Dropping {} byte public key from user {user_id}",
+                public_key.0.len()
+            );
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: "key_exchange".to_owned(),
+                    reason: format!(
+                        "A public key must be {X25519_KEY_LEN} bytes"
+                    ),
+                },
+            );
+            return;
+        }
+        let exchange = ServerCommand::KeyExchange {
+            user_id,
+            target_user_id,
+            public_key,
+        };
+        self.relay_private(index, target_user_id, &exchange, false);
+    }
+
+    /// Relays an end-to-end encrypted whisper, which the server can't read,
+    /// like [`Self::whisper`] does with a plain one.
+    fn encrypted_whisper(
+        &mut self,
+        index: usize,
+        target_user_id: UserId,
+        counter: u64,
+        ciphertext: Bytes,
+    ) {
+        let user_id = self.clients[index].user_id();
+        if ciphertext.0.len() > MAX_MESSAGE_LEN + AEAD_TAG_LEN {
+            warn!(
+                "Dropping {} byte encrypted whisper from user {user_id}",
+                ciphertext.0.len()
+            );
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: "encrypted_whisper".to_owned(),
+                    reason: format!(
+                        "The message is longer than {MAX_MESSAGE_LEN} bytes"
+                    ),
+                },
+            );
+            return;
+        }
+        let whisper = ServerCommand::EncryptedWhisper {
+            user_id,
+            target_user_id,
+            counter,
+            ciphertext,
+        };
+        self.relay_private(index, target_user_id, &whisper, true);
+    }
+
+    /// Sends `command` from the client at `index` to the user with
+    /// `target_user_id` only, and back to the sender if `echo` is set,
+    /// telling the sender if the target is not online.
+    fn relay_private(
+        &mut self,
+        index: usize,
+        target_user_id: UserId,
+        command: &ServerCommand,
+        echo: bool,
+    ) {
+        let online = self
+            .clients
+            .iter()
+            .any(|c| c.user_id() == target_user_id && c.name().is_some());
+        if !online || !self.send_to(target_user_id, command) {
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: command.name().to_owned(),
+                    reason: format!("User {target_user_id} is not online"),
+                },
+            );
+            return;
+        }
+        let user_id = self.clients[index].user_id();
+        if matches!(
+            command,
+            ServerCommand::Whisper { .. }
+                | ServerCommand::EncryptedWhisper { .. }
+        ) {
+            self.clients
+                .iter_mut()
+                .filter(|c| c.user_id() == target_user_id)
+                .for_each(|c| c.add_unread_whisper(user_id));
+        }
+        if echo && target_user_id != user_id {
+            self.reply(index, command);
+        }
+    }
+
+    /// Sends a message from the client at `index` to `channel_id`, after
+    /// archiving and storing it, and to the webhooks. Returns its id, or
+    /// `None` if it was dropped, see [`Self::post`].
+    fn post_message(
+        &mut self,
+        index: usize,
+        channel_id: ChannelId,
+        message: String,
+        content_type: ContentType,
+        quote: Option<Quote>,
+    ) -> Option<MsgId> {
+        let client = &self.clients[index];
+        let (user_id, name) = (
+            client.user_id(),
+            client.name().unwrap_or_default().to_owned(),
+        );
+        let record =
+            self.post(user_id, name, channel_id, message, content_type, quote)?;
+        self.webhooks.publish(&record);
+        Some(record.msg_id)
+    }
+
+    /// Sends a message from `user_id` to `channel_id` after archiving and
+    /// storing it, returning its record, or `None` if it was dropped
+    /// because archiving failed or the ids ran out.
+    fn post(
+        &mut self,
+        user_id: UserId,
+        name: String,
+        channel_id: ChannelId,
+        message: String,
+        content_type: ContentType,
+        quote: Option<Quote>,
+    ) -> Option<ArchiveRecord> {
+        let Some(msg_id) = self.msg_id_gen.get().map(MsgId) else {
+            error!("Dropping a message, no message ids are left");
+            return None;
+        };
+        let record = ArchiveRecord {
+            time: SystemTime::now(),
+            msg_id,
+            user_id,
+            name,
+            channel: self.channel_name(channel_id).to_owned(),
+            message,
+        };
+        if !self.archive(&record) {
+            return None;
+        }
+        let message = ServerCommand::Message {
+            msg_id: record.msg_id,
+            user_id,
+            channel_id,
+            message: record.message.clone(),
+            content_type,
+            quote,
+            time: record
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        self.metrics.record_message(user_id, &record.channel);
+        if let Err(e) = self.store.append_message(&message) {
+            warn!("Failed to store message: {e}");
+        }
+        self.history.push(message.clone());
+        self.channel_activity.insert(channel_id, Instant::now());
+        self.broadcast_channel(channel_id, message);
+        Some(record)
+    }
+
+    /// Posts the messages that bridges sent to the webhook endpoint, each
+    /// under a user of its own name that only exists for that.
+    fn post_webhook_messages(&mut self) {
+        while let Some(InboundMessage {
+            channel,
+            name,
+            message,
+        }) = self.webhooks.poll()
+        {
+            let Some(channel_id) = self.find_channel(&channel) else {
+                warn!("Dropping a bridged message to unknown room '{channel}'");
+                continue;
+            };
+            if message.len() > MAX_MESSAGE_LEN {
+                warn!("Dropping {} byte bridged message", message.len());
+                continue;
+            }
+            if let Err(reason) = validate_name(&name) {
+                warn!("Dropping a bridged message from '{name}': {reason}");
+                continue;
+            }
+            if self.clients.iter().any(|c| c.name() == Some(&name)) {
+                warn!(
+                    "Dropping a bridged message from '{name}', a user here \
+                     has that name"
+                );
+                continue;
+            }
+            let user_id = if let Some(&user_id) = self.bridged_users.get(&name)
+            {
+                user_id
+            } else {
+                let Some(user_id) = self.allocate_user_id() else {
+                    warn!("Dropping a bridged message, no user ids are left");
+                    continue;
+                };
+                self.bridged_users.insert(name.clone(), user_id);
+                self.broadcast_all(ServerCommand::AddUser {
+                    user_id,
+                    name: name.clone(),
+                });
+                user_id
+            };
+            let hooked = Hooked::Bridged {
+                user_id,
+                name,
+                channel_id,
+            };
+            self.queue_hooked(&channel, message, hooked);
+        }
+    }
+
+    /// Quotes the whole message `msg_id` if the client at `index` can see
+    /// it, telling it that `command` failed otherwise. Forwarded messages
+    /// are quoted as the original, keeping its author.
+    fn quote(
+        &mut self,
+        index: usize,
+        msg_id: MsgId,
+        command: &str,
+    ) -> Option<Quote> {
+        let channel = self.clients[index].channel();
+        let quote = match self.history.get(msg_id) {
+            Some(ServerCommand::Message {
+                channel_id,
+                message,
+                quote: Some(quote),
+                ..
+            }) if *channel_id == channel && message.is_empty() => {
+                Some(quote.clone())
+            }
+            Some(ServerCommand::Message {
+                user_id,
+                channel_id,
+                message,
+                ..
+            }) if *channel_id == channel => Some(Quote {
+                msg_id,
+                user_id: *user_id,
+                text: message.clone(),
+            }),
+            _ => None,
+        };
+        if quote.is_none() {
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: command.to_owned(),
+                    reason: format!(
+                        "Message {msg_id} is not in this channel or too old"
+                    ),
+                },
+            );
+        }
+        quote
+    }
+
+    /// The channel called `name`, if anyone ever joined it.
+    fn find_channel(&self, name: &str) -> Option<ChannelId> {
+        if name == ChannelId::LOBBY_NAME {
+            Some(ChannelId::LOBBY)
+        } else {
+            self.channels.get(name).copied()
+        }
+    }
+
+    /// Moves the client at `index` to the channel called `name`, creating
+    /// the channel if it doesn't exist yet.
+    fn join(&mut self, index: usize, name: String) {
+        if !ChannelId::valid_name(&name) {
+            warn!(
+                "User {} tried to join invalid channel '{name}'",
+                self.clients[index].user_id()
+            );
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: "join".to_owned(),
+                    reason: format!(
+                        "A channel name must be 1 to {} letters, digits, \
+                         '-' or '_'",
+                        ChannelId::MAX_NAME_LEN
+                    ),
+                },
+            );
+            return;
+        }
+        let found = self
+            .find_channel(&name)
+            .or_else(|| self.restore_channel(&name));
+        let channel_id = match found {
+            Some(channel_id) => channel_id,
+            None => {
+                let Some(channel_id) = self.channel_id_gen.get().map(ChannelId)
+                else {
+                    self.reply(
+                        index,
+                        &ServerCommand::CommandFailed {
+                            command: "join".to_owned(),
+                            reason: "No more channels can be created"
+                                .to_owned(),
+                        },
+                    );
+                    return;
+                };
+                self.channels.insert(name.clone(), channel_id);
+                let record = ChannelRecord {
+                    name: name.clone(),
+                    channel_id,
+                    topic: String::new(),
+                    archived: false,
+                };
+                if let Err(e) = self.store.put_channel(&record) {
+                    warn!("Failed to store channel '{name}': {e}");
+                }
+                channel_id
+            }
+        };
+        info!(
+            "User {} joined channel {channel_id} ({name})",
+            self.clients[index].user_id()
+        );
+        self.channel_activity.insert(channel_id, Instant::now());
+        self.clients[index].set_channel(channel_id);
+        self.reply(index, &ServerCommand::Joined { channel_id, name });
+        self.send_topic(index);
+        self.replay(index);
+    }
+
+    /// Brings the channel called `name` back into the channel list if it
+    /// was archived, returning its id.
+    fn restore_channel(&mut self, name: &str) -> Option<ChannelId> {
+        let channel_id = self.archived_channels.remove(name)?;
+        let record = self.channel_record(name, channel_id, false);
+        if let Err(e) = self.store.put_channel(&record) {
+            warn!("Failed to store restored channel '{name}': {e}");
+        }
+        info!("Restored archived channel {channel_id} ({name})");
+        self.channels.insert(name.to_owned(), channel_id);
+        Some(channel_id)
+    }
+
+    /// The record of a channel, with the topic it has now.
+    fn channel_record(
+        &self,
+        name: &str,
+        channel_id: ChannelId,
+        archived: bool,
+    ) -> ChannelRecord {
+        ChannelRecord {
+            name: name.to_owned(),
+            channel_id,
+            topic: self.topics.get(&channel_id).cloned().unwrap_or_default(),
+            archived,
+        }
+    }
+
+    /// Archives the channels that nobody was in or posted to for
+    /// [`Config::archive_rooms_after`], telling everyone so they can
+    /// forget them. Their history stays in the store.
+    fn archive_idle_channels(&mut self) {
+        let Some(idle) = self.config.archive_rooms_after else {
+            return;
+        };
+        let now = Instant::now();
+        let since_check = now.duration_since(self.last_archive_check);
+        if since_check < ARCHIVE_CHECK_INTERVAL {
+            return;
+        }
+        self.last_archive_check = now;
+        for client in &self.clients {
+            self.channel_activity.insert(client.channel(), now);
+        }
+        let mut archived: Vec<_> = self
+            .channels
+            .iter()
+            .filter(|(_, id)| {
+                self.channel_activity
+                    .get(id)
+                    .is_none_or(|&last| now.duration_since(last) >= idle)
+            })
+            .map(|(name, &id)| (name.clone(), id))
+            .collect();
+        archived.sort_by_key(|&(_, id)| id);
+        for (name, channel_id) in archived {
+            let record = self.channel_record(&name, channel_id, true);
+            if let Err(e) = self.store.put_channel(&record) {
+                warn!("Failed to archive channel '{name}': {e}");
+                continue;
+            }
+            info!("Archived idle channel {channel_id} ({name})");
+            self.channels.remove(&name);
+            self.channel_activity.remove(&channel_id);
+            self.metrics.remove_room(&name);
+            self.archived_channels.insert(name.clone(), channel_id);
+            let archived = ServerCommand::RoomArchived { channel_id, name };
+            self.broadcast_all(archived);
+        }
+    }
+
+    /// Tells the client at `index` the topic of its channel, if it has one.
+    fn send_topic(&mut self, index: usize) {
+        let channel_id = self.clients[index].channel();
+        if let Some(topic) = self.topics.get(&channel_id).cloned() {
+            self.reply(
+                index,
+                &ServerCommand::TopicChanged {
+                    channel_id,
+                    user_id: None,
+                    topic,
+                },
+            );
+        }
+    }
+
+    /// Sets the topic of the channel of the client at `index`.
+    fn set_topic(&mut self, index: usize, topic: String) {
+        if !self.check_permission(index, Permission::Topic) {
+            return;
+        }
+        if topic.len() > MAX_TOPIC_LEN {
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: "set_topic".to_owned(),
+                    reason: format!(
+                        "The topic is longer than {MAX_TOPIC_LEN} bytes"
+                    ),
+                },
+            );
+            return;
+        }
+        // it is shown on one line, and relayed as one to IRC clients
+        if topic.chars().any(char::is_control) {
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: "set_topic".to_owned(),
+                    reason: "The topic can't contain control characters"
+                        .to_owned(),
+                },
+            );
+            return;
+        }
+        let channel_id = self.clients[index].channel();
+        let user_id = self.clients[index].user_id();
+        let record = ChannelRecord {
+            name: self.channel_name(channel_id).to_owned(),
+            channel_id,
+            topic: topic.clone(),
+            archived: false,
+        };
+        if let Err(e) = self.store.put_channel(&record) {
+            warn!("Failed to store the topic of '{}': {e}", record.name);
+        }
+        if topic.is_empty() {
+            self.topics.remove(&channel_id);
+        } else {
+            self.topics.insert(channel_id, topic.clone());
+        }
+        info!("User {user_id} set the topic of {channel_id} to '{topic}'");
+        self.broadcast_channel(
+            channel_id,
+            ServerCommand::TopicChanged {
+                channel_id,
+                user_id: Some(user_id),
+                topic,
+            },
+        );
+    }
+
+    /// Sends the latest messages of its channel to the client at `index`,
+    /// after how far it read them if it did.
+    fn replay(&mut self, index: usize) {
+        let client = &self.clients[index];
+        let channel_id = client.channel();
+        if let Some(&up_to_msg_id) =
+            self.read_markers.get(&(client.user_id(), channel_id))
+        {
+            self.reply(
+                index,
+                &ServerCommand::ReadMarker {
+                    channel_id,
+                    up_to_msg_id,
+                },
+            );
+        }
+        let messages = self.history.before(
+            self.clients[index].channel(),
+            MsgId::MAX,
+            self.config.history_size,
+        );
+        if !messages.is_empty() {
+            self.send_history(index, messages);
+        }
+    }
+
+    /// Toggles the reaction of the client at `index` to message `msg_id`,
+    /// telling its channel how the message is reacted to now.
+    fn react(&mut self, index: usize, msg_id: MsgId, emoji: String) {
+        let fail = |reason: String| ServerCommand::CommandFailed {
+            command: "react".to_owned(),
+            reason,
+        };
+        if emoji.is_empty()
+            || emoji.len() > MAX_EMOJI_LEN
+            || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            let reason = format!(
+                "A reaction must be one emoji of at most {MAX_EMOJI_LEN} \
+                 bytes"
+            );
+            self.reply(index, &fail(reason));
+            return;
+        }
+        let channel_id = self.clients[index].channel();
+        let in_channel = matches!(
+            self.history.get(msg_id),
+            Some(ServerCommand::Message { channel_id: id, .. })
+                if *id == channel_id
+        );
+        if !in_channel {
+            let reason =
+                format!("Message {msg_id} is not in this channel or too old");
+            self.reply(index, &fail(reason));
+            return;
+        }
+        let reactions = self.history.reactions(msg_id);
+        if reactions.len() >= MAX_REACTIONS
+            && !reactions.iter().any(|r| r.emoji == emoji)
+        {
+            let reason = format!(
+                "Message {msg_id} has {MAX_REACTIONS} different reactions \
+                 already"
+            );
+            self.reply(index, &fail(reason));
+            return;
+        }
+        let user_id = self.clients[index].user_id();
+        if let Some(reactions) =
+            self.history.toggle_reaction(msg_id, user_id, emoji)
+        {
+            self.broadcast_channel(
+                channel_id,
+                ServerCommand::ReactionUpdate {
+                    msg_id,
+                    channel_id,
+                    reactions,
+                },
+            );
+        }
+    }
+
+    /// Sends `messages` as history replies that fit in a frame, newest
+    /// first, since the client puts each reply above what it has, and then
+    /// the reactions to them.
+    fn send_history(&mut self, index: usize, messages: Vec<ServerCommand>) {
+        let messages: Vec<MessageRecord> = messages
+            .into_iter()
+            .filter_map(|m| m.try_into().ok())
+            .collect();
+        let reactions: Vec<_> = messages
+            .iter()
+            .filter_map(|message| {
+                let reactions = self.history.reactions(message.msg_id);
+                (!reactions.is_empty()).then(|| ServerCommand::ReactionUpdate {
+                    msg_id: message.msg_id,
+                    channel_id: message.channel_id,
+                    reactions: reactions.to_vec(),
+                })
+            })
+            .collect();
+        let mut chunks = vec![];
+        let mut chunk = vec![];
+        let mut size = 0;
+        for message in messages.into_iter().rev() {
+            if size + message.coded_size() > MAX_HISTORY_BYTES {
+                chunk.reverse();
+                chunks.push(std::mem::take(&mut chunk));
+                size = 0;
+            }
+            size += message.coded_size();
+            chunk.push(message);
+        }
+        chunk.reverse();
+        chunks.push(chunk);
+        let last = chunks.len() - 1;
+        for (n, messages) in chunks.into_iter().enumerate() {
+            let remaining = u16::try_from(last - n).unwrap_or(u16::MAX);
+            self.reply(index, &ServerCommand::History {
+                messages,
+                remaining,
+            });
+        }
+        for update in reactions {
+            self.reply(index, &update);
+        }
+    }
+
+    /// Tells the client at `index` who is connected and since when, as
+    /// many users as fit in one reply.
+    fn list_users(&mut self, index: usize) {
+        let mut users = vec![];
+        let mut size = 0;
+        for client in self.clients.iter().filter(|c| c.connected()) {
+            let Some(name) = client.name() else {
+                continue;
+            };
+            let user = UserInfo {
+                user_id: client.user_id(),
+                name: name.to_owned(),
+                role: client.role(),
+                joined: client
+                    .joined()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+            };
+            size += user.coded_size();
+            if size > MAX_HISTORY_BYTES {
+                break;
+            }
+            users.push(user);
+        }
+        self.reply(index, &ServerCommand::Users { users });
+    }
+
+    /// Sends the client at `index` a page of the stored messages of its
+    /// channel containing `query`, with where the next page starts if
+    /// there may be more than fit in the reply.
+    fn search(
+        &mut self,
+        index: usize,
+        query: String,
+        before_msg_id: Option<MsgId>,
+        limit: u16,
+    ) {
+        let limit = usize::from(limit.min(MAX_SEARCH_RESULTS));
+        let page = match self.store.search_history(
+            self.clients[index].channel(),
+            &query,
+            before_msg_id.unwrap_or(MsgId::MAX),
+            limit,
+        ) {
+            Ok(page) => page,
+            Err(e) => {
+                warn!("Failed to search the stored messages: {e}");
+                self.reply(
+                    index,
+                    &ServerCommand::CommandFailed {
+                        command: "search".to_owned(),
+                        reason: "The messages could not be searched".to_owned(),
+                    },
+                );
+                return;
+            }
+        };
+        let mut messages: Vec<MessageRecord> = page
+            .messages
+            .into_iter()
+            .filter_map(|m| m.try_into().ok())
+            .collect();
+        let mut start = 0;
+        let mut size = query.coded_size();
+        for (i, message) in messages.iter().enumerate().rev() {
+            size += message.coded_size();
+            if size > MAX_HISTORY_BYTES {
+                start = i + 1;
+                break;
+            }
+        }
+        messages.drain(..start);
+        let before_msg_id = match messages.first() {
+            Some(first) if start > 0 => Some(first.msg_id),
+            _ => page.before_msg_id,
+        };
+        self.reply(
+            index,
+            &ServerCommand::SearchResults {
+                query,
+                messages,
+                before_msg_id,
+            },
+        );
+    }
+
+    /// Tells the client at `index` how long the server has been up and how
+    /// many users are connected.
+    fn server_info(&mut self, index: usize) {
+        let user_count = self
+            .clients
+            .iter()
+            .filter(|c| c.connected() && c.name().is_some())
+            .count();
+        self.reply(
+            index,
+            &ServerCommand::ServerInfo {
+                uptime: self.started.elapsed().as_secs(),
+                user_count: u32::try_from(user_count).unwrap_or(u32::MAX),
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                motd: self.config.motd.clone(),
+            },
+        );
+    }
+
+    /// Tells the operator at `index` how busy each channel is.
+    fn room_stats(&mut self, index: usize) {
+        if !self.check_permission(index, Permission::Stats) {
+            return;
+        }
+        let text = self.metrics.room_stats();
+        self.reply(index, &ServerCommand::Notice { text });
+    }
+
+    /// Tells the client at `index` who else is online, and their roles.
+    fn send_user_list(&mut self, index: usize) {
+        let others: Vec<_> =
+            self.clients
+                .iter()
+                .enumerate()
+                .filter(|(i, c)| *i != index && c.connected())
+                .filter_map(|(_, c)| Some((c.user_id(), c.name()?, c.role())))
+                .map(|(user_id, name, role)| (user_id, name.to_owned(), role))
+                .chain(self.bridged_users.iter().map(|(name, &user_id)| {
+                    (user_id, name.clone(), Role::User)
+                }))
+                .collect();
+        let mut users = vec![];
+        let mut size = 0;
+        for (user_id, name, _) in &others {
+            let user = (*user_id, name.clone());
+            if size + user.coded_size() > MAX_HISTORY_BYTES {
+                let users = std::mem::take(&mut users);
+                self.reply(index, &ServerCommand::UserList { users });
+                size = 0;
+            }
+            size += user.coded_size();
+            users.push(user);
+        }
+        if !users.is_empty() {
+            self.reply(index, &ServerCommand::UserList { users });
+        }
+        for (user_id, _, role) in others {
+            if role != Role::User {
+                self.reply(
+                    index,
+                    &ServerCommand::RoleChanged { user_id, role },
+                );
+            }
+        }
+    }
+
+    /// Name of the channel with `channel_id`.
+    fn channel_name(&self, channel_id: ChannelId) -> &str {
+        if channel_id == ChannelId::LOBBY {
+            return ChannelId::LOBBY_NAME;
+        }
+        self.channels
+            .iter()
+            .find(|(_, id)| **id == channel_id)
+            .map_or("", |(name, _)| name)
+    }
+
+    /// Writes a message to every archive, returning whether that
+    /// succeeded; messages that could not be archived are not sent.
+    fn archive(&mut self, record: &ArchiveRecord) -> bool {
+        let msg_id = record.msg_id;
+        let mut archived = true;
+        for archiver in &mut self.archivers {
+            if let Err(e) = archiver.archive(record) {
+                error!("Failed to archive message {msg_id}, dropping it: {e}");
+                archived = false;
+            }
+        }
+        archived
+    }
+
+    /// Whether `password` is the server's, disconnecting the client at
+    /// `index` if it is not.
+    fn check_password(&mut self, index: usize, password: Option<&str>) -> bool {
+        let Some(expected) = &self.config.password else {
+            return true;
+        };
+        // comparing digests doesn't tell how much of the password was right
+        let hash = |p: &str| digest(&SHA256, p.as_bytes());
+        if password.is_some_and(|p| hash(p).as_ref() == hash(expected).as_ref())
+        {
+            return true;
+        }
+        info!(
+            event = "wrong_password",
+            addr:% = self.clients[index].addr();
+            "Wrong password from {}", self.clients[index].addr()
+        );
+        self.reply(
+            index,
+            &ServerCommand::ConnectRejected {
+                reason: "Wrong password".to_owned(),
+            },
+        );
+        self.clients[index].flush();
+        self.clients[index].disconnect(
+            "wrong_password",
+            Some(Error::new(ErrorKind::PermissionDenied, "wrong password")),
+        );
+        false
+    }
+
+    fn connect_user(
+        &mut self,
+        index: usize,
+        name: String,
+        invite: Option<&str>,
+        credential: Option<&str>,
+    ) {
+        if self.name_taken(&name) {
+            self.reply_name_taken(index, name);
+            return;
+        }
+        if let Some(reason) = self.refuse_name(index, &name) {
+            self.reply(
+                index,
+                &ServerCommand::ConnectRejected {
+                    reason: reason.to_owned(),
+                },
+            );
+            return;
+        }
+        let (identity, role) = self.identify(index, &name, credential);
+        // only names that were checked get Admin, so anyone else needs an
+        // invite
+        let needs_invite = self.config.invite_only && role != Role::Admin;
+        if needs_invite
+            && !invite.is_some_and(|token| {
+                check_invite(&mut *self.store, token).unwrap_or_else(|e| {
+                    warn!("Failed to load invite {token}: {e}");
+                    false
+                })
+            })
+        {
+            let reason = if invite.is_some() {
+                "The invite is invalid or has expired"
+            } else {
+                "This server is invite-only"
+            };
+            self.reply(
+                index,
+                &ServerCommand::ConnectRejected {
+                    reason: reason.to_owned(),
+                },
+            );
+            return;
+        }
+        // logging in already gave the client its account's id
+        if self.clients[index].user_id() == NO_USER {
+            let Some(user_id) = self.allocate_user_id() else {
+                self.reply(
+                    index,
+                    &ServerCommand::ConnectRejected {
+                        reason: "The server is full".to_owned(),
+                    },
+                );
+                return;
+            };
+            self.clients[index].set_user_id(user_id);
+        }
+        // redeemed only now so a refused connection doesn't use it up
+        if let Some(token) = invite.filter(|_| needs_invite) {
+            if let Err(e) = redeem_invite(&mut *self.store, token) {
+                warn!("Failed to redeem invite {token}: {e}");
+            }
+        }
+        let user_id = self.clients[index].user_id();
+        self.clients[index].set_name(name.clone());
+        self.clients[index].set_credential(credential.map(str::to_owned));
+        self.clients[index].set_identity(identity);
+        self.clients[index].set_role(role);
+        self.reply(
+            index,
+            &ServerCommand::Welcome {
+                user_id,
+                motd: self.config.motd.clone(),
+            },
+        );
+        self.hooks.on_connect(user_id, &name);
+        self.send_user_list(index);
+        self.reply(
+            index,
+            &ServerCommand::Joined {
+                channel_id: ChannelId::LOBBY,
+                name: ChannelId::LOBBY_NAME.to_owned(),
+            },
+        );
+        self.send_topic(index);
+        self.replay(index);
+        let whispers = self
+            .store
+            .take_offline_whispers(user_id)
+            .unwrap_or_else(|e| {
+                warn!("Failed to load the whispers for '{name}': {e}");
+                vec![]
+            });
+        for whisper in whispers {
+            let OfflineWhisper {
+                user_id,
+                name,
+                message,
+                time,
+                ..
+            } = whisper;
+            self.clients[index].add_unread_whisper(user_id);
+            self.reply(
+                index,
+                &ServerCommand::OfflineWhisper {
+                    user_id,
+                    name,
+                    message,
+                    time,
+                },
+            );
+        }
+        self.broadcast_all(ServerCommand::AddUser { user_id, name });
+        if role != Role::User {
+            self.broadcast_all(ServerCommand::RoleChanged { user_id, role });
+        }
+    }
+
+    /// How the client at `index` showed that it may use `name`, which it
+    /// connects as with `credential`, and the role that comes with it.
+    /// Anyone can take a name that nobody checked, so that gets no more
+    /// than [`Role::User`].
+    fn identify(
+        &mut self,
+        index: usize,
+        name: &str,
+        credential: Option<&str>,
+    ) -> (Identity, Role) {
+        let account = self.store.get_account(name).unwrap_or_else(|e| {
+            warn!("Failed to load the account '{name}': {e}");
+            None
+        });
+        // logging in is what gives the client the account's id
+        let (identity, role) = if let Some(account) = account
+            .filter(|a| a.user_id == self.clients[index].user_id())
+        {
+            (Identity::Account, account.role)
+        } else if credential.is_some() && self.auth.verifies() {
+            let role = match self.store.get_user(name) {
+                Ok(user) => user.map_or(Role::User, |u| u.role),
+                Err(e) => {
+                    warn!("Failed to load user '{name}': {e}");
+                    Role::User
+                }
+            };
+            (Identity::Verified, role)
+        } else {
+            return (Identity::Anonymous, Role::User);
+        };
+        if self.config.admins.iter().any(|admin| admin == name) {
+            (identity, Role::Admin)
+        } else {
+            (identity, role)
+        }
+    }
+
+    /// Replies to the client at `index` that `name` is taken, with free
+    /// names to try instead.
+    fn reply_name_taken(&mut self, index: usize, name: String) {
+        let suggestions = (1..)
+            .map(|i| format!("{name}_{i}"))
+            .filter(|n| !self.name_taken(n))
+            .take(2)
+            .collect();
+        info!("Name '{name}' is taken, suggesting {suggestions:?}");
+        self.reply(index, &ServerCommand::NameTaken { name, suggestions });
+    }
+
+    /// Has the auth thread check whether the client at `index` may use
+    /// the name it asked for, doing what it asked for once it has.
+    fn authenticate(&mut self, index: usize, pending: PendingAuth) {
+        // renames are checked with what the client connected with
+        let credential = match &pending {
+            PendingAuth::Connect { credential, .. } => credential.as_deref(),
+            PendingAuth::Rename { .. } => self.clients[index].credential(),
+        };
+        self.last_auth_ticket += 1;
+        let ticket = self.last_auth_ticket;
+        let name = pending.name();
+        if let Err(e) = self.auth.request(ticket, name, credential) {
+            error!("Failed to authenticate '{name}': {e}");
+            self.refuse_unauthenticated(index, &pending);
+            return;
+        }
+        self.clients[index].set_auth_ticket(Some(ticket));
+        self.pending_auth.insert(ticket, pending);
+    }
+
+    /// Does what the clients asked for whose names the auth thread
+    /// checked, unless they left in the meantime.
+    fn finish_authentication(&mut self) {
+        while let Some((ticket, result)) = self.auth.poll() {
+            let Some(pending) = self.pending_auth.remove(&ticket) else {
+                continue;
+            };
+            let Some(index) = self
+                .clients
+                .iter()
+                .position(|c| c.auth_ticket() == Some(ticket))
+            else {
+                continue;
+            };
+            self.clients[index].set_auth_ticket(None);
+            let authenticated = result.unwrap_or_else(|e| {
+                error!("Failed to authenticate '{}': {e}", pending.name());
+                false
+            });
+            if !authenticated {
+                self.refuse_unauthenticated(index, &pending);
+                continue;
+            }
+            match pending {
+                PendingAuth::Connect {
+                    name,
+                    invite,
+                    credential,
+                } => self.connect_user(
+                    index,
+                    name,
+                    invite.as_deref(),
+                    credential.as_deref(),
+                ),
+                PendingAuth::Rename { new_name } => {
+                    self.finish_rename(index, new_name);
+                }
+            }
+        }
+    }
+
+    /// Tells the client at `index` that it may not use the name it asked
+    /// for.
+    fn refuse_unauthenticated(&mut self, index: usize, pending: &PendingAuth) {
+        let name = pending.name();
+        info!(
+            event = "auth_failed",
+            addr:% = self.clients[index].addr(),
+            name:% = name;
+            "Authentication failed for '{name}'"
+        );
+        let reason = "Authentication failed".to_owned();
+        let reply = match pending {
+            PendingAuth::Connect { .. } => {
+                ServerCommand::ConnectRejected { reason }
+            }
+            PendingAuth::Rename { .. } => ServerCommand::CommandFailed {
+                command: "rename".to_owned(),
+                reason,
+            },
+        };
+        self.reply(index, &reply);
+    }
+
+    /// Returns why the client at `index` can't take `name`, if it can't,
+    /// not counting other users having it. Whether it may is checked by
+    /// the auth thread before.
+    fn refuse_name(
+        &mut self,
+        index: usize,
+        name: &str,
+    ) -> Option<&'static str> {
+        if let Err(reason) = validate_name(name) {
+            return Some(reason);
+        }
+        if self.banned(&Ban::Name(name.to_owned())) {
+            info!("Refusing banned name '{name}'");
+            return Some("This name is banned");
+        }
+        // logging in is what gives the client the account's id
+        let reserved = match self.store.get_account(name) {
+            Ok(account) => account
+                .is_some_and(|a| a.user_id != self.clients[index].user_id()),
+            Err(e) => {
+                warn!("Failed to load the account '{name}': {e}");
+                true
+            }
+        };
+        reserved.then_some("This name is registered, log in to use it")
+    }
+
+    /// Has the name the client at `index` wants to change to checked,
+    /// then changes it with [`finish_rename`](Self::finish_rename).
+    fn rename(&mut self, index: usize, new_name: String) {
+        let fail = |reason: &str| ServerCommand::CommandFailed {
+            command: "rename".to_owned(),
+            reason: reason.to_owned(),
+        };
+        let Some(old_name) = self.clients[index].name() else {
+            self.reply(index, &fail("Connect before renaming"));
+            return;
+        };
+        if new_name == old_name {
+            return;
+        }
+        if self.clients[index].auth_ticket().is_some() {
+            self.reply(index, &fail("Still checking the last name"));
+            return;
+        }
+        if let Err(reason) = validate_name(&new_name) {
+            self.reply(index, &fail(reason));
+            return;
+        }
+        if self.name_taken(&new_name) {
+            self.reply_name_taken(index, new_name);
+            return;
+        }
+        // providers that tie credentials to names turn the new one away
+        self.authenticate(index, PendingAuth::Rename { new_name });
+    }
+
+    /// Changes the name of the client at `index` to `new_name`, which the
+    /// auth thread let it use, keeping its role, stored under the new
+    /// name from then on.
+    fn finish_rename(&mut self, index: usize, new_name: String) {
+        let fail = |reason: &str| ServerCommand::CommandFailed {
+            command: "rename".to_owned(),
+            reason: reason.to_owned(),
+        };
+        let Some(old_name) = self.clients[index].name().map(str::to_owned)
+        else {
+            return;
+        };
+        // someone may have taken it while it was checked
+        if self.name_taken(&new_name) {
+            self.reply_name_taken(index, new_name);
+            return;
+        }
+        if let Some(reason) = self.refuse_name(index, &new_name) {
+            self.reply(index, &fail(reason));
+            return;
+        }
+        // accounts keep their role whatever the name, and unchecked names
+        // have none stored
+        if self.clients[index].identity() == Identity::Verified {
+            self.move_user_record(&old_name, &new_name);
+        }
+        let user_id = self.clients[index].user_id();
+        info!(
+            event = "renamed", user_id = user_id.0,
+            old_name:% = old_name, name:% = new_name;
+            "User {user_id} renamed from '{old_name}' to '{new_name}'"
+        );
+        self.clients[index].set_name(new_name.clone());
+        self.broadcast_all(ServerCommand::UserRenamed {
+            user_id,
+            old_name,
+            name: new_name,
+        });
+    }
+
+    /// Stores the role of the user called `old_name` under `new_name`.
+    fn move_user_record(&mut self, old_name: &str, new_name: &str) {
+        let user = match self.store.get_user(old_name) {
+            Ok(Some(user)) => user,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to load user '{old_name}': {e}");
+                return;
+            }
+        };
+        let moved = UserRecord {
+            name: new_name.to_owned(),
+            ..user
+        };
+        if let Err(e) = self.store.put_user(&moved) {
+            warn!("Failed to store the role of '{new_name}': {e}");
+            return;
+        }
+        if let Err(e) = self.store.delete_user(old_name) {
+            warn!("Failed to remove the role of '{old_name}': {e}");
+        }
+    }
+
+    /// Checks that the client at `index` has `permission`, telling it why
+    /// not if it doesn't.
+    fn check_permission(
+        &mut self,
+        index: usize,
+        permission: Permission,
+    ) -> bool {
+        let permissions = &self.config.permissions;
+        if permissions.allows(self.clients[index].role(), permission) {
+            return true;
+        }
+        let required = permissions.required(permission);
+        self.reply(
+            index,
+            &ServerCommand::PermissionDenied {
+                command: permission.to_string(),
+                required,
+            },
+        );
+        false
+    }
+
+    fn set_role(&mut self, index: usize, user_id: UserId, role: Role) {
+        if !self.check_permission(index, Permission::SetRole) {
+            return;
+        }
+        let own_role = self.clients[index].role();
+        let Some(target) = self
+            .clients
+            .iter()
+            .position(|c| c.user_id() == user_id && c.name().is_some())
+        else {
+            return;
+        };
+        let target_role = self.clients[target].role();
+        // nobody can hand out or take away more than they have
+        if role > own_role || target_role > own_role {
+            self.reply(
+                index,
+                &ServerCommand::PermissionDenied {
+                    command: Permission::SetRole.to_string(),
+                    required: role.max(target_role),
+                },
+            );
+            return;
+        }
+        self.clients[target].set_role(role);
+        info!("User {user_id} is now {role}");
+        self.store_role(target, role);
+        self.broadcast_all(ServerCommand::RoleChanged { user_id, role });
+    }
+
+    /// Keeps `role` for the next sessions of the client at `index`, with
+    /// its account or its checked name, or only for this session if
+    /// nobody checked its name.
+    fn store_role(&mut self, index: usize, role: Role) {
+        let client = &self.clients[index];
+        let result = match client.identity() {
+            Identity::Anonymous => return,
+            Identity::Verified => self.store.put_user(&UserRecord {
+                name: client.name().unwrap_or_default().to_owned(),
+                role,
+            }),
+            Identity::Account => {
+                let user_id = client.user_id();
+                self.store.list_accounts().and_then(|accounts| {
+                    match accounts.into_iter().find(|a| a.user_id == user_id) {
+                        Some(account) => {
+                            self.store.put_account(&Account { role, ..account })
+                        }
+                        None => Ok(()),
+                    }
+                })
+            }
+        };
+        if let Err(e) = result {
+            warn!("Failed to store the role of user {}: {e}", client.user_id());
+        }
+    }
+
+    /// Disconnects the user with `user_id` on behalf of the client at
+    /// `index`, banning their name and address first if `ban` is set.
+    fn moderate(
+        &mut self,
+        index: usize,
+        user_id: UserId,
+        reason: String,
+        ban: bool,
+    ) {
+        let permission = if ban {
+            Permission::Ban
+        } else {
+            Permission::Kick
+        };
+        if !self.check_permission(index, permission) {
+            return;
+        }
+        let Some(target) = self
+            .clients
+            .iter()
+            .position(|c| c.user_id() == user_id && c.name().is_some())
+        else {
+            self.reply(
+                index,
+                &ServerCommand::CommandFailed {
+                    command: permission.to_string(),
+                    reason: format!("There is no user {user_id}"),
+                },
+            );
+            return;
+        };
+        let target_role = self.clients[target].role();
+        // nobody can get rid of someone with more privileges
+        if target_role > self.clients[index].role() {
+            self.reply(
+                index,
+                &ServerCommand::PermissionDenied {
+                    command: permission.to_string(),
+                    required: target_role,
+                },
+            );
+            return;
+        }
+        let by = self.clients[index].user_id();
+        let notification = if ban {
+            let name = self.clients[target].name().unwrap_or_default();
+            for ban in [
+                Ban::Name(name.to_owned()),
+                Ban::Ip(self.clients[target].addr().ip()),
+            ] {
+                if let Err(e) = self.store.add_ban(&ban) {
+                    warn!("Failed to store the ban {ban}: {e}");
+                }
+            }
+            info!(
+                event = "banned", user_id = user_id.0, by = by.0,
+                reason:% = reason;
+                "User {user_id} was banned by user {by}: {reason}"
+            );
+            ServerCommand::Banned {
+                user_id,
+                by,
+                reason,
+            }
+        } else {
+            info!(
+                event = "kicked", user_id = user_id.0, by = by.0,
+                reason:% = reason;
+                "User {user_id} was kicked by user {by}: {reason}"
+            );
+            ServerCommand::Kicked {
+                user_id,
+                by,
+                reason,
+            }
+        };
+        // the target is told right away, before the connection is closed
+        self.reply(target, &notification);
+        self.clients[target].flush();
+        self.clients[target].disconnect(
+            notification.name(),
+            Some(Error::new(ErrorKind::PermissionDenied, notification.name())),
+        );
+        self.broadcast_all(notification);
+    }
+
+    fn register(&mut self, index: usize, name: String, password: &str) {
+        let fail = |reason: &str| ServerCommand::CommandFailed {
+            command: "register".to_owned(),
+            reason: reason.to_owned(),
+        };
+        let failure = if let Err(reason) = validate_name(&name) {
+            Some(fail(reason))
+        } else if password.is_empty() {
+            Some(fail("The password is empty"))
+        } else if self.banned(&Ban::Name(name.clone())) {
+            Some(fail("This name is banned"))
+        } else if self
+            .clients
+            .iter()
+            .enumerate()
+            .any(|(i, c)| i != index && c.name() == Some(&name))
+        {
+            Some(fail("Someone else is using this name"))
+        } else {
+            match self.store.get_account(&name) {
+                Ok(None) => None,
+                Ok(Some(_)) => Some(fail("This name is already registered")),
+                Err(e) => {
+                    warn!("Failed to load the account '{name}': {e}");
+                    Some(fail("The account could not be checked"))
+                }
+            }
+        };
+        if let Some(failure) = failure {
+            self.reply(index, &failure);
+            return;
+        }
+        // a client connected under the name keeps its id, so nothing
+        // changes for the others
+        let user_id = if self.clients[index].name() == Some(&name) {
+            self.clients[index].user_id()
+        } else if let Some(user_id) = self.allocate_user_id() {
+            user_id
+        } else {
+            self.reply(index, &fail("No more accounts can be created"));
+            return;
+        };
+        let account = match PasswordHash::new(password) {
+            // a client registering the name it is connected under keeps
+            // its role, any other account starts out as a user
+            Ok(password) => Account {
+                role: if self.clients[index].name() == Some(&name) {
+                    self.clients[index].role()
+                } else {
+                    Role::User
+                },
+                name,
+                user_id,
+                password,
+            },
+            Err(e) => {
+                error!("Failed to hash a password: {e}");
+                self.reply(index, &fail("The account could not be created"));
+                return;
+            }
+        };
+        if let Err(e) = self.store.put_account(&account) {
+            warn!("Failed to store the account '{}': {e}", account.name);
+            self.reply(index, &fail("The account could not be created"));
+            return;
+        }
+        info!("Registered '{}' as user {user_id}", account.name);
+        if self.clients[index].name() == Some(&account.name) {
+            self.clients[index].set_identity(Identity::Account);
+        }
+        self.reply(
+            index,
+            &ServerCommand::Registered {
+                name: account.name,
+                user_id,
+            },
+        );
+    }
+
+    fn login(&mut self, index: usize, name: String, password: &str) {
+        let fail = |reason: &str| ServerCommand::CommandFailed {
+            command: "login".to_owned(),
+            reason: reason.to_owned(),
+        };
+        if self.clients[index].name().is_some() {
+            self.reply(index, &fail("Log in before connecting"));
+            return;
+        }
+        if let Err(reason) = validate_name(&name) {
+            self.reply(index, &fail(reason));
+            return;
+        }
+        let account = match self.store.get_account(&name) {
+            Ok(account) => account,
+            Err(e) => {
+                warn!("Failed to load the account '{name}': {e}");
+                None
+            }
+        };
+        let Some(account) = account.filter(|a| a.password.verify(password))
+        else {
+            info!(
+                event = "login_failed",
+                addr:% = self.clients[index].addr(),
+                name:% = name;
+                "Failed login as '{name}'"
+            );
+            self.reply(index, &fail("Wrong name or password"));
+            return;
+        };
+        if self.clients.iter().any(|c| c.user_id() == account.user_id) {
+            self.reply(index, &fail("This account is already logged in"));
+            return;
+        }
+        self.clients[index].set_user_id(account.user_id);
+        info!(
+            event = "logged_in", user_id = account.user_id.0,
+            name:% = name;
+            "User {} logged in as '{name}'", account.user_id
+        );
+        self.reply(
+            index,
+            &ServerCommand::LoggedIn {
+                name,
+                user_id: account.user_id,
+            },
+        );
+    }
+
+    fn banned(&self, ban: &Ban) -> bool {
+        match self.store.list_bans() {
+            Ok(bans) => bans.contains(ban),
+            Err(e) => {
+                warn!("Failed to load bans: {e}");
+                false
+            }
+        }
+    }
+
+    /// A user id that no one has: no client, account, bridged user or user
+    /// of another instance, after the last one handed out and starting
+    /// over past the largest.
+    fn allocate_user_id(&mut self) -> Option<UserId> {
+        let accounts: HashSet<_> = match self.store.list_accounts() {
+            Ok(accounts) => accounts.into_iter().map(|a| a.user_id).collect(),
+            Err(e) => {
+                warn!("Failed to load accounts: {e}");
+                return None;
+            }
+        };
+        for _ in 0..=u16::MAX {
+            let Some(user_id) = self.user_id_gen.get().map(UserId) else {
+                self.user_id_gen = IdGen::starting_after(NO_USER.0);
+                continue;
+            };
+            let taken = accounts.contains(&user_id)
+                || self.clients.iter().any(|c| c.user_id() == user_id)
+                || self.bridged_users.values().any(|&id| id == user_id)
+                || self.remote_ids.has_user(user_id);
+            if !taken {
+                return Some(user_id);
+            }
+        }
+        None
+    }
+
+    fn name_taken(&self, name: &str) -> bool {
+        self.clients.iter().any(|c| c.name() == Some(name))
+            || self.bridged_users.contains_key(name)
+    }
+
+    /// Takes up to [`COMMAND_BUDGET`] commands from every client, one per
+    /// client per round, starting with a different client every tick so a
+    /// chatty client can't crowd out the others.
+    fn poll_clients(&mut self) -> Vec<(usize, ClientCommand)> {
+        let len = self.clients.len();
+        if len == 0 {
+            return vec![];
+        }
+        self.poll_offset = (self.poll_offset + 1) % len;
+        let polled: Vec<_> =
+            in_parallel(&mut self.clients, self.config.io_threads, |clients| {
+                clients
+                    .iter_mut()
+                    .map(|c| {
+                        (0..COMMAND_BUDGET).map_while(|_| c.poll()).collect()
+                    })
+                    .collect::<Vec<Vec<_>>>()
+            })
+            .into_iter()
+            .flatten()
+            .collect();
+        // clients over the budget get the rest of their turn in the next
+        // tick, their sockets won't tell about what was already read
+        self.busy |= polled.iter().any(|c| c.len() == COMMAND_BUDGET);
+        // taking turns, so no client's commands all go first
+        let mut polled: Vec<_> =
+            polled.into_iter().map(Vec::into_iter).collect();
+        let mut commands = vec![];
+        for _ in 0..COMMAND_BUDGET {
+            for index in (0..len).map(|i| (i + self.poll_offset) % len) {
+                if let Some(command) = polled[index].next() {
+                    commands.push((index, command));
+                }
+            }
+        }
+        commands
+    }
+
+    /// Disconnects the client with the most traffic since the last call.
+    fn disconnect_heaviest(&mut self) {
+        let heaviest = self
+            .clients
+            .iter_mut()
+            .map(|c| (c.sample_traffic(), c))
+            .max_by_key(|(traffic, _)| *traffic);
+        if let Some((traffic, client)) = heaviest {
+            warn!(
+                event = "load_shedding", user_id = client.user_id().0, traffic;
+                "Shedding user {} ({traffic} bytes since last check)",
+                client.user_id()
+            );
+            client.disconnect("load_shedding", None);
+        }
+    }
+
+    /// Disconnects the clients with more bytes waiting to be written to
+    /// them than the configured limit.
+    fn evict_slow_consumers(&mut self) {
+        let Some(limit) = self.config.max_queued_bytes else {
+            return;
+        };
+        for client in &mut self.clients {
+            let queued = client.stats().bytes_queued;
+            if client.connected() && queued > limit {
+                warn!(
+                    event = "slow_consumer",
+                    user_id = client.user_id().0, queued;
+                    "Evicting user {}, {queued} bytes are waiting for them",
+                    client.user_id()
+                );
+                client.disconnect(
+                    "slow_consumer",
+                    Some(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("over {limit} bytes queued"),
+                    )),
+                );
+                self.metrics.count_eviction();
+            }
+        }
+    }
+
+    fn poll_listeners(&mut self) -> Result<()> {
+        // leave new connections waiting in the backlog until we recover
+        if self.load.shedding() {
+            return Ok(());
+        }
+        for index in 0..self.listeners.len() {
+            let listener = &self.listeners[index];
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                // HACK: this error might not be fatal
+                Err(e) => return Err(e),
+            };
+            // the listener only reports new connections once, there might
+            // be more waiting
+            self.busy = true;
+            if let Ok(addr) = stream.peer_addr() {
+                if self.banned(&Ban::Ip(addr.ip())) {
+                    info!(
+                        event = "banned_address", addr:% = addr;
+                        "Refusing banned address {}", addr.ip()
+                    );
+                    continue;
+                }
+            }
+            let listener = &self.listeners[index];
+            let name = &listener.config.name;
+            let connections = self
+                .clients
+                .iter()
+                .filter(|c| c.listener() == index)
+                .count();
+            if listener
+                .config
+                .max_connections
+                .is_some_and(|max| connections >= max)
+            {
+                // dropping the stream closes the connection
+                info!(
+                    event = "listener_full", listener = name.as_str();
+                    "Listener {name} is full, refusing a connection"
+                );
+                self.metrics.count_connection(name, false);
+                continue;
+            }
+            self.metrics.count_connection(name, true);
+            if let Err(e) = self.admit(index, stream) {
+                // dropping the stream closes just this connection
+                let name = &self.listeners[index].config.name;
+                warn!(
+                    event = "accept_failed", listener = name.as_str();
+                    "Failed to accept a connection on {name}: {e}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets up a connection accepted by listener `index` and adds its
+    /// client.
+    fn admit(&mut self, index: usize, stream: TcpStream) -> Result<()> {
+        let protocol = self.listeners[index].config.protocol;
+        // closing the socket unregisters it
+        self.poll.registry().register(
+            &mut SourceFd(&stream.as_raw_fd()),
+            CLIENT,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        #[cfg(feature = "tls")]
+        let stream: Box<dyn Transport> = match &self.tls {
+            Some(tls) => {
+                Box::new(common::tls::accept(Arc::clone(tls), stream)?)
+            }
+            None => Box::new(stream),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream: Box<dyn Transport> = Box::new(stream);
+        let max_frame_size = self.config.max_frame_size;
+        let stream: Box<dyn Transport> = match protocol {
+            Protocol::Raw => stream,
+            Protocol::WebSocket => {
+                Box::new(WebSocket::accept(stream, max_frame_size))
+            }
+            Protocol::Irc => Box::new(Irc::accept(stream, max_frame_size)),
+        };
+        self.clients.push(Client::new(
+            stream,
+            NO_USER,
+            index,
+            max_frame_size,
+            &self.config.rate_limits,
+        )?);
+        Ok(())
+    }
+}
+
+/// Checks that `name` can be a user's name: not empty, at most
+/// [`MAX_NAME_LEN`] bytes, without whitespace or control characters, since
+/// names are shown in single words, in IRC prefixes and in logs.
+fn validate_name(name: &str) -> std::result::Result<(), &'static str> {
+    if name.is_empty() {
+        Err("The name is empty")
+    } else if name.len() > MAX_NAME_LEN {
+        Err("The name is too long")
+    } else if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err("The name can't contain spaces or control characters")
+    } else {
+        Ok(())
+    }
+}
+
+/// Shortens a quote to the first [`QUOTE_SNIPPET_LEN`] characters.
+fn snippet(mut quote: Quote) -> Quote {
+    if let Some((end, _)) = quote.text.char_indices().nth(QUOTE_SNIPPET_LEN) {
+        quote.text.truncate(end);
+        quote.text.push('…');
+    }
+    quote
+}
+
+/// Runs `f` on chunks of `clients` on up to `threads` threads, when there
+/// are enough of them for it to be worth it, and returns its results in
+/// the order of the chunks.
+fn in_parallel<T: Send>(
+    clients: &mut [Client],
+    threads: usize,
+    f: impl Fn(&mut [Client]) -> T + Sync,
+) -> Vec<T> {
+    let chunk_len = clients
+        .len()
+        .div_ceil(threads.max(1))
+        .max(MIN_CLIENTS_PER_THREAD);
+    if chunk_len >= clients.len() {
+        return vec![f(clients)];
+    }
+    thread::scope(|s| {
+        let f = &f;
+        let workers: Vec<_> = clients
+            .chunks_mut(chunk_len)
+            .map(|chunk| s.spawn(move || f(chunk)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use common::{Connection, MemoryTransport, DEFAULT_MAX_FRAME_SIZE};
+
+    use super::*;
+    use crate::{AuthConfig, AuthProvider, HookVerdict, PubSub};
+    use crate::storage::MemoryStore;
+
+    type TestClient = Connection<ClientCommand, ServerCommand>;
+
+    fn server(store: MemoryStore) -> Server {
+        Server::new(vec![], Config::default(), Box::new(store)).unwrap()
+    }
+
+    /// A client on an in-memory connection, from an address of its own.
+    fn accept(server: &mut Server) -> TestClient {
+        let last = u8::try_from(server.clients.len() + 1).unwrap();
+        let addr = SocketAddr::from(([192, 0, 2, last], 6969));
+        let (ours, theirs) = MemoryTransport::pair(addr);
+        server.clients.push(
+            Client::new(
+                theirs,
+                NO_USER,
+                0,
+                DEFAULT_MAX_FRAME_SIZE,
+                &server.config.rate_limits,
+            )
+            .unwrap(),
+        );
+        Connection::new(ours).unwrap()
+    }
+
+    /// Accepts a client and connects it as `name`, returning it and its
+    /// user id, with nothing left to receive.
+    fn connect(server: &mut Server, name: &str) -> (TestClient, UserId) {
+        let mut client = accept(server);
+        send(
+            &mut client,
+            ClientCommand::Connect {
+                name: name.to_owned(),
+                invite: None,
+                credential: None,
+                password: None,
+            },
+        );
+        settle(server);
+        let user_id = received(&mut client)
+            .into_iter()
+            .find_map(|c| match c {
+                ServerCommand::Welcome { user_id, .. } => Some(user_id),
+                _ => None,
+            })
+            .expect("no welcome");
+        (client, user_id)
+    }
+
+    /// Updates the server until the auth and hook threads answered
+    /// everything waiting for them.
+    fn settle(server: &mut Server) {
+        server.update().unwrap();
+        while !server.pending_hooks.is_empty()
+            || server.clients.iter().any(|c| c.auth_ticket().is_some())
+        {
+            server.wait(Duration::from_millis(10)).unwrap();
+            server.update().unwrap();
+        }
+    }
+
+    fn send(client: &mut TestClient, command: ClientCommand) {
+        client.send(&command).unwrap();
+        client.flush().unwrap();
+    }
+
+    /// Everything the server sent to `client` so far.
+    fn received(client: &mut TestClient) -> Vec<ServerCommand> {
+        let mut commands = vec![];
+        loop {
+            match client.receive() {
+                Ok(command) => commands.push(command),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("receiving failed: {e}"),
+            }
+        }
+        commands
+    }
+
+    fn names(commands: &[ServerCommand]) -> Vec<&'static str> {
+        commands.iter().map(ServerCommand::name).collect()
+    }
+
+    fn shutdown(reason: &str) -> ServerCommand {
+        ServerCommand::ServerShutdown {
+            reason: reason.to_owned(),
+        }
+    }
+
+    #[test]
+    fn broadcast_all_reaches_every_connected_user() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, _) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        let mut anonymous = accept(&mut server);
+        received(&mut alice);
+        send(
+            &mut bob,
+            ClientCommand::Join {
+                name: "other".to_owned(),
+            },
+        );
+        server.update().unwrap();
+        received(&mut bob);
+        server.broadcast_all(shutdown("everyone"));
+        server.update().unwrap();
+        assert_eq!(names(&received(&mut alice)), ["server_shutdown"]);
+        assert_eq!(names(&received(&mut bob)), ["server_shutdown"]);
+        assert!(received(&mut anonymous).is_empty());
+    }
+
+    #[test]
+    fn invalid_channel_names_are_refused() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, _) = connect(&mut server, "alice");
+        let name = "no spaces".to_owned();
+        send(&mut alice, ClientCommand::Join { name });
+        server.update().unwrap();
+        let failed = received(&mut alice).into_iter().find_map(|c| match c {
+            ServerCommand::CommandFailed { command, .. } => Some(command),
+            _ => None,
+        });
+        assert_eq!(failed.as_deref(), Some("join"));
+        assert_eq!(server.clients[0].channel(), ChannelId::LOBBY);
+    }
+
+    #[test]
+    fn idle_channels_are_archived_until_joined_again() {
+        let mut server = server(MemoryStore::new());
+        server.config.archive_rooms_after = Some(ARCHIVE_CHECK_INTERVAL);
+        let (mut alice, _) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        let join = |server: &mut Server, client: &mut _, name: &str| {
+            let name = name.to_owned();
+            send(client, ClientCommand::Join { name });
+            server.update().unwrap();
+            received(client).into_iter().find_map(|c| match c {
+                ServerCommand::Joined { channel_id, .. } => Some(channel_id),
+                _ => None,
+            })
+        };
+        let idle = join(&mut server, &mut bob, "idle").unwrap();
+        join(&mut server, &mut alice, "busy").unwrap();
+        join(&mut server, &mut bob, ChannelId::LOBBY_NAME).unwrap();
+
+        let long_ago = Instant::now()
+            .checked_sub(ARCHIVE_CHECK_INTERVAL * 2)
+            .unwrap();
+        server.last_archive_check = long_ago;
+        for last in server.channel_activity.values_mut() {
+            *last = long_ago;
+        }
+        server.update().unwrap();
+        let archived: Vec<_> = received(&mut alice)
+            .into_iter()
+            .filter_map(|c| match c {
+                ServerCommand::RoomArchived { channel_id, name } => {
+                    Some((channel_id, name))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(archived, [(idle, "idle".to_owned())]);
+        let stored = server.store.list_channels().unwrap();
+        assert!(stored.iter().any(|c| c.name == "idle" && c.archived));
+        assert!(stored.iter().any(|c| c.name == "busy" && !c.archived));
+
+        assert_eq!(join(&mut server, &mut bob, "idle"), Some(idle));
+        let stored = server.store.list_channels().unwrap();
+        assert!(stored.iter().all(|c| !c.archived));
+    }
+
+    #[test]
+    fn operators_see_how_busy_each_room_is() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, _) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        for client in [&mut alice, &mut bob] {
+            let name = "rust".to_owned();
+            send(client, ClientCommand::Join { name });
+        }
+        send(
+            &mut alice,
+            ClientCommand::Message {
+                message: "hi".to_owned(),
+                content_type: ContentType::Plain,
+                quote: None,
+            },
+        );
+        settle(&mut server);
+        received(&mut alice);
+        send(&mut alice, ClientCommand::RoomStats);
+        server.update().unwrap();
+        assert_eq!(names(&received(&mut alice)), ["permission_denied"]);
+
+        server.clients[0].set_role(Role::Admin);
+        send(&mut alice, ClientCommand::RoomStats);
+        server.update().unwrap();
+        let stats = received(&mut alice).into_iter().find_map(|c| match c {
+            ServerCommand::Notice { text } => Some(text),
+            _ => None,
+        });
+        let stats = stats.unwrap();
+        let rooms: Vec<_> = stats.lines().collect();
+        assert_eq!(rooms.len(), 2, "{stats}");
+        assert!(rooms[0].starts_with("#general: 0 messages, 0 users now"));
+        assert!(rooms[1].starts_with("#rust: 1 messages, 2 users now"));
+        assert!(rooms[1].contains("1m: 1 messages from 1 users, 2 at most"));
+    }
+
+    #[test]
+    fn broadcast_channel_reaches_only_that_channel() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, _) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        let mut anonymous = accept(&mut server);
+        send(
+            &mut bob,
+            ClientCommand::Join {
+                name: "other".to_owned(),
+            },
+        );
+        server.update().unwrap();
+        let other = received(&mut bob)
+            .into_iter()
+            .find_map(|c| match c {
+                ServerCommand::Joined { channel_id, .. } => Some(channel_id),
+                _ => None,
+            })
+            .unwrap();
+        received(&mut alice);
+        server.broadcast_channel(ChannelId::LOBBY, shutdown("lobby"));
+        server.broadcast_channel(other, shutdown("other"));
+        server.update().unwrap();
+        let lobby = received(&mut alice);
+        assert!(matches!(
+            &lobby[..],
+            [ServerCommand::ServerShutdown { reason }] if reason == "lobby"
+        ));
+        let other = received(&mut bob);
+        assert!(matches!(
+            &other[..],
+            [ServerCommand::ServerShutdown { reason }] if reason == "other"
+        ));
+        // without a name, a client is in no channel, not even the lobby
+        assert!(received(&mut anonymous).is_empty());
+    }
+
+    #[test]
+    fn send_to_reaches_only_its_target() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, alice_id) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        let mut anonymous = accept(&mut server);
+        received(&mut alice);
+        assert!(server.send_to(alice_id, &shutdown("alice")));
+        assert!(!server.send_to(NO_USER, &shutdown("nobody")));
+        assert!(!server.send_to(UserId(999), &shutdown("nobody")));
+        server.update().unwrap();
+        assert_eq!(names(&received(&mut alice)), ["server_shutdown"]);
+        assert!(received(&mut bob).is_empty());
+        assert!(received(&mut anonymous).is_empty());
+    }
+
+    #[test]
+    fn a_flood_does_not_hold_up_other_clients() {
+        let mut server = server(MemoryStore::new());
+        let (mut flooder, _) = connect(&mut server, "flooder");
+        let (mut quiet, _) = connect(&mut server, "quiet");
+        received(&mut flooder);
+        for _ in 0..100 {
+            flooder.send(&ClientCommand::ListUsers).unwrap();
+        }
+        flooder.flush().unwrap();
+        send(&mut quiet, ClientCommand::ServerInfo);
+        server.update().unwrap();
+        assert_eq!(names(&received(&mut quiet)), ["server_info"]);
+        assert_eq!(received(&mut flooder).len(), COMMAND_BUDGET);
+        // the rest of the flood waits for the ticks after
+        server.update().unwrap();
+        assert_eq!(received(&mut flooder).len(), COMMAND_BUDGET);
+    }
+
+    /// Hands the bridge what the test puts in `incoming`.
+    #[derive(Debug, Default)]
+    struct FakePubSub {
+        incoming: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    }
+
+    impl PubSub for FakePubSub {
+        fn publish(&mut self, _: Vec<u8>) {}
+
+        fn poll(&mut self) -> Option<Vec<u8>> {
+            self.incoming.lock().unwrap().pop_front()
+        }
+    }
+
+    #[test]
+    fn bridged_ids_are_given_ids_of_this_instance() {
+        let mut server = server(MemoryStore::new());
+        let pubsub = FakePubSub::default();
+        let incoming = Arc::clone(&pubsub.incoming);
+        server.bridge = Some(Bridge::new(Box::new(pubsub)).unwrap());
+        let (mut alice, alice_id) = connect(&mut server, "alice");
+        received(&mut alice);
+        let remote = |command: ServerCommand| {
+            let mut payload = vec![];
+            "remote".code(&mut payload).unwrap();
+            Some(ChannelId::LOBBY_NAME.to_owned())
+                .code(&mut payload)
+                .unwrap();
+            command.code(&mut payload).unwrap();
+            incoming.lock().unwrap().push_back(payload);
+        };
+        let message = |msg_id: u32, quote: Option<Quote>| {
+            ServerCommand::Message {
+                msg_id: MsgId(msg_id),
+                user_id: alice_id,
+                channel_id: ChannelId(7),
+                message: "hi".to_owned(),
+                content_type: ContentType::Plain,
+                quote,
+                time: 0,
+            }
+        };
+        send(
+            &mut alice,
+            ClientCommand::Message {
+                message: "mine".to_owned(),
+                content_type: ContentType::Plain,
+                quote: None,
+            },
+        );
+        server.update().unwrap();
+        let msg_id = received(&mut alice)
+            .into_iter()
+            .find_map(|c| match c {
+                ServerCommand::Ack { msg_id } => Some(msg_id),
+                _ => None,
+            })
+            .unwrap();
+        // the other instance gave its user and message the same ids as
+        // alice and her message have here
+        remote(ServerCommand::AddUser {
+            user_id: alice_id,
+            name: "bob".to_owned(),
+        });
+        remote(message(msg_id.0, None));
+        remote(message(
+            msg_id.0 + 1,
+            Some(Quote {
+                msg_id,
+                user_id: alice_id,
+                text: "hi".to_owned(),
+            }),
+        ));
+        remote(ServerCommand::RemoveUser { user_id: alice_id });
+        server.update().unwrap();
+        let commands = received(&mut alice);
+        let [
+            ServerCommand::AddUser { user_id: bob, .. },
+            ServerCommand::Message {
+                msg_id: first,
+                user_id: author,
+                channel_id,
+                ..
+            },
+            ServerCommand::Message {
+                msg_id: second,
+                quote: Some(quote),
+                ..
+            },
+            ServerCommand::RemoveUser { user_id: gone },
+        ] = &commands[..]
+        else {
+            panic!("unexpected commands: {commands:?}");
+        };
+        assert_ne!(*bob, alice_id);
+        assert_eq!((*author, *gone), (*bob, *bob));
+        assert_eq!(*channel_id, ChannelId::LOBBY);
+        assert!(msg_id < *first && *first < *second);
+        assert_eq!((quote.msg_id, quote.user_id), (*first, *bob));
+        // the id is free again once the user left
+        assert!(!server.remote_ids.has_user(*bob));
+    }
+
+    #[test]
+    fn clients_without_a_name_can_only_connect() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, _) = connect(&mut server, "alice");
+        let mut anonymous = accept(&mut server);
+        received(&mut alice);
+        send(
+            &mut anonymous,
+            ClientCommand::Message {
+                message: "hello".to_owned(),
+                content_type: ContentType::Plain,
+                quote: None,
+            },
+        );
+        server.update().unwrap();
+        assert_eq!(names(&received(&mut anonymous)), ["command_failed"]);
+        assert!(received(&mut alice).is_empty());
+    }
+
+    #[test]
+    fn user_ids_skip_accounts_and_start_over() {
+        let mut store = MemoryStore::new();
+        for (name, user_id) in [("high", u16::MAX), ("low", 1)] {
+            store
+                .put_account(&Account {
+                    name: name.to_owned(),
+                    user_id: UserId(user_id),
+                    password: PasswordHash::new("secret").unwrap(),
+                    role: Role::User,
+                })
+                .unwrap();
+        }
+        let mut server = server(store);
+        server.user_id_gen = IdGen::starting_after(u16::MAX - 2);
+        assert_eq!(server.allocate_user_id(), Some(UserId(u16::MAX - 1)));
+        // past the largest, skipping the account with 1
+        assert_eq!(server.allocate_user_id(), Some(UserId(2)));
+    }
+
+    /// Shouts every message.
+    #[derive(Debug)]
+    struct Shout;
+
+    impl Hook for Shout {
+        fn on_message(
+            &mut self,
+            _: UserId,
+            _: &str,
+            message: &str,
+            _: &mut Vec<String>,
+        ) -> HookVerdict {
+            HookVerdict::Replace(message.to_uppercase())
+        }
+    }
+
+    /// Takes its time over every message.
+    #[derive(Debug)]
+    struct Slow;
+
+    impl Hook for Slow {
+        fn on_message(
+            &mut self,
+            _: UserId,
+            _: &str,
+            _: &str,
+            _: &mut Vec<String>,
+        ) -> HookVerdict {
+            thread::sleep(Duration::from_secs(1));
+            HookVerdict::Accept
+        }
+    }
+
+    #[test]
+    fn others_are_served_while_the_hooks_run() {
+        let mut server = server(MemoryStore::new());
+        server.add_hook(Box::new(Slow));
+        let (mut alice, _) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        received(&mut alice);
+        let start = Instant::now();
+        let message = "hi".to_owned();
+        send(
+            &mut alice,
+            ClientCommand::Message {
+                message,
+                content_type: ContentType::Plain,
+                quote: None,
+            },
+        );
+        server.update().unwrap();
+        send(&mut bob, ClientCommand::ListUsers);
+        server.update().unwrap();
+        assert_eq!(names(&received(&mut bob)), ["users"]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        settle(&mut server);
+        assert_eq!(names(&received(&mut alice)), ["ack", "message"]);
+        assert_eq!(names(&received(&mut bob)), ["message"]);
+    }
+
+    #[test]
+    fn whispers_are_hooked_and_kept_for_offline_accounts() {
+        let mut store = MemoryStore::new();
+        store
+            .put_account(&Account {
+                name: "bob".to_owned(),
+                user_id: UserId(42),
+                password: PasswordHash::new("secret").unwrap(),
+                role: Role::User,
+            })
+            .unwrap();
+        let mut server = server(store);
+        server.add_hook(Box::new(Shout));
+        let (mut alice, alice_id) = connect(&mut server, "alice");
+        let (name, message) = ("bob".to_owned(), "psst".to_owned());
+        send(&mut alice, ClientCommand::OfflineWhisper { name, message });
+        settle(&mut server);
+        assert_eq!(server.store.count_offline_whispers(UserId(42)).unwrap(), 1);
+
+        let mut bob = accept(&mut server);
+        send(
+            &mut bob,
+            ClientCommand::Login {
+                name: "bob".to_owned(),
+                password: "secret".to_owned(),
+            },
+        );
+        send(
+            &mut bob,
+            ClientCommand::Connect {
+                name: "bob".to_owned(),
+                invite: None,
+                credential: None,
+                password: None,
+            },
+        );
+        settle(&mut server);
+        let kept = received(&mut bob).into_iter().find_map(|c| match c {
+            ServerCommand::OfflineWhisper { message, .. } => Some(message),
+            _ => None,
+        });
+        assert_eq!(kept.as_deref(), Some("PSST"));
+        assert_eq!(server.store.count_offline_whispers(UserId(42)).unwrap(), 0);
+
+        let message = "hello".to_owned();
+        let target_user_id = alice_id;
+        send(&mut bob, ClientCommand::Whisper { target_user_id, message });
+        settle(&mut server);
+        let whispered = received(&mut alice).into_iter().find_map(|c| match c {
+            ServerCommand::Whisper { message, .. } => Some(message),
+            _ => None,
+        });
+        assert_eq!(whispered.as_deref(), Some("HELLO"));
+    }
+
+    #[test]
+    fn overlong_whispers_are_refused() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, alice_id) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        received(&mut alice);
+        let message = "a".repeat(MAX_MESSAGE_LEN + 1);
+        let target_user_id = alice_id;
+        send(&mut bob, ClientCommand::Whisper { target_user_id, message });
+        settle(&mut server);
+        let failed = received(&mut bob).into_iter().find_map(|c| match c {
+            ServerCommand::CommandFailed { command, .. } => Some(command),
+            _ => None,
+        });
+        assert_eq!(failed.as_deref(), Some("whisper"));
+        assert!(received(&mut alice).is_empty());
+    }
+
+    #[test]
+    fn only_those_who_whispered_learn_they_were_read() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, alice_id) = connect(&mut server, "alice");
+        let (mut bob, bob_id) = connect(&mut server, "bob");
+        received(&mut alice);
+        let read = ClientCommand::MarkWhispersRead { user_id: alice_id };
+        send(&mut bob, read.clone());
+        settle(&mut server);
+        assert!(received(&mut alice).is_empty());
+
+        let message = "psst".to_owned();
+        let target_user_id = bob_id;
+        send(&mut alice, ClientCommand::Whisper { target_user_id, message });
+        settle(&mut server);
+        received(&mut alice);
+        received(&mut bob);
+        send(&mut bob, read.clone());
+        send(&mut bob, read);
+        settle(&mut server);
+        assert_eq!(names(&received(&mut alice)), ["whispers_read"]);
+    }
+
+    #[test]
+    fn malformed_encrypted_whispers_are_refused() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, alice_id) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        received(&mut alice);
+        let target_user_id = alice_id;
+        let public_key = Bytes(vec![0; X25519_KEY_LEN - 1]);
+        send(
+            &mut bob,
+            ClientCommand::KeyExchange {
+                target_user_id,
+                public_key,
+            },
+        );
+        let ciphertext = Bytes(vec![0; MAX_MESSAGE_LEN + AEAD_TAG_LEN + 1]);
+        send(
+            &mut bob,
+            ClientCommand::EncryptedWhisper {
+                target_user_id,
+                counter: 0,
+                ciphertext,
+            },
+        );
+        settle(&mut server);
+        let failed: Vec<_> = received(&mut bob)
+            .into_iter()
+            .filter_map(|c| match c {
+                ServerCommand::CommandFailed { command, .. } => Some(command),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(failed, ["key_exchange", "encrypted_whisper"]);
+        assert!(received(&mut alice).is_empty());
+    }
+
+    /// Wakes nobody, the tests wait with a timeout.
+    fn wake() -> Wake {
+        Arc::new(|| ())
+    }
+
+    /// Lets in whoever has the token.
+    #[derive(Debug)]
+    struct Token(&'static str);
+
+    impl AuthProvider for Token {
+        fn authenticate(
+            &mut self,
+            _: &str,
+            credential: Option<&str>,
+        ) -> Result<bool> {
+            Ok(credential == Some(self.0))
+        }
+    }
+
+    #[test]
+    fn others_are_served_while_a_name_is_checked() {
+        let mut server = server(MemoryStore::new());
+        let (mut alice, _) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        received(&mut alice);
+        let slow = AuthConfig::Command("sleep 1".to_owned()).open().unwrap();
+        server.auth = AuthWorker::spawn(slow, wake());
+        let start = Instant::now();
+        let mut carol = accept(&mut server);
+        send(
+            &mut carol,
+            ClientCommand::Connect {
+                name: "carol".to_owned(),
+                invite: None,
+                credential: None,
+                password: None,
+            },
+        );
+        server.update().unwrap();
+        let message = "hi".to_owned();
+        send(
+            &mut alice,
+            ClientCommand::Message {
+                message,
+                content_type: ContentType::Plain,
+                quote: None,
+            },
+        );
+        server.update().unwrap();
+        assert_eq!(names(&received(&mut bob)), ["message"]);
+        assert!(received(&mut carol).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        settle(&mut server);
+        assert_eq!(names(&received(&mut carol))[0], "welcome");
+    }
+
+    #[test]
+    fn renaming_keeps_the_credential_and_the_role() {
+        let mut store = MemoryStore::new();
+        let role = Role::Moderator;
+        let user = UserRecord {
+            name: "alice".to_owned(),
+            role,
+        };
+        store.put_user(&user).unwrap();
+        let mut server = server(store);
+        server.auth = AuthWorker::spawn(Box::new(Token("secret")), wake());
+        let mut alice = accept(&mut server);
+        send(
+            &mut alice,
+            ClientCommand::Connect {
+                name: "alice".to_owned(),
+                invite: None,
+                credential: Some("secret".to_owned()),
+                password: None,
+            },
+        );
+        settle(&mut server);
+        received(&mut alice);
+        let new_name = "bob".to_owned();
+        send(&mut alice, ClientCommand::Rename { new_name });
+        settle(&mut server);
+        assert_eq!(names(&received(&mut alice)), ["user_renamed"]);
+        let store = &server.store;
+        assert!(store.get_user("alice").unwrap().is_none());
+        assert_eq!(store.get_user("bob").unwrap().map(|u| u.role), Some(role));
+    }
+
+    #[test]
+    fn connecting_again_does_not_rename() {
+        let mut store = MemoryStore::new();
+        let user = UserRecord {
+            name: "boss".to_owned(),
+            role: Role::Admin,
+        };
+        store.put_user(&user).unwrap();
+        let mut server = server(store);
+        let (mut alice, _) = connect(&mut server, "alice");
+        let (mut bob, _) = connect(&mut server, "bob");
+        received(&mut alice);
+        send(
+            &mut alice,
+            ClientCommand::Connect {
+                name: "boss".to_owned(),
+                invite: None,
+                credential: None,
+                password: None,
+            },
+        );
+        server.update().unwrap();
+        assert_eq!(names(&received(&mut alice)), ["command_failed"]);
+        assert!(received(&mut bob).is_empty());
+        assert_eq!(server.clients[0].name(), Some("alice"));
+        assert_eq!(server.clients[0].role(), Role::User);
+        assert_eq!(server.store.get_user("boss").unwrap(), Some(user));
+    }
+
+    #[test]
+    fn refused_connections_keep_the_invite() {
+        let mut store = MemoryStore::new();
+        let token = create_invite(&mut store, Some(1), None).unwrap();
+        // every user id is taken
+        let password = PasswordHash::new("secret").unwrap();
+        for id in 1..=u16::MAX {
+            let account = Account {
+                name: format!("user{id}"),
+                user_id: UserId(id),
+                password: password.clone(),
+                role: Role::User,
+            };
+            store.put_account(&account).unwrap();
+        }
+        let mut server = server(store);
+        server.config.invite_only = true;
+        let connect_with = |server: &mut Server, name: &str| {
+            let mut client = accept(server);
+            send(
+                &mut client,
+                ClientCommand::Connect {
+                    name: name.to_owned(),
+                    invite: Some(token.clone()),
+                    credential: None,
+                    password: None,
+                },
+            );
+            settle(server);
+            names(&received(&mut client))
+        };
+        assert_eq!(connect_with(&mut server, "alice"), ["connect_rejected"]);
+        assert!(server.store.delete_account("user1").unwrap());
+        assert_eq!(connect_with(&mut server, "bob")[0], "welcome");
+        assert_eq!(server.store.get_invite(&token).unwrap(), None);
+        assert_eq!(connect_with(&mut server, "carol"), ["connect_rejected"]);
+    }
+
+    /// Accepts a client, logs it in to `name` with `password` and connects
+    /// it under that name.
+    fn log_in(server: &mut Server, name: &str, password: &str) -> TestClient {
+        let mut client = accept(server);
+        send(
+            &mut client,
+            ClientCommand::Login {
+                name: name.to_owned(),
+                password: password.to_owned(),
+            },
+        );
+        send(
+            &mut client,
+            ClientCommand::Connect {
+                name: name.to_owned(),
+                invite: None,
+                credential: None,
+                password: None,
+            },
+        );
+        settle(server);
+        client
+    }
+
+    #[test]
+    fn only_checked_names_get_their_role() {
+        let mut store = MemoryStore::new();
+        for name in ["root", "mod"] {
+            let user = UserRecord {
+                name: name.to_owned(),
+                role: Role::Moderator,
+            };
+            store.put_user(&user).unwrap();
+        }
+        store
+            .put_account(&Account {
+                name: "mod".to_owned(),
+                user_id: UserId(42),
+                password: PasswordHash::new("secret").unwrap(),
+                role: Role::Moderator,
+            })
+            .unwrap();
+        let mut server = server(store);
+        server.config.admins = vec!["root".to_owned()];
+        // nothing checks the name without --auth
+        connect(&mut server, "root");
+        assert_eq!(server.clients[0].role(), Role::User);
+        log_in(&mut server, "mod", "secret");
+        assert_eq!(server.clients[1].role(), Role::Moderator);
+        assert_eq!(server.clients[1].identity(), Identity::Account);
+        // the role stays with the account
+        server.clients[0].set_role(Role::Admin);
+        server.set_role(0, UserId(42), Role::User);
+        let account = server.store.get_account("mod").unwrap().unwrap();
+        assert_eq!(account.role, Role::User);
+    }
+
+    #[test]
+    fn names_are_single_printable_words() {
+        assert!(validate_name("alice").is_ok());
+        assert!(validate_name("Ünïcødé_42").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("two words").is_err());
+        assert!(validate_name("tab\there").is_err());
+        assert!(validate_name("crlf\r\nQUIT").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN)).is_ok());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
 }
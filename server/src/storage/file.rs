@@ -0,0 +1,460 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{
+    BufReader, BufWriter, Error, ErrorKind, Read, Result, Write,
+};
+use std::path::{Path, PathBuf};
+
+use common::commands::{Role, ServerCommand};
+use common::{ChannelId, Codec, MsgId, UserId};
+use log::warn;
+
+use super::{
+    Account, Ban, ChannelRecord, Invite, MessageLog, OfflineWhisper,
+    SearchPage, Store, UserRecord,
+};
+
+const MESSAGES_FILE: &str = "messages.log";
+const USERS_FILE: &str = "users";
+const ACCOUNTS_FILE: &str = "accounts";
+const BANS_FILE: &str = "bans";
+const CHANNELS_FILE: &str = "channels";
+const INVITES_FILE: &str = "invites";
+const WHISPERS_FILE: &str = "whispers";
+
+/// A store keeping its data in a directory.
+///
+/// Every file is a sequence of records, each prefixed by its size as a
+/// big-endian `u16`. Messages are appended to `messages.log` as coded
+/// [`ServerCommand`]s behind a version; `users`, `accounts`, `bans`,
+/// `channels`, `invites` and `whispers` are rewritten whole whenever they
+/// change.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    messages: MessageLog,
+    users: BTreeMap<String, UserRecord>,
+    accounts: BTreeMap<String, Account>,
+    bans: HashSet<Ban>,
+    channels: BTreeMap<String, ChannelRecord>,
+    invites: BTreeMap<String, Invite>,
+    /// Waiting for offline accounts, by their user id.
+    whispers: BTreeMap<UserId, Vec<OfflineWhisper>>,
+}
+
+impl FileStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let messages = MessageLog::open(&dir.join(MESSAGES_FILE), None)?;
+        let users = read_records::<UserRecord>(&dir.join(USERS_FILE))?
+            .into_iter()
+            .map(|u| (u.name.clone(), u))
+            .collect();
+        let accounts = read_records::<Account>(&dir.join(ACCOUNTS_FILE))?
+            .into_iter()
+            .map(|a| (a.name.clone(), a))
+            .collect();
+        let bans = read_records::<Ban>(&dir.join(BANS_FILE))?
+            .into_iter()
+            .collect();
+        let channels = read_records::<ChannelRecord>(&dir.join(CHANNELS_FILE))?
+            .into_iter()
+            .map(|c| (c.name.clone(), c))
+            .collect();
+        let invites = read_records::<Invite>(&dir.join(INVITES_FILE))?
+            .into_iter()
+            .map(|i| (i.token.clone(), i))
+            .collect();
+        let mut whispers = BTreeMap::<_, Vec<_>>::new();
+        for whisper in read_records::<OfflineWhisper>(&dir.join(WHISPERS_FILE))?
+        {
+            whispers
+                .entry(whisper.target_user_id)
+                .or_default()
+                .push(whisper);
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+            messages,
+            users,
+            accounts,
+            bans,
+            channels,
+            invites,
+            whispers,
+        })
+    }
+
+    fn save_users(&self) -> Result<()> {
+        write_records(&self.dir.join(USERS_FILE), self.users.values())
+    }
+
+    fn save_accounts(&self) -> Result<()> {
+        write_records(&self.dir.join(ACCOUNTS_FILE), self.accounts.values())
+    }
+
+    fn save_bans(&self) -> Result<()> {
+        write_records(&self.dir.join(BANS_FILE), self.bans.iter())
+    }
+
+    fn save_channels(&self) -> Result<()> {
+        write_records(&self.dir.join(CHANNELS_FILE), self.channels.values())
+    }
+
+    fn save_invites(&self) -> Result<()> {
+        write_records(&self.dir.join(INVITES_FILE), self.invites.values())
+    }
+
+    fn save_whispers(&self) -> Result<()> {
+        write_records(
+            &self.dir.join(WHISPERS_FILE),
+            self.whispers.values().flatten(),
+        )
+    }
+}
+
+impl Store for FileStore {
+    fn append_message(&mut self, message: &ServerCommand) -> Result<()> {
+        self.messages.append(message)
+    }
+
+    fn load_history(&self, limit: usize) -> Result<Vec<ServerCommand>> {
+        self.messages.load(limit)
+    }
+
+    fn search_history(
+        &self,
+        channel_id: ChannelId,
+        query: &str,
+        before_msg_id: MsgId,
+        limit: usize,
+    ) -> Result<SearchPage> {
+        self.messages
+            .search(channel_id, query, before_msg_id, limit)
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<UserRecord>> {
+        Ok(self.users.get(name).cloned())
+    }
+
+    fn put_user(&mut self, user: &UserRecord) -> Result<()> {
+        self.users.insert(user.name.clone(), user.clone());
+        self.save_users()
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        let existed = self.users.remove(name).is_some();
+        if existed {
+            self.save_users()?;
+        }
+        Ok(existed)
+    }
+
+    fn list_users(&self) -> Result<Vec<UserRecord>> {
+        Ok(self.users.values().cloned().collect())
+    }
+
+    fn get_account(&self, name: &str) -> Result<Option<Account>> {
+        Ok(self.accounts.get(name).cloned())
+    }
+
+    fn put_account(&mut self, account: &Account) -> Result<()> {
+        self.accounts.insert(account.name.clone(), account.clone());
+        self.save_accounts()
+    }
+
+    fn delete_account(&mut self, name: &str) -> Result<bool> {
+        let existed = self.accounts.remove(name).is_some();
+        if existed {
+            self.save_accounts()?;
+        }
+        Ok(existed)
+    }
+
+    fn list_accounts(&self) -> Result<Vec<Account>> {
+        Ok(self.accounts.values().cloned().collect())
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> Result<()> {
+        if self.bans.insert(ban.clone()) {
+            self.save_bans()?;
+        }
+        Ok(())
+    }
+
+    fn remove_ban(&mut self, ban: &Ban) -> Result<bool> {
+        let existed = self.bans.remove(ban);
+        if existed {
+            self.save_bans()?;
+        }
+        Ok(existed)
+    }
+
+    fn list_bans(&self) -> Result<Vec<Ban>> {
+        Ok(self.bans.iter().cloned().collect())
+    }
+
+    fn put_channel(&mut self, channel: &ChannelRecord) -> Result<()> {
+        self.channels.insert(channel.name.clone(), channel.clone());
+        self.save_channels()
+    }
+
+    fn list_channels(&self) -> Result<Vec<ChannelRecord>> {
+        Ok(self.channels.values().cloned().collect())
+    }
+
+    fn put_invite(&mut self, invite: &Invite) -> Result<()> {
+        self.invites.insert(invite.token.clone(), invite.clone());
+        self.save_invites()
+    }
+
+    fn get_invite(&self, token: &str) -> Result<Option<Invite>> {
+        Ok(self.invites.get(token).cloned())
+    }
+
+    fn delete_invite(&mut self, token: &str) -> Result<bool> {
+        let existed = self.invites.remove(token).is_some();
+        if existed {
+            self.save_invites()?;
+        }
+        Ok(existed)
+    }
+
+    fn push_offline_whisper(&mut self, whisper: &OfflineWhisper) -> Result<()> {
+        let waiting = self.whispers.entry(whisper.target_user_id).or_default();
+        waiting.push(whisper.clone());
+        self.save_whispers()
+    }
+
+    fn count_offline_whispers(&self, target_user_id: UserId) -> Result<usize> {
+        Ok(self.whispers.get(&target_user_id).map_or(0, Vec::len))
+    }
+
+    fn take_offline_whispers(
+        &mut self,
+        target_user_id: UserId,
+    ) -> Result<Vec<OfflineWhisper>> {
+        let whispers = self.whispers.remove(&target_user_id);
+        if whispers.is_some() {
+            self.save_whispers()?;
+        }
+        Ok(whispers.unwrap_or_default())
+    }
+}
+
+/// Writes a record with its size in front, all of it or nothing, failing
+/// with [`ErrorKind::InvalidData`] if it is too large for the size.
+pub(super) fn write_record<T: Codec + ?Sized>(
+    w: &mut impl Write,
+    record: &T,
+) -> Result<()> {
+    let size = u16::try_from(record.coded_size()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "a record of {} bytes is over the limit of {}",
+                record.coded_size(),
+                u16::MAX
+            ),
+        )
+    })?;
+    let mut buf = Vec::with_capacity(size_of::<u16>() + usize::from(size));
+    size.code(&mut buf)?;
+    record.code(&mut buf)?;
+    w.write_all(&buf)
+}
+
+/// Reads every record of a file, which is treated as empty if it doesn't
+/// exist. A record cut short by a crash is skipped with a warning.
+pub(super) fn read_records<T: Codec<Owned = T>>(path: &Path) -> Result<Vec<T>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut r = BufReader::new(file);
+    let mut records = vec![];
+    loop {
+        let size = match u16::decode(&mut r) {
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let mut buf = vec![0; size.into()];
+        if let Err(e) = r.read_exact(&mut buf) {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                warn!(
+                    "Ignoring truncated record at the end of {}",
+                    path.display()
+                );
+                break;
+            }
+            return Err(e);
+        }
+        records.push(T::decode(&mut buf.as_slice())?);
+    }
+    Ok(records)
+}
+
+/// Replaces a file with the given records, so that a crash leaves either
+/// the old or the new version.
+fn write_records<'a, T: Codec + 'a>(
+    path: &Path,
+    records: impl Iterator<Item = &'a T>,
+) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    for record in records {
+        write_record(&mut w, record)?;
+    }
+    w.into_inner()?.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+impl Codec for UserRecord {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.name.code(w)?;
+        self.role.code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        Ok(Self {
+            name: str::decode(r)?,
+            role: Role::decode(r)?,
+        })
+    }
+
+    fn coded_size(&self) -> usize {
+        self.name.coded_size() + self.role.coded_size()
+    }
+}
+
+impl Codec for Account {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.name.code(w)?;
+        self.user_id.code(w)?;
+        self.password.to_string().code(w)?;
+        self.role.code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        Ok(Self {
+            name: str::decode(r)?,
+            user_id: UserId::decode(r)?,
+            password: str::decode(r)?.parse().map_err(|e: String| {
+                std::io::Error::new(ErrorKind::InvalidData, e)
+            })?,
+            // records from before roles end here
+            role: match Role::decode(r) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => Role::User,
+                role => role?,
+            },
+        })
+    }
+
+    fn coded_size(&self) -> usize {
+        self.name.coded_size()
+            + self.user_id.coded_size()
+            + self.password.to_string().coded_size()
+            + self.role.coded_size()
+    }
+}
+
+impl Codec for Ban {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.to_string().code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        str::decode(r)?
+            .parse()
+            .map_err(|e: String| std::io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn coded_size(&self) -> usize {
+        self.to_string().coded_size()
+    }
+}
+
+impl Codec for ChannelRecord {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.name.code(w)?;
+        self.channel_id.code(w)?;
+        self.topic.code(w)?;
+        self.archived.code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        Ok(Self {
+            name: str::decode(r)?,
+            channel_id: ChannelId::decode(r)?,
+            // records from before topics end here
+            topic: match str::decode(r) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => String::new(),
+                topic => topic?,
+            },
+            // and those from before archiving here
+            archived: match bool::decode(r) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => false,
+                archived => archived?,
+            },
+        })
+    }
+
+    fn coded_size(&self) -> usize {
+        self.name.coded_size()
+            + self.channel_id.coded_size()
+            + self.topic.coded_size()
+            + self.archived.coded_size()
+    }
+}
+
+impl Codec for Invite {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.token.code(w)?;
+        self.uses_left.code(w)?;
+        self.expires.code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        Ok(Self {
+            token: str::decode(r)?,
+            uses_left: Option::decode(r)?,
+            expires: Option::decode(r)?,
+        })
+    }
+
+    fn coded_size(&self) -> usize {
+        self.token.coded_size()
+            + self.uses_left.coded_size()
+            + self.expires.coded_size()
+    }
+}
+
+impl Codec for OfflineWhisper {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        self.target_user_id.code(w)?;
+        self.user_id.code(w)?;
+        self.name.code(w)?;
+        self.message.code(w)?;
+        self.time.code(w)
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        Ok(Self {
+            target_user_id: UserId::decode(r)?,
+            user_id: UserId::decode(r)?,
+            name: str::decode(r)?,
+            message: str::decode(r)?,
+            time: u64::decode(r)?,
+        })
+    }
+
+    fn coded_size(&self) -> usize {
+        self.target_user_id.coded_size()
+            + self.user_id.coded_size()
+            + self.name.coded_size()
+            + self.message.coded_size()
+            + self.time.coded_size()
+    }
+}
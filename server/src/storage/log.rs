@@ -0,0 +1,703 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{
+    BufReader, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom,
+    Write,
+};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use common::commands::{ContentType, Quote, ServerCommand};
+use common::{ChannelId, Codec, MsgId, UserId};
+use log::{info, warn};
+
+use super::file::write_record;
+use super::{
+    search_newest_first, Account, Ban, ChannelRecord, Invite,
+    OfflineWhisper, SearchPage, Store, UserRecord,
+};
+
+/// When a [`MessageLog`] starts a new file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Size in bytes past which the log is rotated.
+    pub max_size: u64,
+    /// Number of rotated files kept, as `<path>.1` (the newest) up to
+    /// `<path>.<keep>`.
+    pub keep: usize,
+}
+
+/// Records a [`MessageLog`] reads at a time, and between the entries of
+/// its index.
+const CHUNK_RECORDS: usize = 256;
+
+/// An append-only file of coded [`ServerCommand`]s, optionally rotated by
+/// size.
+///
+/// Where every [`CHUNK_RECORDS`] records start is kept in memory, so that
+/// reading the latest messages, or searching, only reads the chunks it
+/// gets to.
+#[derive(Debug)]
+pub struct MessageLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    rotation: Option<Rotation>,
+    /// Of the current file first, then of the rotated ones.
+    index: Vec<FileIndex>,
+}
+
+/// Where a chunk of a file of a [`MessageLog`] starts.
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    offset: u64,
+    first_msg_id: MsgId,
+}
+
+/// The chunks of a file of a [`MessageLog`].
+#[derive(Debug, Default)]
+struct FileIndex {
+    chunks: Vec<Chunk>,
+    /// Records in the last chunk.
+    last_len: usize,
+}
+
+impl FileIndex {
+    fn build(path: &Path) -> Result<Self> {
+        let mut index = Self::default();
+        for (offset, message) in read_messages(path, 0, None)? {
+            index.add(offset, &message);
+        }
+        Ok(index)
+    }
+
+    fn add(&mut self, offset: u64, message: &ServerCommand) {
+        if self.chunks.is_empty() || self.last_len == CHUNK_RECORDS {
+            let first_msg_id = match message {
+                ServerCommand::Message { msg_id, .. } => *msg_id,
+                _ => MsgId(0),
+            };
+            self.chunks.push(Chunk {
+                offset,
+                first_msg_id,
+            });
+            self.last_len = 0;
+        }
+        self.last_len += 1;
+    }
+
+    /// The chunks with where the next one starts, `None` for the last.
+    fn ranges(&self) -> Vec<(Chunk, Option<u64>)> {
+        let ends = self.chunks.iter().skip(1).map(|c| Some(c.offset));
+        self.chunks
+            .iter()
+            .copied()
+            .zip(ends.chain(iter::once(None)))
+            .collect()
+    }
+}
+
+impl MessageLog {
+    pub fn open(path: &Path, rotation: Option<Rotation>) -> Result<Self> {
+        let mut index = vec![FileIndex::build(path)?];
+        for n in 1..=rotation.map_or(0, |r| r.keep) {
+            index.push(FileIndex::build(&rotated(path, n))?);
+        }
+        Self::open_indexed(path, rotation, index)
+    }
+
+    fn open_indexed(
+        path: &Path,
+        rotation: Option<Rotation>,
+        index: Vec<FileIndex>,
+    ) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            size: file.metadata()?.len(),
+            file: BufWriter::new(file),
+            rotation,
+            index,
+        })
+    }
+
+    pub fn append(&mut self, message: &ServerCommand) -> Result<()> {
+        let record = StoredMessage(message.clone());
+        let size = (size_of::<u16>() + record.coded_size()) as u64;
+        if let Some(rotation) = self.rotation {
+            if self.size > 0 && self.size + size > rotation.max_size {
+                self.rotate(rotation.keep)?;
+            }
+        }
+        write_record(&mut self.file, &record)?;
+        self.index[0].add(self.size, message);
+        self.size += size;
+        self.file.flush()
+    }
+
+    /// Returns the latest `limit` messages, oldest first, reading rotated
+    /// files as far back as needed.
+    pub fn load(&self, limit: usize) -> Result<Vec<ServerCommand>> {
+        let mut messages = self
+            .newest_first(MsgId::MAX)
+            .take(limit)
+            .collect::<Result<Vec<_>>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Searches the messages like [`Store::search_history`], reading
+    /// rotated files as far back as needed.
+    pub fn search(
+        &self,
+        channel_id: ChannelId,
+        query: &str,
+        before_msg_id: MsgId,
+        limit: usize,
+    ) -> Result<SearchPage> {
+        search_newest_first(
+            self.newest_first(before_msg_id),
+            channel_id,
+            query,
+            before_msg_id,
+            limit,
+        )
+    }
+
+    /// The messages of every file, newest first, read a chunk at a time
+    /// and skipping the chunks starting at `before_msg_id` or later.
+    fn newest_first(
+        &self,
+        before_msg_id: MsgId,
+    ) -> impl Iterator<Item = Result<ServerCommand>> + '_ {
+        self.index
+            .iter()
+            .enumerate()
+            .flat_map(move |(n, index)| {
+                let path = self.file(n);
+                index
+                    .ranges()
+                    .into_iter()
+                    .rev()
+                    .filter(move |(chunk, _)| {
+                        chunk.first_msg_id < before_msg_id
+                    })
+                    .map(move |(chunk, end)| {
+                        read_messages(&path, chunk.offset, end)
+                    })
+            })
+            .flat_map(|chunk| {
+                let (messages, error) = match chunk {
+                    Ok(messages) => (messages, None),
+                    Err(e) => (vec![], Some(Err(e))),
+                };
+                messages.into_iter().rev().map(|(_, m)| Ok(m)).chain(error)
+            })
+    }
+
+    /// The current file for 0, the rotated ones after it.
+    fn file(&self, n: usize) -> PathBuf {
+        if n == 0 {
+            self.path.clone()
+        } else {
+            rotated(&self.path, n)
+        }
+    }
+
+    /// Shifts every rotated file one place back, dropping the oldest, and
+    /// starts the log afresh.
+    fn rotate(&mut self, keep: usize) -> Result<()> {
+        self.file.flush()?;
+        if keep == 0 {
+            remove_if_exists(&self.path)?;
+        } else {
+            remove_if_exists(&rotated(&self.path, keep))?;
+            for n in (1..keep).rev() {
+                match fs::rename(
+                    rotated(&self.path, n),
+                    rotated(&self.path, n + 1),
+                ) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        info!("Rotated the message log {}", self.path.display());
+        let mut index = std::mem::take(&mut self.index);
+        index.insert(0, FileIndex::default());
+        index.truncate(keep + 1);
+        *self = Self::open_indexed(&self.path, self.rotation, index)?;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(format!(".{n}"));
+    path.into()
+}
+
+/// Starts the records of messages since their layout is versioned, the
+/// message coded like on the wire following. Older records start with the
+/// tag of [`ServerCommand::Message`] instead, which is never this.
+const RECORD_VERSION: u16 = 0x101;
+/// The first versioned records, from when message ids were `u16`s like
+/// in the unversioned ones.
+const RECORD_VERSION_NARROW_IDS: u16 = 0x100;
+/// Tag the coding of [`ServerCommand::Message`] starts with.
+const MESSAGE_TAG: u16 = 3;
+/// Fields [`ServerCommand::Message`] gained while its records were not
+/// versioned, in order: the content type, the channel, the quote and the
+/// time. Records have all of the ones before the last they have.
+const LEGACY_FIELDS: usize = 4;
+
+/// A message as kept in a [`MessageLog`], in the layout of any version.
+#[derive(Debug, Clone)]
+struct StoredMessage(ServerCommand);
+
+impl Codec for StoredMessage {
+    fn code(&self, w: &mut impl Write) -> Result<()> {
+        RECORD_VERSION.code(w)?;
+        self.0.code(w)
+    }
+
+    /// Decodes a whole record, everything `r` has.
+    fn decode(r: &mut impl Read) -> Result<Self::Owned> {
+        let mut record = vec![];
+        r.read_to_end(&mut record)?;
+        let mut r = record.as_slice();
+        match u16::decode(&mut r)? {
+            RECORD_VERSION => ServerCommand::decode(&mut r).map(Self),
+            RECORD_VERSION_NARROW_IDS => {
+                // these are like the newest unversioned ones after the tag
+                if u16::decode(&mut r)? != MESSAGE_TAG {
+                    return Err(Error::from(ErrorKind::InvalidData));
+                }
+                decode_legacy(r).map(Self)
+            }
+            MESSAGE_TAG => decode_legacy(r).map(Self),
+            version => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown message record version {version}"),
+            )),
+        }
+    }
+
+    fn coded_size(&self) -> usize {
+        RECORD_VERSION.coded_size() + self.0.coded_size()
+    }
+}
+
+/// The messages in `path` from `offset` up to `end`, or to the end of the
+/// file, with where each of them starts.
+fn read_messages(
+    path: &Path,
+    offset: u64,
+    end: Option<u64>,
+) -> Result<Vec<(u64, ServerCommand)>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    let len = end.map_or(u64::MAX, |end| end - offset);
+    let mut r = BufReader::new(file.take(len));
+    let mut messages = vec![];
+    let mut offset = offset;
+    loop {
+        let size = match u16::decode(&mut r) {
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let mut buf = vec![0; size.into()];
+        if let Err(e) = r.read_exact(&mut buf) {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                warn!(
+                    "Ignoring truncated record at the end of {}",
+                    path.display()
+                );
+                break;
+            }
+            return Err(e);
+        }
+        let StoredMessage(message) = StoredMessage::decode(&mut &buf[..])?;
+        messages.push((offset, message));
+        offset += (size_of::<u16>() + buf.len()) as u64;
+    }
+    Ok(messages)
+}
+
+/// Decodes the fields of a message from before the records were versioned,
+/// trying the layouts it had from the newest to the oldest until one takes
+/// up the whole record.
+fn decode_legacy(record: &[u8]) -> Result<ServerCommand> {
+    for fields in (0..=LEGACY_FIELDS).rev() {
+        let mut r = record;
+        match decode_legacy_layout(&mut r, fields) {
+            Ok(message) if r.is_empty() => return Ok(message),
+            _ => (),
+        }
+    }
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "the record has none of the layouts of a message",
+    ))
+}
+
+/// Decodes a message with the first `fields` of the [`LEGACY_FIELDS`],
+/// the others getting the values messages without them were shown with,
+/// and the `u16` message ids of the time.
+fn decode_legacy_layout(
+    r: &mut &[u8],
+    fields: usize,
+) -> Result<ServerCommand> {
+    let msg_id = MsgId(u16::decode(r)?.into());
+    let user_id = UserId::decode(r)?;
+    let channel_id = if fields >= 2 {
+        ChannelId::decode(r)?
+    } else {
+        ChannelId::LOBBY
+    };
+    let message = String::decode(r)?;
+    let content_type = if fields >= 1 {
+        ContentType::decode(r)?
+    } else {
+        ContentType::Plain
+    };
+    let quote = if fields >= 3 && bool::decode(r)? {
+        Some(Quote {
+            msg_id: MsgId(u16::decode(r)?.into()),
+            user_id: UserId::decode(r)?,
+            text: String::decode(r)?,
+        })
+    } else {
+        None
+    };
+    let time = if fields >= 4 { u64::decode(r)? } else { 0 };
+    Ok(ServerCommand::Message {
+        msg_id,
+        user_id,
+        channel_id,
+        message,
+        content_type,
+        quote,
+        time,
+    })
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// A store keeping messages in a [`MessageLog`] of their own and
+/// everything else in another store.
+#[derive(Debug)]
+pub struct HistoryFileStore {
+    log: MessageLog,
+    inner: Box<dyn Store>,
+}
+
+impl HistoryFileStore {
+    #[must_use]
+    pub fn new(log: MessageLog, inner: Box<dyn Store>) -> Self {
+        Self { log, inner }
+    }
+}
+
+impl Store for HistoryFileStore {
+    fn append_message(&mut self, message: &ServerCommand) -> Result<()> {
+        self.log.append(message)
+    }
+
+    fn load_history(&self, limit: usize) -> Result<Vec<ServerCommand>> {
+        self.log.load(limit)
+    }
+
+    fn search_history(
+        &self,
+        channel_id: ChannelId,
+        query: &str,
+        before_msg_id: MsgId,
+        limit: usize,
+    ) -> Result<SearchPage> {
+        self.log.search(channel_id, query, before_msg_id, limit)
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<UserRecord>> {
+        self.inner.get_user(name)
+    }
+
+    fn put_user(&mut self, user: &UserRecord) -> Result<()> {
+        self.inner.put_user(user)
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        self.inner.delete_user(name)
+    }
+
+    fn list_users(&self) -> Result<Vec<UserRecord>> {
+        self.inner.list_users()
+    }
+
+    fn get_account(&self, name: &str) -> Result<Option<Account>> {
+        self.inner.get_account(name)
+    }
+
+    fn put_account(&mut self, account: &Account) -> Result<()> {
+        self.inner.put_account(account)
+    }
+
+    fn delete_account(&mut self, name: &str) -> Result<bool> {
+        self.inner.delete_account(name)
+    }
+
+    fn list_accounts(&self) -> Result<Vec<Account>> {
+        self.inner.list_accounts()
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> Result<()> {
+        self.inner.add_ban(ban)
+    }
+
+    fn remove_ban(&mut self, ban: &Ban) -> Result<bool> {
+        self.inner.remove_ban(ban)
+    }
+
+    fn list_bans(&self) -> Result<Vec<Ban>> {
+        self.inner.list_bans()
+    }
+
+    fn put_channel(&mut self, channel: &ChannelRecord) -> Result<()> {
+        self.inner.put_channel(channel)
+    }
+
+    fn list_channels(&self) -> Result<Vec<ChannelRecord>> {
+        self.inner.list_channels()
+    }
+
+    fn put_invite(&mut self, invite: &Invite) -> Result<()> {
+        self.inner.put_invite(invite)
+    }
+
+    fn get_invite(&self, token: &str) -> Result<Option<Invite>> {
+        self.inner.get_invite(token)
+    }
+
+    fn delete_invite(&mut self, token: &str) -> Result<bool> {
+        self.inner.delete_invite(token)
+    }
+
+    fn push_offline_whisper(&mut self, whisper: &OfflineWhisper) -> Result<()> {
+        self.inner.push_offline_whisper(whisper)
+    }
+
+    fn count_offline_whispers(&self, target_user_id: UserId) -> Result<usize> {
+        self.inner.count_offline_whispers(target_user_id)
+    }
+
+    fn take_offline_whispers(
+        &mut self,
+        target_user_id: UserId,
+    ) -> Result<Vec<OfflineWhisper>> {
+        self.inner.take_offline_whispers(target_user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_dir;
+    use super::*;
+
+    /// Writes a record the way logs were written before it was versioned,
+    /// with the fields `code` puts after the ids.
+    fn write_legacy(
+        w: &mut impl Write,
+        msg_id: u16,
+        code: impl Fn(&mut Vec<u8>),
+    ) {
+        let mut record = vec![];
+        MESSAGE_TAG.code(&mut record).unwrap();
+        msg_id.code(&mut record).unwrap();
+        UserId(7).code(&mut record).unwrap();
+        code(&mut record);
+        u16::try_from(record.len()).unwrap().code(w).unwrap();
+        w.write_all(&record).unwrap();
+    }
+
+    #[test]
+    fn reads_records_of_every_layout() {
+        let path = test_dir("legacy-log").join("messages.log");
+        let mut file = File::create(&path).unwrap();
+        let channel = ChannelId(2);
+        let markdown = ContentType::Markdown;
+        write_legacy(&mut file, 1, |r| "hi".code(r).unwrap());
+        write_legacy(&mut file, 2, |r| {
+            "hi".code(r).unwrap();
+            markdown.code(r).unwrap();
+        });
+        write_legacy(&mut file, 3, |r| {
+            channel.code(r).unwrap();
+            "hi".code(r).unwrap();
+            markdown.code(r).unwrap();
+        });
+        write_legacy(&mut file, 4, |r| {
+            channel.code(r).unwrap();
+            "hi".code(r).unwrap();
+            markdown.code(r).unwrap();
+            None::<Quote>.code(r).unwrap();
+        });
+        write_legacy(&mut file, 5, |r| {
+            channel.code(r).unwrap();
+            "hi".code(r).unwrap();
+            markdown.code(r).unwrap();
+            None::<Quote>.code(r).unwrap();
+            1234u64.code(r).unwrap();
+        });
+        drop(file);
+        let mut log = MessageLog::open(&path, None).unwrap();
+        log.append(&ServerCommand::Message {
+            msg_id: MsgId(6),
+            user_id: UserId(7),
+            channel_id: channel,
+            message: "hi".to_owned(),
+            content_type: ContentType::Plain,
+            quote: None,
+            time: 5678,
+        })
+        .unwrap();
+        let fields: Vec<_> = log
+            .load(usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|m| match m {
+                ServerCommand::Message {
+                    msg_id,
+                    channel_id,
+                    message,
+                    content_type,
+                    time,
+                    ..
+                } => {
+                    assert_eq!(message, "hi");
+                    (msg_id.0, channel_id.0, content_type, time)
+                }
+                m => panic!("not a message: {m:?}"),
+            })
+            .collect();
+        use ContentType::{Markdown, Plain};
+        assert_eq!(
+            fields,
+            [
+                (1, 0, Plain, 0),
+                (2, 0, Markdown, 0),
+                (3, 2, Markdown, 0),
+                (4, 2, Markdown, 0),
+                (5, 2, Markdown, 1234),
+                (6, 2, Plain, 5678),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_narrow_ids_and_writes_wide_ones() {
+        let path = test_dir("narrow-log").join("messages.log");
+        let mut file = File::create(&path).unwrap();
+        let mut record = vec![];
+        RECORD_VERSION_NARROW_IDS.code(&mut record).unwrap();
+        MESSAGE_TAG.code(&mut record).unwrap();
+        for id in [2u16, 7, 0] {
+            id.code(&mut record).unwrap();
+        }
+        "hi".code(&mut record).unwrap();
+        ContentType::Plain.code(&mut record).unwrap();
+        true.code(&mut record).unwrap();
+        1u16.code(&mut record).unwrap();
+        UserId(3).code(&mut record).unwrap();
+        "quoted".code(&mut record).unwrap();
+        0u64.code(&mut record).unwrap();
+        u16::try_from(record.len()).unwrap().code(&mut file).unwrap();
+        file.write_all(&record).unwrap();
+        drop(file);
+        let mut log = MessageLog::open(&path, None).unwrap();
+        let wide = MsgId(u32::from(u16::MAX) + 1);
+        log.append(&ServerCommand::Message {
+            msg_id: wide,
+            user_id: UserId(7),
+            channel_id: ChannelId::LOBBY,
+            message: "later".to_owned(),
+            content_type: ContentType::Plain,
+            quote: None,
+            time: 0,
+        })
+        .unwrap();
+        let messages = log.load(usize::MAX).unwrap();
+        let [ServerCommand::Message {
+            msg_id: first,
+            quote: Some(quote),
+            ..
+        }, ServerCommand::Message { msg_id: second, .. }] = &messages[..]
+        else {
+            panic!("unexpected messages: {messages:?}");
+        };
+        assert_eq!((*first, quote.msg_id), (MsgId(2), MsgId(1)));
+        assert_eq!(quote.text, "quoted");
+        assert_eq!(*second, wide);
+    }
+
+    fn numbered(msg_id: u32) -> ServerCommand {
+        ServerCommand::Message {
+            msg_id: MsgId(msg_id),
+            user_id: UserId(1),
+            channel_id: ChannelId::LOBBY,
+            message: format!("message {msg_id}"),
+            content_type: ContentType::Plain,
+            quote: None,
+            time: 0,
+        }
+    }
+
+    fn ids(messages: &[ServerCommand]) -> Vec<u32> {
+        messages
+            .iter()
+            .map(|m| match m {
+                ServerCommand::Message { msg_id, .. } => msg_id.0,
+                m => panic!("not a message: {m:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_chunks_across_rotated_files() {
+        let path = test_dir("chunked-log").join("messages.log");
+        let rotation = Rotation {
+            max_size: 20_000,
+            keep: 2,
+        };
+        let mut log = MessageLog::open(&path, Some(rotation)).unwrap();
+        for msg_id in 1..=1500 {
+            log.append(&numbered(msg_id)).unwrap();
+        }
+        // reopening finds the chunks the appends made
+        for log in [log, MessageLog::open(&path, Some(rotation)).unwrap()] {
+            assert!(log.index.len() == 3 && log.index[2].chunks.len() > 1);
+            let latest = log.load(300).unwrap();
+            assert_eq!(ids(&latest), (1201..=1500).collect::<Vec<_>>());
+            let all = ids(&log.load(usize::MAX).unwrap());
+            assert!(all.windows(2).all(|w| w[0] + 1 == w[1]));
+            let page = log
+                .search(ChannelId::LOBBY, "MESSAGE 1", MsgId(1000), 3)
+                .unwrap();
+            assert_eq!(ids(&page.messages), [197, 198, 199]);
+            assert_eq!(page.before_msg_id, Some(MsgId(197)));
+        }
+    }
+}
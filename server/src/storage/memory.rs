@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::Result;
+
+use common::commands::ServerCommand;
+use common::{ChannelId, MsgId, UserId};
+
+use super::{
+    search_newest_first, Account, Ban, ChannelRecord, Invite,
+    OfflineWhisper, SearchPage, Store, UserRecord,
+};
+
+/// A store that forgets everything when the server stops.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    messages: VecDeque<ServerCommand>,
+    users: BTreeMap<String, UserRecord>,
+    accounts: BTreeMap<String, Account>,
+    bans: HashSet<Ban>,
+    channels: BTreeMap<String, ChannelRecord>,
+    invites: BTreeMap<String, Invite>,
+    whispers: BTreeMap<UserId, Vec<OfflineWhisper>>,
+}
+
+impl MemoryStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn append_message(&mut self, message: &ServerCommand) -> Result<()> {
+        self.messages.push_back(message.clone());
+        Ok(())
+    }
+
+    fn load_history(&self, limit: usize) -> Result<Vec<ServerCommand>> {
+        let start = self.messages.len().saturating_sub(limit);
+        Ok(self.messages.range(start..).cloned().collect())
+    }
+
+    fn search_history(
+        &self,
+        channel_id: ChannelId,
+        query: &str,
+        before_msg_id: MsgId,
+        limit: usize,
+    ) -> Result<SearchPage> {
+        search_newest_first(
+            self.messages.iter().rev().cloned().map(Ok),
+            channel_id,
+            query,
+            before_msg_id,
+            limit,
+        )
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<UserRecord>> {
+        Ok(self.users.get(name).cloned())
+    }
+
+    fn put_user(&mut self, user: &UserRecord) -> Result<()> {
+        self.users.insert(user.name.clone(), user.clone());
+        Ok(())
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        Ok(self.users.remove(name).is_some())
+    }
+
+    fn list_users(&self) -> Result<Vec<UserRecord>> {
+        Ok(self.users.values().cloned().collect())
+    }
+
+    fn get_account(&self, name: &str) -> Result<Option<Account>> {
+        Ok(self.accounts.get(name).cloned())
+    }
+
+    fn put_account(&mut self, account: &Account) -> Result<()> {
+        self.accounts.insert(account.name.clone(), account.clone());
+        Ok(())
+    }
+
+    fn delete_account(&mut self, name: &str) -> Result<bool> {
+        Ok(self.accounts.remove(name).is_some())
+    }
+
+    fn list_accounts(&self) -> Result<Vec<Account>> {
+        Ok(self.accounts.values().cloned().collect())
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> Result<()> {
+        self.bans.insert(ban.clone());
+        Ok(())
+    }
+
+    fn remove_ban(&mut self, ban: &Ban) -> Result<bool> {
+        Ok(self.bans.remove(ban))
+    }
+
+    fn list_bans(&self) -> Result<Vec<Ban>> {
+        Ok(self.bans.iter().cloned().collect())
+    }
+
+    fn put_channel(&mut self, channel: &ChannelRecord) -> Result<()> {
+        self.channels.insert(channel.name.clone(), channel.clone());
+        Ok(())
+    }
+
+    fn list_channels(&self) -> Result<Vec<ChannelRecord>> {
+        Ok(self.channels.values().cloned().collect())
+    }
+
+    fn put_invite(&mut self, invite: &Invite) -> Result<()> {
+        self.invites.insert(invite.token.clone(), invite.clone());
+        Ok(())
+    }
+
+    fn get_invite(&self, token: &str) -> Result<Option<Invite>> {
+        Ok(self.invites.get(token).cloned())
+    }
+
+    fn delete_invite(&mut self, token: &str) -> Result<bool> {
+        Ok(self.invites.remove(token).is_some())
+    }
+
+    fn push_offline_whisper(&mut self, whisper: &OfflineWhisper) -> Result<()> {
+        let waiting = self.whispers.entry(whisper.target_user_id).or_default();
+        waiting.push(whisper.clone());
+        Ok(())
+    }
+
+    fn count_offline_whispers(&self, target_user_id: UserId) -> Result<usize> {
+        Ok(self.whispers.get(&target_user_id).map_or(0, Vec::len))
+    }
+
+    fn take_offline_whispers(
+        &mut self,
+        target_user_id: UserId,
+    ) -> Result<Vec<OfflineWhisper>> {
+        Ok(self.whispers.remove(&target_user_id).unwrap_or_default())
+    }
+}
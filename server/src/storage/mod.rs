@@ -0,0 +1,490 @@
+//! Persistence of messages, users, bans, invites and whispers waiting for
+//! offline accounts behind the [`Store`] trait.
+
+use std::fmt::{Debug, Display};
+use std::io::Result;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use common::commands::{Role, ServerCommand};
+use common::{ChannelId, MsgId, UserId};
+
+use crate::PasswordHash;
+
+mod file;
+mod log;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use file::FileStore;
+pub use log::{HistoryFileStore, MessageLog, Rotation};
+pub use memory::MemoryStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+/// Most messages a search looks at for one page, so that a query matching
+/// few messages doesn't have the server go through the whole history at
+/// once.
+const MAX_SEARCH_SCAN: usize = 10_000;
+
+/// What the server remembers about a user name between sessions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserRecord {
+    pub name: String,
+    pub role: Role,
+}
+
+/// A registered name, only usable by whoever knows its password, and the
+/// user id and role that come with it in every session.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub user_id: UserId,
+    pub password: PasswordHash,
+    pub role: Role,
+}
+
+/// A token that lets new users connect to an invite-only server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub token: String,
+    /// Connections it still lets in, unlimited if `None`.
+    pub uses_left: Option<u16>,
+    /// When it stops working, in seconds since the Unix epoch, never if
+    /// `None`.
+    pub expires: Option<u64>,
+}
+
+/// A whisper left for an offline account, kept until it connects and
+/// then sent as a [`ServerCommand::OfflineWhisper`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OfflineWhisper {
+    /// The account it is for.
+    pub target_user_id: UserId,
+    /// Who sent it, and under which name.
+    pub user_id: UserId,
+    pub name: String,
+    pub message: String,
+    /// When it was sent, in seconds since the Unix epoch.
+    pub time: u64,
+}
+
+/// Matches found by [`Store::search_history`].
+#[derive(Debug, Default)]
+pub struct SearchPage {
+    /// Oldest first.
+    pub messages: Vec<ServerCommand>,
+    /// Where the search goes on, `None` once there is nothing older left.
+    pub before_msg_id: Option<MsgId>,
+}
+
+/// The id a channel name was given when it was first joined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelRecord {
+    pub name: String,
+    pub channel_id: ChannelId,
+    /// Shown to users joining the channel, none if empty.
+    pub topic: String,
+    /// Nobody used the channel for a while, so it is out of the channel
+    /// list until someone joins it again.
+    pub archived: bool,
+}
+
+/// Someone who is not allowed to connect.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Ban {
+    Name(String),
+    Ip(IpAddr),
+}
+
+impl Display for Ban {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "name:{name}"),
+            Self::Ip(ip) => write!(f, "ip:{ip}"),
+        }
+    }
+}
+
+impl FromStr for Ban {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("name", name)) => Ok(Self::Name(name.to_owned())),
+            Some(("ip", ip)) => ip
+                .parse()
+                .map(Self::Ip)
+                .map_err(|e| format!("invalid IP address `{ip}`: {e}")),
+            _ => Err(format!(
+                "expected `name:<name>` or `ip:<address>`, got `{s}`"
+            )),
+        }
+    }
+}
+
+/// A persistence backend.
+///
+/// Messages are the [`ServerCommand::Message`] values that were broadcast.
+pub trait Store: Debug {
+    fn append_message(&mut self, message: &ServerCommand) -> Result<()>;
+    /// Returns the latest `limit` messages, oldest first.
+    fn load_history(&self, limit: usize) -> Result<Vec<ServerCommand>>;
+    /// Returns the latest `limit` messages in `channel_id` older than
+    /// `before_msg_id` containing `query`, ignoring case. Looks at no more
+    /// than [`MAX_SEARCH_SCAN`] messages, so the page may have fewer
+    /// matches, or none, and still not be the last.
+    fn search_history(
+        &self,
+        channel_id: ChannelId,
+        query: &str,
+        before_msg_id: MsgId,
+        limit: usize,
+    ) -> Result<SearchPage>;
+
+    fn get_user(&self, name: &str) -> Result<Option<UserRecord>>;
+    /// Inserts or replaces the record with the same name.
+    fn put_user(&mut self, user: &UserRecord) -> Result<()>;
+    /// Removes a user, returning whether it existed.
+    fn delete_user(&mut self, name: &str) -> Result<bool>;
+    fn list_users(&self) -> Result<Vec<UserRecord>>;
+
+    fn get_account(&self, name: &str) -> Result<Option<Account>>;
+    /// Inserts or replaces the account with the same name.
+    fn put_account(&mut self, account: &Account) -> Result<()>;
+    /// Removes an account, returning whether it existed.
+    fn delete_account(&mut self, name: &str) -> Result<bool>;
+    fn list_accounts(&self) -> Result<Vec<Account>>;
+
+    fn add_ban(&mut self, ban: &Ban) -> Result<()>;
+    /// Lifts a ban, returning whether it existed.
+    fn remove_ban(&mut self, ban: &Ban) -> Result<bool>;
+    fn list_bans(&self) -> Result<Vec<Ban>>;
+
+    /// Inserts or replaces the record with the same name.
+    fn put_channel(&mut self, channel: &ChannelRecord) -> Result<()>;
+    fn list_channels(&self) -> Result<Vec<ChannelRecord>>;
+
+    /// Inserts or replaces the invite with the same token.
+    fn put_invite(&mut self, invite: &Invite) -> Result<()>;
+    fn get_invite(&self, token: &str) -> Result<Option<Invite>>;
+    /// Removes an invite, returning whether it existed.
+    fn delete_invite(&mut self, token: &str) -> Result<bool>;
+
+    /// Keeps a whisper after those already waiting for its target.
+    fn push_offline_whisper(&mut self, whisper: &OfflineWhisper) -> Result<()>;
+    /// Number of whispers waiting for `target_user_id`.
+    fn count_offline_whispers(&self, target_user_id: UserId) -> Result<usize>;
+    /// Removes and returns the whispers waiting for `target_user_id`,
+    /// oldest first.
+    fn take_offline_whispers(
+        &mut self,
+        target_user_id: UserId,
+    ) -> Result<Vec<OfflineWhisper>>;
+}
+
+/// Searches `messages`, newest first, the way [`Store::search_history`]
+/// does.
+fn search_newest_first(
+    messages: impl Iterator<Item = Result<ServerCommand>>,
+    channel_id: ChannelId,
+    query: &str,
+    before_msg_id: MsgId,
+    limit: usize,
+) -> Result<SearchPage> {
+    let query = query.to_lowercase();
+    let mut page = SearchPage::default();
+    let mut scanned = 0;
+    for message in messages {
+        let message = message?;
+        let ServerCommand::Message { msg_id, .. } = message else {
+            continue;
+        };
+        if msg_id >= before_msg_id {
+            continue;
+        }
+        if matches_search(&message, channel_id, &query, before_msg_id) {
+            page.messages.push(message);
+        }
+        scanned += 1;
+        if page.messages.len() >= limit || scanned >= MAX_SEARCH_SCAN {
+            page.before_msg_id = Some(msg_id);
+            break;
+        }
+    }
+    page.messages.reverse();
+    Ok(page)
+}
+
+/// Whether `message` is in `channel_id`, older than `before_msg_id` and
+/// contains `query`, which is lowercase.
+fn matches_search(
+    message: &ServerCommand,
+    channel_id: ChannelId,
+    query: &str,
+    before_msg_id: MsgId,
+) -> bool {
+    matches!(
+        message,
+        ServerCommand::Message { msg_id, channel_id: id, message, .. }
+            if *id == channel_id
+                && *msg_id < before_msg_id
+                && message.to_lowercase().contains(query)
+    )
+}
+
+/// Which [`Store`] implementation to use and where it keeps its data.
+#[derive(Debug, Clone, Default)]
+pub enum StoreConfig {
+    #[default]
+    Memory,
+    File(PathBuf),
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+}
+
+impl StoreConfig {
+    pub fn open(&self) -> Result<Box<dyn Store>> {
+        Ok(match self {
+            Self::Memory => Box::new(MemoryStore::new()),
+            Self::File(dir) => Box::new(FileStore::open(dir)?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(path) => Box::new(SqliteStore::open(path)?),
+        })
+    }
+}
+
+impl FromStr for StoreConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(Self::Memory),
+            Some(("file", dir)) => Ok(Self::File(dir.into())),
+            #[cfg(feature = "sqlite")]
+            Some(("sqlite", path)) => Ok(Self::Sqlite(path.into())),
+            #[cfg(not(feature = "sqlite"))]
+            Some(("sqlite", _)) => {
+                Err("the server was built without sqlite support".to_owned())
+            }
+            _ => Err(format!(
+                "expected `memory`, `file:<dir>` or `sqlite:<path>`, got `{s}`"
+            )),
+        }
+    }
+}
+
+/// A fresh, empty directory for a test to keep its files in.
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("tcpchat-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use common::commands::{ContentType, Quote};
+
+    use super::*;
+
+    fn message(
+        msg_id: u32,
+        channel_id: ChannelId,
+        text: &str,
+    ) -> ServerCommand {
+        ServerCommand::Message {
+            msg_id: MsgId(msg_id),
+            user_id: UserId(1),
+            channel_id,
+            message: text.to_owned(),
+            content_type: ContentType::Plain,
+            quote: (msg_id > 1).then(|| Quote {
+                msg_id: MsgId(msg_id - 1),
+                user_id: UserId(7),
+                text: "quoted".to_owned(),
+            }),
+            time: 1000 + u64::from(msg_id),
+        }
+    }
+
+    fn channel() -> ChannelRecord {
+        ChannelRecord {
+            name: "other".to_owned(),
+            channel_id: ChannelId(3),
+            topic: "a topic".to_owned(),
+            archived: true,
+        }
+    }
+
+    fn invite(token: &str, uses_left: Option<u16>) -> Invite {
+        Invite {
+            token: token.to_owned(),
+            uses_left,
+            expires: Some(2000),
+        }
+    }
+
+    fn whisper(target_user_id: u16, text: &str) -> OfflineWhisper {
+        OfflineWhisper {
+            target_user_id: UserId(target_user_id),
+            user_id: UserId(1),
+            name: "dave".to_owned(),
+            message: text.to_owned(),
+            time: 1000,
+        }
+    }
+
+    /// Puts one of everything in `store`, for [`check`] to find.
+    fn fill(store: &mut dyn Store) {
+        store
+            .append_message(&message(1, ChannelId::LOBBY, "Hello"))
+            .unwrap();
+        store.append_message(&message(2, ChannelId(3), "hello")).unwrap();
+        store.append_message(&message(3, ChannelId(3), "bye")).unwrap();
+        for name in ["alice", "bob"] {
+            store
+                .put_user(&UserRecord {
+                    name: name.to_owned(),
+                    role: Role::User,
+                })
+                .unwrap();
+        }
+        store
+            .put_user(&UserRecord {
+                name: "alice".to_owned(),
+                role: Role::Moderator,
+            })
+            .unwrap();
+        assert!(store.delete_user("bob").unwrap());
+        assert!(!store.delete_user("bob").unwrap());
+        store
+            .put_account(&Account {
+                name: "carol".to_owned(),
+                user_id: UserId(42),
+                password: PasswordHash::new("secret").unwrap(),
+                role: Role::Moderator,
+            })
+            .unwrap();
+        store.add_ban(&Ban::Name("mallory".to_owned())).unwrap();
+        store.add_ban(&Ban::Ip([192, 0, 2, 1].into())).unwrap();
+        assert!(store.remove_ban(&Ban::Ip([192, 0, 2, 1].into())).unwrap());
+        store.put_channel(&channel()).unwrap();
+        for (token, uses_left) in [("once", Some(1)), ("gone", None)] {
+            store.put_invite(&invite(token, uses_left)).unwrap();
+        }
+        assert!(store.delete_invite("gone").unwrap());
+        assert!(!store.delete_invite("gone").unwrap());
+        for (target, text) in [(42, "first"), (43, "other"), (42, "second")] {
+            store.push_offline_whisper(&whisper(target, text)).unwrap();
+        }
+    }
+
+    /// Checks that everything [`fill`] put in `store` comes back out.
+    fn check(store: &mut dyn Store) {
+        let history = store.load_history(usize::MAX).unwrap();
+        let expected = [
+            message(1, ChannelId::LOBBY, "Hello"),
+            message(2, ChannelId(3), "hello"),
+            message(3, ChannelId(3), "bye"),
+        ];
+        assert_eq!(format!("{history:?}"), format!("{expected:?}"));
+        let latest = store.load_history(1).unwrap();
+        assert_eq!(format!("{latest:?}"), format!("{:?}", [&expected[2]]));
+        let found = store
+            .search_history(ChannelId(3), "hello", MsgId::MAX, 10)
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", found.messages),
+            format!("{:?}", [&expected[1]])
+        );
+        assert_eq!(found.before_msg_id, None);
+        let found = store
+            .search_history(ChannelId(3), "", MsgId(3), 1)
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", found.messages),
+            format!("{:?}", [&expected[1]])
+        );
+        assert_eq!(found.before_msg_id, Some(MsgId(2)));
+
+        assert_eq!(
+            store.list_users().unwrap(),
+            [UserRecord {
+                name: "alice".to_owned(),
+                role: Role::Moderator,
+            }]
+        );
+        assert_eq!(store.get_user("bob").unwrap(), None);
+        let account = store.get_account("carol").unwrap().unwrap();
+        assert_eq!(account.user_id, UserId(42));
+        assert_eq!(account.role, Role::Moderator);
+        assert!(account.password.verify("secret"));
+        assert!(!account.password.verify("guess"));
+        assert_eq!(store.list_accounts().unwrap().len(), 1);
+        assert_eq!(
+            store.list_bans().unwrap(),
+            [Ban::Name("mallory".to_owned())]
+        );
+        assert_eq!(store.list_channels().unwrap(), [channel()]);
+        assert_eq!(
+            store.get_invite("once").unwrap(),
+            Some(invite("once", Some(1)))
+        );
+        assert_eq!(store.get_invite("gone").unwrap(), None);
+
+        assert_eq!(store.count_offline_whispers(UserId(42)).unwrap(), 2);
+        assert_eq!(
+            store.take_offline_whispers(UserId(42)).unwrap(),
+            [whisper(42, "first"), whisper(42, "second")]
+        );
+        assert_eq!(store.count_offline_whispers(UserId(42)).unwrap(), 0);
+        assert_eq!(store.count_offline_whispers(UserId(43)).unwrap(), 1);
+    }
+
+    #[test]
+    fn memory_store_round_trips() {
+        let mut store = MemoryStore::new();
+        fill(&mut store);
+        check(&mut store);
+    }
+
+    #[test]
+    fn searches_stop_after_looking_at_enough_messages() {
+        let mut store = MemoryStore::new();
+        let count = u32::try_from(MAX_SEARCH_SCAN).unwrap() + 10;
+        for msg_id in 1..=count {
+            store
+                .append_message(&message(msg_id, ChannelId::LOBBY, "nope"))
+                .unwrap();
+        }
+        let page = store
+            .search_history(ChannelId::LOBBY, "yes", MsgId::MAX, 10)
+            .unwrap();
+        assert!(page.messages.is_empty());
+        assert_eq!(page.before_msg_id, Some(MsgId(11)));
+        let page = store
+            .search_history(ChannelId::LOBBY, "yes", MsgId(11), 10)
+            .unwrap();
+        assert_eq!(page.before_msg_id, None);
+    }
+
+    #[test]
+    fn file_store_round_trips() {
+        let dir = test_dir("file-store");
+        fill(&mut FileStore::open(&dir).unwrap());
+        check(&mut FileStore::open(&dir).unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_round_trips() {
+        let path = test_dir("sqlite-store").join("chat.db");
+        fill(&mut SqliteStore::open(&path).unwrap());
+        check(&mut SqliteStore::open(&path).unwrap());
+    }
+}
@@ -0,0 +1,547 @@
+use std::io::{Error, Result};
+use std::path::Path;
+
+use common::commands::{ContentType, Quote, Role, ServerCommand};
+use common::{ChannelId, MsgId, UserId};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::{
+    search_newest_first, Account, Ban, ChannelRecord, Invite,
+    OfflineWhisper, SearchPage, Store, UserRecord,
+};
+
+/// A store backed by an SQLite database.
+#[derive(Debug)]
+pub struct SqliteStore {
+    db: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Connection::open(path).map_err(Error::other)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                msg_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                channel_id INTEGER NOT NULL,
+                message TEXT NOT NULL,
+                content_type INTEGER NOT NULL,
+                quote_msg_id INTEGER,
+                quote_user_id INTEGER,
+                quote_text TEXT,
+                time INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS users (
+                name TEXT PRIMARY KEY,
+                role TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS accounts (
+                name TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                password TEXT NOT NULL,
+                role TEXT NOT NULL DEFAULT 'user'
+            );
+            CREATE TABLE IF NOT EXISTS bans (target TEXT PRIMARY KEY);
+            CREATE TABLE IF NOT EXISTS channels (
+                name TEXT PRIMARY KEY,
+                channel_id INTEGER NOT NULL,
+                topic TEXT NOT NULL DEFAULT '',
+                archived INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS invites (
+                token TEXT PRIMARY KEY,
+                uses_left INTEGER,
+                expires INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS offline_whispers (
+                target_user_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                message TEXT NOT NULL,
+                time INTEGER NOT NULL
+            );",
+        )
+        .map_err(Error::other)?;
+        // databases from before messages had a content type, a channel, a
+        // quote or a time, channels a topic or an archived flag or accounts
+        // a role, lack the columns
+        for (column, definition) in [
+            ("content_type", "INTEGER NOT NULL DEFAULT 0"),
+            ("channel_id", "INTEGER NOT NULL DEFAULT 0"),
+            ("quote_msg_id", "INTEGER"),
+            ("quote_user_id", "INTEGER"),
+            ("quote_text", "TEXT"),
+            ("time", "INTEGER NOT NULL DEFAULT 0"),
+        ] {
+            add_missing_column(&db, "messages", column, definition)?;
+        }
+        for (column, definition) in [
+            ("topic", "TEXT NOT NULL DEFAULT ''"),
+            ("archived", "INTEGER NOT NULL DEFAULT 0"),
+        ] {
+            add_missing_column(&db, "channels", column, definition)?;
+        }
+        add_missing_column(
+            &db,
+            "accounts",
+            "role",
+            "TEXT NOT NULL DEFAULT 'user'",
+        )?;
+        Ok(Self { db })
+    }
+}
+
+fn add_missing_column(
+    db: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists: bool = db
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+            [table, column],
+            |row| row.get(0),
+        )
+        .map_err(Error::other)?;
+    if !exists {
+        db.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+        ))
+        .map_err(Error::other)?;
+    }
+    Ok(())
+}
+
+impl Store for SqliteStore {
+    fn append_message(&mut self, message: &ServerCommand) -> Result<()> {
+        let ServerCommand::Message {
+            msg_id,
+            user_id,
+            channel_id,
+            message,
+            content_type,
+            quote,
+            time,
+        } = message
+        else {
+            return Ok(());
+        };
+        self.db
+            .execute(
+                "INSERT INTO messages (msg_id, user_id, channel_id, message,
+                    content_type, quote_msg_id, quote_user_id, quote_text,
+                    time)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    msg_id.0,
+                    user_id.0,
+                    channel_id.0,
+                    message,
+                    *content_type as u16,
+                    quote.as_ref().map(|q| q.msg_id.0),
+                    quote.as_ref().map(|q| q.user_id.0),
+                    quote.as_ref().map(|q| &q.text),
+                    i64::try_from(*time).unwrap_or(i64::MAX),
+                ],
+            )
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    fn load_history(&self, limit: usize) -> Result<Vec<ServerCommand>> {
+        let mut stmt = self
+            .db
+            .prepare(
+                "SELECT msg_id, user_id, channel_id, message, content_type,
+                    quote_msg_id, quote_user_id, quote_text, time
+                 FROM messages
+                 ORDER BY rowid DESC LIMIT ?1",
+            )
+            .map_err(Error::other)?;
+        let mut messages = stmt
+            .query_map(
+                [i64::try_from(limit).unwrap_or(i64::MAX)],
+                message_from_row,
+            )
+            .map_err(Error::other)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Error::other)?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn search_history(
+        &self,
+        channel_id: ChannelId,
+        query: &str,
+        before_msg_id: MsgId,
+        limit: usize,
+    ) -> Result<SearchPage> {
+        // SQLite only folds the case of ASCII letters, so the text is
+        // matched here
+        let mut stmt = self
+            .db
+            .prepare(
+                "SELECT msg_id, user_id, channel_id, message, content_type,
+                    quote_msg_id, quote_user_id, quote_text, time
+                 FROM messages
+                 WHERE channel_id = ?1 AND msg_id < ?2
+                 ORDER BY rowid DESC",
+            )
+            .map_err(Error::other)?;
+        let rows = stmt
+            .query_map(params![channel_id.0, before_msg_id.0], message_from_row)
+            .map_err(Error::other)?;
+        search_newest_first(
+            rows.map(|row| row.map_err(Error::other)),
+            channel_id,
+            query,
+            before_msg_id,
+            limit,
+        )
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<UserRecord>> {
+        let role: Option<String> = self
+            .db
+            .query_row(
+                "SELECT role FROM users WHERE name = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(Error::other)?;
+        role.map(|role| {
+            Ok(UserRecord {
+                name: name.to_owned(),
+                role: parse_role(&role)?,
+            })
+        })
+        .transpose()
+    }
+
+    fn put_user(&mut self, user: &UserRecord) -> Result<()> {
+        self.db
+            .execute(
+                "INSERT OR REPLACE INTO users (name, role) VALUES (?1, ?2)",
+                params![user.name, user.role.to_string()],
+            )
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        self.db
+            .execute("DELETE FROM users WHERE name = ?1", [name])
+            .map(|n| n > 0)
+            .map_err(Error::other)
+    }
+
+    fn list_users(&self) -> Result<Vec<UserRecord>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT name, role FROM users ORDER BY name")
+            .map_err(Error::other)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(Error::other)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Error::other)?;
+        rows.into_iter()
+            .map(|(name, role)| {
+                Ok(UserRecord {
+                    name,
+                    role: parse_role(&role)?,
+                })
+            })
+            .collect()
+    }
+
+    fn get_account(&self, name: &str) -> Result<Option<Account>> {
+        let row: Option<(u16, String, String)> = self
+            .db
+            .query_row(
+                "SELECT user_id, password, role FROM accounts WHERE name = ?1",
+                [name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(Error::other)?;
+        row.map(|(user_id, password, role)| {
+            Ok(Account {
+                name: name.to_owned(),
+                user_id: UserId(user_id),
+                password: password.parse().map_err(Error::other)?,
+                role: parse_role(&role)?,
+            })
+        })
+        .transpose()
+    }
+
+    fn put_account(&mut self, account: &Account) -> Result<()> {
+        self.db
+            .execute(
+                "INSERT OR REPLACE INTO accounts (name, user_id, password,
+                    role)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    account.name,
+                    account.user_id.0,
+                    account.password.to_string(),
+                    account.role.to_string(),
+                ],
+            )
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    fn delete_account(&mut self, name: &str) -> Result<bool> {
+        self.db
+            .execute("DELETE FROM accounts WHERE name = ?1", [name])
+            .map(|n| n > 0)
+            .map_err(Error::other)
+    }
+
+    fn list_accounts(&self) -> Result<Vec<Account>> {
+        let mut stmt = self
+            .db
+            .prepare(
+                "SELECT name, user_id, password, role FROM accounts
+                 ORDER BY name",
+            )
+            .map_err(Error::other)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u16>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })
+            .map_err(Error::other)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Error::other)?;
+        rows.into_iter()
+            .map(|(name, user_id, password, role)| {
+                Ok(Account {
+                    name,
+                    user_id: UserId(user_id),
+                    password: password.parse().map_err(Error::other)?,
+                    role: parse_role(&role)?,
+                })
+            })
+            .collect()
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> Result<()> {
+        self.db
+            .execute(
+                "INSERT OR IGNORE INTO bans (target) VALUES (?1)",
+                [ban.to_string()],
+            )
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    fn remove_ban(&mut self, ban: &Ban) -> Result<bool> {
+        self.db
+            .execute("DELETE FROM bans WHERE target = ?1", [ban.to_string()])
+            .map(|n| n > 0)
+            .map_err(Error::other)
+    }
+
+    fn list_bans(&self) -> Result<Vec<Ban>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT target FROM bans")
+            .map_err(Error::other)?;
+        let targets = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(Error::other)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Error::other)?;
+        targets
+            .iter()
+            .map(|target| target.parse().map_err(Error::other))
+            .collect()
+    }
+
+    fn put_channel(&mut self, channel: &ChannelRecord) -> Result<()> {
+        self.db
+            .execute(
+                "INSERT OR REPLACE INTO channels
+                 (name, channel_id, topic, archived) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    channel.name,
+                    channel.channel_id.0,
+                    channel.topic,
+                    channel.archived,
+                ],
+            )
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    fn list_channels(&self) -> Result<Vec<ChannelRecord>> {
+        let mut stmt = self
+            .db
+            .prepare(
+                "SELECT name, channel_id, topic, archived FROM channels
+                 ORDER BY name",
+            )
+            .map_err(Error::other)?;
+        let channels = stmt
+            .query_map([], |row| {
+                Ok(ChannelRecord {
+                    name: row.get(0)?,
+                    channel_id: ChannelId(row.get(1)?),
+                    topic: row.get(2)?,
+                    archived: row.get(3)?,
+                })
+            })
+            .map_err(Error::other)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Error::other)?;
+        Ok(channels)
+    }
+
+    fn put_invite(&mut self, invite: &Invite) -> Result<()> {
+        self.db
+            .execute(
+                "INSERT OR REPLACE INTO invites (token, uses_left, expires)
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    invite.token,
+                    invite.uses_left,
+                    invite
+                        .expires
+                        .map(|t| i64::try_from(t).unwrap_or(i64::MAX)),
+                ],
+            )
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    fn get_invite(&self, token: &str) -> Result<Option<Invite>> {
+        self.db
+            .query_row(
+                "SELECT uses_left, expires FROM invites WHERE token = ?1",
+                [token],
+                |row| {
+                    Ok(Invite {
+                        token: token.to_owned(),
+                        uses_left: row.get(0)?,
+                        expires: row
+                            .get::<_, Option<i64>>(1)?
+                            .map(|t| t.try_into().unwrap_or(0)),
+                    })
+                },
+            )
+            .optional()
+            .map_err(Error::other)
+    }
+
+    fn delete_invite(&mut self, token: &str) -> Result<bool> {
+        self.db
+            .execute("DELETE FROM invites WHERE token = ?1", [token])
+            .map(|n| n > 0)
+            .map_err(Error::other)
+    }
+
+    fn push_offline_whisper(&mut self, whisper: &OfflineWhisper) -> Result<()> {
+        self.db
+            .execute(
+                "INSERT INTO offline_whispers (target_user_id, user_id, name,
+                    message, time)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    whisper.target_user_id.0,
+                    whisper.user_id.0,
+                    whisper.name,
+                    whisper.message,
+                    i64::try_from(whisper.time).unwrap_or(i64::MAX),
+                ],
+            )
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    fn count_offline_whispers(&self, target_user_id: UserId) -> Result<usize> {
+        let count: i64 = self
+            .db
+            .query_row(
+                "SELECT COUNT(*) FROM offline_whispers
+                 WHERE target_user_id = ?1",
+                [target_user_id.0],
+                |row| row.get(0),
+            )
+            .map_err(Error::other)?;
+        Ok(count.try_into().unwrap_or(0))
+    }
+
+    fn take_offline_whispers(
+        &mut self,
+        target_user_id: UserId,
+    ) -> Result<Vec<OfflineWhisper>> {
+        // read and deleted together, so none are lost or sent twice
+        let tx = self.db.transaction().map_err(Error::other)?;
+        let whispers = tx
+            .prepare(
+                "SELECT user_id, name, message, time FROM offline_whispers
+                 WHERE target_user_id = ?1 ORDER BY rowid",
+            )
+            .map_err(Error::other)?
+            .query_map([target_user_id.0], |row| {
+                Ok(OfflineWhisper {
+                    target_user_id,
+                    user_id: UserId(row.get(0)?),
+                    name: row.get(1)?,
+                    message: row.get(2)?,
+                    time: row.get::<_, i64>(3)?.try_into().unwrap_or(0),
+                })
+            })
+            .map_err(Error::other)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Error::other)?;
+        tx.execute(
+            "DELETE FROM offline_whispers WHERE target_user_id = ?1",
+            [target_user_id.0],
+        )
+        .map_err(Error::other)?;
+        tx.commit().map_err(Error::other)?;
+        Ok(whispers)
+    }
+}
+
+fn parse_role(role: &str) -> Result<Role> {
+    role.parse().map_err(|_| {
+        Error::other(format!("invalid role `{role}` in the database"))
+    })
+}
+
+/// A message from the columns selected by the history queries.
+fn message_from_row(row: &Row) -> rusqlite::Result<ServerCommand> {
+    Ok(ServerCommand::Message {
+        msg_id: MsgId(row.get(0)?),
+        user_id: UserId(row.get(1)?),
+        channel_id: ChannelId(row.get(2)?),
+        message: row.get(3)?,
+        content_type: match row.get::<_, u16>(4)? {
+            1 => ContentType::Markdown,
+            _ => ContentType::Plain,
+        },
+        quote: match (row.get(5)?, row.get(6)?, row.get(7)?) {
+            (Some(msg_id), Some(user_id), Some(text)) => Some(Quote {
+                msg_id: MsgId(msg_id),
+                user_id: UserId(user_id),
+                text,
+            }),
+            _ => None,
+        },
+        time: row.get::<_, i64>(8)?.try_into().unwrap_or(0),
+    })
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use common::{TransferId, UserId};
+
+/// Transfers a user may have offered and not finished at the same time.
+const MAX_TRANSFERS_PER_SENDER: usize = 4;
+
+/// A file relayed from one user to another, chunk by chunk.
+#[derive(Debug)]
+pub struct Transfer {
+    pub sender: UserId,
+    pub receiver: UserId,
+    /// Size of the file as offered, in bytes.
+    pub size: u64,
+    /// Bytes relayed so far.
+    pub relayed: u64,
+    /// Whether the receiver accepted the file, no chunks are relayed before.
+    pub accepted: bool,
+}
+
+/// The file transfers under way on a server. Files are never stored, the
+/// server only checks that chunks go where they were accepted and stay
+/// within the offered size.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    transfers: HashMap<TransferId, Transfer>,
+    last_id: u16,
+}
+
+impl Transfers {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a transfer waiting for `receiver` to accept it, `None` if
+    /// the sender has too many already.
+    pub fn offer(
+        &mut self,
+        sender: UserId,
+        receiver: UserId,
+        size: u64,
+    ) -> Option<TransferId> {
+        let pending = self
+            .transfers
+            .values()
+            .filter(|t| t.sender == sender)
+            .count();
+        if pending >= MAX_TRANSFERS_PER_SENDER {
+            return None;
+        }
+        // ids are reused once the transfer is over, there are few at once
+        let id = loop {
+            self.last_id = self.last_id.wrapping_add(1);
+            let id = TransferId(self.last_id);
+            if !self.transfers.contains_key(&id) {
+                break id;
+            }
+        };
+        self.transfers.insert(
+            id,
+            Transfer {
+                sender,
+                receiver,
+                size,
+                relayed: 0,
+                accepted: false,
+            },
+        );
+        Some(id)
+    }
+
+    pub fn get_mut(&mut self, id: TransferId) -> Option<&mut Transfer> {
+        self.transfers.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: TransferId) -> Option<Transfer> {
+        self.transfers.remove(&id)
+    }
+
+    /// Ends the transfers `user_id` sends or receives, returning them.
+    pub fn remove_user(
+        &mut self,
+        user_id: UserId,
+    ) -> Vec<(TransferId, Transfer)> {
+        let ids: Vec<_> = self
+            .transfers
+            .iter()
+            .filter(|(_, t)| t.sender == user_id || t.receiver == user_id)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.transfers.remove(&id)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,330 @@
+//! Bridging rooms to other chat networks, like Matrix, Slack or Discord,
+//! over plain HTTP.
+//!
+//! Every message posted by a user here is POSTed to each configured URL
+//! as a JSON [`ArchiveRecord`]. Messages from the other side are POSTed to
+//! `/messages` on the inbound endpoint as
+//!
+//! ```text
+//! {"channel":"general","name":"alice-matrix","message":"hi"}
+//! ```
+//!
+//! and show up as sent by a user with that name. They are answered with
+//! `202 Accepted` before the server looks at them, so a message to a room
+//! that doesn't exist is only dropped with a warning. Bridged messages are
+//! not sent back to the URLs.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use ring::digest::{digest, SHA256};
+
+use crate::{ArchiveRecord, Wake};
+
+/// Largest request body the inbound endpoint reads.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Where messages are bridged to and from.
+#[derive(Debug, Clone, Default)]
+pub struct WebhookConfig {
+    /// Every message is POSTed to each of these.
+    pub urls: Vec<String>,
+    /// Where messages that could not be POSTed are appended.
+    pub dead_letter: PathBuf,
+    /// Accept messages from bridges on this address, if set.
+    pub inbound: Option<SocketAddr>,
+    /// Bridges must send this as `Authorization: Bearer <token>`, if set.
+    pub token: Option<String>,
+}
+
+/// A message from a bridge, to be posted under `name`.
+#[derive(Debug, Clone)]
+pub struct InboundMessage {
+    pub channel: String,
+    pub name: String,
+    pub message: String,
+}
+
+/// The open side of a [`WebhookConfig`].
+#[derive(Debug, Default)]
+pub struct Webhooks {
+    #[cfg(feature = "webhook")]
+    outbound: Vec<crate::webhook::Webhook>,
+    inbound: Option<Receiver<InboundMessage>>,
+}
+
+impl Webhooks {
+    /// Starts serving the inbound endpoint, calling `wake` whenever a
+    /// message arrives on it.
+    pub fn open(config: &WebhookConfig, wake: Wake) -> Result<Self> {
+        #[cfg(not(feature = "webhook"))]
+        if !config.urls.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "the server was built without webhook support",
+            ));
+        }
+        let inbound = match config.inbound {
+            Some(addr) => Some(serve(addr, config.token.clone(), wake)?),
+            None => None,
+        };
+        Ok(Self {
+            #[cfg(feature = "webhook")]
+            outbound: config
+                .urls
+                .iter()
+                .map(|url| {
+                    crate::webhook::Webhook::new(
+                        url.clone(),
+                        config.dead_letter.clone(),
+                    )
+                })
+                .collect(),
+            inbound,
+        })
+    }
+
+    /// POSTs `record` to every URL, in the background.
+    #[cfg_attr(
+        not(feature = "webhook"),
+        allow(unused_variables, clippy::unused_self)
+    )]
+    pub fn publish(&self, record: &ArchiveRecord) {
+        #[cfg(feature = "webhook")]
+        for webhook in &self.outbound {
+            webhook.send(record.to_string());
+        }
+    }
+
+    /// Returns the next message from a bridge, if any arrived.
+    pub fn poll(&self) -> Option<InboundMessage> {
+        self.inbound.as_ref()?.try_recv().ok()
+    }
+}
+
+/// Starts answering `POST /messages` on `addr`.
+fn serve(
+    addr: SocketAddr,
+    token: Option<String>,
+    wake: Wake,
+) -> Result<Receiver<InboundMessage>> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Accepting bridged messages on http://{addr}/messages");
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| {
+                respond(stream, token.as_deref(), &sender, &wake)
+            });
+            if let Err(e) = result {
+                warn!("Failed to serve a bridged message: {e}");
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// Answers one HTTP request, closing the connection afterwards.
+fn respond(
+    stream: TcpStream,
+    token: Option<&str>,
+    sender: &Sender<InboundMessage>,
+    wake: &Wake,
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut content_length = 0;
+    let mut authorization = None;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().unwrap_or(usize::MAX);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization =
+                    value.strip_prefix("Bearer ").map(str::to_owned);
+            }
+        }
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("POST"), Some("/messages")) => {
+            if !authorized(token, authorization.as_deref()) {
+                ("401 Unauthorized", "Missing or wrong token\n".to_owned())
+            } else if content_length > MAX_BODY_LEN {
+                ("413 Payload Too Large", "Body too large\n".to_owned())
+            } else {
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body)?;
+                match parse_message(&body) {
+                    Ok(message) => {
+                        // the server only stops taking them when it stops
+                        let _ = sender.send(message);
+                        wake();
+                        ("202 Accepted", String::new())
+                    }
+                    Err(reason) => ("400 Bad Request", reason + "\n"),
+                }
+            }
+        }
+        (_, Some("/messages")) => {
+            ("405 Method Not Allowed", "Method not allowed\n".to_owned())
+        }
+        _ => ("404 Not Found", "Not found\n".to_owned()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+fn authorized(token: Option<&str>, given: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    // comparing digests doesn't tell how much of the token was right
+    let hash = |t: &str| digest(&SHA256, t.as_bytes());
+    given.is_some_and(|given| hash(given).as_ref() == hash(token).as_ref())
+}
+
+fn parse_message(body: &[u8]) -> std::result::Result<InboundMessage, String> {
+    let body = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+    let mut fields =
+        parse_object(body).map_err(|e| format!("invalid JSON: {e}"))?;
+    let mut field = |name: &str| {
+        fields
+            .remove(name)
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| format!("`{name}` is missing or empty"))
+    };
+    Ok(InboundMessage {
+        channel: field("channel")?,
+        name: field("name")?,
+        message: field("message")?,
+    })
+}
+
+/// Parses a JSON object, keeping its string members. Other scalars are
+/// skipped, nested objects and arrays are not supported.
+fn parse_object(s: &str) -> Result<HashMap<String, String>> {
+    let invalid = |what: &str| Error::new(ErrorKind::InvalidData, what);
+    let mut chars = s.trim().chars().peekable();
+    let mut members = HashMap::new();
+    let skip_space = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    if chars.next() != Some('{') {
+        return Err(invalid("expected an object"));
+    }
+    skip_space(&mut chars);
+    if chars.next_if_eq(&'}').is_none() {
+        loop {
+            skip_space(&mut chars);
+            let key = parse_string(&mut chars)?;
+            skip_space(&mut chars);
+            if chars.next() != Some(':') {
+                return Err(invalid("expected `:`"));
+            }
+            skip_space(&mut chars);
+            if chars.peek() == Some(&'"') {
+                members.insert(key, parse_string(&mut chars)?);
+            } else {
+                let mut scalar = String::new();
+                while let Some(c) = chars.next_if(|c| {
+                    c.is_ascii_alphanumeric() || "+-.".contains(*c)
+                }) {
+                    scalar.push(c);
+                }
+                let number = scalar.parse::<f64>().is_ok();
+                if !number && !["true", "false", "null"].contains(&&*scalar) {
+                    return Err(invalid("expected a string or a scalar"));
+                }
+            }
+            skip_space(&mut chars);
+            match chars.next() {
+                Some(',') => (),
+                Some('}') => break,
+                _ => return Err(invalid("expected `,` or `}`")),
+            }
+        }
+    }
+    if chars.next().is_some() {
+        return Err(invalid("trailing characters"));
+    }
+    Ok(members)
+}
+
+fn parse_string(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<String> {
+    let invalid = |what: &str| Error::new(ErrorKind::InvalidData, what);
+    if chars.next() != Some('"') {
+        return Err(invalid("expected a string"));
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next().ok_or_else(|| invalid("unterminated string"))? {
+            '"' => return Ok(s),
+            '\\' => match chars.next() {
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('/') => s.push('/'),
+                Some('b') => s.push('\u{8}'),
+                Some('f') => s.push('\u{c}'),
+                Some('n') => s.push('\n'),
+                Some('r') => s.push('\r'),
+                Some('t') => s.push('\t'),
+                Some('u') => {
+                    let mut code = parse_hex4(chars)?;
+                    // a surrogate pair spells one character
+                    if (0xD800..0xDC00).contains(&code) {
+                        if chars.next() != Some('\\')
+                            || chars.next() != Some('u')
+                        {
+                            return Err(invalid("unpaired surrogate"));
+                        }
+                        let low = parse_hex4(chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(invalid("unpaired surrogate"));
+                        }
+                        code =
+                            0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    s.push(
+                        char::from_u32(code)
+                            .ok_or_else(|| invalid("invalid \\u escape"))?,
+                    );
+                }
+                _ => return Err(invalid("invalid escape")),
+            },
+            c if c.is_control() => {
+                return Err(invalid("unescaped control character"))
+            }
+            c => s.push(c),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<u32> {
+    let hex: String = chars.by_ref().take(4).collect();
+    u32::from_str_radix(&hex, 16)
+        .ok()
+        .filter(|_| hex.len() == 4)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid \\u escape"))
+}
@@ -0,0 +1,352 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::debug;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+
+use common::{DataSize, Transport};
+
+/// Appended to the client's key to prove the server speaks WebSocket.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest HTTP upgrade request accepted.
+const MAX_REQUEST_LEN: usize = 8192;
+/// Bytes written and not yet taken by the peer past which writes are
+/// refused, so the backlog stays with the
+/// [`Connection`](common::Connection), which counts it as queued.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Handshake,
+    Open,
+    Closed,
+}
+
+/// The server side of a WebSocket connection carrying the chat protocol.
+///
+/// Every binary message holds one coded command, without the size the raw
+/// protocol puts in front of it; the size is added to incoming messages and
+/// taken off outgoing ones, so a [`Connection`](common::Connection) can run
+/// over this like over a TCP stream. The upgrade handshake happens as the
+/// stream is read, writes before it finishes are held back.
+#[derive(Debug)]
+pub struct WebSocket<T> {
+    inner: T,
+    state: State,
+    /// Bytes read from the peer and not yet parsed.
+    raw: Vec<u8>,
+    /// Fragments of a message not yet finished.
+    fragments: Vec<u8>,
+    /// Commands received, in the framing of the raw protocol.
+    incoming: Vec<u8>,
+    /// Commands written, in the framing of the raw protocol.
+    pending: Vec<u8>,
+    /// Bytes to write to the peer.
+    outgoing: Vec<u8>,
+    /// Largest message accepted, like a frame of the raw protocol.
+    max_message_len: usize,
+}
+
+impl<T: Transport> WebSocket<T> {
+    /// Waits for the upgrade request of the client on `inner`.
+    pub const fn accept(inner: T, max_message_len: DataSize) -> Self {
+        Self {
+            inner,
+            state: State::Handshake,
+            raw: Vec::new(),
+            fragments: Vec::new(),
+            incoming: Vec::new(),
+            pending: Vec::new(),
+            outgoing: Vec::new(),
+            max_message_len: max_message_len as usize,
+        }
+    }
+
+    /// Answers the upgrade request once all of it is read.
+    fn handshake(&mut self) -> Result<()> {
+        let Some(end) = self.raw.windows(4).position(|w| w == b"\r\n\r\n")
+        else {
+            if self.raw.len() > MAX_REQUEST_LEN {
+                return Err(self.refuse("the upgrade request is too long"));
+            }
+            return Ok(());
+        };
+        let request = String::from_utf8_lossy(&self.raw[..end]).into_owned();
+        self.raw.drain(..end + 4);
+        let mut lines = request.split("\r\n");
+        if !lines.next().is_some_and(|line| line.starts_with("GET ")) {
+            return Err(self.refuse("expected a GET request"));
+        }
+        let key = lines
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| {
+                name.trim().eq_ignore_ascii_case("sec-websocket-key")
+            })
+            .map(|(_, value)| value.trim().to_owned());
+        let Some(key) = key else {
+            return Err(self.refuse("not a WebSocket upgrade request"));
+        };
+        let accept = BASE64_STANDARD.encode(digest(
+            &SHA1_FOR_LEGACY_USE_ONLY,
+            (key + ACCEPT_GUID).as_bytes(),
+        ));
+        self.outgoing.extend_from_slice(
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {accept}\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        self.state = State::Open;
+        self.frame_pending();
+        Ok(())
+    }
+
+    /// Answers a bad upgrade request and returns the error to fail with.
+    fn refuse(&mut self, reason: &str) -> Error {
+        debug!("Refusing a WebSocket upgrade: {reason}");
+        self.outgoing
+            .extend_from_slice(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        let _ = self.write_outgoing();
+        Error::new(ErrorKind::InvalidData, reason)
+    }
+
+    /// Parses the complete frames among the bytes read.
+    fn parse_frames(&mut self) -> Result<()> {
+        while self.state == State::Open {
+            let Some((header, len)) =
+                frame_header(&self.raw, self.max_message_len)?
+            else {
+                return Ok(());
+            };
+            if self.raw.len() < header + len {
+                return Ok(());
+            }
+            let (fin, opcode) = (self.raw[0] & 0x80 != 0, self.raw[0] & 0x0F);
+            let mask = [
+                self.raw[header - 4],
+                self.raw[header - 3],
+                self.raw[header - 2],
+                self.raw[header - 1],
+            ];
+            let payload: Vec<u8> = self.raw[header..header + len]
+                .iter()
+                .zip(mask.iter().cycle())
+                .map(|(byte, mask)| byte ^ mask)
+                .collect();
+            self.raw.drain(..header + len);
+            match opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    if self.fragments.len() + payload.len()
+                        > self.max_message_len
+                    {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "WebSocket message too long",
+                        ));
+                    }
+                    self.fragments.extend(payload);
+                    if fin {
+                        #[allow(clippy::cast_possible_truncation)]
+                        let size = self.fragments.len() as DataSize;
+                        self.incoming.extend_from_slice(&size.to_be_bytes());
+                        self.incoming.append(&mut self.fragments);
+                    }
+                }
+                OPCODE_PING => self.push_frame(OPCODE_PONG, &payload),
+                OPCODE_PONG => (),
+                OPCODE_CLOSE => {
+                    self.push_frame(OPCODE_CLOSE, &payload);
+                    self.state = State::Closed;
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unsupported WebSocket opcode {opcode}"),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the complete commands written into binary frames.
+    fn frame_pending(&mut self) {
+        const PREFIX: usize = size_of::<DataSize>();
+        while self.state == State::Open && self.pending.len() >= PREFIX {
+            let size = DataSize::from_be_bytes(
+                self.pending[..PREFIX].try_into().unwrap(),
+            );
+            let end = PREFIX + size as usize;
+            if self.pending.len() < end {
+                return;
+            }
+            let message: Vec<u8> =
+                self.pending.drain(..end).skip(PREFIX).collect();
+            self.push_frame(OPCODE_BINARY, &message);
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn push_frame(&mut self, opcode: u8, payload: &[u8]) {
+        self.outgoing.push(0x80 | opcode);
+        match payload.len() {
+            len @ ..=125 => self.outgoing.push(len as u8),
+            len @ ..=0xFFFF => {
+                self.outgoing.push(126);
+                self.outgoing.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.outgoing.push(127);
+                self.outgoing.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.outgoing.extend_from_slice(payload);
+    }
+
+    /// Writes out as much of the outgoing bytes as the stream takes.
+    fn write_outgoing(&mut self) -> Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.inner.write(&self.outgoing)? {
+                0 => return Err(Error::from(ErrorKind::WriteZero)),
+                n => drop(self.outgoing.drain(..n)),
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+/// Size of the header and of the payload of the frame at the start of
+/// `raw`, `None` if the header is not complete yet.
+fn frame_header(raw: &[u8], max_len: usize) -> Result<Option<(usize, usize)>> {
+    if raw.len() < 2 {
+        return Ok(None);
+    }
+    if raw[1] & 0x80 == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "unmasked WebSocket frame from a client",
+        ));
+    }
+    let (extended, len) = match raw[1] & 0x7F {
+        126 => match raw.get(2..4) {
+            Some(len) => (2, u16::from_be_bytes([len[0], len[1]]) as usize),
+            None => return Ok(None),
+        },
+        127 => match raw.get(2..10) {
+            Some(len) => {
+                let len = u64::from_be_bytes(len.try_into().unwrap());
+                (8, usize::try_from(len).unwrap_or(usize::MAX))
+            }
+            None => return Ok(None),
+        },
+        len => (0, len as usize),
+    };
+    if len > max_len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "WebSocket frame too long",
+        ));
+    }
+    let header = 2 + extended + 4;
+    Ok((raw.len() >= header).then_some((header, len)))
+}
+
+impl<T: Transport> Read for WebSocket<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            if !self.incoming.is_empty() {
+                let n = buf.len().min(self.incoming.len());
+                buf[..n].copy_from_slice(&self.incoming[..n]);
+                self.incoming.drain(..n);
+                return Ok(n);
+            }
+            if self.state == State::Closed {
+                return Ok(0);
+            }
+            let mut chunk = [0; 4096];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.raw.extend_from_slice(&chunk[..n]);
+            if self.state == State::Handshake {
+                self.handshake()?;
+            }
+            self.parse_frames()?;
+            match self.write_outgoing() {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<T: Transport> Write for WebSocket<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.write_outgoing() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+            _ => (),
+        }
+        if self.pending.len() + self.outgoing.len() >= MAX_BUFFERED {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        self.pending.extend_from_slice(buf);
+        self.frame_pending();
+        match self.write_outgoing() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_outgoing()
+    }
+}
+
+impl<T: Transport> Transport for WebSocket<T> {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn a_slow_peer_leaves_the_backlog_to_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut websocket = WebSocket::accept(stream, DataSize::MAX);
+        websocket.state = State::Open;
+        let mut command = 1000u32.to_be_bytes().to_vec();
+        command.resize(4 + 1000, b'x');
+        let refused =
+            (0..100_000).find_map(|_| websocket.write(&command).err());
+        assert_eq!(refused.map(|e| e.kind()), Some(ErrorKind::WouldBlock));
+        let buffered = websocket.pending.len() + websocket.outgoing.len();
+        assert!(buffered < MAX_BUFFERED + command.len() + 16);
+    }
+}
@@ -0,0 +1,247 @@
+//! A whole session over TLS on a server with a password: accounts, a room,
+//! messages, the history a late joiner is sent and the goodbyes. And how
+//! clients that have no authority for its certificate come to trust it.
+
+#![cfg(feature = "tls")]
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use common::commands::{ClientCommand, ContentType, Role, ServerCommand};
+use common::tls::{SeenCertificate, Unsigned};
+use common::{Connection, UserId, DEFAULT_MAX_FRAME_SIZE};
+use server::storage::{Account, MemoryStore, Store};
+use server::{Config, PasswordHash, Server, TlsConfig};
+
+type Client = Connection<ClientCommand, ServerCommand>;
+
+const PASSWORD: &str = "letmein";
+/// Longest wait for an answer, the server runs on the same thread.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn data(file: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(file)
+}
+
+fn start() -> (Server, SocketAddr) {
+    let config = Config {
+        password: Some(PASSWORD.to_owned()),
+        tls: Some(TlsConfig {
+            cert: data("localhost.crt"),
+            key: data("localhost.key"),
+        }),
+        history_size: 10,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        ..Config::default()
+    };
+    let mut store = MemoryStore::new();
+    store
+        .put_account(&Account {
+            name: "alice".to_owned(),
+            user_id: UserId(1),
+            password: PasswordHash::new("hunter2").unwrap(),
+            role: Role::User,
+        })
+        .unwrap();
+    let listener = "127.0.0.1:0".parse().unwrap();
+    let server =
+        Server::new(vec![listener], config, Box::new(store)).unwrap();
+    let addr = server.local_addrs().unwrap()[0];
+    (server, addr)
+}
+
+/// Connects over TLS, trusting only the test certificate.
+fn dial(addr: SocketAddr) -> Client {
+    let ca = data("localhost.crt");
+    let (client, _) = dial_with(addr, Some(&ca), Unsigned::Refuse);
+    client
+}
+
+fn dial_with(
+    addr: SocketAddr,
+    ca: Option<&Path>,
+    unsigned: Unsigned,
+) -> (Client, SeenCertificate) {
+    let (config, seen) = common::tls::client_config(ca, unsigned).unwrap();
+    let stream = TcpStream::connect(addr).unwrap();
+    let stream = common::tls::connect(config, "localhost", stream).unwrap();
+    (Connection::new(stream).unwrap(), seen)
+}
+
+fn send(client: &mut Client, command: ClientCommand) {
+    client.send(&command).unwrap();
+}
+
+/// Runs the server until `client` receives a command named `name`,
+/// returning everything it received up to and including it.
+fn until(
+    server: &mut Server,
+    client: &mut Client,
+    name: &str,
+) -> Vec<ServerCommand> {
+    let start = Instant::now();
+    let mut commands = vec![];
+    while start.elapsed() < TIMEOUT {
+        // the handshake may hold writes back until the server answers
+        match client.flush() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => panic!("{e}"),
+            _ => (),
+        }
+        server.wait(Duration::from_millis(10)).unwrap();
+        server.update().unwrap();
+        loop {
+            match client.receive() {
+                Ok(command) => {
+                    let done = command.name() == name;
+                    commands.push(command);
+                    if done {
+                        return commands;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("receiving failed: {e}"),
+            }
+        }
+    }
+    panic!("no {name} within {TIMEOUT:?}, got {commands:?}");
+}
+
+fn connect(name: &str) -> ClientCommand {
+    ClientCommand::Connect {
+        name: name.to_owned(),
+        invite: None,
+        credential: None,
+        password: Some(PASSWORD.to_owned()),
+    }
+}
+
+fn say(message: &str) -> ClientCommand {
+    ClientCommand::Message {
+        message: message.to_owned(),
+        content_type: ContentType::Plain,
+        quote: None,
+    }
+}
+
+fn join(server: &mut Server, client: &mut Client) -> Vec<ServerCommand> {
+    let name = "rust".to_owned();
+    send(client, ClientCommand::Join { name });
+    until(server, client, "joined")
+}
+
+/// Texts of the messages among `commands`, in the order they came.
+fn texts(commands: &[ServerCommand]) -> Vec<&str> {
+    commands
+        .iter()
+        .flat_map(|c| match c {
+            ServerCommand::Message { message, .. } => vec![message.as_str()],
+            ServerCommand::History { messages, .. } => {
+                messages.iter().map(|m| m.message.as_str()).collect()
+            }
+            _ => vec![],
+        })
+        .collect()
+}
+
+#[test]
+fn a_session_over_tls_with_accounts_and_rooms() {
+    let (mut server, addr) = start();
+
+    let mut stranger = dial(addr);
+    send(
+        &mut stranger,
+        ClientCommand::Connect {
+            name: "stranger".to_owned(),
+            invite: None,
+            credential: None,
+            password: None,
+        },
+    );
+    until(&mut server, &mut stranger, "connect_rejected");
+
+    // alice has an account already, bob makes one once connected
+    let mut alice = dial(addr);
+    let (name, password) = ("alice".to_owned(), "hunter2".to_owned());
+    send(&mut alice, ClientCommand::Login { name, password });
+    until(&mut server, &mut alice, "logged_in");
+    send(&mut alice, connect("alice"));
+    until(&mut server, &mut alice, "welcome");
+
+    let mut bob = dial(addr);
+    send(&mut bob, connect("bob"));
+    until(&mut server, &mut bob, "welcome");
+    let (name, password) = ("bob".to_owned(), "swordfish".to_owned());
+    send(&mut bob, ClientCommand::Register { name, password });
+    until(&mut server, &mut bob, "registered");
+
+    join(&mut server, &mut alice);
+    join(&mut server, &mut bob);
+    send(&mut alice, say("hi bob"));
+    until(&mut server, &mut alice, "message");
+    let seen = until(&mut server, &mut bob, "message");
+    assert_eq!(texts(&seen), ["hi bob"]);
+    send(&mut bob, say("hi alice"));
+    until(&mut server, &mut bob, "message");
+    let seen = until(&mut server, &mut alice, "message");
+    assert_eq!(texts(&seen), ["hi alice"]);
+
+    let mut carol = dial(addr);
+    send(&mut carol, connect("carol"));
+    until(&mut server, &mut carol, "welcome");
+    let mut seen = join(&mut server, &mut carol);
+    seen.extend(until(&mut server, &mut carol, "history"));
+    assert_eq!(texts(&seen), ["hi bob", "hi alice"]);
+
+    drop(bob);
+    let gone = until(&mut server, &mut alice, "remove_user");
+    let ServerCommand::RemoveUser { user_id } = gone.last().unwrap() else {
+        unreachable!();
+    };
+    let seen = until(&mut server, &mut carol, "remove_user");
+    assert!(matches!(
+        seen.last(),
+        Some(ServerCommand::RemoveUser { user_id: id }) if id == user_id
+    ));
+}
+
+/// The test certificate, as `openssl x509 -fingerprint -sha256` shows it.
+const FINGERPRINT: &str = "A2:59:BA:52:44:B9:EA:E9:EC:AF:12:E1:07:E1:22:B9:\
+                           5E:41:1F:CF:D8:F6:00:AA:99:31:98:FD:3C:92:51:41";
+
+#[test]
+fn unsigned_certificates_are_trusted_on_first_use_then_pinned() {
+    let (mut server, addr) = start();
+    for (name, unsigned) in [
+        ("first", Unsigned::TrustFirst),
+        ("again", Unsigned::Pinned(FINGERPRINT.to_owned())),
+    ] {
+        let (mut client, seen) = dial_with(addr, None, unsigned);
+        send(&mut client, connect(name));
+        until(&mut server, &mut client, "welcome");
+        let certificate = seen.get().unwrap();
+        assert_eq!(certificate.fingerprint, FINGERPRINT);
+        assert!(!certificate.signed);
+    }
+
+    for unsigned in [Unsigned::Refuse, Unsigned::Pinned("AB:CD".to_owned())]
+    {
+        let (mut client, seen) = dial_with(addr, None, unsigned);
+        send(&mut client, connect("refused"));
+        let start = Instant::now();
+        let error = loop {
+            let _ = client.flush();
+            server.wait(Duration::from_millis(10)).unwrap();
+            server.update().unwrap();
+            match client.receive() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => break e,
+                Ok(command) => panic!("got {command:?} from a refused server"),
+            }
+            assert!(start.elapsed() < TIMEOUT, "the handshake never failed");
+        };
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(seen.get().unwrap().fingerprint, FINGERPRINT);
+    }
+}